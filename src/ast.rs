@@ -17,6 +17,12 @@ pub enum Expr {
         callee: Box<Expr>,
         args: Vec<Expr>,
     },
+    /// `object:method(args)`, which passes `object` as the first argument
+    MethodCall {
+        object: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+    },
     Index {
         object: Box<Expr>,
         index: Box<Expr>,
@@ -25,6 +31,29 @@ pub enum Expr {
         object: Box<Expr>,
         field: String,
     },
+    Table(Vec<TableField>),
+    Function {
+        params: Vec<String>,
+        body: Vec<Statement>,
+    },
+    /// A parenthesized expression, which keeps only the first value of a
+    /// call
+    Paren(Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+pub enum TableField {
+    Positional(Expr),
+    Named(String, Expr),
+    Keyed(Expr, Expr),
+}
+
+/// A statement and the line it starts on, which the code compiled from it
+/// reports in errors.
+#[derive(Debug, Clone)]
+pub struct Statement {
+    pub line: usize,
+    pub stmt: Stmt,
 }
 
 #[derive(Debug, Clone)]
@@ -34,32 +63,48 @@ pub enum Stmt {
         target: String,
         value: Expr,
     },
+    IndexAssignment {
+        object: Expr,
+        index: Expr,
+        value: Expr,
+    },
+    /// `a, t.x = ...` with more than one target or value. Every target
+    /// is a name, index or field access.
+    MultipleAssignment {
+        targets: Vec<Expr>,
+        values: Vec<Expr>,
+    },
     LocalAssignment {
         names: Vec<String>,
         values: Vec<Expr>,
     },
     If {
         condition: Expr,
-        then_branch: Vec<Stmt>,
-        else_branch: Option<Vec<Stmt>>,
+        then_branch: Vec<Statement>,
+        else_branch: Option<Vec<Statement>>,
     },
     While {
         condition: Expr,
-        body: Vec<Stmt>,
+        body: Vec<Statement>,
     },
     For {
         var: String,
         start: Expr,
         end: Expr,
         step: Option<Expr>,
-        body: Vec<Stmt>,
+        body: Vec<Statement>,
+    },
+    ForIn {
+        vars: Vec<String>,
+        exprs: Vec<Expr>,
+        body: Vec<Statement>,
     },
     Function {
         name: String,
         params: Vec<String>,
-        body: Vec<Stmt>,
+        body: Vec<Statement>,
     },
-    Return(Option<Expr>),
+    Return(Vec<Expr>),
     Break,
 }
 
@@ -90,5 +135,5 @@ pub enum UnaryOp {
 
 #[derive(Debug, Clone)]
 pub struct Program {
-    pub statements: Vec<Stmt>,
+    pub statements: Vec<Statement>,
 }
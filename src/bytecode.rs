@@ -1,4 +1,10 @@
 use crate::value::Value;
+use std::rc::Rc;
+
+/// Result count of a call that keeps every result. The call leaves them on
+/// the stack followed by how many there are, for the instruction consuming
+/// them to take.
+pub const MULTIPLE: usize = usize::MAX;
 
 #[derive(Debug, Clone)]
 pub enum Instruction {
@@ -7,6 +13,9 @@ pub enum Instruction {
     StoreGlobal(String),
     LoadLocal(usize),
     StoreLocal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    CloseUpvalues(usize), // Close the captured locals from this slot on
 
     Add,
     Sub,
@@ -31,16 +40,23 @@ pub enum Instruction {
     JumpIfFalse(usize),
     JumpIfTrue(usize),
     Call(usize),
+    CallMulti(usize, usize), // Call keeping exactly this many results, or MULTIPLE
+    CallSpread(usize, usize), // Call with this many arguments and then a counted run of them
     Return,
+    ReturnMulti(usize), // Return this many values
+    ReturnSpread(usize), // Return this many values and then a counted run of them
 
     Pop,
     Dup,
 
-    MakeFunction(usize),
+    MakeFunction(usize), // Index into the chunk's function prototypes
 
     NewTable,
     GetIndex,
     SetIndex,
+    GetMethod(usize), // Index the receiver below this many arguments with the key on top
+    GetMethodSpread(usize), // GetMethod below this many arguments and then a counted run of them
+    SetList(usize), // Store a counted run in the table below it from this index on
 
     Concat,
 
@@ -53,6 +69,30 @@ pub struct Chunk {
     pub instructions: Vec<Instruction>,
     pub constants: Vec<Value>,
     pub line_numbers: Vec<usize>,
+    pub functions: Vec<Rc<FunctionProto>>,
+    /// How the chunk the code came from is named in messages, as in
+    /// `[string "x = 1"]` or `main.lua`
+    pub source: Rc<str>,
+}
+
+/// Compiled body of a Lua function, shared by every closure created from it.
+#[derive(Debug)]
+pub struct FunctionProto {
+    pub name: String,
+    pub param_count: usize,
+    pub chunk: Rc<Chunk>,
+    /// Variables of enclosing functions the function uses, captured when a
+    /// closure is created
+    pub upvalues: Vec<Capture>,
+}
+
+/// Where a new closure finds a variable it captures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capture {
+    /// A local of the function creating the closure, by slot
+    Local(usize),
+    /// An upvalue of the function creating the closure, by index
+    Upvalue(usize),
 }
 
 impl Chunk {
@@ -61,6 +101,8 @@ impl Chunk {
             instructions: Vec::new(),
             constants: Vec::new(),
             line_numbers: Vec::new(),
+            functions: Vec::new(),
+            source: "?".into(),
         }
     }
 
@@ -88,66 +130,131 @@ impl Chunk {
     }
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct Compiler {
     chunk: Chunk,
-    #[allow(dead_code)]
-    scope_depth: usize,
     locals: Vec<String>,
+    // Slots of the locals in scope that a nested function captures
+    captured: Vec<usize>,
+    // Names of the variables of enclosing functions this one uses, and
+    // where they come from
+    upvalues: Vec<(String, Capture)>,
+    // The compiler of the enclosing function, while compiling a nested one
+    parent: Option<Box<Compiler>>,
+    // Line of the statement being compiled
+    line: usize,
 }
 
 impl Compiler {
     pub fn new() -> Self {
         Self {
             chunk: Chunk::new(),
-            scope_depth: 0,
             locals: Vec::new(),
+            captured: Vec::new(),
+            upvalues: Vec::new(),
+            parent: None,
+            line: 0,
         }
     }
 
+    /// A compiler for the chunk `source` names in messages.
+    pub fn with_source(source: &str) -> Self {
+        let mut compiler = Self::new();
+        compiler.chunk.source = source.into();
+        compiler
+    }
+
     pub fn compile(&mut self, program: &crate::ast::Program) -> Result<Chunk, String> {
         for stmt in &program.statements {
             self.compile_statement(stmt)?;
         }
 
-        self.chunk.emit(Instruction::LoadConst(Value::Nil), 0);
-        self.chunk.emit(Instruction::Return, 0);
+        self.chunk.emit(Instruction::ReturnMulti(0), self.line);
 
         Ok(self.chunk.clone())
     }
 
-    fn compile_statement(&mut self, stmt: &crate::ast::Stmt) -> Result<(), String> {
-        match stmt {
+    fn compile_statement(&mut self, statement: &crate::ast::Statement) -> Result<(), String> {
+        self.line = statement.line;
+        match &statement.stmt {
             crate::ast::Stmt::Expression(expr) => {
                 self.compile_expression(expr)?;
-                self.chunk.emit(Instruction::Pop, 0);
+                self.chunk.emit(Instruction::Pop, self.line);
             }
 
             crate::ast::Stmt::Assignment { target, value } => {
                 self.compile_expression(value)?;
+                self.emit_store(target)?;
+            }
 
-                if let Some(local_index) = self.resolve_local(target) {
-                    self.chunk.emit(Instruction::StoreLocal(local_index), 0);
-                } else {
-                    self.chunk.emit(Instruction::StoreGlobal(target.clone()), 0);
-                }
+            crate::ast::Stmt::Function { name, params, body } => {
+                let index = self.compile_function(name, params, body)?;
+                self.chunk.emit(Instruction::MakeFunction(index), self.line);
+                self.emit_store(name)?;
             }
 
-            crate::ast::Stmt::LocalAssignment { names, values } => {
-                // Compile values first
-                for value in values {
-                    self.compile_expression(value)?;
+            crate::ast::Stmt::IndexAssignment { object, index, value } => {
+                self.compile_expression(object)?;
+                self.compile_expression(index)?;
+                self.compile_expression(value)?;
+                self.chunk.emit(Instruction::SetIndex, self.line);
+            }
+
+            crate::ast::Stmt::MultipleAssignment { targets, values } => {
+                // Tables and keys of the targets are evaluated before the
+                // values, and kept in hidden locals until they are stored to
+                let mut indexed = Vec::new();
+                for target in targets {
+                    match target {
+                        crate::ast::Expr::Index { object, index } => {
+                            self.compile_expression(object)?;
+                            self.compile_expression(index)?;
+                        }
+                        crate::ast::Expr::FieldAccess { object, field } => {
+                            self.compile_expression(object)?;
+                            self.chunk.emit(Instruction::LoadConst(Value::String(field.as_str().into())), self.line);
+                        }
+                        _ => continue,
+                    }
+                    let first = self.locals.len();
+                    self.add_local("(assign table)".to_string());
+                    self.add_local("(assign key)".to_string());
+                    self.chunk.emit(Instruction::StoreLocal(first + 1), self.line);
+                    self.chunk.emit(Instruction::StoreLocal(first), self.line);
+                    indexed.push(first);
                 }
 
-                // Pad with nils if fewer values than names
-                for _ in values.len()..names.len() {
-                    self.chunk.emit(Instruction::LoadConst(Value::Nil), 0);
+                self.compile_expression_list(values, targets.len())?;
+
+                let value = self.locals.len();
+                self.add_local("(assign value)".to_string());
+                for target in targets.iter().rev() {
+                    if let crate::ast::Expr::Identifier(name) = target {
+                        self.emit_store(name)?;
+                        continue;
+                    }
+                    let table = indexed.pop().unwrap();
+                    self.chunk.emit(Instruction::StoreLocal(value), self.line);
+                    self.chunk.emit(Instruction::LoadLocal(table), self.line);
+                    self.chunk.emit(Instruction::LoadLocal(table + 1), self.line);
+                    self.chunk.emit(Instruction::LoadLocal(value), self.line);
+                    self.chunk.emit(Instruction::SetIndex, self.line);
                 }
+            }
+
+            crate::ast::Stmt::LocalAssignment { names, values } => {
+                self.compile_expression_list(values, names.len())?;
 
                 // Store in locals
                 for name in names.iter().rev() {
                     self.add_local(name.clone());
                     let local_index = self.locals.len() - 1;
-                    self.chunk.emit(Instruction::StoreLocal(local_index), 0);
+                    self.chunk.emit(Instruction::StoreLocal(local_index), self.line);
                 }
             }
 
@@ -155,17 +262,13 @@ impl Compiler {
                 self.compile_expression(condition)?;
                 let else_jump = self.emit_jump(Instruction::JumpIfFalse(0));
 
-                for stmt in then_branch {
-                    self.compile_statement(stmt)?;
-                }
+                self.compile_block(then_branch)?;
 
                 let end_jump = self.emit_jump(Instruction::Jump(0));
                 self.patch_jump(else_jump);
 
                 if let Some(else_stmts) = else_branch {
-                    for stmt in else_stmts {
-                        self.compile_statement(stmt)?;
-                    }
+                    self.compile_block(else_stmts)?;
                 }
 
                 self.patch_jump(end_jump);
@@ -177,73 +280,127 @@ impl Compiler {
                 self.compile_expression(condition)?;
                 let exit_jump = self.emit_jump(Instruction::JumpIfFalse(0));
 
-                for stmt in body {
-                    self.compile_statement(stmt)?;
-                }
+                self.compile_block(body)?;
 
-                self.chunk.emit(Instruction::Jump(loop_start), 0);
+                self.chunk.emit(Instruction::Jump(loop_start), self.line);
                 self.patch_jump(exit_jump);
             }
 
-            crate::ast::Stmt::Return(expr) => {
-                if let Some(expr) = expr {
-                    self.compile_expression(expr)?;
-                } else {
-                    self.chunk.emit(Instruction::LoadConst(Value::Nil), 0);
+            crate::ast::Stmt::Return(values) => match values.split_last() {
+                Some((last, rest)) if Self::is_call(last) => {
+                    for value in rest {
+                        self.compile_expression(value)?;
+                    }
+                    self.compile_values(last, MULTIPLE)?;
+                    self.chunk.emit(Instruction::ReturnSpread(rest.len()), self.line);
                 }
-                self.chunk.emit(Instruction::Return, 0);
-            }
+                Some((value, [])) => {
+                    self.compile_expression(value)?;
+                    self.chunk.emit(Instruction::Return, self.line);
+                }
+                _ => {
+                    for value in values {
+                        self.compile_expression(value)?;
+                    }
+                    self.chunk.emit(Instruction::ReturnMulti(values.len()), self.line);
+                }
+            },
 
             crate::ast::Stmt::For { var, start, end, step, body } => {
                 // Compile start value and store in loop variable
+                let scope = self.locals.len();
                 self.compile_expression(start)?;
                 self.add_local(var.clone());
                 let var_index = self.locals.len() - 1;
-                self.chunk.emit(Instruction::StoreLocal(var_index), 0);
+                self.chunk.emit(Instruction::StoreLocal(var_index), self.line);
 
                 // Compile and store end value
                 self.compile_expression(end)?;
                 self.add_local(format!("{}_end", var));
                 let end_index = self.locals.len() - 1;
-                self.chunk.emit(Instruction::StoreLocal(end_index), 0);
+                self.chunk.emit(Instruction::StoreLocal(end_index), self.line);
 
                 // Compile and store step value
                 if let Some(step_expr) = step {
                     self.compile_expression(step_expr)?;
                 } else {
-                    self.chunk.emit(Instruction::LoadConst(Value::Number(1.0)), 0);
+                    self.chunk.emit(Instruction::LoadConst(Value::Number(1.0)), self.line);
                 }
                 self.add_local(format!("{}_step", var));
                 let step_index = self.locals.len() - 1;
-                self.chunk.emit(Instruction::StoreLocal(step_index), 0);
+                self.chunk.emit(Instruction::StoreLocal(step_index), self.line);
 
                 // Mark the start of the loop
                 let loop_start = self.chunk.instructions.len();
 
                 // Load loop variable and end value for comparison
-                self.chunk.emit(Instruction::LoadLocal(var_index), 0);
-                self.chunk.emit(Instruction::LoadLocal(end_index), 0);
-                self.chunk.emit(Instruction::LessEqual, 0);
+                self.chunk.emit(Instruction::LoadLocal(var_index), self.line);
+                self.chunk.emit(Instruction::LoadLocal(end_index), self.line);
+                self.chunk.emit(Instruction::LessEqual, self.line);
 
                 // Jump out of loop if condition is false
                 let exit_jump = self.emit_jump(Instruction::JumpIfFalse(0));
 
-                // Compile loop body
-                for stmt in body {
-                    self.compile_statement(stmt)?;
-                }
+                // Compile loop body. Each iteration gets its own copy of the
+                // loop variable to capture.
+                self.compile_block(body)?;
+                self.close_captured(var_index);
 
                 // Increment loop variable
-                self.chunk.emit(Instruction::LoadLocal(var_index), 0);     // current value
-                self.chunk.emit(Instruction::LoadLocal(step_index), 0);    // step
-                self.chunk.emit(Instruction::Add, 0);
-                self.chunk.emit(Instruction::StoreLocal(var_index), 0);
+                self.chunk.emit(Instruction::LoadLocal(var_index), self.line);     // current value
+                self.chunk.emit(Instruction::LoadLocal(step_index), self.line);    // step
+                self.chunk.emit(Instruction::Add, self.line);
+                self.chunk.emit(Instruction::StoreLocal(var_index), self.line);
 
                 // Jump back to loop start
-                self.chunk.emit(Instruction::Jump(loop_start), 0);
+                self.chunk.emit(Instruction::Jump(loop_start), self.line);
 
                 // Patch the exit jump
                 self.patch_jump(exit_jump);
+                self.end_scope(scope);
+            }
+
+            crate::ast::Stmt::ForIn { vars, exprs, body } => {
+                // Iterator function, invariant state and control variable
+                self.compile_expression_list(exprs, 3)?;
+                let first = self.locals.len();
+                for name in ["(for iterator)", "(for state)", "(for control)"] {
+                    self.add_local(name.to_string());
+                }
+                for index in (first..first + 3).rev() {
+                    self.chunk.emit(Instruction::StoreLocal(index), self.line);
+                }
+                let (iterator, state, control) = (first, first + 1, first + 2);
+
+                let loop_start = self.chunk.instructions.len();
+                self.chunk.emit(Instruction::LoadLocal(state), self.line);
+                self.chunk.emit(Instruction::LoadLocal(control), self.line);
+                self.chunk.emit(Instruction::LoadLocal(iterator), self.line);
+                self.chunk.emit(Instruction::CallMulti(2, vars.len()), self.line);
+
+                let first_var = self.locals.len();
+                for var in vars {
+                    self.add_local(var.clone());
+                }
+                for index in (first_var..first_var + vars.len()).rev() {
+                    self.chunk.emit(Instruction::StoreLocal(index), self.line);
+                }
+
+                // The loop ends when the iterator returns nil
+                self.chunk.emit(Instruction::LoadLocal(first_var), self.line);
+                self.chunk.emit(Instruction::LoadConst(Value::Nil), self.line);
+                self.chunk.emit(Instruction::Equal, self.line);
+                let exit_jump = self.emit_jump(Instruction::JumpIfTrue(0));
+
+                self.chunk.emit(Instruction::LoadLocal(first_var), self.line);
+                self.chunk.emit(Instruction::StoreLocal(control), self.line);
+
+                self.compile_block(body)?;
+                self.close_captured(first_var);
+
+                self.chunk.emit(Instruction::Jump(loop_start), self.line);
+                self.patch_jump(exit_jump);
+                self.end_scope(first);
             }
 
             _ => return Err("Statement not implemented".to_string()),
@@ -255,17 +412,24 @@ impl Compiler {
     fn compile_expression(&mut self, expr: &crate::ast::Expr) -> Result<(), String> {
         match expr {
             crate::ast::Expr::Literal(value) => {
-                self.chunk.emit(Instruction::LoadConst(value.clone()), 0);
+                self.chunk.emit(Instruction::LoadConst(value.clone()), self.line);
             }
 
             crate::ast::Expr::Identifier(name) => {
                 if let Some(local_index) = self.resolve_local(name) {
-                    self.chunk.emit(Instruction::LoadLocal(local_index), 0);
+                    self.chunk.emit(Instruction::LoadLocal(local_index), self.line);
+                } else if let Some(upvalue) = self.resolve_upvalue(name) {
+                    self.chunk.emit(Instruction::GetUpvalue(upvalue), self.line);
                 } else {
-                    self.chunk.emit(Instruction::LoadGlobal(name.clone()), 0);
+                    self.chunk.emit(Instruction::LoadGlobal(name.clone()), self.line);
                 }
             }
 
+            crate::ast::Expr::Function { params, body } => {
+                let index = self.compile_function("anonymous", params, body)?;
+                self.chunk.emit(Instruction::MakeFunction(index), self.line);
+            }
+
             crate::ast::Expr::Binary { left, operator, right } => {
                 self.compile_expression(left)?;
                 self.compile_expression(right)?;
@@ -288,7 +452,7 @@ impl Compiler {
                     crate::ast::BinaryOp::Concat => Instruction::Concat,
                 };
 
-                self.chunk.emit(instruction, 0);
+                self.chunk.emit(instruction, self.line);
             }
 
             crate::ast::Expr::Unary { operator, operand } => {
@@ -299,35 +463,253 @@ impl Compiler {
                     crate::ast::UnaryOp::Not => Instruction::Not,
                 };
 
-                self.chunk.emit(instruction, 0);
+                self.chunk.emit(instruction, self.line);
             }
 
-            crate::ast::Expr::Call { callee, args } => {
-                // First compile all arguments
-                for arg in args {
-                    self.compile_expression(arg)?;
-                }
-
-                // Then compile the function (so it's on top of stack)
-                self.compile_expression(callee)?;
+            crate::ast::Expr::Call { .. } | crate::ast::Expr::MethodCall { .. } => {
+                self.compile_values(expr, 1)?;
+            }
 
-                self.chunk.emit(Instruction::Call(args.len()), 0);
+            crate::ast::Expr::Paren(expr) => {
+                self.compile_expression(expr)?;
             }
 
             crate::ast::Expr::FieldAccess { object, field } => {
                 self.compile_expression(object)?;
-                self.chunk.emit(Instruction::LoadConst(Value::String(field.clone())), 0);
-                self.chunk.emit(Instruction::GetIndex, 0);
+                self.chunk.emit(Instruction::LoadConst(Value::String(field.as_str().into())), self.line);
+                self.chunk.emit(Instruction::GetIndex, self.line);
+            }
+
+            crate::ast::Expr::Index { object, index } => {
+                self.compile_expression(object)?;
+                self.compile_expression(index)?;
+                self.chunk.emit(Instruction::GetIndex, self.line);
+            }
+
+            crate::ast::Expr::Table(fields) => {
+                self.chunk.emit(Instruction::NewTable, self.line);
+
+                let mut array_index = 0;
+                for (position, field) in fields.iter().enumerate() {
+                    // Keep the table on the stack while each field is stored
+                    self.chunk.emit(Instruction::Dup, self.line);
+                    match field {
+                        // A call at the end fills in all of its results
+                        crate::ast::TableField::Positional(value)
+                            if position + 1 == fields.len() && Self::is_call(value) =>
+                        {
+                            self.compile_values(value, MULTIPLE)?;
+                            self.chunk.emit(Instruction::SetList(array_index + 1), self.line);
+                            continue;
+                        }
+                        crate::ast::TableField::Positional(value) => {
+                            array_index += 1;
+                            self.chunk.emit(Instruction::LoadConst(Value::Number(array_index as f64)), self.line);
+                            self.compile_expression(value)?;
+                        }
+                        crate::ast::TableField::Named(name, value) => {
+                            self.chunk.emit(Instruction::LoadConst(Value::String(name.as_str().into())), self.line);
+                            self.compile_expression(value)?;
+                        }
+                        crate::ast::TableField::Keyed(key, value) => {
+                            self.compile_expression(key)?;
+                            self.compile_expression(value)?;
+                        }
+                    }
+                    self.chunk.emit(Instruction::SetIndex, self.line);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        name: &str,
+        params: &[String],
+        body: &[crate::ast::Statement],
+    ) -> Result<usize, String> {
+        let mut compiler = Compiler::new();
+        compiler.chunk.source = self.chunk.source.clone();
+        for param in params {
+            compiler.add_local(param.clone());
+        }
+
+        // The nested compiler keeps this one as its parent while it runs,
+        // so that names it resolves can capture locals here
+        let parent = std::mem::replace(self, compiler);
+        self.parent = Some(Box::new(parent));
+        let result = body.iter().try_for_each(|stmt| self.compile_statement(stmt));
+        let parent = self.parent.take().expect("nested compiler without a parent");
+        let mut compiler = std::mem::replace(self, *parent);
+        result?;
+        compiler.chunk.emit(Instruction::ReturnMulti(0), compiler.line);
+
+        self.chunk.functions.push(Rc::new(FunctionProto {
+            name: name.to_string(),
+            param_count: params.len(),
+            chunk: Rc::new(compiler.chunk),
+            upvalues: compiler.upvalues.into_iter().map(|(_, capture)| capture).collect(),
+        }));
+        Ok(self.chunk.functions.len() - 1)
+    }
+
+    fn emit_store(&mut self, name: &str) -> Result<(), String> {
+        if let Some(local_index) = self.resolve_local(name) {
+            self.chunk.emit(Instruction::StoreLocal(local_index), self.line);
+        } else if let Some(upvalue) = self.resolve_upvalue(name) {
+            self.chunk.emit(Instruction::SetUpvalue(upvalue), self.line);
+        } else {
+            self.chunk.emit(Instruction::StoreGlobal(name.to_string()), self.line);
+        }
+        Ok(())
+    }
+
+    // Compiles `body` as a block, whose locals go out of scope at its end
+    fn compile_block(&mut self, body: &[crate::ast::Statement]) -> Result<(), String> {
+        let scope = self.locals.len();
+        for stmt in body {
+            self.compile_statement(stmt)?;
+        }
+        self.end_scope(scope);
+        Ok(())
+    }
+
+    // Ends the scope of the locals from slot `first` on
+    fn end_scope(&mut self, first: usize) {
+        self.close_captured(first);
+        self.locals.truncate(first);
+    }
+
+    // Closes the locals from slot `first` on if a nested function captured
+    // any of them, so that the closures keep the variables they captured
+    // and the slots hold new ones the next time they are declared
+    fn close_captured(&mut self, first: usize) {
+        if self.captured.iter().any(|&slot| slot >= first) {
+            self.captured.retain(|&slot| slot < first);
+            self.chunk.emit(Instruction::CloseUpvalues(first), self.line);
+        }
+    }
+
+    fn is_call(expr: &crate::ast::Expr) -> bool {
+        matches!(expr, crate::ast::Expr::Call { .. } | crate::ast::Expr::MethodCall { .. })
+    }
+
+    // Leaves exactly `count` values on the stack: the values of `exprs`
+    // with the results of a call at the end filling in the missing ones,
+    // then nils. Values beyond `count` are evaluated and dropped.
+    fn compile_expression_list(&mut self, exprs: &[crate::ast::Expr], count: usize) -> Result<(), String> {
+        for (position, expr) in exprs.iter().enumerate() {
+            if position + 1 == exprs.len() && position < count {
+                return self.compile_values(expr, count - position);
             }
+            self.compile_expression(expr)?;
+            if position >= count {
+                self.chunk.emit(Instruction::Pop, self.line);
+            }
+        }
+        for _ in exprs.len()..count {
+            self.chunk.emit(Instruction::LoadConst(Value::Nil), self.line);
+        }
+        Ok(())
+    }
 
-            _ => return Err("Expression not implemented".to_string()),
+    // Leaves `count` values of `expr` on the stack, all the results of a
+    // call and their count for MULTIPLE, or its value followed by nils if
+    // it is not a call
+    fn compile_values(&mut self, expr: &crate::ast::Expr, count: usize) -> Result<(), String> {
+        match expr {
+            crate::ast::Expr::Call { callee, args } => self.compile_call(callee, args, count),
+            crate::ast::Expr::MethodCall { object, method, args } => {
+                self.compile_method_call(object, method, args, count)
+            }
+            _ => {
+                self.compile_expression(expr)?;
+                if count == MULTIPLE {
+                    self.chunk.emit(Instruction::LoadConst(Value::Number(1.0)), self.line);
+                } else {
+                    for _ in 1..count {
+                        self.chunk.emit(Instruction::LoadConst(Value::Nil), self.line);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Arguments of a call; a call in the last position passes all of its
+    // results. Returns how many values there are before those results and
+    // whether they follow.
+    fn compile_arguments(&mut self, args: &[crate::ast::Expr]) -> Result<(usize, bool), String> {
+        match args.split_last() {
+            Some((last, rest)) if Self::is_call(last) => {
+                for arg in rest {
+                    self.compile_expression(arg)?;
+                }
+                self.compile_values(last, MULTIPLE)?;
+                Ok((rest.len(), true))
+            }
+            _ => {
+                for arg in args {
+                    self.compile_expression(arg)?;
+                }
+                Ok((args.len(), false))
+            }
+        }
+    }
+
+    fn emit_call(&mut self, arg_count: usize, spread: bool, result_count: usize) {
+        let instruction = match (spread, result_count) {
+            (true, _) => Instruction::CallSpread(arg_count, result_count),
+            (false, 1) => Instruction::Call(arg_count),
+            (false, _) => Instruction::CallMulti(arg_count, result_count),
+        };
+        self.chunk.emit(instruction, self.line);
+    }
+
+    // Emits a call leaving `result_count` results on the stack
+    fn compile_call(
+        &mut self,
+        callee: &crate::ast::Expr,
+        args: &[crate::ast::Expr],
+        result_count: usize,
+    ) -> Result<(), String> {
+        // First compile all arguments
+        let (arg_count, spread) = self.compile_arguments(args)?;
+
+        // Then compile the function (so it's on top of stack)
+        self.compile_expression(callee)?;
+
+        self.emit_call(arg_count, spread, result_count);
+        Ok(())
+    }
+
+    // The receiver is evaluated once and passed as the first argument; the
+    // method is looked up on it after the other arguments are evaluated
+    fn compile_method_call(
+        &mut self,
+        object: &crate::ast::Expr,
+        method: &str,
+        args: &[crate::ast::Expr],
+        result_count: usize,
+    ) -> Result<(), String> {
+        self.compile_expression(object)?;
+        let (arg_count, spread) = self.compile_arguments(args)?;
+
+        self.chunk.emit(Instruction::LoadConst(Value::String(method.into())), self.line);
+        if spread {
+            self.chunk.emit(Instruction::GetMethodSpread(arg_count), self.line);
+        } else {
+            self.chunk.emit(Instruction::GetMethod(arg_count), self.line);
         }
 
+        self.emit_call(arg_count + 1, spread, result_count);
         Ok(())
     }
 
     fn emit_jump(&mut self, instruction: Instruction) -> usize {
-        self.chunk.emit(instruction, 0);
+        self.chunk.emit(instruction, self.line);
         self.chunk.instructions.len() - 1
     }
 
@@ -347,4 +729,30 @@ impl Compiler {
         }
         None
     }
+
+    // Finds `name` among the variables of the enclosing functions, which
+    // every function in between captures too
+    fn resolve_upvalue(&mut self, name: &str) -> Option<usize> {
+        if let Some(index) = self.upvalues.iter().position(|(upvalue, _)| upvalue == name) {
+            return Some(index);
+        }
+        let parent = self.parent.as_mut()?;
+        let capture = match parent.resolve_local(name) {
+            Some(slot) => {
+                if !parent.captured.contains(&slot) {
+                    parent.captured.push(slot);
+                }
+                Capture::Local(slot)
+            }
+            None => Capture::Upvalue(parent.resolve_upvalue(name)?),
+        };
+        self.upvalues.push((name.to_string(), capture));
+        Some(self.upvalues.len() - 1)
+    }
+}
+
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
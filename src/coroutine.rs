@@ -0,0 +1,185 @@
+use crate::gc::{write_barrier, GcBox, GcObject, Trace, Tracer};
+use crate::nanbox::{PackedValue, ValueStack};
+use crate::runtime::CallFrame;
+use crate::value::Value;
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoroutineStatus {
+    /// Not started yet, or stopped in a call to `yield`
+    Suspended,
+    Running,
+    /// Active, but waiting on a coroutine it resumed
+    Normal,
+    Dead,
+}
+
+impl CoroutineStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CoroutineStatus::Suspended => "suspended",
+            CoroutineStatus::Running => "running",
+            CoroutineStatus::Normal => "normal",
+            CoroutineStatus::Dead => "dead",
+        }
+    }
+}
+
+/// A Lua thread. Every coroutine has its own value stack and call frames;
+/// they are kept here while it is suspended and moved into the runtime
+/// while it runs.
+pub struct Coroutine {
+    pub(crate) function: Value,
+    pub(crate) stack: ValueStack,
+    pub(crate) call_stack: Vec<CallFrame>,
+    pub(crate) status: CoroutineStatus,
+    pub(crate) started: bool,
+    // How many results the call to `yield` it is suspended in expects
+    pub(crate) yield_results: usize,
+    // Depth of native-to-Lua calls when it was resumed. A yield from any
+    // deeper would have to unwind native frames, which cannot be resumed.
+    pub(crate) native_depth: usize,
+    // The error it died with, reported again by `close`
+    pub(crate) error: Option<Value>,
+}
+
+impl Coroutine {
+    pub fn new(function: Value) -> Self {
+        Self {
+            function,
+            stack: ValueStack::new(),
+            call_stack: Vec::new(),
+            status: CoroutineStatus::Suspended,
+            started: false,
+            yield_results: 0,
+            native_depth: 0,
+            error: None,
+        }
+    }
+
+    /// The main thread, which is running from the start and has no
+    /// function to run.
+    pub(crate) fn main() -> Self {
+        Self {
+            status: CoroutineStatus::Running,
+            started: true,
+            ..Self::new(Value::Nil)
+        }
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.status
+    }
+}
+
+impl Trace for Coroutine {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark_value(&self.function);
+        if let Some(error) = &self.error {
+            tracer.mark_value(error);
+        }
+        for value in self.stack.iter() {
+            tracer.mark_value(&value);
+        }
+        for frame in &self.call_stack {
+            frame.trace(tracer);
+        }
+    }
+
+    fn clear_references(&mut self) -> Vec<Value> {
+        let mut references = vec![std::mem::replace(&mut self.function, Value::Nil)];
+        references.extend(self.error.take());
+        references.extend(self.stack.drain(..));
+        for frame in self.call_stack.drain(..) {
+            references.extend(frame.references().map(|value| value.clone()));
+        }
+        self.status = CoroutineStatus::Dead;
+        references
+    }
+
+    fn estimated_size(&self) -> usize {
+        let slot = std::mem::size_of::<PackedValue>();
+        let frames: usize = self
+            .call_stack
+            .iter()
+            .map(|frame| std::mem::size_of::<CallFrame>() + frame.locals().count() * slot)
+            .sum();
+        std::mem::size_of::<GcBox<RefCell<Coroutine>>>() + self.stack.len() * slot + frames
+    }
+}
+
+/// Shared handle to a coroutine. Clones alias the same thread.
+#[derive(Clone)]
+pub struct CoroutineRef(Rc<GcBox<RefCell<Coroutine>>>);
+
+impl CoroutineRef {
+    /// Allocates a coroutine that is not registered with any collector. Use
+    /// `Heap::alloc_coroutine` for coroutines that may take part in cycles.
+    pub fn new(coroutine: Coroutine) -> Self {
+        Self(Rc::new(GcBox::new(RefCell::new(coroutine))))
+    }
+
+    pub(crate) fn from_gc_box(gc_box: GcBox<RefCell<Coroutine>>) -> Self {
+        Self(Rc::new(gc_box))
+    }
+
+    pub(crate) fn from_rc(gc_box: Rc<GcBox<RefCell<Coroutine>>>) -> Self {
+        Self(gc_box)
+    }
+
+    pub(crate) fn gc_box(&self) -> &Rc<GcBox<RefCell<Coroutine>>> {
+        &self.0
+    }
+
+    pub fn borrow(&self) -> Ref<'_, Coroutine> {
+        self.0.borrow()
+    }
+
+    /// Mutable access for the runtime, which only changes a coroutine while
+    /// switching to or from it. Goes through the collector's write barrier,
+    /// since a suspending coroutine takes back a stack full of values the
+    /// collector may not have seen in it.
+    pub(crate) fn borrow_mut(&self) -> RefMut<'_, Coroutine> {
+        if self.0.header().needs_barrier() {
+            write_barrier(GcObject::Thread(self.clone()));
+        }
+        self.0.borrow_mut()
+    }
+
+    pub fn status(&self) -> CoroutineStatus {
+        self.borrow().status
+    }
+
+    pub fn ptr_eq(&self, other: &CoroutineRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const GcBox<RefCell<Coroutine>>))
+    }
+}
+
+impl PartialEq for CoroutineRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl fmt::Debug for CoroutineRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "thread: {:p}", self.as_ptr())
+    }
+}
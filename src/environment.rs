@@ -1,4 +1,6 @@
+use crate::function::NativeRef;
 use crate::value::Value;
+use crate::vm::BuiltinFunction;
 use std::collections::HashMap;
 
 #[derive(Debug, Clone)]
@@ -30,13 +32,18 @@ impl Environment {
 
     fn add_builtins(&mut self) {
         // Add built-in functions
-        self.global_scope.insert("print".to_string(), Value::Function(0));
-        self.global_scope.insert("type".to_string(), Value::Function(1));
-        self.global_scope.insert("tostring".to_string(), Value::Function(2));
-        self.global_scope.insert("tonumber".to_string(), Value::Function(3));
+        let builtins: [(&str, BuiltinFunction); 4] = [
+            ("print", crate::stdlib::builtin_print),
+            ("type", crate::stdlib::builtin_type),
+            ("tostring", crate::stdlib::builtin_tostring),
+            ("tonumber", crate::stdlib::builtin_tonumber),
+        ];
+        for (name, function) in builtins {
+            self.global_scope.insert(name.to_string(), Value::Native(NativeRef::builtin(name, function)));
+        }
 
         // Add constants
-        self.global_scope.insert("_VERSION".to_string(), Value::String("Luna 1.0".to_string()));
+        self.global_scope.insert("_VERSION".to_string(), Value::String("Luna 1.0".into()));
     }
 
     pub fn push_scope(&mut self) -> usize {
@@ -165,6 +172,12 @@ impl Environment {
     }
 }
 
+impl Default for Environment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Scope {
     pub fn new() -> Self {
         Self {
@@ -174,6 +187,12 @@ impl Scope {
     }
 }
 
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 // Helper for managing function environments
 #[derive(Debug, Clone)]
 pub struct FunctionEnvironment {
@@ -213,11 +232,10 @@ pub struct EnvironmentManager {
 
 impl EnvironmentManager {
     pub fn new() -> Self {
-        let manager = Self {
+        Self {
             environments: vec![Environment::new()],
             current: 0,
-        };
-        manager
+        }
     }
 
     pub fn current_env(&self) -> &Environment {
@@ -249,7 +267,7 @@ impl EnvironmentManager {
     where
         F: FnOnce(&mut Environment) -> R,
     {
-        let scope_id = self.current_env_mut().push_scope();
+        self.current_env_mut().push_scope();
         let result = f(self.current_env_mut());
         self.current_env_mut().pop_scope();
         result
@@ -260,9 +278,8 @@ impl EnvironmentManager {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Scope {
-  variables: HashMap<String, Value>,
-  #[allow(dead_code)]
-  parent: Option<usize>,
+impl Default for EnvironmentManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -1,3 +1,4 @@
+use crate::value::Value;
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -72,6 +73,14 @@ pub enum LuaError {
         message: String,
         error_type: String,
     },
+
+    // A value raised by `error`, which need not be a string. `handled` is
+    // set once the message handler of an enclosing `xpcall` has replaced
+    // it, so that the handler only runs once.
+    Thrown {
+        value: Value,
+        handled: bool,
+    },
 }
 
 impl LuaError {
@@ -127,6 +136,12 @@ impl LuaError {
         }
     }
 
+    /// An invalid argument, worded as reference Lua words it:
+    /// "bad argument #2 to 'format' (number expected, got nil)".
+    pub fn bad_argument(position: usize, function: &str, message: &str) -> Self {
+        Self::runtime_error(&format!("bad argument #{} to '{}' ({})", position, function, message))
+    }
+
     pub fn call_error(message: &str, function: &str) -> Self {
         Self::CallError {
             message: message.to_string(),
@@ -161,6 +176,20 @@ impl LuaError {
         }
     }
 
+    pub fn thrown(value: Value) -> Self {
+        Self::Thrown { value, handled: false }
+    }
+
+    /// The error as `pcall` returns it: the value raised, or the message of
+    /// an error the runtime raised itself.
+    pub fn into_value(self) -> Value {
+        match self {
+            LuaError::Thrown { value, .. } => value,
+            LuaError::RuntimeError { message, line: None } => Value::String(message.into()),
+            other => Value::String(other.to_string().into()),
+        }
+    }
+
     pub fn error_type(&self) -> &'static str {
         match self {
             LuaError::LexError { .. } => "LexError",
@@ -176,6 +205,7 @@ impl LuaError {
             LuaError::JitError { .. } => "JitError",
             LuaError::IoError { .. } => "IoError",
             LuaError::CustomError { .. } => "CustomError",
+            LuaError::Thrown { .. } => "Thrown",
         }
     }
 
@@ -190,25 +220,24 @@ impl LuaError {
             LuaError::IoError { message, .. } |
             LuaError::CustomError { message, .. } => message,
 
-            LuaError::TypeError { expected, found, operation } => {
+            LuaError::TypeError { .. } => {
                 // This is a bit hacky, but we need to return a &str
                 // In a real implementation, you might want to use Cow<str>
                 "Type error (see Display implementation for details)"
             }
 
-            LuaError::UndefinedVariable { name } => "Undefined variable",
+            LuaError::UndefinedVariable { .. } => "Undefined variable",
             LuaError::ArgumentError { .. } => "Wrong number of arguments",
             LuaError::StackOverflow => "Stack overflow",
             LuaError::StackUnderflow => "Stack underflow",
+            LuaError::Thrown { value: Value::String(s), .. } => s.to_str().unwrap_or("error object"),
+            LuaError::Thrown { .. } => "error object",
         }
     }
 
     pub fn with_line(mut self, line: usize) -> Self {
-        match &mut self {
-            LuaError::RuntimeError { line: line_ref, .. } => {
-                *line_ref = Some(line);
-            }
-            _ => {}
+        if let LuaError::RuntimeError { line: line_ref, .. } = &mut self {
+            *line_ref = Some(line);
         }
         self
     }
@@ -271,12 +300,31 @@ impl fmt::Display for LuaError {
             LuaError::CustomError { message, error_type } => {
                 write!(f, "{}: {}", error_type, message)
             }
+
+            // As the standalone interpreter reports errors nobody caught
+            LuaError::Thrown { value, .. } => match value {
+                Value::String(_) | Value::Number(_) => write!(f, "{}", value),
+                other => write!(f, "(error object is a {} value)", other.type_name()),
+            },
         }
     }
 }
 
 impl std::error::Error for LuaError {}
 
+// Errors the runtime raises itself are plain messages
+impl From<String> for LuaError {
+    fn from(message: String) -> Self {
+        LuaError::runtime_error(&message)
+    }
+}
+
+impl From<&str> for LuaError {
+    fn from(message: &str) -> Self {
+        LuaError::runtime_error(message)
+    }
+}
+
 // Conversion from std::io::Error
 impl From<std::io::Error> for LuaError {
     fn from(error: std::io::Error) -> Self {
@@ -294,7 +342,7 @@ pub fn division_by_zero() -> LuaError {
 
 pub fn invalid_operation(op: &str, left_type: &str, right_type: &str) -> LuaError {
     LuaError::type_error(
-        &format!("number or string"),
+        "number or string",
         &format!("{} and {}", left_type, right_type),
         op
     )
@@ -365,3 +413,9 @@ impl ErrorContext {
         result
     }
 }
+
+impl Default for ErrorContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,516 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus};
+
+/// Bytes read ahead at a time, and the default size of a write buffer.
+pub const BUFFER_SIZE: usize = 8192;
+
+// Longest numeral `read("n")` accepts, as in the reference implementation
+const MAX_NUMERAL: usize = 200;
+
+enum Stream {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(fs::File),
+    /// A process started by `io.popen`, whose output is read or whose input
+    /// is written
+    Process(Child),
+    Closed,
+}
+
+/// When buffered writes reach the stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Buffering {
+    /// Every write goes straight through
+    No,
+    /// Writes are held until the buffer is full
+    Full(usize),
+    /// Writes are held until a newline or a full buffer
+    Line(usize),
+}
+
+/// What `read` is asked for, one per format argument.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadFormat {
+    /// `"n"`: a numeral
+    Number,
+    /// `"l"`: the next line without its newline
+    Line,
+    /// `"L"`: the next line with its newline
+    LineWithNewline,
+    /// `"a"`: the rest of the file
+    All,
+    /// A count: up to that many bytes
+    Count(usize),
+}
+
+impl ReadFormat {
+    /// Reads a string format, which may start with a `*` as in Lua 5.3.
+    pub fn parse(format: &[u8]) -> Option<Self> {
+        let format = format.strip_prefix(b"*").unwrap_or(format);
+        match format.first() {
+            Some(b'n') => Some(ReadFormat::Number),
+            Some(b'l') => Some(ReadFormat::Line),
+            Some(b'L') => Some(ReadFormat::LineWithNewline),
+            Some(b'a') => Some(ReadFormat::All),
+            _ => None,
+        }
+    }
+}
+
+/// The payload of a file handle: an open file or standard stream with its
+/// own read-ahead and write buffers, like a C `FILE`. Reads and writes may
+/// be mixed freely; each one drops what the other left buffered.
+pub struct LuaFile {
+    stream: Stream,
+    input: Vec<u8>,
+    // Position of the next unread byte in `input`
+    position: usize,
+    output: Vec<u8>,
+    buffering: Buffering,
+    // Removed when the file is closed, for `io.tmpfile`
+    temporary: Option<PathBuf>,
+}
+
+impl LuaFile {
+    fn with_stream(stream: Stream, buffering: Buffering) -> Self {
+        Self {
+            stream,
+            input: Vec::new(),
+            position: 0,
+            output: Vec::new(),
+            buffering,
+            temporary: None,
+        }
+    }
+
+    // The standard streams do their own buffering, which `print` shares
+    pub fn stdin() -> Self {
+        Self::with_stream(Stream::Stdin, Buffering::No)
+    }
+
+    pub fn stdout() -> Self {
+        Self::with_stream(Stream::Stdout, Buffering::No)
+    }
+
+    pub fn stderr() -> Self {
+        Self::with_stream(Stream::Stderr, Buffering::No)
+    }
+
+    /// Opens `path` with a C `fopen` mode: `r`, `w` or `a`, optionally
+    /// followed by `+`, then any number of `b`. Returns None for any other
+    /// mode.
+    pub fn open(path: &str, mode: &str) -> Option<io::Result<Self>> {
+        let mut options = fs::OpenOptions::new();
+        let (kind, rest) = mode.split_at(mode.len().min(1));
+        let update = rest.starts_with('+');
+        let rest = rest.strip_prefix('+').unwrap_or(rest);
+        if !rest.bytes().all(|b| b == b'b') {
+            return None;
+        }
+        match kind {
+            "r" => options.read(true).write(update),
+            "w" => options.write(true).create(true).truncate(true).read(update),
+            "a" => options.append(true).create(true).read(update),
+            _ => return None,
+        };
+        Some(options.open(path).map(Self::from_file))
+    }
+
+    pub fn from_file(file: fs::File) -> Self {
+        Self::with_stream(Stream::File(file), Buffering::Full(BUFFER_SIZE))
+    }
+
+    /// A pipe to the standard input or from the standard output of `child`,
+    /// whichever it was spawned with.
+    pub fn from_process(child: Child) -> Self {
+        Self::with_stream(Stream::Process(child), Buffering::Full(BUFFER_SIZE))
+    }
+
+    /// A new file opened for update that is deleted when closed.
+    pub fn temporary() -> io::Result<Self> {
+        let (file, path) = create_temporary()?;
+        let mut file = Self::from_file(file);
+        file.temporary = Some(path);
+        Ok(file)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        matches!(self.stream, Stream::Closed)
+    }
+
+    /// Whether this is standard input, output or error, which stay open.
+    pub fn is_standard(&self) -> bool {
+        matches!(self.stream, Stream::Stdin | Stream::Stdout | Stream::Stderr)
+    }
+
+    /// Flushes and closes the file. Closing a closed file does nothing.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.close_with_status().map(|_| ())
+    }
+
+    /// Closes the file like `close`. For a pipe to a process, also waits
+    /// for the process to exit and returns its status.
+    pub fn close_with_status(&mut self) -> io::Result<Option<ExitStatus>> {
+        let flushed = self.flush();
+        let stream = std::mem::replace(&mut self.stream, Stream::Closed);
+        self.input.clear();
+        self.position = 0;
+        self.output.clear();
+        if let Some(path) = self.temporary.take() {
+            let _ = fs::remove_file(path);
+        }
+        let status = match stream {
+            Stream::Process(mut child) => {
+                // Closing our ends lets the process see end of file, or fail
+                // writing, instead of waiting for us forever
+                drop(child.stdin.take());
+                drop(child.stdout.take());
+                Some(child.wait()?)
+            }
+            _ => None,
+        };
+        flushed.map(|_| status)
+    }
+
+    pub fn set_buffering(&mut self, buffering: Buffering) -> io::Result<()> {
+        self.flush()?;
+        self.buffering = buffering;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.output.is_empty() {
+            let output = std::mem::take(&mut self.output);
+            self.write_stream(&output)?;
+        }
+        match &mut self.stream {
+            Stream::Stdout => io::stdout().flush(),
+            Stream::Stderr => io::stderr().flush(),
+            Stream::File(file) => file.flush(),
+            Stream::Process(child) => child.stdin.as_mut().map_or(Ok(()), |stdin| stdin.flush()),
+            Stream::Stdin | Stream::Closed => Ok(()),
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.drop_input()?;
+        match self.buffering {
+            Buffering::No => self.write_stream(bytes),
+            Buffering::Full(size) => {
+                self.output.extend_from_slice(bytes);
+                if self.output.len() >= size {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+            Buffering::Line(size) => {
+                self.output.extend_from_slice(bytes);
+                if self.output.len() >= size || bytes.contains(&b'\n') {
+                    self.flush()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Moves to `position` and returns the new offset from the start.
+    pub fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.flush()?;
+        // The stream is ahead of the reader by what is still buffered
+        let unread = (self.input.len() - self.position) as i64;
+        let position = match position {
+            SeekFrom::Current(offset) => SeekFrom::Current(offset - unread),
+            other => other,
+        };
+        let offset = match &mut self.stream {
+            Stream::File(file) => file.seek(position)?,
+            _ => return Err(illegal_seek()),
+        };
+        self.input.clear();
+        self.position = 0;
+        Ok(offset)
+    }
+
+    /// The next line, or None at the end of the file.
+    pub fn read_line(&mut self, keep_newline: bool) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        loop {
+            if !self.fill()? {
+                return Ok(if line.is_empty() { None } else { Some(line) });
+            }
+            let available = &self.input[self.position..];
+            match available.iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    let end = if keep_newline { end + 1 } else { end };
+                    line.extend_from_slice(&available[..end]);
+                    // Skip the newline either way
+                    self.position += available[..end].len() + usize::from(!keep_newline);
+                    return Ok(Some(line));
+                }
+                None => {
+                    line.extend_from_slice(available);
+                    self.position = self.input.len();
+                }
+            }
+        }
+    }
+
+    /// Everything up to the end of the file, empty if already there.
+    pub fn read_all(&mut self) -> io::Result<Vec<u8>> {
+        let mut all = Vec::new();
+        while self.fill()? {
+            all.extend_from_slice(&self.input[self.position..]);
+            self.position = self.input.len();
+        }
+        Ok(all)
+    }
+
+    /// Up to `count` bytes, or None at the end of the file. A count of zero
+    /// reads nothing and only tests for the end.
+    pub fn read_count(&mut self, count: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut bytes = Vec::new();
+        if !self.fill()? {
+            return Ok(None);
+        }
+        while bytes.len() < count && self.fill()? {
+            let take = (count - bytes.len()).min(self.input.len() - self.position);
+            bytes.extend_from_slice(&self.input[self.position..self.position + take]);
+            self.position += take;
+        }
+        Ok(Some(bytes))
+    }
+
+    /// The longest prefix of the input that looks like a numeral, skipping
+    /// whitespace before it. The caller decides whether it is a number.
+    pub fn read_numeral(&mut self) -> io::Result<Vec<u8>> {
+        while let Some(b) = self.peek()? {
+            if !b.is_ascii_whitespace() {
+                break;
+            }
+            self.position += 1;
+        }
+
+        let mut numeral = Vec::new();
+        self.accept(&mut numeral, b"-+")?;
+        let mut hex = false;
+        let mut count = 0;
+        if self.accept(&mut numeral, b"0")? {
+            if self.accept(&mut numeral, b"xX")? {
+                hex = true;
+            } else {
+                count = 1;
+            }
+        }
+        count += self.accept_digits(&mut numeral, hex)?;
+        if self.accept(&mut numeral, b".")? {
+            count += self.accept_digits(&mut numeral, hex)?;
+        }
+        if count > 0 && self.accept(&mut numeral, if hex { b"pP" } else { b"eE" })? {
+            self.accept(&mut numeral, b"-+")?;
+            self.accept_digits(&mut numeral, false)?;
+        }
+        Ok(numeral)
+    }
+
+    fn accept(&mut self, numeral: &mut Vec<u8>, set: &[u8]) -> io::Result<bool> {
+        match self.peek()? {
+            Some(b) if set.contains(&b) && numeral.len() < MAX_NUMERAL => {
+                numeral.push(b);
+                self.position += 1;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn accept_digits(&mut self, numeral: &mut Vec<u8>, hex: bool) -> io::Result<usize> {
+        let mut count = 0;
+        while let Some(b) = self.peek()? {
+            let digit = if hex { b.is_ascii_hexdigit() } else { b.is_ascii_digit() };
+            if !digit || numeral.len() >= MAX_NUMERAL {
+                break;
+            }
+            numeral.push(b);
+            self.position += 1;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        Ok(if self.fill()? { Some(self.input[self.position]) } else { None })
+    }
+
+    // Makes sure there is unread input, returning false at the end of the
+    // file
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.position < self.input.len() {
+            return Ok(true);
+        }
+        self.flush()?;
+        self.input.resize(BUFFER_SIZE, 0);
+        self.position = 0;
+        let read = match &mut self.stream {
+            Stream::Stdin => io::stdin().read(&mut self.input),
+            Stream::File(file) => file.read(&mut self.input),
+            Stream::Process(Child { stdout: Some(stdout), .. }) => stdout.read(&mut self.input),
+            Stream::Stdout | Stream::Stderr | Stream::Process(_) | Stream::Closed => Err(bad_descriptor()),
+        };
+        let read = read.inspect_err(|_| self.input.clear())?;
+        self.input.truncate(read);
+        Ok(read > 0)
+    }
+
+    // Gives back read-ahead before a write, so the write lands where the
+    // reader stopped
+    fn drop_input(&mut self) -> io::Result<()> {
+        let unread = self.input.len() - self.position;
+        if unread > 0 {
+            if let Stream::File(file) = &mut self.stream {
+                file.seek(SeekFrom::Current(-(unread as i64)))?;
+            }
+        }
+        self.input.clear();
+        self.position = 0;
+        Ok(())
+    }
+
+    fn write_stream(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.stream {
+            Stream::Stdout => io::stdout().write_all(bytes),
+            Stream::Stderr => io::stderr().write_all(bytes),
+            Stream::File(file) => file.write_all(bytes),
+            Stream::Process(Child { stdin: Some(stdin), .. }) => stdin.write_all(bytes),
+            Stream::Stdin | Stream::Process(_) | Stream::Closed => Err(bad_descriptor()),
+        }
+    }
+}
+
+impl Drop for LuaFile {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(unix)]
+fn bad_descriptor() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
+#[cfg(not(unix))]
+fn bad_descriptor() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Bad file descriptor")
+}
+
+/// The error of seeking before the start of a file.
+#[cfg(unix)]
+pub fn invalid_argument() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+#[cfg(not(unix))]
+pub fn invalid_argument() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "Invalid argument")
+}
+
+#[cfg(unix)]
+fn illegal_seek() -> io::Error {
+    io::Error::from_raw_os_error(libc::ESPIPE)
+}
+
+#[cfg(not(unix))]
+fn illegal_seek() -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, "Illegal seek")
+}
+
+/// Creates a new, empty file with a unique name in the temporary directory,
+/// opened for reading and writing.
+pub fn create_temporary() -> io::Result<(fs::File, PathBuf)> {
+    let directory = std::env::temp_dir();
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.subsec_nanos());
+    for attempt in 0..100u32 {
+        let path = directory.join(format!("lua_{}_{:x}_{}", std::process::id(), nanos, attempt));
+        match fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((file, path)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AlreadyExists, "cannot create a temporary file"))
+}
+
+/// The message of an I/O error without the "(os error N)" Rust appends,
+/// and its C `errno`, or 0 when it has none.
+pub fn error_parts(error: &io::Error) -> (String, i32) {
+    let message = error.to_string();
+    match error.raw_os_error() {
+        Some(code) => {
+            let suffix = format!(" (os error {})", code);
+            (message.strip_suffix(&suffix).unwrap_or(&message).to_string(), code)
+        }
+        None => (message, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temporary_with(contents: &[u8]) -> LuaFile {
+        let mut file = LuaFile::temporary().unwrap();
+        file.write(contents).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_lines_and_counts() {
+        let mut file = temporary_with(b"one\ntwo\r\nthree");
+        assert_eq!(file.read_line(false).unwrap(), Some(b"one".to_vec()));
+        assert_eq!(file.read_line(true).unwrap(), Some(b"two\r\n".to_vec()));
+        assert_eq!(file.read_count(2).unwrap(), Some(b"th".to_vec()));
+        assert_eq!(file.read_count(0).unwrap(), Some(Vec::new()));
+        assert_eq!(file.read_all().unwrap(), b"ree".to_vec());
+        assert_eq!(file.read_line(false).unwrap(), None);
+        assert_eq!(file.read_count(0).unwrap(), None);
+        assert_eq!(file.read_all().unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_numerals() {
+        let mut file = temporary_with(b"  12.5e3 -0x1F 7abc .");
+        assert_eq!(file.read_numeral().unwrap(), b"12.5e3".to_vec());
+        assert_eq!(file.read_numeral().unwrap(), b"-0x1F".to_vec());
+        assert_eq!(file.read_numeral().unwrap(), b"7".to_vec());
+        assert_eq!(file.read_count(3).unwrap(), Some(b"abc".to_vec()));
+        assert_eq!(file.read_numeral().unwrap(), b".".to_vec());
+    }
+
+    #[test]
+    fn test_mixed_reads_and_writes() {
+        let mut file = temporary_with(b"abcdef");
+        assert_eq!(file.read_count(2).unwrap(), Some(b"ab".to_vec()));
+        file.write(b"XY").unwrap();
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 4);
+        assert_eq!(file.read_all().unwrap(), b"ef".to_vec());
+        assert_eq!(file.seek(SeekFrom::Start(0)).unwrap(), 0);
+        assert_eq!(file.read_all().unwrap(), b"abXYef".to_vec());
+        assert_eq!(file.seek(SeekFrom::End(-1)).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_modes() {
+        assert!(LuaFile::open("x", "rw").is_none());
+        assert!(LuaFile::open("x", "").is_none());
+        assert!(LuaFile::open("x", "r+bx").is_none());
+        let missing = LuaFile::open("/nonexistent/file", "rb").unwrap();
+        let (message, code) = error_parts(&missing.err().unwrap());
+        assert!(!message.contains("os error"), "{}", message);
+        assert_ne!(code, 0);
+    }
+}
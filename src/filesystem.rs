@@ -0,0 +1,216 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Attribute names `fs.attributes` reports, in the order LuaFileSystem
+/// lists them.
+pub const ATTRIBUTE_NAMES: &[&str] = &[
+    "dev",
+    "ino",
+    "mode",
+    "nlink",
+    "uid",
+    "gid",
+    "rdev",
+    "access",
+    "modification",
+    "change",
+    "size",
+    "permissions",
+    "blocks",
+    "blksize",
+];
+
+/// The value of one attribute: a number, or a string for `mode` and
+/// `permissions`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Attribute {
+    Integer(i64),
+    Text(String),
+}
+
+/// The attribute called `name`, or None if there is no such attribute.
+pub fn attribute(metadata: &fs::Metadata, name: &str) -> Option<Attribute> {
+    let value = match name {
+        "mode" => Attribute::Text(mode_name(&metadata.file_type()).to_string()),
+        "permissions" => Attribute::Text(permissions(metadata)),
+        "size" => Attribute::Integer(metadata.len() as i64),
+        "access" => Attribute::Integer(seconds(metadata.accessed())),
+        "modification" => Attribute::Integer(seconds(metadata.modified())),
+        _ => Attribute::Integer(platform::number(metadata, name)?),
+    };
+    Some(value)
+}
+
+/// Names the kind of file the way LuaFileSystem does.
+fn mode_name(file_type: &fs::FileType) -> &'static str {
+    if file_type.is_file() {
+        "file"
+    } else if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_symlink() {
+        "link"
+    } else {
+        platform::special_mode_name(file_type)
+    }
+}
+
+/// Permission bits as in `ls -l`, such as `rwxr-xr-x`.
+fn permissions(metadata: &fs::Metadata) -> String {
+    let mode = platform::permission_bits(metadata);
+    let mut text = String::with_capacity(9);
+    for (i, c) in "rwxrwxrwx".chars().enumerate() {
+        text.push(if mode & (0o400 >> i) != 0 { c } else { '-' });
+    }
+    text
+}
+
+// Whole seconds since the epoch, 0 when the platform keeps no such time
+fn seconds(time: io::Result<SystemTime>) -> i64 {
+    match time.map(|time| time.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(elapsed)) => elapsed.as_secs() as i64,
+        Ok(Err(before)) => -(before.duration().as_secs() as i64),
+        Err(_) => 0,
+    }
+}
+
+/// The payload of the directory objects `fs.dir` returns: the entries not
+/// read yet, listing `.` and `..` first as `readdir` does.
+pub struct Directory {
+    dots: std::slice::Iter<'static, &'static str>,
+    // None once closed
+    entries: Option<fs::ReadDir>,
+}
+
+impl Directory {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(Self { dots: [".", ".."].iter(), entries: Some(fs::read_dir(path)?) })
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.entries.is_none()
+    }
+
+    pub fn close(&mut self) {
+        self.entries = None;
+    }
+
+    /// The name of the next entry, or None at the end, which also closes
+    /// the directory.
+    pub fn next_name(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(dot) = self.dots.next() {
+            return Ok(Some(dot.as_bytes().to_vec()));
+        }
+        let entry = match self.entries.as_mut().and_then(Iterator::next) {
+            Some(entry) => entry?,
+            None => {
+                self.close();
+                return Ok(None);
+            }
+        };
+        Ok(Some(entry.file_name().into_encoded_bytes()))
+    }
+}
+
+/// Sets the access and modification times of `path`, in seconds since the
+/// epoch. The file must exist.
+pub fn touch(path: &str, access: i64, modification: i64) -> io::Result<()> {
+    let time = |seconds: i64| match u64::try_from(seconds) {
+        Ok(seconds) => UNIX_EPOCH + Duration::from_secs(seconds),
+        Err(_) => UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs()),
+    };
+    let times = fs::FileTimes::new().set_accessed(time(access)).set_modified(time(modification));
+    fs::File::open(path)?.set_times(times)
+}
+
+#[cfg(unix)]
+mod platform {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    pub fn number(metadata: &fs::Metadata, name: &str) -> Option<i64> {
+        let n = match name {
+            "dev" => metadata.dev() as i64,
+            "ino" => metadata.ino() as i64,
+            "nlink" => metadata.nlink() as i64,
+            "uid" => metadata.uid() as i64,
+            "gid" => metadata.gid() as i64,
+            "rdev" => metadata.rdev() as i64,
+            "change" => metadata.ctime(),
+            "blocks" => metadata.blocks() as i64,
+            "blksize" => metadata.blksize() as i64,
+            _ => return None,
+        };
+        Some(n)
+    }
+
+    pub fn special_mode_name(file_type: &fs::FileType) -> &'static str {
+        if file_type.is_socket() {
+            "socket"
+        } else if file_type.is_fifo() {
+            "named pipe"
+        } else if file_type.is_char_device() {
+            "char device"
+        } else if file_type.is_block_device() {
+            "block device"
+        } else {
+            "other"
+        }
+    }
+
+    pub fn permission_bits(metadata: &fs::Metadata) -> u32 {
+        metadata.mode()
+    }
+}
+
+// Elsewhere only what std knows is reported; the other numbers are 0 and
+// the permissions follow the read-only flag
+#[cfg(not(unix))]
+mod platform {
+    use std::fs;
+
+    pub fn number(metadata: &fs::Metadata, name: &str) -> Option<i64> {
+        match name {
+            "change" => Some(super::seconds(metadata.created())),
+            "dev" | "ino" | "nlink" | "uid" | "gid" | "rdev" | "blocks" | "blksize" => Some(0),
+            _ => None,
+        }
+    }
+
+    pub fn special_mode_name(_file_type: &fs::FileType) -> &'static str {
+        "other"
+    }
+
+    pub fn permission_bits(metadata: &fs::Metadata) -> u32 {
+        if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o666
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attributes_of_a_file() {
+        let path = std::env::temp_dir().join(format!("luna_filesystem_{}", std::process::id()));
+        fs::write(&path, "12345").unwrap();
+        fs::set_permissions(&path, std::os::unix::fs::PermissionsExt::from_mode(0o640)).unwrap();
+        touch(path.to_str().unwrap(), 1_000_000, 2_000_000).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(attribute(&metadata, "mode"), Some(Attribute::Text("file".to_string())));
+        assert_eq!(attribute(&metadata, "permissions"), Some(Attribute::Text("rw-r-----".to_string())));
+        assert_eq!(attribute(&metadata, "size"), Some(Attribute::Integer(5)));
+        assert_eq!(attribute(&metadata, "access"), Some(Attribute::Integer(1_000_000)));
+        assert_eq!(attribute(&metadata, "modification"), Some(Attribute::Integer(2_000_000)));
+        assert_eq!(attribute(&metadata, "target"), None);
+        for name in ATTRIBUTE_NAMES {
+            assert!(attribute(&metadata, name).is_some(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use crate::value::Value;
+
+// Longest run of flags, width and precision a conversion may carry
+const MAX_SPEC: usize = 22;
+
+/// One `%` conversion of a `string.format` template, as C `printf` reads it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Spec {
+    pub left: bool,
+    pub plus: bool,
+    pub space: bool,
+    pub alternate: bool,
+    pub zero: bool,
+    pub width: usize,
+    pub precision: Option<usize>,
+    pub conversion: u8,
+}
+
+impl Spec {
+    /// Parses the conversion starting just after a `%`, returning it and the
+    /// number of bytes it spans. Each conversion accepts only the flags C
+    /// defines for it, and width and precision take at most two digits.
+    pub fn parse(bytes: &[u8]) -> Result<(Spec, usize), String> {
+        let span = bytes.iter().take_while(|b| b"-+ #0123456789.".contains(b)).count();
+        let form = String::from_utf8_lossy(&bytes[..(span + 1).min(bytes.len())]).into_owned();
+        if span >= MAX_SPEC {
+            return Err("invalid format string to 'format'".to_string());
+        }
+
+        let conversion = bytes.get(span).copied().unwrap_or(0);
+        let (flags, precision_allowed): (&[u8], bool) = match conversion {
+            b'c' => (b"-", false),
+            b'd' | b'i' => (b"-+ 0", true),
+            b'u' => (b"-0", true),
+            b'o' | b'x' | b'X' => (b"-#0", true),
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => (b"-+ #0", true),
+            b's' => (b"-", true),
+            b'q' if span == 0 => (b"", false),
+            b'q' => return Err("specifier '%q' cannot have modifiers".to_string()),
+            _ => return Err(format!("invalid conversion '%{}' to 'format'", form)),
+        };
+
+        let mut spec = Spec { conversion, ..Spec::default() };
+        let mut i = 0;
+        while i < span && flags.contains(&bytes[i]) {
+            match bytes[i] {
+                b'-' => spec.left = true,
+                b'+' => spec.plus = true,
+                b' ' => spec.space = true,
+                b'#' => spec.alternate = true,
+                _ => spec.zero = true,
+            }
+            i += 1;
+        }
+        // A width cannot start with '0', which is only valid as a flag
+        if bytes[i] != b'0' {
+            let (width, digits) = two_digits(&bytes[i..span]);
+            spec.width = width;
+            i += digits;
+            if i < span && bytes[i] == b'.' && precision_allowed {
+                let (precision, digits) = two_digits(&bytes[i + 1..span]);
+                spec.precision = Some(precision);
+                i += digits + 1;
+            }
+        }
+        if i != span {
+            return Err(format!("invalid conversion specification: '%{}'", form));
+        }
+        Ok((spec, span + 1))
+    }
+}
+
+fn two_digits(bytes: &[u8]) -> (usize, usize) {
+    let digits = bytes.iter().take(2).take_while(|b| b.is_ascii_digit()).count();
+    let value = bytes[..digits].iter().fold(0, |n, b| n * 10 + (b - b'0') as usize);
+    (value, digits)
+}
+
+/// Pads `prefix` and `body` out to the spec's width. Zero padding goes
+/// between the two, so signs and `0x` stay in front.
+fn pad(spec: &Spec, prefix: &str, body: &[u8], zero_allowed: bool) -> Vec<u8> {
+    let len = prefix.len() + body.len();
+    let fill = spec.width.saturating_sub(len);
+    let mut out = Vec::with_capacity(len + fill);
+    if spec.left {
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body);
+        out.resize(out.len() + fill, b' ');
+    } else if spec.zero && zero_allowed {
+        out.extend_from_slice(prefix.as_bytes());
+        out.resize(out.len() + fill, b'0');
+        out.extend_from_slice(body);
+    } else {
+        out.resize(fill, b' ');
+        out.extend_from_slice(prefix.as_bytes());
+        out.extend_from_slice(body);
+    }
+    out
+}
+
+fn sign(spec: &Spec, negative: bool) -> &'static str {
+    if negative {
+        "-"
+    } else if spec.plus {
+        "+"
+    } else if spec.space {
+        " "
+    } else {
+        ""
+    }
+}
+
+/// Applies an integer precision, the minimum number of digits. A zero
+/// precision prints zero as nothing at all.
+fn with_precision(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if digits.len() < p => format!("{}{}", "0".repeat(p - digits.len()), digits),
+        _ => digits,
+    }
+}
+
+/// `%d` and `%i`.
+pub fn format_integer(spec: &Spec, n: i64) -> Vec<u8> {
+    let digits = with_precision(n.unsigned_abs().to_string(), spec.precision);
+    pad(spec, sign(spec, n < 0), digits.as_bytes(), spec.precision.is_none())
+}
+
+/// `%u`, `%o`, `%x` and `%X`. Negative integers print as their two's
+/// complement, as they do in C.
+pub fn format_unsigned(spec: &Spec, n: u64) -> Vec<u8> {
+    let digits = match spec.conversion {
+        b'o' => format!("{:o}", n),
+        b'x' => format!("{:x}", n),
+        b'X' => format!("{:X}", n),
+        _ => n.to_string(),
+    };
+    let mut digits = with_precision(digits, spec.precision);
+    let prefix = match spec.conversion {
+        b'x' if spec.alternate && n != 0 => "0x",
+        b'X' if spec.alternate && n != 0 => "0X",
+        _ => "",
+    };
+    if spec.conversion == b'o' && spec.alternate && !digits.starts_with('0') {
+        digits.insert(0, '0');
+    }
+    pad(spec, prefix, digits.as_bytes(), spec.precision.is_none())
+}
+
+/// `%c`.
+pub fn format_char(spec: &Spec, c: u8) -> Vec<u8> {
+    pad(spec, "", &[c], false)
+}
+
+/// `%s` with a width or precision. The precision truncates.
+pub fn format_string(spec: &Spec, s: &[u8]) -> Vec<u8> {
+    let s = match spec.precision {
+        Some(p) if p < s.len() => &s[..p],
+        _ => s,
+    };
+    pad(spec, "", s, false)
+}
+
+/// `%a`, `%e`, `%f` and `%g` in either case.
+pub fn format_float(spec: &Spec, n: f64) -> Vec<u8> {
+    let upper = spec.conversion.is_ascii_uppercase();
+    let sign = sign(spec, n.is_sign_negative());
+    if !n.is_finite() {
+        let body = match (n.is_nan(), upper) {
+            (true, false) => "nan",
+            (true, true) => "NAN",
+            (false, false) => "inf",
+            (false, true) => "INF",
+        };
+        return pad(spec, sign, body.as_bytes(), false);
+    }
+
+    let n = n.abs();
+    let (prefix, body) = match spec.conversion.to_ascii_lowercase() {
+        b'a' => ("0x", hex_float(n, spec.precision, spec.alternate)),
+        b'e' => ("", exponential(n, spec.precision.unwrap_or(6), spec.alternate)),
+        b'g' => ("", general(n, spec.precision.unwrap_or(6), spec.alternate)),
+        _ => ("", fixed(n, spec.precision.unwrap_or(6), spec.alternate)),
+    };
+    let (prefix, body) = if upper {
+        (prefix.to_ascii_uppercase(), body.to_ascii_uppercase())
+    } else {
+        (prefix.to_string(), body)
+    };
+    pad(spec, &format!("{}{}", sign, prefix), body.as_bytes(), true)
+}
+
+fn fixed(n: f64, precision: usize, alternate: bool) -> String {
+    let mut s = format!("{:.*}", precision, n);
+    if alternate && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+fn exponential(n: f64, precision: usize, alternate: bool) -> String {
+    let s = format!("{:.*e}", precision, n);
+    let (mantissa, exponent) = s.split_once('e').unwrap_or((&s, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+    let point = if alternate && precision == 0 { "." } else { "" };
+    let sign = if exponent < 0 { '-' } else { '+' };
+    format!("{}{}e{}{:02}", mantissa, point, sign, exponent.abs())
+}
+
+/// `%g` picks `%e` or `%f` by the decimal exponent and then drops trailing
+/// zeros, unless the `#` flag keeps them.
+fn general(n: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let exponent = if n == 0.0 {
+        0
+    } else {
+        let s = format!("{:.*e}", precision - 1, n);
+        s.split_once('e').and_then(|(_, e)| e.parse::<i64>().ok()).unwrap_or(0)
+    };
+    let s = if exponent < -4 || exponent >= precision as i64 {
+        exponential(n, precision - 1, alternate)
+    } else {
+        fixed(n, (precision as i64 - 1 - exponent) as usize, alternate)
+    };
+    if alternate {
+        return s;
+    }
+    let (mantissa, exponent) = match s.find('e') {
+        Some(at) => s.split_at(at),
+        None => (s.as_str(), ""),
+    };
+    let mantissa = if mantissa.contains('.') {
+        mantissa.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        mantissa
+    };
+    format!("{}{}", mantissa, exponent)
+}
+
+/// The digits of `%a` after the `0x`: a hexadecimal mantissa and a binary
+/// exponent. Without a precision the mantissa is exact.
+fn hex_float(n: f64, precision: Option<usize>, alternate: bool) -> String {
+    const MANTISSA_DIGITS: usize = 13;
+    let bits = n.to_bits();
+    let biased = (bits >> 52) & 0x7ff;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    let (mut lead, exponent) = match (n == 0.0, biased) {
+        (true, _) => (0u64, 0),
+        (false, 0) => (0, -1022),
+        (false, biased) => (1, biased as i64 - 1023),
+    };
+
+    let digits = match precision {
+        None => {
+            let digits = format!("{:013x}", mantissa);
+            digits.trim_end_matches('0').to_string()
+        }
+        Some(p) if p < MANTISSA_DIGITS => {
+            // Round to nearest, ties to even, carrying into the leading digit
+            let shift = (MANTISSA_DIGITS - p) * 4;
+            let rest = mantissa & ((1 << shift) - 1);
+            let half = 1 << (shift - 1);
+            mantissa >>= shift;
+            if rest > half || (rest == half && mantissa & 1 == 1) {
+                mantissa += 1;
+                if mantissa >> (p * 4) != 0 {
+                    mantissa = 0;
+                    lead += 1;
+                }
+            }
+            if p == 0 {
+                String::new()
+            } else {
+                format!("{:0width$x}", mantissa, width = p)
+            }
+        }
+        Some(p) => format!("{:013x}{}", mantissa, "0".repeat(p - MANTISSA_DIGITS)),
+    };
+    let point = if !digits.is_empty() || alternate { "." } else { "" };
+    format!("{}{}{}p{:+}", lead, point, digits, exponent)
+}
+
+/// `%q`: a literal that reads back as the same value.
+pub fn quote(value: &Value) -> Result<Vec<u8>, String> {
+    match value {
+        Value::String(s) => Ok(quote_string(s)),
+        Value::Number(n) => Ok(quote_number(*n).into_bytes()),
+        Value::Nil | Value::Boolean(_) => Ok(value.to_string().into_bytes()),
+        _ => Err("value has no literal form".to_string()),
+    }
+}
+
+fn quote_string(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len() + 2);
+    out.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => {
+                out.push(b'\\');
+                out.push(c);
+            }
+            c if c.is_ascii_control() => {
+                // A following digit would extend a short escape
+                let escape = if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                    format!("\\{:03}", c)
+                } else {
+                    format!("\\{}", c)
+                };
+                out.extend_from_slice(escape.as_bytes());
+            }
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+    out
+}
+
+// Reference Lua writes floats in hexadecimal, which the lexer does not read.
+// The shortest decimal that round-trips reads back just as exactly. NaN is
+// not `(0/0)` as there, since dividing by zero is an error here.
+fn quote_number(n: f64) -> String {
+    if n.is_nan() {
+        "(1e9999-1e9999)".to_string()
+    } else if n.is_infinite() {
+        if n > 0.0 { "1e9999" } else { "-1e9999" }.to_string()
+    } else {
+        format!("{}", n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(template: &str, f: impl Fn(&Spec) -> Vec<u8>) -> String {
+        let (spec, len) = Spec::parse(&template.as_bytes()[1..]).unwrap();
+        assert_eq!(len, template.len() - 1);
+        String::from_utf8(f(&spec)).unwrap()
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(format("%5d", |s| format_integer(s, -42)), "  -42");
+        assert_eq!(format("%-5d", |s| format_integer(s, 42)), "42   ");
+        assert_eq!(format("%05d", |s| format_integer(s, -42)), "-0042");
+        assert_eq!(format("%+.3d", |s| format_integer(s, 7)), "+007");
+        assert_eq!(format("%.0d", |s| format_integer(s, 0)), "");
+        assert_eq!(format("%#x", |s| format_unsigned(s, 255)), "0xff");
+        assert_eq!(format("%#o", |s| format_unsigned(s, 8)), "010");
+        assert_eq!(format("%X", |s| format_unsigned(s, -1i64 as u64)), "FFFFFFFFFFFFFFFF");
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(format("%f", |s| format_float(s, 1.25)), "1.250000");
+        assert_eq!(format("%.2e", |s| format_float(s, 12345.678)), "1.23e+04");
+        assert_eq!(format("%E", |s| format_float(s, 0.0)), "0.000000E+00");
+        assert_eq!(format("%g", |s| format_float(s, 100000.0)), "100000");
+        assert_eq!(format("%g", |s| format_float(s, 1000000.0)), "1e+06");
+        assert_eq!(format("%g", |s| format_float(s, 0.0001)), "0.0001");
+        assert_eq!(format("%#g", |s| format_float(s, 1.5)), "1.50000");
+        assert_eq!(format("%08.3f", |s| format_float(s, -1.5)), "-001.500");
+        assert_eq!(format("%5f", |s| format_float(s, f64::INFINITY)), "  inf");
+        assert_eq!(format("%a", |s| format_float(s, 1.0)), "0x1p+0");
+        assert_eq!(format("%a", |s| format_float(s, 0.5)), "0x1p-1");
+        assert_eq!(format("%A", |s| format_float(s, 255.5)), "0X1.FFP+7");
+        assert_eq!(format("%.1a", |s| format_float(s, 1.96875)), "0x2.0p+0");
+        assert_eq!(format("%a", |s| format_float(s, 0.0)), "0x0p+0");
+    }
+
+    #[test]
+    fn test_invalid_specs() {
+        assert_eq!(Spec::parse(b"y"), Err("invalid conversion '%y' to 'format'".to_string()));
+        assert_eq!(
+            Spec::parse(b"123d"),
+            Err("invalid conversion specification: '%123d'".to_string())
+        );
+        assert_eq!(
+            Spec::parse(b"#d"),
+            Err("invalid conversion specification: '%#d'".to_string())
+        );
+        assert_eq!(Spec::parse(b"5q"), Err("specifier '%q' cannot have modifiers".to_string()));
+        assert_eq!(Spec::parse(b""), Err("invalid conversion '%' to 'format'".to_string()));
+    }
+
+    #[test]
+    fn test_quote() {
+        let quoted = quote(&Value::String(b"a\"b\n\0c\x011"[..].into())).unwrap();
+        assert_eq!(quoted, b"\"a\\\"b\\\n\\0c\\0011\"");
+        assert_eq!(quote(&Value::Number(0.1)).unwrap(), b"0.1");
+        assert_eq!(quote(&Value::Number(-3.0)).unwrap(), b"-3");
+        assert_eq!(quote(&Value::Number(f64::INFINITY)).unwrap(), b"1e9999");
+        assert!(quote(&Value::Table(crate::table::TableRef::new(crate::table::LuaTable::new()))).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use crate::bytecode::FunctionProto;
+use crate::gc::{forward_barrier, GcBox, Tracer};
+use crate::value::Value;
+use crate::vm::{BoundFunction, BuiltinFunction, RuntimeFunction};
+use std::cell::{Ref, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+/// A local variable captured by one or more closures. It is open while the
+/// variable is in scope in the frame that declared it, which then reads and
+/// writes the variable here as well, and closed once the scope ends, when
+/// the frame gets a copy of the value back and only the closures share it.
+#[derive(Debug)]
+pub struct Upvalue {
+    value: RefCell<Value>,
+}
+
+impl Upvalue {
+    pub fn new(value: Value) -> Self {
+        Self {
+            value: RefCell::new(value),
+        }
+    }
+
+    pub fn get(&self) -> Value {
+        self.value.borrow().clone()
+    }
+
+    /// Stores `value`, which the collector has not seen in here. Upvalues
+    /// have no write barrier of their own, so the value is reported itself.
+    pub fn set(&self, value: Value) {
+        forward_barrier(&value);
+        *self.value.borrow_mut() = value;
+    }
+
+    pub(crate) fn borrow(&self) -> Ref<'_, Value> {
+        self.value.borrow()
+    }
+}
+
+/// A Lua function value. Every evaluation of a function expression creates
+/// a new closure, so two closures of the same prototype are still distinct
+/// values.
+#[derive(Debug)]
+pub struct LuaClosure {
+    pub proto: Rc<FunctionProto>,
+    // The value global names are looked up in, like `_ENV` in reference
+    // Lua, or None for the runtime's globals. Like the upvalues, never
+    // changed after creation except by the collector clearing it.
+    env: RefCell<Option<Value>>,
+    upvalues: RefCell<Vec<Rc<Upvalue>>>,
+}
+
+impl LuaClosure {
+    pub fn new(proto: Rc<FunctionProto>, env: Option<Value>, upvalues: Vec<Rc<Upvalue>>) -> Self {
+        Self {
+            proto,
+            env: RefCell::new(env),
+            upvalues: RefCell::new(upvalues),
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        if let Some(env) = &*self.env.borrow() {
+            tracer.mark_value(env);
+        }
+        for upvalue in self.upvalues.borrow().iter() {
+            tracer.mark_upvalue(upvalue);
+        }
+    }
+
+    // Upvalues are dropped rather than emptied, since closures the
+    // collector reached may share them
+    pub(crate) fn clear_references(&self) -> Vec<Value> {
+        let upvalues = self.upvalues.take();
+        let mut references: Vec<Value> = self.env.take().into_iter().collect();
+        references.extend(
+            upvalues
+                .into_iter()
+                .filter_map(|upvalue| Rc::try_unwrap(upvalue).ok())
+                .map(|upvalue| upvalue.value.into_inner()),
+        );
+        references
+    }
+
+    pub(crate) fn estimated_size(&self) -> usize {
+        std::mem::size_of::<GcBox<LuaClosure>>()
+            + self.upvalues.borrow().len() * (std::mem::size_of::<Rc<Upvalue>>() + std::mem::size_of::<Upvalue>())
+    }
+}
+
+/// Shared handle to a closure. Clones alias the same function.
+#[derive(Clone)]
+pub struct ClosureRef(Rc<GcBox<LuaClosure>>);
+
+impl ClosureRef {
+    /// Allocates a closure that is not registered with any collector and
+    /// captures nothing. Use `Heap::alloc_closure` for closures that may
+    /// take part in cycles.
+    pub fn new(proto: Rc<FunctionProto>) -> Self {
+        Self::from_gc_box(GcBox::new(LuaClosure::new(proto, None, Vec::new())))
+    }
+
+    pub(crate) fn from_gc_box(gc_box: GcBox<LuaClosure>) -> Self {
+        Self(Rc::new(gc_box))
+    }
+
+    pub(crate) fn from_rc(gc_box: Rc<GcBox<LuaClosure>>) -> Self {
+        Self(gc_box)
+    }
+
+    pub(crate) fn gc_box(&self) -> &Rc<GcBox<LuaClosure>> {
+        &self.0
+    }
+
+    pub fn proto(&self) -> &Rc<FunctionProto> {
+        &self.0.proto
+    }
+
+    /// The value globals are read from and written to instead of the
+    /// runtime's globals, if any.
+    pub fn env(&self) -> Option<Value> {
+        self.0.env.borrow().clone()
+    }
+
+    /// The variable the function captured at `index`, in the order its
+    /// prototype lists them.
+    pub fn upvalue(&self, index: usize) -> Option<Rc<Upvalue>> {
+        self.0.upvalues.borrow().get(index).cloned()
+    }
+
+    /// The name the function was declared with.
+    pub fn name(&self) -> &str {
+        &self.0.proto.name
+    }
+
+    pub fn ptr_eq(&self, other: &ClosureRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const GcBox<LuaClosure>))
+    }
+}
+
+impl PartialEq for ClosureRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl fmt::Debug for ClosureRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "function: {:p}", self.as_ptr())
+    }
+}
+
+/// How a native function is called: plain builtins only see their
+/// arguments, runtime functions also get the runtime itself, and bound
+/// functions also get the values they were created with.
+#[derive(Clone, Copy)]
+pub enum NativeKind {
+    Builtin(BuiltinFunction),
+    Runtime(RuntimeFunction),
+    Bound(BoundFunction),
+}
+
+/// A function implemented in Rust. Registered builtins are created once, so
+/// every lookup of one yields the same value; bound functions are created
+/// at runtime, for instance as the iterator returned by `string.gmatch`.
+pub struct NativeFunction {
+    pub name: String,
+    pub kind: NativeKind,
+    // Values a bound function carries from one call to the next. Never
+    // changed after creation except by the collector clearing them.
+    upvalues: RefCell<Vec<Value>>,
+}
+
+impl NativeFunction {
+    pub fn new(name: &str, kind: NativeKind) -> Self {
+        Self::with_upvalues(name, kind, Vec::new())
+    }
+
+    pub fn with_upvalues(name: &str, kind: NativeKind, upvalues: Vec<Value>) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            upvalues: RefCell::new(upvalues),
+        }
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        for value in self.upvalues.borrow().iter() {
+            tracer.mark_value(value);
+        }
+    }
+
+    pub(crate) fn clear_references(&self) -> Vec<Value> {
+        self.upvalues.take()
+    }
+
+    pub(crate) fn estimated_size(&self) -> usize {
+        std::mem::size_of::<GcBox<NativeFunction>>() + self.upvalues.borrow().len() * std::mem::size_of::<Value>()
+    }
+}
+
+/// Shared handle to a native function. Clones alias the same function.
+#[derive(Clone)]
+pub struct NativeRef(Rc<GcBox<NativeFunction>>);
+
+impl NativeRef {
+    /// Allocates a function that is not registered with any collector. Use
+    /// `Heap::alloc_native` for functions whose upvalues may take part in
+    /// cycles.
+    pub fn new(function: NativeFunction) -> Self {
+        Self(Rc::new(GcBox::new(function)))
+    }
+
+    pub fn builtin(name: &str, function: BuiltinFunction) -> Self {
+        Self::new(NativeFunction::new(name, NativeKind::Builtin(function)))
+    }
+
+    pub fn runtime(name: &str, function: RuntimeFunction) -> Self {
+        Self::new(NativeFunction::new(name, NativeKind::Runtime(function)))
+    }
+
+    pub(crate) fn from_gc_box(gc_box: GcBox<NativeFunction>) -> Self {
+        Self(Rc::new(gc_box))
+    }
+
+    pub(crate) fn from_rc(gc_box: Rc<GcBox<NativeFunction>>) -> Self {
+        Self(gc_box)
+    }
+
+    pub(crate) fn gc_box(&self) -> &Rc<GcBox<NativeFunction>> {
+        &self.0
+    }
+
+    /// The registered name, e.g. `"print"` or `"math.abs"`.
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn kind(&self) -> NativeKind {
+        self.0.kind
+    }
+
+    /// Whether this is a function created at runtime with upvalues, which
+    /// the collector manages, rather than a registered builtin.
+    pub fn is_bound(&self) -> bool {
+        matches!(self.0.kind, NativeKind::Bound(_))
+    }
+
+    pub fn upvalues(&self) -> Vec<Value> {
+        self.0.upvalues.borrow().clone()
+    }
+
+    pub fn ptr_eq(&self, other: &NativeRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const GcBox<NativeFunction>))
+    }
+}
+
+impl PartialEq for NativeRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl fmt::Debug for NativeRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "builtin: {:p}", self.as_ptr())
+    }
+}
@@ -0,0 +1,1375 @@
+use crate::coroutine::{Coroutine, CoroutineRef};
+use crate::function::{ClosureRef, LuaClosure, NativeFunction, NativeRef, Upvalue};
+use crate::table::{LuaTable, TableRef, WeakMode};
+use crate::userdata::{Userdata, UserdataRef};
+use crate::value::Value;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+// Every collection cycle stamps reachable objects with a fresh epoch, so marks
+// never have to be reset and objects shared between runtimes cannot be
+// mistaken for marked by another heap.
+static NEXT_EPOCH: AtomicU32 = AtomicU32::new(1);
+
+const MIN_THRESHOLD: usize = 64 * 1024;
+
+// Rough cost of visiting one object or table slot, used to turn the
+// incremental step size (in bytes) into an amount of work
+const BYTES_PER_WORK_UNIT: usize = 16;
+
+pub const DEFAULT_PAUSE: usize = 200;
+pub const DEFAULT_STEP_MULTIPLIER: usize = 100;
+pub const DEFAULT_STEP_SIZE: u32 = 13;
+pub const DEFAULT_MINOR_MULTIPLIER: usize = 20;
+pub const DEFAULT_MAJOR_MULTIPLIER: usize = 100;
+
+/// Upper bounds of the pause-time histogram buckets; the last bucket is
+/// unbounded.
+pub const PAUSE_BUCKETS: [Duration; 5] = [
+    Duration::from_micros(10),
+    Duration::from_micros(100),
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GcMode {
+    Incremental,
+    Generational,
+}
+
+impl GcMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GcMode::Incremental => "incremental",
+            GcMode::Generational => "generational",
+        }
+    }
+}
+
+/// Implemented by every object that can hold references to other heap objects.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Drops every reference held by an unreachable object so that cycles
+    /// through it can be freed. The caller drops the returned values.
+    fn clear_references(&mut self) -> Vec<Value>;
+
+    fn estimated_size(&self) -> usize;
+}
+
+// State shared between a heap and the objects it allocated, read by the
+// write barrier whenever one of those objects is mutated. `bytes` is the
+// running total of the sizes charged to the heap's objects, which they
+// adjust themselves as they grow and are freed.
+struct BarrierState {
+    epoch: Cell<u32>,
+    marking: Cell<bool>,
+    generational: Cell<bool>,
+    touched: RefCell<Vec<GcObject>>,
+    bytes: Cell<usize>,
+}
+
+/// Collector state kept in front of every collectable object.
+pub struct GcHeader {
+    mark: Cell<u32>,
+    old: Cell<bool>,
+    touched: Cell<bool>,
+    finalizer: Cell<bool>,
+    // Bytes charged to the heap for this object
+    size: Cell<usize>,
+    barrier: Option<Rc<BarrierState>>,
+}
+
+impl GcHeader {
+    fn is_marked(&self, epoch: u32) -> bool {
+        self.mark.get() == epoch
+    }
+
+    /// Records the object's new estimated size, updating the total of the
+    /// heap it belongs to.
+    pub(crate) fn resize(&self, size: usize) {
+        if let Some(state) = &self.barrier {
+            state.bytes.set(state.bytes.get() - self.size.get() + size);
+        }
+        self.size.set(size);
+    }
+
+    /// Whether a mutation of this object has to be reported to its heap: a
+    /// marked object may be about to point at an unmarked one while an
+    /// incremental cycle is running, and an old object may be about to point
+    /// at a young one between generational collections.
+    pub(crate) fn needs_barrier(&self) -> bool {
+        match &self.barrier {
+            Some(state) if !self.touched.get() => {
+                if state.generational.get() {
+                    self.old.get()
+                } else {
+                    state.marking.get() && self.is_marked(state.epoch.get())
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Allocation shared by all collectable objects: the collector's header
+/// followed by the object itself. Objects bring their own interior
+/// mutability, so the collector never has to borrow one mutably while the
+/// host may be using it.
+pub struct GcBox<T> {
+    header: GcHeader,
+    value: T,
+}
+
+impl<T> GcBox<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            header: GcHeader {
+                mark: Cell::new(0),
+                old: Cell::new(false),
+                touched: Cell::new(false),
+                finalizer: Cell::new(false),
+                size: Cell::new(0),
+                barrier: None,
+            },
+            value,
+        }
+    }
+
+    pub(crate) fn header(&self) -> &GcHeader {
+        &self.header
+    }
+}
+
+impl<T> Drop for GcBox<T> {
+    fn drop(&mut self) {
+        self.header.resize(0);
+    }
+}
+
+impl<T> Deref for GcBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Reports a mutation of `object` to the heap that allocated it.
+pub(crate) fn write_barrier(object: GcObject) {
+    let header = object.header();
+    if let Some(state) = &header.barrier {
+        header.touched.set(true);
+        state.touched.borrow_mut().push(object.clone());
+    }
+}
+
+/// Reports `value` being stored where no write barrier covers the store,
+/// like in a captured local. An object the running cycle has not reached
+/// yet, or a young one between generational collections, is traversed
+/// again as if it had been mutated, which keeps it alive for the cycle.
+pub(crate) fn forward_barrier(value: &Value) {
+    let object = match GcObject::from_value(value) {
+        Some(object) => object,
+        None => return,
+    };
+    let header = object.header();
+    if let Some(state) = &header.barrier {
+        let unseen = if state.generational.get() {
+            !header.old.get()
+        } else {
+            state.marking.get() && !header.is_marked(state.epoch.get())
+        };
+        if unseen && !header.touched.get() {
+            write_barrier(object);
+        }
+    }
+}
+
+enum WeakObject {
+    Table(Weak<GcBox<RefCell<LuaTable>>>),
+    Userdata(Weak<GcBox<Userdata>>),
+    Thread(Weak<GcBox<RefCell<Coroutine>>>),
+    Function(Weak<GcBox<NativeFunction>>),
+    Closure(Weak<GcBox<LuaClosure>>),
+}
+
+impl WeakObject {
+    fn upgrade(&self) -> Option<GcObject> {
+        match self {
+            WeakObject::Table(weak) => weak.upgrade().map(|gc_box| GcObject::Table(TableRef::from_rc(gc_box))),
+            WeakObject::Userdata(weak) => weak
+                .upgrade()
+                .map(|gc_box| GcObject::Userdata(UserdataRef::from_rc(gc_box))),
+            WeakObject::Thread(weak) => weak
+                .upgrade()
+                .map(|gc_box| GcObject::Thread(CoroutineRef::from_rc(gc_box))),
+            WeakObject::Function(weak) => weak
+                .upgrade()
+                .map(|gc_box| GcObject::Function(NativeRef::from_rc(gc_box))),
+            WeakObject::Closure(weak) => weak
+                .upgrade()
+                .map(|gc_box| GcObject::Closure(ClosureRef::from_rc(gc_box))),
+        }
+    }
+}
+
+/// A strong reference to any object the collector manages.
+#[derive(Clone)]
+pub(crate) enum GcObject {
+    Table(TableRef),
+    Userdata(UserdataRef),
+    Thread(CoroutineRef),
+    Function(NativeRef),
+    Closure(ClosureRef),
+}
+
+impl GcObject {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Table(table) => Some(GcObject::Table(table.clone())),
+            Value::Userdata(userdata) => Some(GcObject::Userdata(userdata.clone())),
+            Value::Thread(coroutine) => Some(GcObject::Thread(coroutine.clone())),
+            Value::Native(function) if function.is_bound() => Some(GcObject::Function(function.clone())),
+            Value::Closure(closure) => Some(GcObject::Closure(closure.clone())),
+            _ => None,
+        }
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            GcObject::Table(table) => Value::Table(table),
+            GcObject::Userdata(userdata) => Value::Userdata(userdata),
+            GcObject::Thread(coroutine) => Value::Thread(coroutine),
+            GcObject::Function(function) => Value::Native(function),
+            GcObject::Closure(closure) => Value::Closure(closure),
+        }
+    }
+
+    fn header(&self) -> &GcHeader {
+        match self {
+            GcObject::Table(table) => table.gc_box().header(),
+            GcObject::Userdata(userdata) => userdata.gc_box().header(),
+            GcObject::Thread(coroutine) => coroutine.gc_box().header(),
+            GcObject::Function(function) => function.gc_box().header(),
+            GcObject::Closure(closure) => closure.gc_box().header(),
+        }
+    }
+
+    fn downgrade(&self) -> WeakObject {
+        match self {
+            GcObject::Table(table) => WeakObject::Table(Rc::downgrade(table.gc_box())),
+            GcObject::Userdata(userdata) => WeakObject::Userdata(Rc::downgrade(userdata.gc_box())),
+            GcObject::Thread(coroutine) => WeakObject::Thread(Rc::downgrade(coroutine.gc_box())),
+            GcObject::Function(function) => WeakObject::Function(Rc::downgrade(function.gc_box())),
+            GcObject::Closure(closure) => WeakObject::Closure(Rc::downgrade(closure.gc_box())),
+        }
+    }
+
+    fn metatable(&self) -> Option<TableRef> {
+        match self {
+            GcObject::Table(table) => table.borrow().metatable(),
+            GcObject::Userdata(userdata) => userdata.metatable(),
+            GcObject::Thread(_) | GcObject::Function(_) | GcObject::Closure(_) => None,
+        }
+    }
+
+    fn estimated_size(&self) -> usize {
+        match self {
+            GcObject::Table(table) => table.borrow().estimated_size(),
+            GcObject::Userdata(userdata) => userdata.gc_box().estimated_size(),
+            GcObject::Thread(coroutine) => coroutine.borrow().estimated_size(),
+            GcObject::Function(function) => function.gc_box().estimated_size(),
+            GcObject::Closure(closure) => closure.gc_box().estimated_size(),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            GcObject::Table(table) => Rc::strong_count(table.gc_box()),
+            GcObject::Userdata(userdata) => Rc::strong_count(userdata.gc_box()),
+            GcObject::Thread(coroutine) => Rc::strong_count(coroutine.gc_box()),
+            GcObject::Function(function) => Rc::strong_count(function.gc_box()),
+            GcObject::Closure(closure) => Rc::strong_count(closure.gc_box()),
+        }
+    }
+
+    // Visits every reference the object holds, weak or not, for a tracer
+    // that is counting them
+    fn count_references(&self, tracer: &mut Tracer) {
+        match self {
+            GcObject::Table(table) => table.borrow().count_references(tracer),
+            GcObject::Userdata(userdata) => userdata.gc_box().trace(tracer),
+            GcObject::Thread(coroutine) => coroutine.borrow().trace(tracer),
+            GcObject::Function(function) => function.gc_box().trace(tracer),
+            GcObject::Closure(closure) => closure.gc_box().trace(tracer),
+        }
+    }
+
+    // Bypasses the write barrier: the collector is not a mutator
+    fn clear_references(&self) -> Vec<Value> {
+        match self {
+            GcObject::Table(table) => table.gc_box().borrow_mut().clear_references(),
+            GcObject::Userdata(userdata) => userdata.gc_box().clear_references(),
+            GcObject::Thread(coroutine) => coroutine.gc_box().borrow_mut().clear_references(),
+            GcObject::Function(function) => function.gc_box().clear_references(),
+            GcObject::Closure(closure) => closure.gc_box().clear_references(),
+        }
+    }
+}
+
+pub struct Tracer {
+    epoch: u32,
+    minor: bool,
+    gray: Vec<GcObject>,
+    // Tables whose traversal ran out of budget, and the slot to resume at
+    partial: Vec<(TableRef, usize)>,
+    // Weak tables traversed this cycle, cleared once marking is complete
+    weak: Vec<TableRef>,
+    // Set while counting references to unreached objects instead of marking
+    counts: Option<ReferenceCounts>,
+}
+
+// References found to each unreached object, by the address of its header,
+// and the upvalues already visited, whose value is one reference however
+// many closures share them
+#[derive(Default)]
+struct ReferenceCounts {
+    objects: HashMap<*const GcHeader, usize>,
+    upvalues: HashSet<*const Upvalue>,
+}
+
+impl Tracer {
+    fn new(minor: bool) -> Self {
+        Self {
+            epoch: NEXT_EPOCH.fetch_add(1, Ordering::Relaxed),
+            minor,
+            gray: Vec::new(),
+            partial: Vec::new(),
+            weak: Vec::new(),
+            counts: None,
+        }
+    }
+
+    pub fn mark_value(&mut self, value: &Value) {
+        match value {
+            Value::Table(table) => self.mark_table(table),
+            Value::Userdata(userdata) => self.mark_userdata(userdata),
+            Value::Thread(coroutine) => self.mark(coroutine.gc_box().header(), || GcObject::Thread(coroutine.clone())),
+            Value::Native(function) if function.is_bound() => {
+                self.mark(function.gc_box().header(), || GcObject::Function(function.clone()))
+            }
+            Value::Closure(closure) => self.mark(closure.gc_box().header(), || GcObject::Closure(closure.clone())),
+            _ => {}
+        }
+    }
+
+    pub fn mark_table(&mut self, table: &TableRef) {
+        self.mark(table.gc_box().header(), || GcObject::Table(table.clone()));
+    }
+
+    pub fn mark_userdata(&mut self, userdata: &UserdataRef) {
+        self.mark(userdata.gc_box().header(), || GcObject::Userdata(userdata.clone()));
+    }
+
+    /// Marks the value of a captured local, which several closures and a
+    /// suspended frame may share.
+    pub fn mark_upvalue(&mut self, upvalue: &Rc<Upvalue>) {
+        if let Some(counts) = &mut self.counts {
+            if !counts.upvalues.insert(Rc::as_ptr(upvalue)) {
+                return;
+            }
+        }
+        self.mark_value(&upvalue.borrow());
+    }
+
+    fn mark_object(&mut self, object: &GcObject) {
+        self.mark(object.header(), || object.clone());
+    }
+
+    fn mark(&mut self, header: &GcHeader, object: impl FnOnce() -> GcObject) {
+        if self.counts.is_some() {
+            if !self.is_reached(header) {
+                if let Some(counts) = &mut self.counts {
+                    *counts.objects.entry(header as *const GcHeader).or_default() += 1;
+                }
+            }
+            return;
+        }
+        // A minor collection only traverses the young generation; old objects
+        // that gained references since the last one come from the barrier
+        if self.minor && header.old.get() {
+            return;
+        }
+        if !header.is_marked(self.epoch) {
+            header.mark.set(self.epoch);
+            self.gray.push(object());
+        }
+    }
+
+    /// Whether `value` has been found reachable so far in this cycle. Only
+    /// objects can be unreachable; every other value, including registered
+    /// builtins, counts as alive.
+    pub fn is_alive(&self, value: &Value) -> bool {
+        match value {
+            Value::Table(table) => self.is_reached(table.gc_box().header()),
+            Value::Userdata(userdata) => self.is_reached(userdata.gc_box().header()),
+            Value::Thread(coroutine) => self.is_reached(coroutine.gc_box().header()),
+            Value::Native(function) if function.is_bound() => self.is_reached(function.gc_box().header()),
+            Value::Closure(closure) => self.is_reached(closure.gc_box().header()),
+            _ => true,
+        }
+    }
+
+    fn is_reached(&self, header: &GcHeader) -> bool {
+        header.is_marked(self.epoch) || (self.minor && header.old.get())
+    }
+
+    // Queues an object for traversal even if it has already been marked
+    fn retrace(&mut self, object: GcObject) {
+        object.header().mark.set(self.epoch);
+        self.gray.push(object);
+    }
+
+    /// Traverses gray objects until `budget` units of work are done, each
+    /// object and each table slot counting as one, and returns the work
+    /// done. A table is left part way through if the budget runs out.
+    fn propagate(&mut self, budget: usize) -> usize {
+        let mut work = 0;
+        while work < budget {
+            // What a table marked is traversed before the rest of the table,
+            // so the gray list does not grow with the size of the table
+            let (table, start) = match self.gray.pop() {
+                Some(GcObject::Table(table)) => (table, 0),
+                Some(object) => {
+                    match object {
+                        GcObject::Userdata(userdata) => userdata.gc_box().trace(self),
+                        GcObject::Thread(coroutine) => coroutine.borrow().trace(self),
+                        GcObject::Function(function) => function.gc_box().trace(self),
+                        GcObject::Closure(closure) => closure.gc_box().trace(self),
+                        GcObject::Table(_) => unreachable!(),
+                    }
+                    work += 1;
+                    continue;
+                }
+                None => match self.partial.pop() {
+                    Some(partial) => partial,
+                    None => break,
+                },
+            };
+            let end = table.borrow().trace_slots(self, start, budget - work);
+            work += 1 + end.saturating_sub(start);
+            if end < table.borrow().slot_count() {
+                self.partial.push((table, end));
+            } else if table.borrow().weak_mode() != WeakMode::Strong {
+                self.weak.push(table);
+            }
+        }
+        work
+    }
+
+    fn is_done(&self) -> bool {
+        self.gray.is_empty() && self.partial.is_empty()
+    }
+
+    /// Propagates until marking is complete. Ephemeron tables are revisited
+    /// until no value becomes reachable through a newly marked key.
+    fn converge(&mut self) {
+        loop {
+            self.propagate(usize::MAX);
+            let mut marked = false;
+            for table in self.weak.clone() {
+                let table = table.borrow();
+                if table.weak_mode() != WeakMode::Keys {
+                    continue;
+                }
+                for (key, value) in table.iter() {
+                    if self.is_alive(&key) && !self.is_alive(&value) {
+                        self.mark_value(&value);
+                        marked = true;
+                    }
+                }
+            }
+            if !marked {
+                break;
+            }
+        }
+    }
+
+    /// Removes the entries of traversed weak tables whose weak value, or
+    /// also weak key if `keys` is set, was never reached.
+    fn clear_weak(&mut self, keys: bool) {
+        for table in &self.weak {
+            let (weak_keys, weak_values) = match table.borrow().weak_mode() {
+                WeakMode::Strong => continue,
+                WeakMode::Keys => (keys, false),
+                WeakMode::Values => (false, true),
+                WeakMode::Both => (keys, true),
+            };
+            // Bypasses the write barrier: the collector is not a mutator
+            table.gc_box().borrow_mut().retain(|key, value| {
+                (!weak_keys || self.is_alive(key)) && (!weak_values || self.is_alive(value))
+            });
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GcStats {
+    pub cycles: usize,
+    pub minor_collections: usize,
+    pub objects_freed: usize,
+    pub pause_histogram: [usize; PAUSE_BUCKETS.len() + 1],
+    pub longest_pause: Duration,
+}
+
+impl GcStats {
+    fn record_pause(&mut self, pause: Duration) {
+        let bucket = PAUSE_BUCKETS
+            .iter()
+            .position(|limit| pause < *limit)
+            .unwrap_or(PAUSE_BUCKETS.len());
+        self.pause_histogram[bucket] += 1;
+        self.longest_pause = self.longest_pause.max(pause);
+    }
+}
+
+enum Phase {
+    Pause,
+    Propagate(Tracer),
+    Sweep { epoch: u32, freed: usize },
+}
+
+/// Tracing collector for tables and other heap objects.
+///
+/// Objects are reference counted, so acyclic garbage is freed as soon as the
+/// last reference goes away. The collector exists for cycles: it marks
+/// everything reachable from the roots it is given and clears the contents of
+/// every registered object it did not reach, which lets the reference counts
+/// drop to zero. Values the host keeps a `Root` for are roots too. Objects
+/// it holds any other way are never cleared either: once marking is done,
+/// an unreached object with more references than the heap accounts for is
+/// held from outside, and is kept along with everything it references.
+///
+/// In incremental mode a cycle is spread over many small steps, with a
+/// write barrier re-queuing marked tables that are mutated before the cycle
+/// finishes. In generational mode, minor collections only visit objects
+/// allocated since the previous collection plus old objects the barrier saw
+/// being written to.
+///
+/// Tables and userdata whose metatable has a `__gc` field when it is set are marked for
+/// finalization and held strongly until found unreachable. They are then
+/// resurrected for one more cycle and queued for the runtime to call their
+/// finalizers, in reverse order of marking.
+pub struct Heap {
+    objects: Vec<WeakObject>,
+    young: Vec<WeakObject>,
+    sweeping: Vec<WeakObject>,
+    finalizable: Vec<GcObject>,
+    to_finalize: VecDeque<GcObject>,
+    closing: bool,
+    barrier: Rc<BarrierState>,
+    host_roots: Rc<RefCell<HostRoots>>,
+    mode: GcMode,
+    phase: Phase,
+    allocated: usize,
+    debt: usize,
+    live: usize,
+    major_base: usize,
+    threshold: usize,
+    pause: usize,
+    step_multiplier: usize,
+    step_size: u32,
+    minor_multiplier: usize,
+    major_multiplier: usize,
+    running: bool,
+    stats: GcStats,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: Vec::new(),
+            young: Vec::new(),
+            sweeping: Vec::new(),
+            finalizable: Vec::new(),
+            to_finalize: VecDeque::new(),
+            closing: false,
+            barrier: Rc::new(BarrierState {
+                epoch: Cell::new(0),
+                marking: Cell::new(false),
+                generational: Cell::new(false),
+                touched: RefCell::new(Vec::new()),
+                bytes: Cell::new(0),
+            }),
+            host_roots: Rc::default(),
+            mode: GcMode::Incremental,
+            phase: Phase::Pause,
+            allocated: 0,
+            debt: 0,
+            live: 0,
+            major_base: 0,
+            threshold: MIN_THRESHOLD,
+            pause: DEFAULT_PAUSE,
+            step_multiplier: DEFAULT_STEP_MULTIPLIER,
+            step_size: DEFAULT_STEP_SIZE,
+            minor_multiplier: DEFAULT_MINOR_MULTIPLIER,
+            major_multiplier: DEFAULT_MAJOR_MULTIPLIER,
+            running: true,
+            stats: GcStats::default(),
+        }
+    }
+
+    pub fn alloc_table(&mut self, table: LuaTable) -> TableRef {
+        let size = table.estimated_size();
+        let table = TableRef::from_gc_box(self.gc_box(RefCell::new(table)));
+        self.register(GcObject::Table(table.clone()), size);
+        table
+    }
+
+    pub fn alloc_userdata(&mut self, userdata: Userdata) -> UserdataRef {
+        let gc_box = self.gc_box(userdata);
+        let size = gc_box.estimated_size();
+        let userdata = UserdataRef::from_gc_box(gc_box);
+        self.register(GcObject::Userdata(userdata.clone()), size);
+        userdata
+    }
+
+    pub fn alloc_coroutine(&mut self, coroutine: Coroutine) -> CoroutineRef {
+        let size = coroutine.estimated_size();
+        let coroutine = CoroutineRef::from_gc_box(self.gc_box(RefCell::new(coroutine)));
+        self.register(GcObject::Thread(coroutine.clone()), size);
+        coroutine
+    }
+
+    pub fn alloc_native(&mut self, function: NativeFunction) -> NativeRef {
+        let gc_box = self.gc_box(function);
+        let size = gc_box.estimated_size();
+        let function = NativeRef::from_gc_box(gc_box);
+        self.register(GcObject::Function(function.clone()), size);
+        function
+    }
+
+    pub fn alloc_closure(&mut self, closure: LuaClosure) -> ClosureRef {
+        let gc_box = self.gc_box(closure);
+        let size = gc_box.estimated_size();
+        let closure = ClosureRef::from_gc_box(gc_box);
+        self.register(GcObject::Closure(closure.clone()), size);
+        closure
+    }
+
+    fn gc_box<T>(&self, value: T) -> GcBox<T> {
+        let mut gc_box = GcBox::new(value);
+        gc_box.header.barrier = Some(self.barrier.clone());
+        gc_box
+    }
+
+    fn register(&mut self, object: GcObject, size: usize) {
+        self.allocated += size;
+        self.debt += size;
+        object.header().resize(size);
+
+        // Objects created while marking start out white and are found through
+        // the roots or the barrier like any other. Objects created while
+        // sweeping go to a list the current sweep never looks at.
+        match self.mode {
+            GcMode::Incremental => self.objects.push(object.downgrade()),
+            GcMode::Generational => self.young.push(object.downgrade()),
+        }
+    }
+
+    /// Makes `value` a root until the returned handle and all its clones are
+    /// dropped.
+    pub fn root(&self, value: Value) -> Root {
+        Root::new(&self.host_roots, value)
+    }
+
+    // Marks everything the host holds a `Root` for. The handles can be
+    // created and dropped at any time, so an incremental cycle marks them
+    // again in its atomic step.
+    fn mark_host_roots(&self, tracer: &mut Tracer) {
+        for value in self.host_roots.borrow().slots.iter().flatten() {
+            tracer.mark_value(value);
+        }
+    }
+
+    /// Marks `object` for finalization if its metatable has a `__gc` field.
+    /// Only called when a metatable is set, so adding `__gc` to a metatable
+    /// afterwards has no effect. Values that are not objects allocated by
+    /// this heap are ignored.
+    pub fn check_finalizer(&mut self, object: &Value) {
+        let object = match GcObject::from_value(object) {
+            Some(object) => object,
+            None => return,
+        };
+        let header = object.header();
+        if self.closing || header.finalizer.get() || header.barrier.is_none() {
+            return;
+        }
+        let has_gc = match object.metatable() {
+            Some(metatable) => !matches!(metatable.get_field("__gc"), Value::Nil),
+            None => false,
+        };
+        if has_gc {
+            header.finalizer.set(true);
+            self.finalizable.push(object);
+        }
+    }
+
+    /// Takes the next object whose finalizer is due. The object is no longer
+    /// marked for finalization afterwards.
+    pub fn next_finalizer(&mut self) -> Option<Value> {
+        let object = self.to_finalize.pop_front()?;
+        object.header().finalizer.set(false);
+        Some(object.into_value())
+    }
+
+    /// Queues every object marked for finalization, reachable or not, and
+    /// stops marking new ones. Used when the owning runtime is closed.
+    pub fn finalize_all(&mut self) {
+        self.closing = true;
+        self.to_finalize.extend(self.finalizable.drain(..).rev());
+    }
+
+    // Objects waiting for their finalizer must survive until it has run, as
+    // must everything they reference
+    fn mark_to_finalize(&self, tracer: &mut Tracer) {
+        for object in &self.to_finalize {
+            tracer.mark_object(object);
+        }
+    }
+
+    // Marks the unreached objects something outside the heap still holds,
+    // like the host or a native function that is running. Once marking is
+    // complete a reached object only references reached ones, so every
+    // reference the heap has to an unreached object comes from another
+    // unreached one, a weak table or the list of objects to finalize.
+    // Objects with more references than that are held from outside.
+    fn mark_host_held(&self, tracer: &mut Tracer) {
+        loop {
+            let lists: Vec<&Vec<WeakObject>> = if tracer.minor {
+                vec![&self.young]
+            } else {
+                vec![&self.objects, &self.young, &self.sweeping]
+            };
+            let unreached: Vec<GcObject> = lists
+                .into_iter()
+                .flatten()
+                .filter_map(WeakObject::upgrade)
+                .filter(|object| !tracer.is_reached(object.header()))
+                .collect();
+            if unreached.is_empty() {
+                return;
+            }
+
+            tracer.counts = Some(ReferenceCounts::default());
+            for object in &unreached {
+                object.count_references(tracer);
+            }
+            for table in tracer.weak.clone() {
+                table.borrow().count_references(tracer);
+            }
+            for object in &self.finalizable {
+                tracer.mark_object(object);
+            }
+            let counts = tracer.counts.take().unwrap().objects;
+
+            // Besides the references counted, `unreached` holds one
+            let mut held = false;
+            for object in &unreached {
+                let counted = counts.get(&(object.header() as *const GcHeader)).copied().unwrap_or(0);
+                if object.strong_count() > counted + 1 {
+                    tracer.mark_object(object);
+                    held = true;
+                }
+            }
+            if !held {
+                return;
+            }
+            tracer.converge();
+        }
+    }
+
+    // Completes marking. Weak values are cleared before unreachable objects
+    // with finalizers are resurrected, so caches never hand out an object
+    // that is being finalized; weak keys are only cleared afterwards.
+    fn finish_marking(&mut self, tracer: &mut Tracer) {
+        tracer.converge();
+        self.mark_host_held(tracer);
+        tracer.clear_weak(false);
+
+        let (unreachable, reachable): (Vec<GcObject>, Vec<GcObject>) = self
+            .finalizable
+            .drain(..)
+            .partition(|object| !tracer.is_reached(object.header()));
+        self.finalizable = reachable;
+        for object in unreachable.into_iter().rev() {
+            tracer.mark_object(&object);
+            self.to_finalize.push_back(object);
+        }
+
+        tracer.converge();
+        tracer.clear_weak(true);
+    }
+
+    pub fn mode(&self) -> GcMode {
+        self.mode
+    }
+
+    /// Switches to incremental mode. Zero leaves a parameter unchanged.
+    pub fn set_incremental(&mut self, pause: usize, step_multiplier: usize, step_size: u32) -> GcMode {
+        let previous = self.mode;
+        if pause != 0 {
+            self.pause = pause;
+        }
+        if step_multiplier != 0 {
+            self.step_multiplier = step_multiplier;
+        }
+        if step_size != 0 {
+            self.step_size = step_size;
+        }
+        if previous != GcMode::Incremental {
+            self.mode = GcMode::Incremental;
+            self.barrier.generational.set(false);
+            self.objects.append(&mut self.young);
+            self.release_touched();
+            self.threshold = self.pause_threshold();
+        }
+        previous
+    }
+
+    /// Switches to generational mode, running a full collection so that every
+    /// surviving object starts out old. Zero leaves a parameter unchanged.
+    pub fn set_generational<I>(&mut self, roots: I, minor_multiplier: usize, major_multiplier: usize) -> GcMode
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        let previous = self.mode;
+        if minor_multiplier != 0 {
+            self.minor_multiplier = minor_multiplier;
+        }
+        if major_multiplier != 0 {
+            self.major_multiplier = major_multiplier;
+        }
+        if previous != GcMode::Generational {
+            self.mode = GcMode::Generational;
+            self.barrier.generational.set(true);
+            self.collect(roots);
+        }
+        previous
+    }
+
+    pub fn should_step(&self) -> bool {
+        if !self.running {
+            return false;
+        }
+        match (&self.phase, self.mode) {
+            (Phase::Pause, GcMode::Incremental) => self.live + self.allocated >= self.threshold,
+            (Phase::Pause, GcMode::Generational) => {
+                self.allocated >= (self.live * self.minor_multiplier / 100).max(MIN_THRESHOLD)
+            }
+            _ => self.debt >= 1 << self.step_size,
+        }
+    }
+
+    /// Performs one unit of collector work: an incremental step, or a minor
+    /// (occasionally major) collection in generational mode. Returns true if
+    /// a collection cycle finished.
+    pub fn step<I>(&mut self, roots: I) -> bool
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        let start = Instant::now();
+        let finished = match self.mode {
+            GcMode::Incremental => self.incremental_step(roots),
+            GcMode::Generational => {
+                if self.live > self.major_base * (100 + self.major_multiplier) / 100 {
+                    self.full_cycle(roots);
+                } else {
+                    self.minor_cycle(roots);
+                }
+                true
+            }
+        };
+        self.stats.record_pause(start.elapsed());
+        finished
+    }
+
+    /// Runs a full collection cycle, abandoning any cycle in progress, and
+    /// returns the number of objects freed.
+    pub fn collect<I>(&mut self, roots: I) -> usize
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        let start = Instant::now();
+        let freed = self.full_cycle(roots);
+        self.stats.record_pause(start.elapsed());
+        freed
+    }
+
+    fn full_cycle<I>(&mut self, roots: I) -> usize
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        self.barrier.marking.set(false);
+        self.release_touched();
+
+        let mut tracer = Tracer::new(false);
+        for root in roots {
+            tracer.mark_value(&root);
+        }
+        self.mark_host_roots(&mut tracer);
+        self.mark_to_finalize(&mut tracer);
+        self.finish_marking(&mut tracer);
+
+        let epoch = tracer.epoch;
+        let mut objects = std::mem::take(&mut self.objects);
+        objects.append(&mut self.young);
+        objects.append(&mut self.sweeping);
+        let mut garbage = Vec::new();
+        let (freed, live) = sweep_list(&mut objects, epoch, &mut garbage, |header| {
+            header.old.set(true);
+        });
+        self.objects = objects;
+
+        // Dropping the cleared references may free further objects, so only
+        // do it once the object lists are consistent again
+        drop(garbage);
+
+        self.phase = Phase::Pause;
+        self.finish_cycle(freed, live);
+        self.major_base = live;
+        freed
+    }
+
+    fn minor_cycle<I>(&mut self, roots: I) -> usize
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        let mut tracer = Tracer::new(true);
+        for root in roots {
+            tracer.mark_value(&root);
+        }
+        self.mark_host_roots(&mut tracer);
+        self.mark_to_finalize(&mut tracer);
+        for object in self.barrier.touched.borrow_mut().drain(..) {
+            object.header().touched.set(false);
+            tracer.retrace(object);
+        }
+        self.finish_marking(&mut tracer);
+
+        let epoch = tracer.epoch;
+        let mut young = std::mem::take(&mut self.young);
+        let mut garbage = Vec::new();
+        let (freed, survived) = sweep_list(&mut young, epoch, &mut garbage, |header| {
+            header.old.set(true);
+        });
+        self.objects.append(&mut young);
+        drop(garbage);
+
+        self.stats.minor_collections += 1;
+        self.stats.objects_freed += freed;
+        self.live += survived;
+        self.allocated = 0;
+        self.debt = 0;
+        freed
+    }
+
+    fn incremental_step<I>(&mut self, roots: I) -> bool
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        let budget = ((1usize << self.step_size) * self.step_multiplier / 100 / BYTES_PER_WORK_UNIT).max(1);
+        self.debt = 0;
+
+        match std::mem::replace(&mut self.phase, Phase::Pause) {
+            Phase::Pause => {
+                let mut tracer = Tracer::new(false);
+                for root in roots {
+                    tracer.mark_value(&root);
+                }
+                self.mark_host_roots(&mut tracer);
+                self.mark_to_finalize(&mut tracer);
+                self.barrier.epoch.set(tracer.epoch);
+                self.barrier.marking.set(true);
+                self.phase = Phase::Propagate(tracer);
+                false
+            }
+            Phase::Propagate(mut tracer) => {
+                if tracer.propagate(budget) < budget {
+                    self.atomic(&mut tracer, roots);
+                    self.sweeping = std::mem::take(&mut self.objects);
+                    // Survivors are moved back a batch at a time
+                    self.objects.reserve(self.sweeping.len());
+                    self.phase = Phase::Sweep {
+                        epoch: tracer.epoch,
+                        freed: 0,
+                    };
+                } else {
+                    self.phase = Phase::Propagate(tracer);
+                }
+                false
+            }
+            Phase::Sweep { epoch, freed } => self.sweep_step(epoch, freed, budget),
+        }
+    }
+
+    // Finishes marking in one go: roots are not covered by the barrier, so
+    // they are scanned again along with every table mutated during the cycle
+    fn atomic<I>(&mut self, tracer: &mut Tracer, roots: I)
+    where
+        I: IntoIterator,
+        I::Item: Deref<Target = Value>,
+    {
+        for root in roots {
+            tracer.mark_value(&root);
+        }
+        self.mark_host_roots(tracer);
+        self.mark_to_finalize(tracer);
+        loop {
+            let touched: Vec<GcObject> = self.barrier.touched.borrow_mut().drain(..).collect();
+            if touched.is_empty() && tracer.is_done() {
+                break;
+            }
+            for object in touched {
+                object.header().touched.set(false);
+                tracer.retrace(object);
+            }
+            tracer.propagate(usize::MAX);
+        }
+        self.finish_marking(tracer);
+        self.barrier.marking.set(false);
+    }
+
+    fn sweep_step(&mut self, epoch: u32, mut freed: usize, budget: usize) -> bool {
+        let mut garbage = Vec::new();
+        let split = self.sweeping.len().saturating_sub(budget);
+        let mut batch = self.sweeping.split_off(split);
+
+        let (batch_freed, _) = sweep_list(&mut batch, epoch, &mut garbage, |_| {});
+        self.objects.append(&mut batch);
+        drop(garbage);
+        freed += batch_freed;
+
+        if self.sweeping.is_empty() {
+            let live = self.memory_used();
+            self.finish_cycle(freed, live);
+            true
+        } else {
+            self.phase = Phase::Sweep { epoch, freed };
+            false
+        }
+    }
+
+    fn finish_cycle(&mut self, freed: usize, live: usize) {
+        self.live = live;
+        self.allocated = 0;
+        self.debt = 0;
+        self.threshold = self.pause_threshold();
+        self.stats.cycles += 1;
+        self.stats.objects_freed += freed;
+    }
+
+    fn pause_threshold(&self) -> usize {
+        (self.live * self.pause / 100).max(MIN_THRESHOLD)
+    }
+
+    fn release_touched(&mut self) {
+        for object in self.barrier.touched.borrow_mut().drain(..) {
+            object.header().touched.set(false);
+        }
+    }
+
+    /// Estimated number of bytes held by objects that have not been freed
+    /// yet, kept up to date as they are allocated, resized and freed.
+    pub fn memory_used(&self) -> usize {
+        self.barrier.bytes.get()
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len() + self.young.len() + self.sweeping.len()
+    }
+
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn restart(&mut self) {
+        self.running = true;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn stats(&self) -> &GcStats {
+        &self.stats
+    }
+
+    pub fn print_stats(&self) {
+        println!("=== Garbage Collector Statistics ===");
+        println!("Mode: {}", self.mode.name());
+        println!("Memory in use: {} bytes", self.memory_used());
+        println!("Cycles completed: {}", self.stats.cycles);
+        println!("Minor collections: {}", self.stats.minor_collections);
+        println!("Objects freed: {}", self.stats.objects_freed);
+        println!("Pause times:");
+
+        let mut lower = Duration::ZERO;
+        for (i, count) in self.stats.pause_histogram.iter().enumerate() {
+            match PAUSE_BUCKETS.get(i) {
+                Some(upper) => {
+                    println!("  {:?} - {:?}: {}", lower, upper, count);
+                    lower = *upper;
+                }
+                None => println!("  >= {:?}: {}", lower, count),
+            }
+        }
+        println!("Longest pause: {:?}", self.stats.longest_pause);
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        // Touched tables point back at the barrier state
+        self.release_touched();
+    }
+}
+
+// Values rooted by the host, in slots that are reused once their handle is
+// dropped
+#[derive(Default)]
+struct HostRoots {
+    slots: Vec<Option<Value>>,
+    free: Vec<usize>,
+}
+
+/// A value the host keeps a handle to, which the collector treats as a root
+/// for as long as the handle lives. Created by `LuaJitRuntime::root`.
+pub struct Root {
+    roots: Rc<RefCell<HostRoots>>,
+    slot: usize,
+}
+
+impl Root {
+    fn new(roots: &Rc<RefCell<HostRoots>>, value: Value) -> Self {
+        let mut host_roots = roots.borrow_mut();
+        let slot = match host_roots.free.pop() {
+            Some(slot) => {
+                host_roots.slots[slot] = Some(value);
+                slot
+            }
+            None => {
+                host_roots.slots.push(Some(value));
+                host_roots.slots.len() - 1
+            }
+        };
+        Self {
+            roots: roots.clone(),
+            slot,
+        }
+    }
+
+    /// The rooted value.
+    pub fn value(&self) -> Value {
+        self.roots.borrow().slots[self.slot].clone().unwrap_or(Value::Nil)
+    }
+}
+
+impl Clone for Root {
+    fn clone(&self) -> Self {
+        Root::new(&self.roots, self.value())
+    }
+}
+
+impl Drop for Root {
+    fn drop(&mut self) {
+        // Dropping the value may run arbitrary destructors, so do it once
+        // the slots are no longer borrowed
+        let value = {
+            let mut host_roots = self.roots.borrow_mut();
+            host_roots.free.push(self.slot);
+            host_roots.slots[self.slot].take()
+        };
+        drop(value);
+    }
+}
+
+impl fmt::Debug for Root {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Root({:?})", self.value())
+    }
+}
+
+// Clears every unmarked object in `objects`, calling `survive` on the rest.
+// Returns the number of objects freed and the estimated size of survivors,
+// which is charged to the heap again in case they changed size unnoticed.
+fn sweep_list<F>(objects: &mut Vec<WeakObject>, epoch: u32, garbage: &mut Vec<Value>, survive: F) -> (usize, usize)
+where
+    F: Fn(&GcHeader),
+{
+    let mut freed = 0;
+    let mut live = 0;
+
+    objects.retain(|object| match object.upgrade() {
+        Some(object) if object.header().is_marked(epoch) => {
+            survive(object.header());
+            let size = object.estimated_size();
+            object.header().resize(size);
+            live += size;
+            true
+        }
+        Some(object) => {
+            garbage.extend(object.clear_references());
+            freed += 1;
+            false
+        }
+        None => false,
+    });
+
+    (freed, live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table_value(table: &TableRef) -> Value {
+        Value::Table(table.clone())
+    }
+
+    #[test]
+    fn test_barrier_keeps_object_moved_behind_marked_table() {
+        let mut heap = Heap::new();
+        heap.set_incremental(0, 1, 0);
+
+        let black = heap.alloc_table(LuaTable::new());
+        let gray = heap.alloc_table(LuaTable::new());
+        let white = heap.alloc_table(LuaTable::new());
+        white.set(Value::String("value".into()), Value::Number(7.0));
+        gray.set(Value::String("white".into()), table_value(&white));
+
+        let roots = [table_value(&gray), table_value(&black)];
+
+        // Start the cycle, then traverse only `black`
+        assert!(!heap.step(roots.iter()));
+        assert!(!heap.step(roots.iter()));
+
+        black.set(Value::String("white".into()), table_value(&white));
+        gray.set(Value::String("white".into()), Value::Nil);
+        drop(white);
+
+        while !heap.step(roots.iter()) {}
+
+        let white = black.get_field("white");
+        match white {
+            Value::Table(white) => assert_eq!(white.get_field("value"), Value::Number(7.0)),
+            other => panic!("expected table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_minor_collection_keeps_young_objects_stored_in_old_ones() {
+        let mut heap = Heap::new();
+        let old = heap.alloc_table(LuaTable::new());
+        let roots = [table_value(&old)];
+        heap.set_generational(roots.iter(), 0, 0);
+
+        let young = heap.alloc_table(LuaTable::new());
+        young.set(Value::String("value".into()), Value::Number(1.0));
+        old.set(Value::String("young".into()), table_value(&young));
+        drop(young);
+
+        let cycle = heap.alloc_table(LuaTable::new());
+        cycle.set(Value::String("me".into()), table_value(&cycle));
+        drop(cycle);
+
+        heap.step(roots.iter());
+
+        assert_eq!(heap.stats().minor_collections, 1);
+        assert_eq!(heap.stats().objects_freed, 1);
+        match old.get_field("young") {
+            Value::Table(young) => assert_eq!(young.get_field("value"), Value::Number(1.0)),
+            other => panic!("expected table, got {:?}", other),
+        }
+    }
+
+    fn weak_table(heap: &mut Heap, mode: &str) -> TableRef {
+        let metatable = heap.alloc_table(LuaTable::new());
+        metatable.set(Value::String("__mode".into()), Value::String(mode.into()));
+        let table = heap.alloc_table(LuaTable::new());
+        table.borrow_mut().set_metatable(Some(metatable));
+        table
+    }
+
+    #[test]
+    fn test_weak_value_taken_out_during_cycle_survives() {
+        let mut heap = Heap::new();
+        heap.set_incremental(0, 1, 1);
+
+        let holder = heap.alloc_table(LuaTable::new());
+        let cache = weak_table(&mut heap, "v");
+        let value = heap.alloc_table(LuaTable::new());
+        value.set(Value::String("value".into()), Value::Number(7.0));
+        cache.set(Value::Number(1.0), table_value(&value));
+        drop(value);
+
+        let roots = [table_value(&holder), table_value(&cache)];
+
+        // Start the cycle and traverse only the cache before the value is
+        // stored somewhere strong
+        assert!(!heap.step(roots.iter()));
+        assert!(!heap.step(roots.iter()));
+
+        let value = cache.get(&Value::Number(1.0));
+        holder.set(Value::String("value".into()), value);
+
+        while !heap.step(roots.iter()) {}
+
+        match cache.get(&Value::Number(1.0)) {
+            Value::Table(value) => assert_eq!(value.get_field("value"), Value::Number(7.0)),
+            other => panic!("expected table, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_minor_collection_keeps_old_entries_of_weak_tables() {
+        let mut heap = Heap::new();
+        let holder = heap.alloc_table(LuaTable::new());
+        let cache = weak_table(&mut heap, "v");
+        let value = heap.alloc_table(LuaTable::new());
+        holder.set(Value::String("value".into()), table_value(&value));
+        cache.set(Value::Number(1.0), table_value(&value));
+        drop(value);
+
+        let roots = [table_value(&holder), table_value(&cache)];
+        heap.set_generational(roots.iter(), 0, 0);
+
+        holder.set(Value::String("value".into()), Value::Nil);
+        heap.step(roots.iter());
+        assert_eq!(heap.stats().minor_collections, 1);
+        assert!(matches!(cache.get(&Value::Number(1.0)), Value::Table(_)));
+
+        heap.collect(roots.iter());
+        assert_eq!(cache.get(&Value::Number(1.0)), Value::Nil);
+    }
+}
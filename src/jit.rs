@@ -2,9 +2,6 @@ use crate::bytecode::{Chunk, Instruction};
 use crate::value::Value;
 use std::collections::HashMap;
 
-#[cfg(target_arch = "x86_64")]
-use std::mem;
-
 pub struct HotSpot {
     pub start_pc: usize,
     pub end_pc: usize,
@@ -66,7 +63,9 @@ impl JitCompiler {
                 Instruction::Jump(_) |
                 Instruction::JumpIfFalse(_) |
                 Instruction::JumpIfTrue(_) |
-                Instruction::Return => return i + 1,
+                Instruction::Return |
+                Instruction::ReturnMulti(_) |
+                Instruction::ReturnSpread(_) => return i + 1,
                 _ => continue,
             }
         }
@@ -214,6 +213,12 @@ impl JitCompiler {
     }
 }
 
+impl Default for JitCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Clone for JitCompiler {
     fn clone(&self) -> Self {
         Self {
@@ -226,5 +231,5 @@ impl Clone for JitCompiler {
 
 // Trait for JIT-enabled execution
 pub trait JitEnabled {
-    fn execute_with_jit(&mut self, chunk: &Chunk, jit: &mut JitCompiler) -> Result<Value, String>;
+    fn execute_with_jit(&mut self, chunk: &Chunk, jit: &mut JitCompiler) -> crate::error::LuaResult<Value>;
 }
@@ -2,7 +2,7 @@
 pub enum TokenType {
     // Literals
     Number(f64),
-    String(String),
+    String(Vec<u8>),
     Identifier(String),
 
     // Keywords
@@ -49,6 +49,7 @@ pub enum TokenType {
     RightBracket,
     Comma,
     Semicolon,
+    Colon,
     Dot,
     DotDot, // .. concatenation operator
 
@@ -65,16 +66,17 @@ pub struct Token {
 }
 
 pub struct Lexer {
-    input: Vec<char>,
+    // Source is bytes: string literals keep bytes that are not UTF-8
+    input: Vec<u8>,
     position: usize,
     line: usize,
     column: usize,
 }
 
 impl Lexer {
-    pub fn new(input: &str) -> Self {
+    pub fn new(input: &[u8]) -> Self {
         Self {
-            input: input.chars().collect(),
+            input: input.to_vec(),
             position: 0,
             line: 1,
             column: 1,
@@ -104,75 +106,89 @@ impl Lexer {
         Ok(tokens)
     }
 
+    /// The line the lexer has reached, which after an error is the line of
+    /// the offending input.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
     fn next_token(&mut self) -> Result<Token, String> {
         let start_line = self.line;
         let start_column = self.column;
         let ch = self.advance();
 
         let token_type = match ch {
-            '+' => TokenType::Plus,
-            '-' => TokenType::Minus,
-            '*' => TokenType::Star,
-            '/' => TokenType::Slash,
-            '%' => TokenType::Percent,
-            '^' => TokenType::Caret,
-            '(' => TokenType::LeftParen,
-            ')' => TokenType::RightParen,
-            '{' => TokenType::LeftBrace,
-            '}' => TokenType::RightBrace,
-            '[' => TokenType::LeftBracket,
-            ']' => TokenType::RightBracket,
-            ',' => TokenType::Comma,
-            ';' => TokenType::Semicolon,
-            '.' => {
-                if self.peek() == '.' {
+            b'+' => TokenType::Plus,
+            b'-' => TokenType::Minus,
+            b'*' => TokenType::Star,
+            b'/' => TokenType::Slash,
+            b'%' => TokenType::Percent,
+            b'^' => TokenType::Caret,
+            b'(' => TokenType::LeftParen,
+            b')' => TokenType::RightParen,
+            b'{' => TokenType::LeftBrace,
+            b'}' => TokenType::RightBrace,
+            b'[' => TokenType::LeftBracket,
+            b']' => TokenType::RightBracket,
+            b',' => TokenType::Comma,
+            b';' => TokenType::Semicolon,
+            b':' => TokenType::Colon,
+            b'.' => {
+                if self.peek() == b'.' {
                     self.advance(); // consume the second '.'
                     TokenType::DotDot
                 } else {
                     TokenType::Dot
                 }
             }
-            '\n' => TokenType::Newline,
+            b'\n' => {
+                self.line += 1;
+                self.column = 1;
+                TokenType::Newline
+            }
 
-            '=' => {
-                if self.match_char('=') {
+            b'=' => {
+                if self.match_char(b'=') {
                     TokenType::Equal
                 } else {
                     TokenType::Assign
                 }
             }
 
-            '~' => {
-                if self.match_char('=') {
+            b'~' => {
+                if self.match_char(b'=') {
                     TokenType::NotEqual
                 } else {
                     return Err(format!("Unexpected character '~' at {}:{}", start_line, start_column));
                 }
             }
 
-            '<' => {
-                if self.match_char('=') {
+            b'<' => {
+                if self.match_char(b'=') {
                     TokenType::LessEqual
                 } else {
                     TokenType::Less
                 }
             }
 
-            '>' => {
-                if self.match_char('=') {
+            b'>' => {
+                if self.match_char(b'=') {
                     TokenType::GreaterEqual
                 } else {
                     TokenType::Greater
                 }
             }
 
-            '"' | '\'' => self.string_literal(ch)?,
+            b'"' | b'\'' => self.string_literal(ch)?,
 
             ch if ch.is_ascii_digit() => self.number_literal()?,
 
-            ch if ch.is_alphabetic() || ch == '_' => self.identifier_or_keyword()?,
+            ch if ch.is_ascii_alphabetic() || ch == b'_' => self.identifier_or_keyword()?,
 
-            _ => return Err(format!("Unexpected character '{}' at {}:{}", ch, start_line, start_column)),
+            _ => {
+                let shown = if ch.is_ascii_graphic() { (ch as char).to_string() } else { format!("<\\{}>", ch) };
+                return Err(format!("Unexpected character '{}' at {}:{}", shown, start_line, start_column));
+            }
         };
 
         Ok(Token {
@@ -182,15 +198,21 @@ impl Lexer {
         })
     }
 
-    fn string_literal(&mut self, quote: char) -> Result<TokenType, String> {
-        let mut value = String::new();
+    fn string_literal(&mut self, quote: u8) -> Result<TokenType, String> {
+        // Strings are byte sequences; escapes can produce any byte value
+        let mut value = Vec::new();
 
         while !self.is_at_end() && self.peek() != quote {
-            if self.peek() == '\n' {
-                self.line += 1;
-                self.column = 0;
+            let ch = self.advance();
+            match ch {
+                b'\\' => self.escape_sequence(&mut value)?,
+                b'\n' => {
+                    self.line += 1;
+                    self.column = 1;
+                    value.push(b'\n');
+                }
+                _ => value.push(ch),
             }
-            value.push(self.advance());
         }
 
         if self.is_at_end() {
@@ -201,14 +223,107 @@ impl Lexer {
         Ok(TokenType::String(value))
     }
 
+    fn escape_sequence(&mut self, value: &mut Vec<u8>) -> Result<(), String> {
+        if self.is_at_end() {
+            return Err("Unterminated string".to_string());
+        }
+
+        let ch = self.advance();
+        match ch {
+            b'n' => value.push(b'\n'),
+            b't' => value.push(b'\t'),
+            b'r' => value.push(b'\r'),
+            b'a' => value.push(0x07),
+            b'b' => value.push(0x08),
+            b'f' => value.push(0x0C),
+            b'v' => value.push(0x0B),
+            b'\\' | b'"' | b'\'' => value.push(ch),
+            b'\n' => {
+                self.line += 1;
+                self.column = 1;
+                value.push(b'\n');
+            }
+            b'x' => {
+                let mut byte = 0;
+                for _ in 0..2 {
+                    match (self.peek() as char).to_digit(16) {
+                        Some(digit) if !self.is_at_end() => {
+                            self.advance();
+                            byte = byte * 16 + digit;
+                        }
+                        _ => return Err(format!("Hexadecimal digit expected at {}:{}", self.line, self.column)),
+                    }
+                }
+                value.push(byte as u8);
+            }
+            b'z' => {
+                while !self.is_at_end() && self.peek().is_ascii_whitespace() {
+                    if self.advance() == b'\n' {
+                        self.line += 1;
+                        self.column = 1;
+                    }
+                }
+            }
+            b'u' => {
+                if !self.match_char(b'{') {
+                    return Err(format!("Missing '{{' in \\u{{xxxx}} at {}:{}", self.line, self.column));
+                }
+                let mut code: u32 = 0;
+                let mut digits = 0;
+                while let Some(digit) = (self.peek() as char).to_digit(16).filter(|_| !self.is_at_end()) {
+                    self.advance();
+                    code = code
+                        .checked_mul(16)
+                        .and_then(|code| code.checked_add(digit))
+                        .filter(|&code| code <= 0x7FFF_FFFF)
+                        .ok_or_else(|| format!("UTF-8 value too large at {}:{}", self.line, self.column))?;
+                    digits += 1;
+                }
+                if digits == 0 || !self.match_char(b'}') {
+                    return Err(format!("Invalid \\u{{xxxx}} escape at {}:{}", self.line, self.column));
+                }
+                encode_utf8_extended(code, value);
+            }
+            digit if digit.is_ascii_digit() => {
+                // Up to three decimal digits
+                let mut byte = (digit - b'0') as u32;
+                for _ in 0..2 {
+                    match (self.peek() as char).to_digit(10) {
+                        Some(next) if !self.is_at_end() => {
+                            self.advance();
+                            byte = byte * 10 + next;
+                        }
+                        _ => break,
+                    }
+                }
+                if byte > 255 {
+                    return Err(format!("Decimal escape too large at {}:{}", self.line, self.column));
+                }
+                value.push(byte as u8);
+            }
+            _ => return Err(format!("Invalid escape sequence '\\{}' at {}:{}", ch, self.line, self.column)),
+        }
+        Ok(())
+    }
+
     fn number_literal(&mut self) -> Result<TokenType, String> {
         let start = self.position - 1;
 
-        while !self.is_at_end() && (self.peek().is_ascii_digit() || self.peek() == '.') {
+        while !self.is_at_end() && (self.peek().is_ascii_digit() || self.peek() == b'.') {
+            self.advance();
+        }
+        // An exponent, as in `1e9999` or `2.5E-3`
+        if matches!(self.peek(), b'e' | b'E') {
             self.advance();
+            if matches!(self.peek(), b'+' | b'-') {
+                self.advance();
+            }
+            while self.peek().is_ascii_digit() {
+                self.advance();
+            }
         }
 
-        let number_str: String = self.input[start..self.position].iter().collect();
+        let number_str = String::from_utf8_lossy(&self.input[start..self.position]).into_owned();
         let number = number_str.parse::<f64>()
             .map_err(|_| format!("Invalid number: {}", number_str))?;
 
@@ -218,11 +333,11 @@ impl Lexer {
     fn identifier_or_keyword(&mut self) -> Result<TokenType, String> {
         let start = self.position - 1;
 
-        while !self.is_at_end() && (self.peek().is_alphanumeric() || self.peek() == '_') {
+        while !self.is_at_end() && (self.peek().is_ascii_alphanumeric() || self.peek() == b'_') {
             self.advance();
         }
 
-        let text: String = self.input[start..self.position].iter().collect();
+        let text = String::from_utf8_lossy(&self.input[start..self.position]).into_owned();
 
         let token_type = match text.as_str() {
             "local" => TokenType::Local,
@@ -252,12 +367,12 @@ impl Lexer {
     fn skip_whitespace(&mut self) {
         while !self.is_at_end() {
             match self.peek() {
-                ' ' | '\r' | '\t' => {
+                b' ' | b'\r' | b'\t' => {
                     self.advance();
                 }
-                '-' if self.peek_next() == Some('-') => {
+                b'-' if self.peek_next() == Some(b'-') => {
                     // Skip comment
-                    while !self.is_at_end() && self.peek() != '\n' {
+                    while !self.is_at_end() && self.peek() != b'\n' {
                         self.advance();
                     }
                 }
@@ -266,22 +381,22 @@ impl Lexer {
         }
     }
 
-    fn advance(&mut self) -> char {
+    fn advance(&mut self) -> u8 {
         let ch = self.input[self.position];
         self.position += 1;
         self.column += 1;
         ch
     }
 
-    fn peek(&self) -> char {
+    fn peek(&self) -> u8 {
         if self.is_at_end() {
-            '\0'
+            b'\0'
         } else {
-            self.input.get(self.position).copied().unwrap_or('\0')
+            self.input.get(self.position).copied().unwrap_or(b'\0')
         }
     }
 
-    fn peek_next(&self) -> Option<char> {
+    fn peek_next(&self) -> Option<u8> {
         if self.position + 1 >= self.input.len() {
             None
         } else {
@@ -289,7 +404,7 @@ impl Lexer {
         }
     }
 
-    fn match_char(&mut self, expected: char) -> bool {
+    fn match_char(&mut self, expected: u8) -> bool {
         if self.is_at_end() || self.input[self.position] != expected {
             false
         } else {
@@ -303,3 +418,23 @@ impl Lexer {
         self.position >= self.input.len()
     }
 }
+
+/// Encodes `code` the way Lua's `\u{...}` escape does, which allows values
+/// up to 2^31 and surrogates using the original, longer UTF-8 forms.
+pub(crate) fn encode_utf8_extended(code: u32, value: &mut Vec<u8>) {
+    if code < 0x80 {
+        value.push(code as u8);
+        return;
+    }
+
+    let mut tail = Vec::new();
+    let mut rest = code;
+    let mut limit = 0x3F; // Largest value that fits in the first byte
+    while rest > limit {
+        tail.push(0x80 | (rest & 0x3F) as u8);
+        rest >>= 6;
+        limit >>= 1;
+    }
+    value.push(((!limit << 1) | rest) as u8);
+    value.extend(tail.iter().rev());
+}
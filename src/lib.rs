@@ -1,15 +1,31 @@
 pub mod ast;
 pub mod bytecode;
+pub mod coroutine;
 pub mod environment;
 pub mod error;
+pub mod file;
+pub mod filesystem;
+pub mod format;
+pub mod function;
+pub mod gc;
 pub mod jit;
 pub mod lexer;
+pub mod nanbox;
+pub mod os;
+pub mod pack;
+pub mod package;
 pub mod parser;
+pub mod pattern;
+pub mod random;
 pub mod runtime;
 pub mod stdlib;
+pub mod string;
+pub mod table;
+pub mod userdata;
 pub mod value;
 pub mod vm;
 
+use gc::GcMode;
 use runtime::LuaJitRuntime;
 
 pub use crate::value::Value;
@@ -19,6 +35,23 @@ pub use crate::error::{LuaError, LuaResult};
 pub struct LunaConfig {
     pub jit_enabled: bool,
     pub optimization_level: u8,
+    pub gc_mode: GcMode,
+    /// Percentage the heap may grow before an incremental cycle starts.
+    pub gc_pause: usize,
+    /// Collector work done per step, relative to allocation.
+    pub gc_step_multiplier: usize,
+    /// Log2 of the bytes allocated between incremental steps.
+    pub gc_step_size: u32,
+    /// Percentage of heap growth that triggers a minor collection.
+    pub gc_minor_multiplier: usize,
+    /// Percentage of old-generation growth that triggers a major collection.
+    pub gc_major_multiplier: usize,
+    /// Whether scripts may start processes with `os.execute` and
+    /// `io.popen`. Off unless the embedder trusts its scripts.
+    pub allow_process_spawning: bool,
+    /// Steps one attempt to match a pattern may take before it fails with
+    /// "pattern too complex", besides the square of the subject length.
+    pub pattern_step_limit: usize,
 }
 
 impl Default for LunaConfig {
@@ -26,6 +59,14 @@ impl Default for LunaConfig {
         Self {
             jit_enabled: true,
             optimization_level: 2,
+            gc_mode: GcMode::Incremental,
+            gc_pause: gc::DEFAULT_PAUSE,
+            gc_step_multiplier: gc::DEFAULT_STEP_MULTIPLIER,
+            gc_step_size: gc::DEFAULT_STEP_SIZE,
+            gc_minor_multiplier: gc::DEFAULT_MINOR_MULTIPLIER,
+            gc_major_multiplier: gc::DEFAULT_MAJOR_MULTIPLIER,
+            allow_process_spawning: false,
+            pattern_step_limit: pattern::DEFAULT_STEP_LIMIT,
         }
     }
 }
@@ -58,13 +99,13 @@ mod tests {
 
     #[test]
     fn test_version() {
-        assert_eq!(version(), "0.1.0");
+        assert_eq!(version(), "1.0.0");
     }
 
     #[test]
     fn test_default_config() {
         let config = LunaConfig::default();
-        assert_eq!(config.jit_enabled, true);
+        assert!(config.jit_enabled);
         assert_eq!(config.optimization_level, 2);
     }
 
@@ -1,9 +1,16 @@
-use luna::{execute, new_runtime, Value};
+use luna::runtime::LuaJitRuntime;
+use luna::{new_runtime_with_config, LunaConfig, Value};
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::time::Instant;
 
+// The interpreter runs the user's own scripts, so like `lua` it lets them
+// start processes
+fn new_runtime() -> LuaJitRuntime {
+    new_runtime_with_config(LunaConfig { allow_process_spawning: true, ..LunaConfig::default() })
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
@@ -14,22 +21,22 @@ fn main() {
         } else if args.contains(&"--benchmark".to_string()) {
             run_benchmark(&args[1]);
         } else {
-            // Regular file execution
+            // Regular file execution, with modules resolved next to the file
             let filename = &args[1];
-            match fs::read_to_string(filename) {
-                Ok(code) => {
-                    println!("Running file: {}", filename);
-                    let start = Instant::now();
-                    match execute(&code) {
-                        Ok(result) => {
-                            let duration = start.elapsed();
-                            println!("Result: {}", result);
-                            println!("Execution time: {:?}", duration);
-                        }
-                        Err(e) => eprintln!("Error: {}", e),
-                    }
+            if let Err(e) = fs::metadata(filename) {
+                eprintln!("Could not read file '{}': {}", filename, e);
+                return;
+            }
+            println!("Running file: {}", filename);
+            let mut runtime = new_runtime();
+            let start = Instant::now();
+            match runtime.execute_file(filename) {
+                Ok(result) => {
+                    let duration = start.elapsed();
+                    println!("Result: {}", result);
+                    println!("Execution time: {:?}", duration);
                 }
-                Err(e) => eprintln!("Could not read file '{}': {}", filename, e),
+                Err(e) => eprintln!("Error: {}", e),
             }
         }
     } else {
@@ -0,0 +1,491 @@
+use crate::coroutine::CoroutineRef;
+use crate::function::{ClosureRef, NativeRef};
+use crate::string::LuaString;
+use crate::table::TableRef;
+use crate::userdata::UserdataRef;
+use crate::value::Value;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, RangeBounds};
+
+// Layout of a packed value. Numbers are stored as their IEEE 754 bits, with
+// every NaN canonicalized to a positive quiet NaN. That leaves the negative
+// quiet NaNs free: their top 13 bits are the prefix, the next 3 bits a tag
+// and the low 48 bits a payload, which is enough for a pointer on every
+// 64-bit platform we run on. Light userdata can be any address the host
+// makes up, so those that do not fit are boxed.
+const TAG_PREFIX: u64 = 0xFFF8_0000_0000_0000;
+const TAG_SHIFT: u32 = 48;
+const TAG_BITS: u64 = 0x7;
+const PAYLOAD_MASK: u64 = (1 << TAG_SHIFT) - 1;
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+// Nil and the booleans, or a pointer to a boxed light userdata
+const TAG_SPECIAL: u64 = 0;
+const TAG_FUNCTION: u64 = 1;
+const TAG_STRING: u64 = 2;
+const TAG_TABLE: u64 = 3;
+const TAG_CLOSURE: u64 = 4;
+const TAG_USERDATA: u64 = 5;
+// A host pointer, not owned; every other pointer tag holds a reference
+const TAG_LIGHT_USERDATA: u64 = 6;
+const TAG_THREAD: u64 = 7;
+
+const NIL: u64 = TAG_PREFIX;
+const FALSE: u64 = TAG_PREFIX | 1;
+const TRUE: u64 = TAG_PREFIX | 2;
+
+fn tagged(tag: u64, payload: u64) -> u64 {
+    assert!(payload & !PAYLOAD_MASK == 0, "value payload does not fit in 48 bits");
+    TAG_PREFIX | (tag << TAG_SHIFT) | payload
+}
+
+fn tag_of(bits: u64) -> Option<u64> {
+    if bits & TAG_PREFIX == TAG_PREFIX {
+        Some((bits >> TAG_SHIFT) & TAG_BITS)
+    } else {
+        None
+    }
+}
+
+fn pack_bits(value: Value) -> u64 {
+    match value {
+        Value::Nil => NIL,
+        Value::Boolean(false) => FALSE,
+        Value::Boolean(true) => TRUE,
+        Value::Number(n) => PackedValue::number(n).into_bits(),
+        Value::Native(n) => tagged(TAG_FUNCTION, n.into_raw() as usize as u64),
+        Value::String(s) => tagged(TAG_STRING, s.into_raw() as usize as u64),
+        Value::Table(t) => tagged(TAG_TABLE, t.into_raw() as usize as u64),
+        Value::Closure(c) => tagged(TAG_CLOSURE, c.into_raw() as usize as u64),
+        Value::Userdata(u) => tagged(TAG_USERDATA, u.into_raw() as usize as u64),
+        Value::LightUserdata(p) => {
+            let address = p as usize as u64;
+            if address & !PAYLOAD_MASK == 0 {
+                tagged(TAG_LIGHT_USERDATA, address)
+            } else {
+                tagged(TAG_SPECIAL, Box::into_raw(Box::new(p)) as usize as u64)
+            }
+        }
+        Value::Thread(t) => tagged(TAG_THREAD, t.into_raw() as usize as u64),
+    }
+}
+
+/// Rebuilds the value `bits` encode, taking over the reference it holds. A
+/// boxed light userdata is only read; its box stays with `bits`.
+///
+/// # Safety
+///
+/// `bits` must come from `pack_bits`, and the reference it owns must not be
+/// released anywhere else.
+unsafe fn unpack_bits(bits: u64) -> Value {
+    let pointer = (bits & PAYLOAD_MASK) as usize as *const ();
+    match tag_of(bits) {
+        None => Value::Number(f64::from_bits(bits)),
+        Some(TAG_SPECIAL) => match bits {
+            NIL => Value::Nil,
+            FALSE => Value::Boolean(false),
+            TRUE => Value::Boolean(true),
+            _ => Value::LightUserdata(*(pointer as *const *mut std::ffi::c_void)),
+        },
+        Some(TAG_FUNCTION) => Value::Native(NativeRef::from_raw(pointer)),
+        Some(TAG_STRING) => Value::String(LuaString::from_raw(pointer)),
+        Some(TAG_TABLE) => Value::Table(TableRef::from_raw(pointer)),
+        Some(TAG_CLOSURE) => Value::Closure(ClosureRef::from_raw(pointer)),
+        Some(TAG_USERDATA) => Value::Userdata(UserdataRef::from_raw(pointer)),
+        Some(TAG_LIGHT_USERDATA) => Value::LightUserdata(pointer as *mut _),
+        Some(TAG_THREAD) => Value::Thread(CoroutineRef::from_raw(pointer)),
+        Some(tag) => unreachable!("invalid value tag {}", tag),
+    }
+}
+
+/// An 8-byte NaN-boxed `Value`. This is how the interpreter stores values on
+/// its stack, in frame locals and in tables; `Value` is the unpacked form
+/// everything else works with.
+///
+/// Nil, booleans, numbers and light userdata are stored inline. Heap objects
+/// and functions are stored as a pointer that owns one reference, so cloning
+/// and dropping a packed value adjusts the reference count exactly as
+/// cloning and dropping the `Value` would.
+#[repr(transparent)]
+pub struct PackedValue(u64);
+
+impl PackedValue {
+    pub const NIL: PackedValue = PackedValue(NIL);
+
+    pub fn number(n: f64) -> Self {
+        Self(if n.is_nan() { CANONICAL_NAN } else { n.to_bits() })
+    }
+
+    pub fn boolean(b: bool) -> Self {
+        Self(if b { TRUE } else { FALSE })
+    }
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == NIL
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        self.0 != NIL && self.0 != FALSE
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match tag_of(self.0) {
+            None => Some(f64::from_bits(self.0)),
+            Some(_) => None,
+        }
+    }
+
+    /// Borrows the unpacked value without touching any reference count.
+    pub fn get(&self) -> ValueRef<'_> {
+        ValueRef {
+            // The copy never drops its reference; `self` keeps it alive
+            value: ManuallyDrop::new(unsafe { unpack_bits(self.0) }),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        (*self.get()).clone()
+    }
+
+    pub fn into_value(self) -> Value {
+        if self.is_boxed() {
+            // Dropping `self` frees the box the value was copied out of
+            return unsafe { unpack_bits(self.0) };
+        }
+        let this = ManuallyDrop::new(self);
+        unsafe { unpack_bits(this.0) }
+    }
+
+    /// A packed copy of `value` that shares its reference.
+    pub(crate) fn borrowed(value: &Value) -> Borrowed<'_> {
+        Borrowed {
+            packed: ManuallyDrop::new(Self(pack_bits(unsafe { std::ptr::read(value) }))),
+            marker: PhantomData,
+        }
+    }
+
+    fn into_bits(self) -> u64 {
+        ManuallyDrop::new(self).0
+    }
+
+    fn is_boxed(&self) -> bool {
+        tag_of(self.0) == Some(TAG_SPECIAL) && !matches!(self.0, NIL | FALSE | TRUE)
+    }
+
+    fn is_reference(&self) -> bool {
+        matches!(
+            tag_of(self.0),
+            Some(TAG_FUNCTION | TAG_STRING | TAG_TABLE | TAG_CLOSURE | TAG_USERDATA | TAG_THREAD)
+        )
+    }
+}
+
+impl From<Value> for PackedValue {
+    fn from(value: Value) -> Self {
+        Self(pack_bits(value))
+    }
+}
+
+impl Default for PackedValue {
+    fn default() -> Self {
+        Self::NIL
+    }
+}
+
+impl Clone for PackedValue {
+    fn clone(&self) -> Self {
+        if self.is_boxed() {
+            return Self(pack_bits(self.to_value()));
+        }
+        if self.is_reference() {
+            // Take the new reference by leaking a clone of the object
+            std::mem::forget(self.to_value());
+        }
+        Self(self.0)
+    }
+}
+
+impl Drop for PackedValue {
+    fn drop(&mut self) {
+        if self.is_reference() {
+            drop(unsafe { unpack_bits(self.0) });
+        } else if self.is_boxed() {
+            drop(unsafe { Box::from_raw((self.0 & PAYLOAD_MASK) as usize as *mut *mut std::ffi::c_void) });
+        }
+    }
+}
+
+/// A packed copy of a value that shares its reference, returned by
+/// `PackedValue::borrowed`. Only a box made for the copy is freed with it.
+pub(crate) struct Borrowed<'a> {
+    packed: ManuallyDrop<PackedValue>,
+    marker: PhantomData<&'a Value>,
+}
+
+impl Deref for Borrowed<'_> {
+    type Target = PackedValue;
+
+    fn deref(&self) -> &PackedValue {
+        &self.packed
+    }
+}
+
+impl Drop for Borrowed<'_> {
+    fn drop(&mut self) {
+        if self.packed.is_boxed() {
+            unsafe { ManuallyDrop::drop(&mut self.packed) };
+        }
+    }
+}
+
+// Same equality as `Value`: numbers by value, strings by contents and
+// everything else by identity, which for packed values is their bits or,
+// for boxed light userdata, the pointer in the box
+impl PartialEq for PackedValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (tag_of(self.0), tag_of(other.0)) {
+            (None, None) => f64::from_bits(self.0) == f64::from_bits(other.0),
+            (Some(TAG_STRING), Some(TAG_STRING)) => *self.get() == *other.get(),
+            _ if self.is_boxed() && other.is_boxed() => *self.get() == *other.get(),
+            _ => self.0 == other.0,
+        }
+    }
+}
+
+// Tables never accept NaN keys, see `Value`
+impl Eq for PackedValue {}
+
+impl Hash for PackedValue {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match tag_of(self.0) {
+            // 0.0 and -0.0 are equal
+            None if self.0 == (-0.0f64).to_bits() => 0u64.hash(state),
+            Some(TAG_STRING) => self.get().hash(state),
+            _ if self.is_boxed() => self.get().hash(state),
+            _ => self.0.hash(state),
+        }
+    }
+}
+
+impl fmt::Debug for PackedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.get(), f)
+    }
+}
+
+/// A `Value` borrowed from somewhere it is stored in another form, such as
+/// a `PackedValue`. Derefs to the value; clone that to keep it.
+pub struct ValueRef<'a> {
+    value: ManuallyDrop<Value>,
+    marker: PhantomData<&'a Value>,
+}
+
+impl Deref for ValueRef<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.value
+    }
+}
+
+impl<'a> From<&'a Value> for ValueRef<'a> {
+    fn from(value: &'a Value) -> Self {
+        Self {
+            // A bitwise copy that is never dropped only aliases `value`
+            value: ManuallyDrop::new(unsafe { std::ptr::read(value) }),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl ValueRef<'_> {
+    /// A number, which owns nothing and so needs nothing to borrow from.
+    pub fn number(n: f64) -> Self {
+        Self {
+            value: ManuallyDrop::new(Value::Number(n)),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl fmt::Debug for ValueRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&*self.value, f)
+    }
+}
+
+/// The interpreter's operand stack. Values go in and come out unpacked but
+/// are stored packed.
+#[derive(Debug, Default)]
+pub struct ValueStack {
+    values: Vec<PackedValue>,
+}
+
+impl ValueStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, value: Value) {
+        self.values.push(value.into());
+    }
+
+    pub fn push_packed(&mut self, value: PackedValue) {
+        self.values.push(value);
+    }
+
+    pub fn insert(&mut self, index: usize, value: Value) {
+        self.values.insert(index, value.into());
+    }
+
+    pub fn pop(&mut self) -> Option<Value> {
+        self.values.pop().map(PackedValue::into_value)
+    }
+
+    pub fn pop_packed(&mut self) -> Option<PackedValue> {
+        self.values.pop()
+    }
+
+    /// Pops the top two values if they are both numbers and leaves the stack
+    /// untouched otherwise. Arithmetic takes this path before falling back
+    /// to the general one that converts strings and reports errors.
+    pub fn pop_numbers(&mut self) -> Option<(f64, f64)> {
+        let len = self.values.len();
+        if len < 2 {
+            return None;
+        }
+        let a = self.values[len - 2].as_number()?;
+        let b = self.values[len - 1].as_number()?;
+        self.values.truncate(len - 2);
+        Some((a, b))
+    }
+
+    pub fn last(&self) -> Option<ValueRef<'_>> {
+        self.values.last().map(PackedValue::get)
+    }
+
+    pub fn get(&self, index: usize) -> Option<ValueRef<'_>> {
+        self.values.get(index).map(PackedValue::get)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.values.clear();
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.values.truncate(len);
+    }
+
+    pub fn extend(&mut self, values: impl IntoIterator<Item = Value>) {
+        self.values.extend(values.into_iter().map(PackedValue::from));
+    }
+
+    pub fn drain(&mut self, range: impl RangeBounds<usize>) -> impl Iterator<Item = Value> + '_ {
+        self.values.drain(range).map(PackedValue::into_value)
+    }
+
+    pub fn drain_packed(&mut self, range: impl RangeBounds<usize>) -> impl Iterator<Item = PackedValue> + '_ {
+        self.values.drain(range)
+    }
+
+    /// Copies of the values from `start` to the top of the stack.
+    pub fn values_from(&self, start: usize) -> Vec<Value> {
+        self.values[start..].iter().map(PackedValue::to_value).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ValueRef<'_>> {
+        self.values.iter().map(PackedValue::get)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::LuaTable;
+
+    fn round_trip(value: Value) -> Value {
+        PackedValue::from(value).into_value()
+    }
+
+    #[test]
+    fn test_values_round_trip() {
+        assert_eq!(std::mem::size_of::<PackedValue>(), 8);
+        for value in [
+            Value::Nil,
+            Value::Boolean(false),
+            Value::Boolean(true),
+            Value::Number(-0.0),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::NEG_INFINITY),
+            Value::Number(1.5e300),
+            Value::String("packed".into()),
+            Value::Native(NativeRef::builtin("type", crate::stdlib::builtin_type)),
+        ] {
+            assert_eq!(round_trip(value.clone()), value);
+        }
+
+        // Every NaN, including the negative ones used for tags, stays a number
+        let nan = f64::from_bits(0xFFF8_0000_0000_0001);
+        assert!(matches!(round_trip(Value::Number(nan)), Value::Number(n) if n.is_nan()));
+        assert!(PackedValue::from(Value::Number(-f64::NAN)).as_number().unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_references_are_counted() {
+        let table = TableRef::new(LuaTable::new());
+        let packed = PackedValue::from(Value::Table(table.clone()));
+        let copy = packed.clone();
+        assert_eq!(std::rc::Rc::strong_count(table.gc_box()), 3);
+
+        assert!(matches!(&*packed.get(), Value::Table(t) if t.ptr_eq(&table)));
+        drop(packed);
+        assert!(matches!(copy.into_value(), Value::Table(t) if t.ptr_eq(&table)));
+        assert_eq!(std::rc::Rc::strong_count(table.gc_box()), 1);
+    }
+
+    #[test]
+    fn test_equality_matches_values() {
+        let pack = |value: Value| PackedValue::from(value);
+        let long = "a string that is long enough not to be interned";
+
+        assert_eq!(pack(Value::Number(0.0)), pack(Value::Number(-0.0)));
+        assert_eq!(pack(Value::String(long.into())), pack(Value::String(long.into())));
+        assert_ne!(pack(Value::Nil), pack(Value::Boolean(false)));
+        assert_ne!(
+            pack(Value::Table(TableRef::new(LuaTable::new()))),
+            pack(Value::Table(TableRef::new(LuaTable::new())))
+        );
+    }
+
+    #[test]
+    fn test_high_light_userdata_addresses_are_boxed() {
+        let high = Value::LightUserdata(usize::MAX as *mut std::ffi::c_void);
+        let low = Value::LightUserdata(0x1000 as *mut std::ffi::c_void);
+        assert_eq!(round_trip(high.clone()), high);
+
+        let packed = PackedValue::from(high.clone());
+        assert_eq!(packed.clone(), packed);
+        assert!(packed.is_truthy());
+        assert_ne!(packed, PackedValue::from(low.clone()));
+        assert_ne!(packed, PackedValue::from(Value::Nil));
+
+        let mut table = LuaTable::new();
+        table.set(high.clone(), Value::Number(1.0));
+        table.set(Value::Number(2.0), high.clone());
+        assert_eq!(table.get(&high), Value::Number(1.0));
+        assert_eq!(table.get(&low), Value::Nil);
+        assert_eq!(table.get(&Value::Number(2.0)), high);
+    }
+}
@@ -0,0 +1,259 @@
+// Calendar time, CPU time, the environment and the shell for the `os` and
+// `io` libraries, over the C library on Unix
+
+/// Broken-down time as `os.date("*t")` reports it and `os.time` reads it:
+/// months count from 1, `wday` from Sunday as 1 and `yday` from January 1st
+/// as 1.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct DateTime {
+    pub year: i64,
+    pub month: i64,
+    pub day: i64,
+    pub hour: i64,
+    pub min: i64,
+    pub sec: i64,
+    pub wday: i64,
+    pub yday: i64,
+    /// Whether daylight saving time is in effect, None when unknown
+    pub isdst: Option<bool>,
+}
+
+/// Conversions `os.date` accepts after a `%`: the single characters of C99,
+/// then the two-character `E` and `O` modifiers.
+const STRFTIME_SINGLE: &[u8] = b"aAbBcCdDeFgGhHIjmMnprRStTuUVwWxXyYzZ%";
+const STRFTIME_MODIFIED: &[&[u8]] = &[
+    b"Ec", b"EC", b"Ex", b"EX", b"Ey", b"EY", b"Od", b"Oe", b"OH", b"OI", b"Om", b"OM", b"OS", b"Ou", b"OU", b"OV",
+    b"Ow", b"OW", b"Oy",
+];
+
+// Room for the expansion of one conversion, as in the reference
+// implementation
+const MAX_CONVERSION_SIZE: usize = 250;
+
+/// The length of the valid conversion at the start of `spec`, which follows
+/// a `%`, or None if it is not one.
+pub fn conversion_length(spec: &[u8]) -> Option<usize> {
+    match spec {
+        [first, ..] if STRFTIME_SINGLE.contains(first) => Some(1),
+        [_, _, ..] if STRFTIME_MODIFIED.contains(&&spec[..2]) => Some(2),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+pub use self::unix::*;
+
+#[cfg(not(unix))]
+pub use self::fallback::*;
+
+#[cfg(unix)]
+mod unix {
+    use super::{DateTime, MAX_CONVERSION_SIZE};
+    use std::ffi::CString;
+    use std::process::{Command, ExitStatus};
+
+    /// A point in time broken down in local time or UTC, as the C library
+    /// sees it.
+    pub struct Calendar {
+        tm: libc::tm,
+    }
+
+    impl Calendar {
+        /// `time` in local time, or in UTC if `utc` is set. None if the
+        /// C library cannot represent it.
+        pub fn at(time: i64, utc: bool) -> Option<Self> {
+            let time = libc::time_t::try_from(time).ok()?;
+            // SAFETY: tm is plain data, and the reentrant conversions only
+            // write to it
+            unsafe {
+                let mut tm: libc::tm = std::mem::zeroed();
+                let result = if utc { libc::gmtime_r(&time, &mut tm) } else { libc::localtime_r(&time, &mut tm) };
+                if result.is_null() {
+                    None
+                } else {
+                    Some(Self { tm })
+                }
+            }
+        }
+
+        pub fn fields(&self) -> DateTime {
+            from_tm(&self.tm)
+        }
+
+        /// Expands a single `strftime` conversion, such as `%Y`.
+        pub fn format(&self, conversion: &[u8]) -> Vec<u8> {
+            let conversion = match CString::new(conversion) {
+                Ok(conversion) => conversion,
+                Err(_) => return Vec::new(),
+            };
+            let mut buffer = vec![0u8; MAX_CONVERSION_SIZE];
+            // SAFETY: the buffer holds the size passed and the format is
+            // NUL-terminated
+            let len = unsafe {
+                libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), conversion.as_ptr(), &self.tm)
+            };
+            buffer.truncate(len);
+            buffer
+        }
+    }
+
+    /// The time `fields` describe in local time, and the fields normalized
+    /// the way `mktime` does, so that 32 January becomes 1 February. None
+    /// if the result cannot be represented.
+    pub fn make_time(fields: &DateTime) -> Option<(i64, DateTime)> {
+        let int = |n: i64| libc::c_int::try_from(n).ok();
+        // SAFETY: tm is plain data
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = int(fields.year - 1900)?;
+        tm.tm_mon = int(fields.month - 1)?;
+        tm.tm_mday = int(fields.day)?;
+        tm.tm_hour = int(fields.hour)?;
+        tm.tm_min = int(fields.min)?;
+        tm.tm_sec = int(fields.sec)?;
+        tm.tm_isdst = match fields.isdst {
+            Some(true) => 1,
+            Some(false) => 0,
+            None => -1,
+        };
+        // SAFETY: mktime only reads and normalizes tm
+        let time = unsafe { libc::mktime(&mut tm) };
+        if time == -1 {
+            return None;
+        }
+        Some((time as i64, from_tm(&tm)))
+    }
+
+    /// Processor time used by the program, in seconds.
+    pub fn cpu_time() -> f64 {
+        // SAFETY: timespec is plain data that clock_gettime fills in
+        unsafe {
+            let mut time: libc::timespec = std::mem::zeroed();
+            if libc::clock_gettime(libc::CLOCK_PROCESS_CPUTIME_ID, &mut time) != 0 {
+                return 0.0;
+            }
+            time.tv_sec as f64 + time.tv_nsec as f64 / 1e9
+        }
+    }
+
+    /// The value of an environment variable, as bytes.
+    pub fn getenv(name: &[u8]) -> Option<Vec<u8>> {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+        std::env::var_os(OsStr::from_bytes(name)).map(|value| value.into_vec())
+    }
+
+    /// A command running `command` through the shell, as `system` and
+    /// `popen` do.
+    pub fn shell(command: &[u8]) -> Command {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let mut shell = Command::new("/bin/sh");
+        shell.arg("-c").arg(OsStr::from_bytes(command));
+        shell
+    }
+
+    /// How a process ended: `"exit"` and its exit code, or `"signal"` and
+    /// the signal that killed it.
+    pub fn termination(status: &ExitStatus) -> (&'static str, i64) {
+        use std::os::unix::process::ExitStatusExt;
+        match (status.code(), status.signal()) {
+            (_, Some(signal)) => ("signal", signal as i64),
+            (code, None) => ("exit", code.unwrap_or(-1) as i64),
+        }
+    }
+
+    fn from_tm(tm: &libc::tm) -> DateTime {
+        DateTime {
+            year: tm.tm_year as i64 + 1900,
+            month: tm.tm_mon as i64 + 1,
+            day: tm.tm_mday as i64,
+            hour: tm.tm_hour as i64,
+            min: tm.tm_min as i64,
+            sec: tm.tm_sec as i64,
+            wday: tm.tm_wday as i64 + 1,
+            yday: tm.tm_yday as i64 + 1,
+            isdst: if tm.tm_isdst < 0 { None } else { Some(tm.tm_isdst > 0) },
+        }
+    }
+}
+
+// Without the C library there is no calendar; `os.date` and `os.time` with
+// a table fail as if the time could not be represented
+#[cfg(not(unix))]
+mod fallback {
+    use super::DateTime;
+    use std::process::{Command, ExitStatus};
+
+    pub struct Calendar;
+
+    impl Calendar {
+        pub fn at(_time: i64, _utc: bool) -> Option<Self> {
+            None
+        }
+
+        pub fn fields(&self) -> DateTime {
+            DateTime::default()
+        }
+
+        pub fn format(&self, _conversion: &[u8]) -> Vec<u8> {
+            Vec::new()
+        }
+    }
+
+    pub fn make_time(_fields: &DateTime) -> Option<(i64, DateTime)> {
+        None
+    }
+
+    pub fn cpu_time() -> f64 {
+        0.0
+    }
+
+    pub fn getenv(name: &[u8]) -> Option<Vec<u8>> {
+        let name = String::from_utf8_lossy(name).into_owned();
+        std::env::var_os(name).map(|value| value.to_string_lossy().into_owned().into_bytes())
+    }
+
+    pub fn shell(command: &[u8]) -> Command {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(String::from_utf8_lossy(command).into_owned());
+        shell
+    }
+
+    pub fn termination(status: &ExitStatus) -> (&'static str, i64) {
+        ("exit", status.code().unwrap_or(-1) as i64)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversion_length() {
+        assert_eq!(conversion_length(b"Y-%m"), Some(1));
+        assert_eq!(conversion_length(b"Ec"), Some(2));
+        assert_eq!(conversion_length(b"Ez"), None);
+        assert_eq!(conversion_length(b"q"), None);
+        assert_eq!(conversion_length(b""), None);
+    }
+
+    #[test]
+    fn test_utc_fields_and_format() {
+        // 2000-03-01 12:34:56 UTC, a Wednesday
+        let calendar = Calendar::at(951_914_096, true).unwrap();
+        let fields = calendar.fields();
+        assert_eq!((fields.year, fields.month, fields.day), (2000, 3, 1));
+        assert_eq!((fields.hour, fields.min, fields.sec), (12, 34, 56));
+        assert_eq!((fields.wday, fields.yday), (4, 61));
+        assert_eq!(calendar.format(b"%Y-%m-%d %H:%M:%S"), b"2000-03-01 12:34:56".to_vec());
+        assert_eq!(calendar.format(b"%A %B"), b"Wednesday March".to_vec());
+    }
+
+    #[test]
+    fn test_make_time_normalizes() {
+        let fields = DateTime { year: 2021, month: 1, day: 32, hour: 12, ..DateTime::default() };
+        let (time, normalized) = make_time(&fields).unwrap();
+        assert_eq!((normalized.month, normalized.day), (2, 1));
+        assert_eq!(Calendar::at(time, false).unwrap().fields().day, 1);
+    }
+}
@@ -0,0 +1,289 @@
+/// Largest integer size, in bytes, an `i[n]`, `I[n]` or `s[n]` option may ask for.
+pub const MAX_INT_SIZE: usize = 16;
+
+/// Byte used for padding and alignment.
+pub const PAD_BYTE: u8 = 0;
+
+// Bytes in a Lua integer
+const INT_SIZE: usize = 8;
+
+// Alignment `!` selects when given no size: that of the widest native type
+const NATIVE_ALIGN: usize = 8;
+
+/// What a format option reads or writes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// Signed integer: `b h i l j`
+    Int,
+    /// Unsigned integer: `B H I L J T`
+    Unsigned,
+    /// Single precision float: `f`
+    Float,
+    /// Double precision float: `d n`
+    Double,
+    /// String of a fixed size: `c[n]`
+    Fixed,
+    /// String preceded by its length: `s[n]`
+    Counted,
+    /// Zero-terminated string: `z`
+    Zero,
+    /// One byte of padding: `x`
+    Padding,
+    /// Padding up to the alignment of the next option: `X`
+    Align,
+    /// Spaces and the endianness and alignment settings
+    Nop,
+}
+
+impl Kind {
+    /// Whether the option consumes an argument when packing and produces a
+    /// value when unpacking.
+    pub fn takes_value(self) -> bool {
+        !matches!(self, Kind::Padding | Kind::Align | Kind::Nop)
+    }
+}
+
+/// One option of a format string, with the padding needed to align it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Item {
+    pub kind: Kind,
+    pub size: usize,
+    pub padding: usize,
+}
+
+/// A malformed format string.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormatError {
+    /// Reported against the format argument
+    Argument(String),
+    /// Reported as is
+    Message(String),
+}
+
+/// Reads a format string for `string.pack` and `string.unpack` one option
+/// at a time.
+pub struct Format<'a> {
+    format: &'a [u8],
+    position: usize,
+    little_endian: bool,
+    max_align: usize,
+}
+
+impl<'a> Format<'a> {
+    pub fn new(format: &'a [u8]) -> Self {
+        Self {
+            format,
+            position: 0,
+            little_endian: cfg!(target_endian = "little"),
+            max_align: 1,
+        }
+    }
+
+    pub fn little_endian(&self) -> bool {
+        self.little_endian
+    }
+
+    /// The next option, aligned for data that is `offset` bytes long so far.
+    pub fn next_item(&mut self, offset: usize) -> Option<Result<Item, FormatError>> {
+        if self.position >= self.format.len() {
+            return None;
+        }
+        Some(self.details(offset))
+    }
+
+    fn details(&mut self, offset: usize) -> Result<Item, FormatError> {
+        let (kind, size) = self.option()?;
+        let mut align = size;
+        if kind == Kind::Align {
+            // 'X' takes its alignment from the option after it
+            let next = if self.position < self.format.len() { Some(self.option()?) } else { None };
+            match next {
+                Some((next_kind, next_size)) if next_kind != Kind::Fixed && next_size != 0 => align = next_size,
+                _ => return Err(FormatError::Argument("invalid next option for option 'X'".to_string())),
+            }
+        }
+
+        let padding = if align <= 1 || kind == Kind::Fixed {
+            0
+        } else {
+            let align = align.min(self.max_align);
+            if !align.is_power_of_two() {
+                return Err(FormatError::Argument("format asks for alignment not power of 2".to_string()));
+            }
+            (align - (offset & (align - 1))) & (align - 1)
+        };
+        Ok(Item { kind, size, padding })
+    }
+
+    fn option(&mut self) -> Result<(Kind, usize), FormatError> {
+        let option = self.format[self.position];
+        self.position += 1;
+        let option = match option {
+            b'b' => (Kind::Int, 1),
+            b'B' => (Kind::Unsigned, 1),
+            b'h' => (Kind::Int, 2),
+            b'H' => (Kind::Unsigned, 2),
+            b'l' | b'j' => (Kind::Int, 8),
+            b'L' | b'J' | b'T' => (Kind::Unsigned, 8),
+            b'f' => (Kind::Float, 4),
+            b'd' | b'n' => (Kind::Double, 8),
+            b'i' => (Kind::Int, self.size_limit(4)?),
+            b'I' => (Kind::Unsigned, self.size_limit(4)?),
+            b's' => (Kind::Counted, self.size_limit(8)?),
+            b'c' => match self.number() {
+                Some(size) => (Kind::Fixed, size),
+                None => return Err(FormatError::Message("missing size for format option 'c'".to_string())),
+            },
+            b'z' => (Kind::Zero, 0),
+            b'x' => (Kind::Padding, 1),
+            b'X' => (Kind::Align, 0),
+            b' ' => (Kind::Nop, 0),
+            b'<' => {
+                self.little_endian = true;
+                (Kind::Nop, 0)
+            }
+            b'>' => {
+                self.little_endian = false;
+                (Kind::Nop, 0)
+            }
+            b'=' => {
+                self.little_endian = cfg!(target_endian = "little");
+                (Kind::Nop, 0)
+            }
+            b'!' => {
+                self.max_align = self.size_limit(NATIVE_ALIGN)?;
+                (Kind::Nop, 0)
+            }
+            other => {
+                let msg = format!("invalid format option '{}'", other as char);
+                return Err(FormatError::Message(msg));
+            }
+        };
+        Ok(option)
+    }
+
+    // Reads an optional decimal size, saturating well below overflow
+    fn number(&mut self) -> Option<usize> {
+        let digits = self.format[self.position..].iter().take_while(|b| b.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+        let mut n: usize = 0;
+        for &digit in &self.format[self.position..self.position + digits] {
+            if n > (usize::MAX / 2 - 9) / 10 {
+                break;
+            }
+            n = n * 10 + (digit - b'0') as usize;
+        }
+        self.position += digits;
+        Some(n)
+    }
+
+    fn size_limit(&mut self, default: usize) -> Result<usize, FormatError> {
+        let size = self.number().unwrap_or(default);
+        if size == 0 || size > MAX_INT_SIZE {
+            let msg = format!("integral size ({}) out of limits [1,{}]", size, MAX_INT_SIZE);
+            return Err(FormatError::Message(msg));
+        }
+        Ok(size)
+    }
+}
+
+/// Appends `n` as a `size`-byte integer. Bytes beyond the eighth repeat
+/// the sign.
+pub fn pack_int(out: &mut Vec<u8>, n: u64, little_endian: bool, size: usize, negative: bool) {
+    let start = out.len();
+    for i in 0..size {
+        let byte = match i {
+            i if i < INT_SIZE => (n >> (8 * i)) as u8,
+            _ if negative => 0xff,
+            _ => 0,
+        };
+        out.push(byte);
+    }
+    if !little_endian {
+        out[start..].reverse();
+    }
+}
+
+/// Reads a `bytes.len()`-byte integer. Integers wider than eight bytes must
+/// fit in a Lua integer.
+pub fn unpack_int(bytes: &[u8], little_endian: bool, signed: bool) -> Result<i64, String> {
+    let size = bytes.len();
+    let byte = |i: usize| if little_endian { bytes[i] } else { bytes[size - 1 - i] };
+    let limit = size.min(INT_SIZE);
+    let mut n: u64 = 0;
+    for i in (0..limit).rev() {
+        n = (n << 8) | byte(i) as u64;
+    }
+
+    if size < INT_SIZE {
+        if signed {
+            let mask = 1u64 << (size * 8 - 1);
+            n = (n ^ mask).wrapping_sub(mask);
+        }
+    } else if size > INT_SIZE {
+        let extension = if !signed || (n as i64) >= 0 { 0 } else { 0xff };
+        if (limit..size).any(|i| byte(i) != extension) {
+            return Err(format!("{}-byte integer does not fit into Lua Integer", size));
+        }
+    }
+    Ok(n as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items(format: &str) -> Result<Vec<Item>, FormatError> {
+        let mut format = Format::new(format.as_bytes());
+        let mut offset = 0;
+        let mut items = Vec::new();
+        while let Some(item) = format.next_item(offset) {
+            let item = item?;
+            offset += item.padding + item.size;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    #[test]
+    fn test_alignment() {
+        let aligned = items("!4 b i8 Xh").unwrap();
+        let padding: Vec<usize> = aligned.iter().filter(|i| i.kind != Kind::Nop).map(|i| i.padding).collect();
+        // 'b' is one byte, 'i8' aligns to four and 'Xh' to two after twelve bytes
+        assert_eq!(padding, vec![0, 3, 0]);
+        assert_eq!(
+            items("Xc1"),
+            Err(FormatError::Argument("invalid next option for option 'X'".to_string()))
+        );
+        assert_eq!(
+            items("!3 i3"),
+            Err(FormatError::Argument("format asks for alignment not power of 2".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert_eq!(items("y"), Err(FormatError::Message("invalid format option 'y'".to_string())));
+        assert_eq!(items("c"), Err(FormatError::Message("missing size for format option 'c'".to_string())));
+        assert_eq!(
+            items("i17"),
+            Err(FormatError::Message("integral size (17) out of limits [1,16]".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_integers_round_trip() {
+        for (n, size) in [(0i64, 1), (-1, 1), (-129, 2), (i64::MAX, 8), (i64::MIN, 16), (-5, 3)] {
+            for little_endian in [true, false] {
+                let mut bytes = Vec::new();
+                pack_int(&mut bytes, n as u64, little_endian, size, n < 0);
+                assert_eq!(bytes.len(), size);
+                assert_eq!(unpack_int(&bytes, little_endian, true), Ok(n));
+            }
+        }
+        assert_eq!(unpack_int(&[0xff, 0xff], true, false), Ok(65535));
+        assert!(unpack_int(&[0, 0, 0, 0, 0, 0, 0, 0, 1], true, true).is_err());
+    }
+}
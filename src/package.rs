@@ -0,0 +1,115 @@
+use std::fs;
+
+/// Where `require` looks for Lua modules when neither `LUA_PATH_5_4` nor
+/// `LUA_PATH` is set.
+pub const DEFAULT_PATH: &str = "/usr/local/share/lua/5.4/?.lua;/usr/local/share/lua/5.4/?/init.lua;\
+/usr/local/lib/lua/5.4/?.lua;/usr/local/lib/lua/5.4/?/init.lua;./?.lua;./?/init.lua";
+
+/// `package.config`: the directory separator, the template separator, the
+/// substitution mark, the executable directory mark and the ignore mark.
+pub const CONFIG: &str = "/\n;\n?\n!\n-\n";
+
+/// The initial `package.path`, from the environment if set there. A `;;`
+/// in the variable stands for the default path.
+pub fn initial_path() -> String {
+    let from_environment = std::env::var("LUA_PATH_5_4").or_else(|_| std::env::var("LUA_PATH")).ok();
+    expand_default(from_environment.as_deref())
+}
+
+fn expand_default(path: Option<&str>) -> String {
+    let path = match path {
+        Some(path) => path,
+        None => return DEFAULT_PATH.to_string(),
+    };
+    match path.split_once(";;") {
+        None => path.to_string(),
+        Some((prefix, suffix)) => {
+            let mut expanded = String::new();
+            if !prefix.is_empty() {
+                expanded.push_str(prefix);
+                expanded.push(';');
+            }
+            expanded.push_str(DEFAULT_PATH);
+            if !suffix.is_empty() {
+                expanded.push(';');
+                expanded.push_str(suffix);
+            }
+            expanded
+        }
+    }
+}
+
+/// The templates that find modules next to a script in `directory`, to go
+/// in front of the path.
+pub fn script_templates(directory: &str) -> String {
+    if directory.is_empty() {
+        return "./?.lua;./?/init.lua".to_string();
+    }
+    let directory = directory.trim_end_matches('/');
+    format!("{0}/?.lua;{0}/?/init.lua", directory)
+}
+
+/// Puts `templates` in front of `path`, leaving out those `path` already
+/// has, so that a script run from its own directory does not search it
+/// twice.
+pub fn prepend_templates(templates: &str, path: &str) -> String {
+    let mut merged: Vec<&str> = Vec::new();
+    for template in templates.split(';').chain(path.split(';')) {
+        if !template.is_empty() && !merged.contains(&template) {
+            merged.push(template);
+        }
+    }
+    merged.join(";")
+}
+
+/// Looks for `name` along `path` as `package.searchpath` does: every `sep`
+/// in the name becomes `rep`, then each `;`-separated template has its `?`
+/// replaced by the name. Returns the first file that can be opened, or
+/// the list of files tried as the error.
+pub fn search_path(name: &str, path: &str, sep: &str, rep: &str) -> Result<String, String> {
+    let name = if sep.is_empty() { name.to_string() } else { name.replace(sep, rep) };
+    let mut tried = Vec::new();
+    for template in path.split(';').filter(|template| !template.is_empty()) {
+        let filename = template.replace('?', &name);
+        if is_readable(&filename) {
+            return Ok(filename);
+        }
+        tried.push(format!("no file '{}'", filename));
+    }
+    Err(tried.join("\n\t"))
+}
+
+fn is_readable(filename: &str) -> bool {
+    fs::File::open(filename).and_then(|file| file.metadata()).is_ok_and(|metadata| !metadata.is_dir())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_default() {
+        assert_eq!(expand_default(None), DEFAULT_PATH);
+        assert_eq!(expand_default(Some("a/?.lua")), "a/?.lua");
+        assert_eq!(expand_default(Some(";;")), DEFAULT_PATH);
+        assert_eq!(expand_default(Some("a/?.lua;;")), format!("a/?.lua;{}", DEFAULT_PATH));
+        assert_eq!(expand_default(Some(";;b/?.lua")), format!("{};b/?.lua", DEFAULT_PATH));
+    }
+
+    #[test]
+    fn test_search_path_lists_files_tried() {
+        let error = search_path("a.b", "/nonexistent/?.lua;;/nonexistent/?/init.lua", ".", "/").unwrap_err();
+        assert_eq!(error, "no file '/nonexistent/a/b.lua'\n\tno file '/nonexistent/a/b/init.lua'");
+        assert_eq!(script_templates("scripts/"), "scripts/?.lua;scripts/?/init.lua");
+        assert_eq!(script_templates(""), "./?.lua;./?/init.lua");
+    }
+
+    #[test]
+    fn test_prepend_templates_skips_duplicates() {
+        let path = prepend_templates(&script_templates(""), DEFAULT_PATH);
+        assert!(path.starts_with("./?.lua;./?/init.lua;/usr/local/share"), "{}", path);
+        assert_eq!(path.matches("./?.lua").count(), 1);
+        assert_eq!(prepend_templates("a/?.lua", &path), format!("a/?.lua;{}", path));
+        assert_eq!(prepend_templates("a/?.lua", "a/?.lua;;b/?.lua"), "a/?.lua;b/?.lua");
+    }
+}
@@ -25,7 +25,22 @@ impl Parser {
         Ok(Program { statements })
     }
 
-    fn statement(&mut self) -> Result<Stmt, String> {
+    /// The line of the token the parser is at, which after an error is
+    /// where parsing stopped.
+    pub fn line(&self) -> usize {
+        self.tokens
+            .get(self.current)
+            .or(self.tokens.last())
+            .map_or(1, |token| token.line)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let line = self.line();
+        let stmt = self.stmt()?;
+        Ok(Statement { line, stmt })
+    }
+
+    fn stmt(&mut self) -> Result<Stmt, String> {
         if self.match_types(&[TokenType::Local]) {
             self.local_assignment()
         } else if self.match_types(&[TokenType::Function]) {
@@ -41,11 +56,11 @@ impl Parser {
         } else if self.match_types(&[TokenType::Break]) {
             Ok(Stmt::Break)
         } else {
-            // Check for assignment
-            if self.check_assignment() {
-                self.assignment()
+            let expr = self.expression()?;
+            if self.check(&TokenType::Assign) || self.check(&TokenType::Comma) {
+                self.assignment(expr)
             } else {
-                Ok(Stmt::Expression(self.expression()?))
+                Ok(Stmt::Expression(expr))
             }
         }
     }
@@ -73,7 +88,13 @@ impl Parser {
 
     fn function_declaration(&mut self) -> Result<Stmt, String> {
         let name = self.consume_identifier("Expected function name")?;
+        let (params, body) = self.function_body()?;
 
+        Ok(Stmt::Function { name, params, body })
+    }
+
+    // Parameter list and body shared by function statements and expressions
+    fn function_body(&mut self) -> Result<(Vec<String>, Vec<Statement>), String> {
         self.consume(&TokenType::LeftParen, "Expected '(' after function name")?;
 
         let mut params = Vec::new();
@@ -97,7 +118,7 @@ impl Parser {
 
         self.consume(&TokenType::End, "Expected 'end' after function body")?;
 
-        Ok(Stmt::Function { name, params, body })
+        Ok((params, body))
     }
 
     fn if_statement(&mut self) -> Result<Stmt, String> {
@@ -153,6 +174,9 @@ impl Parser {
 
     fn for_statement(&mut self) -> Result<Stmt, String> {
         let var = self.consume_identifier("Expected variable name in for loop")?;
+        if self.check(&TokenType::Comma) || self.check(&TokenType::In) {
+            return self.generic_for(var);
+        }
         self.consume(&TokenType::Assign, "Expected '=' in for loop")?;
 
         let start = self.expression()?;
@@ -187,14 +211,47 @@ impl Parser {
         })
     }
 
+    fn generic_for(&mut self, first: String) -> Result<Stmt, String> {
+        let mut vars = vec![first];
+        while self.match_types(&[TokenType::Comma]) {
+            vars.push(self.consume_identifier("Expected variable name in for loop")?);
+        }
+        self.consume(&TokenType::In, "Expected 'in' in for loop")?;
+
+        let mut exprs = vec![self.expression()?];
+        while self.match_types(&[TokenType::Comma]) {
+            exprs.push(self.expression()?);
+        }
+
+        self.consume(&TokenType::Do, "Expected 'do' after for loop header")?;
+
+        let mut body = Vec::new();
+        while !self.check(&TokenType::End) && !self.is_at_end() {
+            if self.match_types(&[TokenType::Newline]) {
+                continue;
+            }
+            body.push(self.statement()?);
+        }
+
+        self.consume(&TokenType::End, "Expected 'end' after for loop body")?;
+
+        Ok(Stmt::ForIn { vars, exprs, body })
+    }
+
     fn return_statement(&mut self) -> Result<Stmt, String> {
-        let value = if self.check(&TokenType::Newline) || self.is_at_end() {
-            None
-        } else {
-            Some(self.expression()?)
-        };
+        let mut values = Vec::new();
+        if !(self.check(&TokenType::Newline)
+            || self.check(&TokenType::End)
+            || self.check(&TokenType::Else)
+            || self.is_at_end())
+        {
+            values.push(self.expression()?);
+            while self.match_types(&[TokenType::Comma]) {
+                values.push(self.expression()?);
+            }
+        }
 
-        Ok(Stmt::Return(value))
+        Ok(Stmt::Return(values))
     }
 
     fn check_assignment(&mut self) -> bool {
@@ -209,12 +266,48 @@ impl Parser {
         }
     }
 
-    fn assignment(&mut self) -> Result<Stmt, String> {
-        let target = self.consume_identifier("Expected variable name")?;
+    // Targets after the first one, then the values
+    fn assignment(&mut self, first: Expr) -> Result<Stmt, String> {
+        let mut targets = vec![first];
+        while self.match_types(&[TokenType::Comma]) {
+            targets.push(self.call()?);
+        }
         self.consume(&TokenType::Assign, "Expected '=' in assignment")?;
-        let value = self.expression()?;
 
-        Ok(Stmt::Assignment { target, value })
+        let mut values = vec![self.expression()?];
+        while self.match_types(&[TokenType::Comma]) {
+            values.push(self.expression()?);
+        }
+
+        if targets.len() == 1 && values.len() == 1 {
+            let (target, value) = (targets.pop().unwrap(), values.pop().unwrap());
+            return match target {
+                Expr::Identifier(target) => Ok(Stmt::Assignment { target, value }),
+                target => self.index_assignment(target, value),
+            };
+        }
+        for target in &targets {
+            if !matches!(target, Expr::Identifier(_) | Expr::Index { .. } | Expr::FieldAccess { .. }) {
+                return Err("Invalid assignment target".to_string());
+            }
+        }
+        Ok(Stmt::MultipleAssignment { targets, values })
+    }
+
+    fn index_assignment(&mut self, target: Expr, value: Expr) -> Result<Stmt, String> {
+        match target {
+            Expr::Index { object, index } => Ok(Stmt::IndexAssignment {
+                object: *object,
+                index: *index,
+                value,
+            }),
+            Expr::FieldAccess { object, field } => Ok(Stmt::IndexAssignment {
+                object: *object,
+                index: Expr::Literal(Value::String(field.into())),
+                value,
+            }),
+            _ => Err("Invalid assignment target".to_string()),
+        }
     }
 
     fn expression(&mut self) -> Result<Expr, String> {
@@ -368,12 +461,28 @@ impl Parser {
         loop {
             if self.match_types(&[TokenType::LeftParen]) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_types(&[TokenType::Colon]) {
+                let method = self.consume_identifier("Expected method name after ':'")?;
+                self.consume(&TokenType::LeftParen, "Expected '(' after method name")?;
+                let args = self.arguments()?;
+                expr = Expr::MethodCall {
+                    object: Box::new(expr),
+                    method,
+                    args,
+                };
             } else if self.match_types(&[TokenType::Dot]) {
                 let name = self.consume_identifier("Expected field name after '.'")?;
                 expr = Expr::FieldAccess {
                     object: Box::new(expr),
                     field: name,
                 };
+            } else if self.match_types(&[TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                self.consume(&TokenType::RightBracket, "Expected ']' after index")?;
+                expr = Expr::Index {
+                    object: Box::new(expr),
+                    index: Box::new(index),
+                };
             } else {
                 break;
             }
@@ -383,6 +492,16 @@ impl Parser {
     }
 
     fn finish_call(&mut self, callee: Expr) -> Result<Expr, String> {
+        let args = self.arguments()?;
+
+        Ok(Expr::Call {
+            callee: Box::new(callee),
+            args,
+        })
+    }
+
+    // Argument list after the opening parenthesis, through the closing one
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
         let mut args = Vec::new();
 
         if !self.check(&TokenType::RightParen) {
@@ -393,11 +512,7 @@ impl Parser {
         }
 
         self.consume(&TokenType::RightParen, "Expected ')' after arguments")?;
-
-        Ok(Expr::Call {
-            callee: Box::new(callee),
-            args,
-        })
+        Ok(args)
     }
 
     fn primary(&mut self) -> Result<Expr, String> {
@@ -407,12 +522,17 @@ impl Parser {
                 TokenType::False => Ok(Expr::Literal(Value::Boolean(false))),
                 TokenType::Nil => Ok(Expr::Literal(Value::Nil)),
                 TokenType::Number(n) => Ok(Expr::Literal(Value::Number(n))),
-                TokenType::String(s) => Ok(Expr::Literal(Value::String(s))),
+                TokenType::String(s) => Ok(Expr::Literal(Value::String(s.into()))),
                 TokenType::Identifier(name) => Ok(Expr::Identifier(name)),
                 TokenType::LeftParen => {
                     let expr = self.expression()?;
                     self.consume(&TokenType::RightParen, "Expected ')' after expression")?;
-                    Ok(expr)
+                    Ok(Expr::Paren(Box::new(expr)))
+                }
+                TokenType::LeftBrace => self.table_constructor(),
+                TokenType::Function => {
+                    let (params, body) = self.function_body()?;
+                    Ok(Expr::Function { params, body })
                 }
                 _ => Err(format!("Unexpected token: {:?}", token_type)),
             }
@@ -421,6 +541,42 @@ impl Parser {
         }
     }
 
+    fn table_constructor(&mut self) -> Result<Expr, String> {
+        let mut fields = Vec::new();
+
+        loop {
+            while self.match_types(&[TokenType::Newline]) {}
+
+            if self.match_types(&[TokenType::RightBrace]) {
+                break;
+            }
+
+            let field = if self.match_types(&[TokenType::LeftBracket]) {
+                let key = self.expression()?;
+                self.consume(&TokenType::RightBracket, "Expected ']' after table key")?;
+                self.consume(&TokenType::Assign, "Expected '=' after table key")?;
+                TableField::Keyed(key, self.expression()?)
+            } else if self.check_assignment() {
+                let name = self.consume_identifier("Expected field name")?;
+                self.consume(&TokenType::Assign, "Expected '=' after field name")?;
+                TableField::Named(name, self.expression()?)
+            } else {
+                TableField::Positional(self.expression()?)
+            };
+            fields.push(field);
+
+            while self.match_types(&[TokenType::Newline]) {}
+
+            if !self.match_types(&[TokenType::Comma, TokenType::Semicolon]) {
+                while self.match_types(&[TokenType::Newline]) {}
+                self.consume(&TokenType::RightBrace, "Expected '}' after table fields")?;
+                break;
+            }
+        }
+
+        Ok(Expr::Table(fields))
+    }
+
     fn match_binary_op(&mut self, types: &[TokenType]) -> Option<BinaryOp> {
         for token_type in types {
             if self.check(token_type) {
@@ -0,0 +1,554 @@
+use crate::value::Value;
+
+/// Most captures a single pattern may open.
+pub const MAX_CAPTURES: usize = 32;
+
+const ESCAPE: u8 = b'%';
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+// Nesting of the recursive matcher, which recurses on captures and on every
+// repetition that has to try the rest of the pattern
+const MAX_DEPTH: usize = 200;
+
+/// Steps one attempt to match a pattern may take besides the square of the
+/// subject length, unless the runtime is configured otherwise.
+///
+/// Matching backtracks, so a pattern like "a*a*a*a*b" takes exponential time
+/// on a long run of a's. Each attempt, that is each `match_at` and so each
+/// match of `string.gsub` or `string.gmatch`, fails with an error once it
+/// has used up its budget, so hostile patterns cannot hang the host. The
+/// square lets patterns that are merely quadratic, like `^%s*(.-)%s*$`,
+/// run on long subjects.
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+/// A capture of a successful match.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    /// The byte range of a substring
+    Bytes(usize, usize),
+    /// A `()` capture, holding a byte offset
+    Position(usize),
+}
+
+impl Capture {
+    fn to_value(self, subject: &[u8]) -> Value {
+        match self {
+            Capture::Bytes(start, end) => Value::String(subject[start..end].into()),
+            Capture::Position(position) => Value::Number((position + 1) as f64),
+        }
+    }
+}
+
+/// A successful match: the byte range of the whole match and its captures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub start: usize,
+    pub end: usize,
+    pub captures: Vec<Capture>,
+}
+
+impl Match {
+    /// Capture `index`, counting from zero. A pattern without captures has
+    /// the whole match as its only one.
+    pub fn capture(&self, index: usize, subject: &[u8]) -> Option<Value> {
+        match self.captures.get(index) {
+            Some(capture) => Some(capture.to_value(subject)),
+            None if index == 0 && self.captures.is_empty() => Some(self.whole(subject)),
+            None => None,
+        }
+    }
+
+    /// The captures as Lua values, or the whole match if there are none.
+    pub fn values(&self, subject: &[u8]) -> Vec<Value> {
+        if self.captures.is_empty() {
+            return vec![self.whole(subject)];
+        }
+        self.captures.iter().map(|capture| capture.to_value(subject)).collect()
+    }
+
+    pub fn whole(&self, subject: &[u8]) -> Value {
+        Value::String(subject[self.start..self.end].into())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum CaptureLength {
+    Unfinished,
+    Position,
+    Bytes(usize),
+}
+
+/// Matches one Lua pattern against one subject, any number of times.
+pub struct Matcher<'a> {
+    subject: &'a [u8],
+    pattern: &'a [u8],
+    anchored: bool,
+    step_limit: usize,
+    // Steps left to the running attempt
+    steps: usize,
+    depth: usize,
+    level: usize,
+    captures: [(usize, CaptureLength); MAX_CAPTURES],
+}
+
+impl<'a> Matcher<'a> {
+    /// A leading `^` anchors the pattern at the position matching starts
+    /// from.
+    pub fn new(subject: &'a [u8], pattern: &'a [u8]) -> Self {
+        match pattern.strip_prefix(b"^") {
+            Some(rest) => Self::with_anchor(subject, rest, true),
+            None => Self::with_anchor(subject, pattern, false),
+        }
+    }
+
+    /// Like `new`, but a leading `^` is an ordinary character, as in
+    /// `string.gmatch`.
+    pub fn unanchored(subject: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self::with_anchor(subject, pattern, false)
+    }
+
+    fn with_anchor(subject: &'a [u8], pattern: &'a [u8], anchored: bool) -> Self {
+        Self {
+            subject,
+            pattern,
+            anchored,
+            step_limit: DEFAULT_STEP_LIMIT,
+            steps: 0,
+            depth: 0,
+            level: 0,
+            captures: [(0, CaptureLength::Unfinished); MAX_CAPTURES],
+        }
+    }
+
+    /// Uses `limit` instead of `DEFAULT_STEP_LIMIT` for every attempt.
+    pub fn with_step_limit(mut self, limit: usize) -> Self {
+        self.step_limit = limit;
+        self
+    }
+
+    pub fn is_anchored(&self) -> bool {
+        self.anchored
+    }
+
+    /// Matches the pattern starting exactly at byte `start`.
+    pub fn match_at(&mut self, start: usize) -> Result<Option<Match>, String> {
+        let length = self.subject.len();
+        self.steps = self.step_limit.saturating_add(length.saturating_mul(length));
+        self.level = 0;
+        self.depth = 0;
+        match self.do_match(start, 0)? {
+            Some(end) => Ok(Some(Match {
+                start,
+                end,
+                captures: self.finished_captures()?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// The first match starting at or after byte `start`, or only at it if
+    /// the pattern is anchored. Each position tried is an attempt of its
+    /// own.
+    pub fn find(&mut self, start: usize) -> Result<Option<Match>, String> {
+        let mut position = start;
+        loop {
+            if let Some(found) = self.match_at(position)? {
+                return Ok(Some(found));
+            }
+            position += 1;
+            if self.anchored || position > self.subject.len() {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn finished_captures(&self) -> Result<Vec<Capture>, String> {
+        self.captures[..self.level]
+            .iter()
+            .map(|&(start, length)| match length {
+                CaptureLength::Bytes(length) => Ok(Capture::Bytes(start, start + length)),
+                CaptureLength::Position => Ok(Capture::Position(start)),
+                CaptureLength::Unfinished => Err("unfinished capture".to_string()),
+            })
+            .collect()
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        match self.steps.checked_sub(1) {
+            Some(steps) => {
+                self.steps = steps;
+                Ok(())
+            }
+            None => Err("pattern too complex".to_string()),
+        }
+    }
+
+    // Returns the end of the match of the pattern from `p` on against the
+    // subject from `s` on
+    fn do_match(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if self.depth >= MAX_DEPTH {
+            return Err("pattern too complex".to_string());
+        }
+        self.depth += 1;
+        let result = self.match_here(s, p);
+        self.depth -= 1;
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> Result<Option<usize>, String> {
+        let pattern = self.pattern;
+        loop {
+            self.step()?;
+            if p == pattern.len() {
+                return Ok(Some(s));
+            }
+            let next = pattern.get(p + 1).copied();
+            match pattern[p] {
+                b'(' if next == Some(b')') => return self.start_capture(s, p + 2, CaptureLength::Position),
+                b'(' => return self.start_capture(s, p + 1, CaptureLength::Unfinished),
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == pattern.len() => {
+                    return Ok((s == self.subject.len()).then_some(s));
+                }
+                ESCAPE if next == Some(b'b') => match self.match_balance(s, p + 2)? {
+                    Some(end) => {
+                        s = end;
+                        p += 4;
+                    }
+                    None => return Ok(None),
+                },
+                ESCAPE if next == Some(b'f') => {
+                    p += 2;
+                    if pattern.get(p) != Some(&b'[') {
+                        return Err("missing '[' after '%f' in pattern".to_string());
+                    }
+                    let end = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.subject[s - 1] };
+                    let current = self.subject.get(s).copied().unwrap_or(0);
+                    if self.match_bracket_class(previous, p, end - 1) || !self.match_bracket_class(current, p, end - 1) {
+                        return Ok(None);
+                    }
+                    p = end;
+                }
+                ESCAPE if next.is_some_and(|c| c.is_ascii_digit()) => match self.match_capture(s, next.unwrap())? {
+                    Some(end) => {
+                        s = end;
+                        p += 2;
+                    }
+                    None => return Ok(None),
+                },
+                _ => {
+                    let end = self.class_end(p)?;
+                    let repetition = pattern.get(end).copied();
+                    if !self.single_match(s, p, end) {
+                        // Repetitions that accept zero matches skip the item
+                        if matches!(repetition, Some(b'*' | b'?' | b'-')) {
+                            p = end + 1;
+                            continue;
+                        }
+                        return Ok(None);
+                    }
+                    match repetition {
+                        Some(b'?') => {
+                            if let Some(found) = self.do_match(s + 1, end + 1)? {
+                                return Ok(Some(found));
+                            }
+                            p = end + 1;
+                        }
+                        Some(b'+') => return self.max_expand(s + 1, p, end),
+                        Some(b'*') => return self.max_expand(s, p, end),
+                        Some(b'-') => return self.min_expand(s, p, end),
+                        _ => {
+                            s += 1;
+                            p = end;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Matches as many repetitions as possible, then gives them back one at a
+    // time until the rest of the pattern matches
+    fn max_expand(&mut self, s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        let mut count = 0;
+        while self.single_match(s + count, p, end) {
+            self.step()?;
+            count += 1;
+        }
+        loop {
+            if let Some(found) = self.do_match(s + count, end + 1)? {
+                return Ok(Some(found));
+            }
+            if count == 0 {
+                return Ok(None);
+            }
+            count -= 1;
+        }
+    }
+
+    // Matches as few repetitions as possible, adding one at a time until the
+    // rest of the pattern matches
+    fn min_expand(&mut self, mut s: usize, p: usize, end: usize) -> Result<Option<usize>, String> {
+        loop {
+            if let Some(found) = self.do_match(s, end + 1)? {
+                return Ok(Some(found));
+            }
+            if !self.single_match(s, p, end) {
+                return Ok(None);
+            }
+            self.step()?;
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, length: CaptureLength) -> Result<Option<usize>, String> {
+        if self.level >= MAX_CAPTURES {
+            return Err("too many captures".to_string());
+        }
+        self.captures[self.level] = (s, length);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        let index = self.capture_to_close()?;
+        let start = self.captures[index].0;
+        self.captures[index].1 = CaptureLength::Bytes(s - start);
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.captures[index].1 = CaptureLength::Unfinished;
+        }
+        Ok(result)
+    }
+
+    fn capture_to_close(&self) -> Result<usize, String> {
+        self.captures[..self.level]
+            .iter()
+            .rposition(|(_, length)| matches!(length, CaptureLength::Unfinished))
+            .ok_or_else(|| "invalid pattern capture".to_string())
+    }
+
+    // Matches `%bxy`, with `p` at the `x`
+    fn match_balance(&mut self, s: usize, p: usize) -> Result<Option<usize>, String> {
+        if p + 1 >= self.pattern.len() {
+            return Err("malformed pattern (missing arguments to '%b')".to_string());
+        }
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.subject.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.subject.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    // Matches a back reference `%1` to `%9`
+    fn match_capture(&mut self, s: usize, digit: u8) -> Result<Option<usize>, String> {
+        // `%0` is never a valid back reference
+        let index = digit.wrapping_sub(b'1') as usize;
+        let (start, length) = match self.captures[..self.level].get(index) {
+            Some((start, CaptureLength::Bytes(length))) => (*start, *length),
+            Some((_, CaptureLength::Position)) => return Ok(Some(s)),
+            _ => return Err(format!("invalid capture index %{}", digit as char)),
+        };
+        let captured = &self.subject[start..start + length];
+        Ok(self.subject[s..].starts_with(captured).then_some(s + length))
+    }
+
+    // The index just past the single-character class starting at `p`
+    fn class_end(&self, p: usize) -> Result<usize, String> {
+        let pattern = self.pattern;
+        let mut p = p;
+        let c = pattern[p];
+        p += 1;
+        match c {
+            ESCAPE => {
+                if p >= pattern.len() {
+                    return Err("malformed pattern (ends with '%')".to_string());
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if pattern.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+                // The first character is part of the set even if it is a ']'
+                loop {
+                    if p >= pattern.len() {
+                        return Err("malformed pattern (missing ']')".to_string());
+                    }
+                    let c = pattern[p];
+                    p += 1;
+                    if c == ESCAPE && p < pattern.len() {
+                        p += 1;
+                    }
+                    if pattern.get(p) == Some(&b']') {
+                        return Ok(p + 1);
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    fn single_match(&self, s: usize, p: usize, end: usize) -> bool {
+        let c = match self.subject.get(s) {
+            Some(&c) => c,
+            None => return false,
+        };
+        match self.pattern[p] {
+            b'.' => true,
+            ESCAPE => match_class(c, self.pattern[p + 1]),
+            b'[' => self.match_bracket_class(c, p, end - 1),
+            literal => literal == c,
+        }
+    }
+
+    // Matches a set `[...]` spanning `p` to `end`, which is at the `]`
+    fn match_bracket_class(&self, c: u8, p: usize, end: usize) -> bool {
+        let pattern = self.pattern;
+        let mut p = p + 1;
+        let mut found = true;
+        if pattern[p] == b'^' {
+            found = false;
+            p += 1;
+        }
+        while p < end {
+            if pattern[p] == ESCAPE {
+                p += 1;
+                if match_class(c, pattern[p]) {
+                    return found;
+                }
+                p += 1;
+            } else if pattern[p + 1] == b'-' && p + 2 < end {
+                if pattern[p] <= c && c <= pattern[p + 2] {
+                    return found;
+                }
+                p += 3;
+            } else {
+                if pattern[p] == c {
+                    return found;
+                }
+                p += 1;
+            }
+        }
+        !found
+    }
+}
+
+// Matches `c` against a class letter such as the `a` of `%a`. Upper case
+// letters are the complements; anything else matches itself.
+fn match_class(c: u8, class: u8) -> bool {
+    let matched = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // Same set as C's isspace, which unlike Rust's includes \v
+        b's' => matches!(c, b' ' | b'\t'..=b'\r'),
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return class == c,
+    };
+    if class.is_ascii_uppercase() {
+        !matched
+    } else {
+        matched
+    }
+}
+
+/// Whether `pattern` uses any magic characters, as opposed to being a
+/// plain string.
+pub fn has_specials(pattern: &[u8]) -> bool {
+    pattern.iter().any(|c| SPECIALS.contains(c))
+}
+
+/// Byte offset of the first occurrence of `needle` in `haystack` at or
+/// after `start`.
+pub fn find_plain(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    if needle.is_empty() {
+        return (start <= haystack.len()).then_some(start);
+    }
+    haystack
+        .get(start..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|offset| start + offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(subject: &str, pattern: &str) -> Option<(usize, usize)> {
+        Matcher::new(subject.as_bytes(), pattern.as_bytes())
+            .find(0)
+            .unwrap()
+            .map(|found| (found.start, found.end))
+    }
+
+    #[test]
+    fn test_classes_and_repetitions() {
+        assert_eq!(find("hello world", "o w"), Some((4, 7)));
+        assert_eq!(find("  key = 42", "%a+"), Some((2, 5)));
+        assert_eq!(find("abc123", "%d+$"), Some((3, 6)));
+        assert_eq!(find("aaa", "a-b"), None);
+        assert_eq!(find("<<a>>", "<.->"), Some((0, 4)));
+        assert_eq!(find("x = [[y]]", "[%[%]]+"), Some((4, 6)));
+        assert_eq!(find("abc", "^b"), None);
+        assert_eq!(find("THE (quick) fox", "%f[%a]%l+"), Some((5, 10)));
+    }
+
+    #[test]
+    fn test_captures() {
+        let subject = b"key=value";
+        let found = Matcher::new(subject, b"(%w+)=()(%w+)").find(0).unwrap().unwrap();
+        assert_eq!(
+            found.captures,
+            vec![Capture::Bytes(0, 3), Capture::Position(4), Capture::Bytes(4, 9)]
+        );
+        assert_eq!(find("f(a(b)c)d", "%b()"), Some((1, 8)));
+        assert_eq!(find("say 'hi' now", "(['\"]).-%1"), Some((4, 8)));
+    }
+
+    #[test]
+    fn test_malformed_patterns() {
+        for pattern in ["%", "[a", "(a", "a)", "%1", "%f", "%b"] {
+            assert!(Matcher::new(b"a", pattern.as_bytes()).find(0).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn test_backtracking_is_bounded() {
+        let subject = "a".repeat(40);
+        let mut matcher = Matcher::new(subject.as_bytes(), b"a*a*a*a*a*a*a*a*a*b");
+        assert_eq!(matcher.find(0), Err("pattern too complex".to_string()));
+    }
+
+    #[test]
+    fn test_each_attempt_has_its_own_budget() {
+        // Every attempt splits the rest of the `a`s between the two
+        // repetitions, so the whole search takes the cube of the length,
+        // more steps than any single attempt may
+        let subject = "a".repeat(300);
+        let mut matcher = Matcher::new(subject.as_bytes(), b"a*a*b");
+        assert_eq!(matcher.find(0), Ok(None));
+    }
+}
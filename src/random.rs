@@ -0,0 +1,105 @@
+/// The xoshiro256** generator behind `math.random`, seeded the same way as
+/// the reference implementation so a seed gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: [u64; 4],
+}
+
+// 2^-53, the spacing of the floats `next_float` returns
+const FLOAT_STEP: f64 = 1.0 / (1u64 << 53) as f64;
+
+impl Random {
+    /// A generator seeded with `n1` and `n2`.
+    pub fn seeded(n1: u64, n2: u64) -> Self {
+        // 0xff keeps the state from being all zeros
+        let mut random = Self { state: [n1, 0xff, n2, 0] };
+        // Discard the first values to spread the seed over the state
+        for _ in 0..16 {
+            random.next_u64();
+        }
+        random
+    }
+
+    /// Seeds from the clock and an address, returning the generator and the
+    /// two seed values.
+    pub fn from_time() -> (Self, u64, u64) {
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        let local = 0u8;
+        let address = std::ptr::addr_of!(local) as u64;
+        (Self::seeded(time, address), time, address)
+    }
+
+    /// The next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        let [s0, s1, s2, s3] = &mut self.state;
+        let result = s1.wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = *s1 << 17;
+        *s2 ^= *s0;
+        *s3 ^= *s1;
+        *s1 ^= *s2;
+        *s0 ^= *s3;
+        *s2 ^= t;
+        *s3 = s3.rotate_left(45);
+        result
+    }
+
+    /// A float in [0, 1) from the top 53 bits of the next value.
+    pub fn next_float(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 * FLOAT_STEP
+    }
+
+    /// A uniform value in [0, n], starting from `random`. Rejects values
+    /// above `n` instead of taking a remainder, which would favor small
+    /// results.
+    pub fn project(&mut self, random: u64, n: u64) -> u64 {
+        if n & n.wrapping_add(1) == 0 {
+            // n + 1 is a power of two
+            return random & n;
+        }
+        // The smallest 2^b - 1 not below n
+        let mut limit = n;
+        limit |= limit >> 1;
+        limit |= limit >> 2;
+        limit |= limit >> 4;
+        limit |= limit >> 8;
+        limit |= limit >> 16;
+        limit |= limit >> 32;
+        let mut random = random & limit;
+        while random > n {
+            random = self.next_u64() & limit;
+        }
+        random
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_seed_same_sequence() {
+        let mut a = Random::seeded(42, 0);
+        let mut b = Random::seeded(42, 0);
+        let mut c = Random::seeded(42, 1);
+        let first: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+        assert_eq!(first, (0..8).map(|_| b.next_u64()).collect::<Vec<_>>());
+        assert_ne!(first, (0..8).map(|_| c.next_u64()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_project_stays_in_range() {
+        let mut random = Random::seeded(7, 0);
+        for n in [0, 1, 2, 5, 6, 7, 100, u64::MAX] {
+            for _ in 0..200 {
+                let value = random.next_u64();
+                assert!(random.project(value, n) <= n);
+            }
+        }
+        for _ in 0..200 {
+            let f = random.next_float();
+            assert!((0.0..1.0).contains(&f));
+        }
+    }
+}
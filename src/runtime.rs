@@ -1,23 +1,227 @@
-use crate::bytecode::{Chunk, Compiler};
+use crate::bytecode::{Capture, Chunk, Compiler, FunctionProto, MULTIPLE};
+use crate::coroutine::{Coroutine, CoroutineRef, CoroutineStatus};
+use crate::error::{LuaError, LuaResult};
+use crate::file::LuaFile;
+use crate::function::{ClosureRef, LuaClosure, NativeFunction, NativeKind, NativeRef, Upvalue};
+use crate::gc::{GcMode, GcStats, Heap, Root, Tracer};
 use crate::jit::{JitCompiler, JitEnabled};
 use crate::lexer::Lexer;
+use crate::nanbox::{PackedValue, ValueRef, ValueStack};
 use crate::parser::Parser;
+use crate::random::Random;
+use crate::string::LuaString;
+use crate::table::{LuaTable, TableRef};
+use crate::userdata::{Userdata, UserdataRef};
 use crate::value::Value;
+use crate::vm::BoundFunction;
+use std::any::Any;
+use std::cell::Ref;
 use std::collections::HashMap;
+use std::ops::Deref;
+use std::path::Path;
+use std::rc::Rc;
+
+const MAX_CALL_DEPTH: usize = 200_000;
+
+// Longest chain of `__index` or `__newindex` tables followed before giving up
+const MAX_META_CHAIN: usize = 2000;
 
 pub struct LuaJitRuntime {
     globals: HashMap<String, Value>,
-    stack: Vec<Value>,
+    stack: ValueStack,
     call_stack: Vec<CallFrame>,
     jit_compiler: JitCompiler,
     stdlib: crate::vm::StandardLibrary,
+    heap: Heap,
+    finalizing: bool,
+    warnings: Vec<String>,
+    // The coroutine whose stack and frames are in `stack` and `call_stack`,
+    // or nil on the main thread. Kept as a value so it can be a GC root.
+    current: Value,
+    // The thread scripts see as `coroutine.running()` outside coroutines.
+    // It never runs a function of its own and cannot be resumed.
+    main_thread: CoroutineRef,
+    // Stacks of the threads that resumed the running coroutine, innermost
+    // last
+    resumers: Vec<Resumer>,
+    // Metatable shared by all strings, a table whose `__index` is the
+    // string library. Nil until the builtins are added.
+    string_metatable: Value,
+    // Library state that scripts cannot reach, like the registry of the
+    // reference implementation. Nil until the builtins are added.
+    registry: Value,
+    // How many native functions up the call stack are running Lua code
+    native_depth: usize,
+    transfer: Option<Transfer>,
+    // Values passed to `yield`, set until `resume` picks them up
+    yielded: Option<Vec<Value>>,
+    // State of `math.random`, seeded from the clock until `math.randomseed`
+    random: Random,
+    // Whether `os.execute` and `io.popen` may start processes
+    process_spawning: bool,
+    // Steps each attempt to match a pattern may take besides the square of
+    // the subject length
+    pattern_step_limit: usize,
+    // Whether the running native function was called by another one rather
+    // than by a Lua frame
+    native_caller: bool,
 }
 
 #[derive(Debug)]
-struct CallFrame {
-    chunk: Chunk,
+pub(crate) struct CallFrame {
+    chunk: Rc<Chunk>,
     pc: usize,
-    locals: Vec<Value>,
+    locals: Vec<PackedValue>,
+    // Stack height when the frame was entered, restored on return
+    base: usize,
+    // Number of values the caller expects back
+    results: usize,
+    entry: Entry,
+    // Where globals live for the running function, if not in `globals`
+    env: Option<Value>,
+    // The closure running in the frame, whose upvalues it uses, or nil for
+    // a chunk run directly
+    function: Value,
+    // Captured locals still in scope, by slot, which the frame reads and
+    // writes through their upvalue instead of its own slot
+    open_upvalues: Vec<(usize, Rc<Upvalue>)>,
+}
+
+/// A value a frame keeps alive: one it holds itself, or the value of a
+/// captured local it has open.
+pub(crate) enum FrameValue<'a> {
+    Held(ValueRef<'a>),
+    Captured(Ref<'a, Value>),
+}
+
+impl Deref for FrameValue<'_> {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        match self {
+            FrameValue::Held(value) => value,
+            FrameValue::Captured(value) => value,
+        }
+    }
+}
+
+// How a frame was entered, which decides where its errors go and how the
+// levels of `error` count through it
+#[derive(Debug)]
+enum Entry {
+    /// Called by the frame below
+    Lua,
+    /// Called by a native function, which gets its errors
+    Native,
+    /// Entered through `pcall`, or `xpcall` with its message handler:
+    /// errors stop here, and results are prefixed with true
+    Protected(Option<Value>),
+}
+
+impl CallFrame {
+    pub(crate) fn locals(&self) -> impl Iterator<Item = ValueRef<'_>> {
+        self.locals.iter().map(PackedValue::get)
+    }
+
+    /// Every value the frame keeps alive: its locals, open or not, its
+    /// function, environment and message handler.
+    pub(crate) fn references(&self) -> impl Iterator<Item = FrameValue<'_>> {
+        let captured = self.open_upvalues.iter().map(|(_, upvalue)| FrameValue::Captured(upvalue.borrow()));
+        self.held().map(FrameValue::Held).chain(captured)
+    }
+
+    /// Marks what the frame keeps alive. Its open upvalues are marked as
+    /// such, since closures share them.
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        for value in self.held() {
+            tracer.mark_value(&value);
+        }
+        for (_, upvalue) in &self.open_upvalues {
+            tracer.mark_upvalue(upvalue);
+        }
+    }
+
+    // The values the frame holds itself, which are all but its open locals
+    fn held(&self) -> impl Iterator<Item = ValueRef<'_>> {
+        let handler = match &self.entry {
+            Entry::Protected(handler) => handler.as_ref(),
+            _ => None,
+        };
+        let fields = self.env.iter().chain(handler).chain(std::iter::once(&self.function));
+        self.locals().chain(fields.map(ValueRef::from))
+    }
+
+    fn open_upvalue(&self, slot: usize) -> Option<&Rc<Upvalue>> {
+        self.open_upvalues
+            .iter()
+            .find(|(open, _)| *open == slot)
+            .map(|(_, upvalue)| upvalue)
+    }
+
+    // The upvalue of the local in `slot`, which is opened with the value of
+    // the local if no closure captured it yet
+    fn capture(&mut self, slot: usize) -> Rc<Upvalue> {
+        if let Some(upvalue) = self.open_upvalue(slot) {
+            return upvalue.clone();
+        }
+        let value = match self.locals.get_mut(slot) {
+            Some(local) => std::mem::take(local).into_value(),
+            None => Value::Nil,
+        };
+        let upvalue = Rc::new(Upvalue::new(value));
+        self.open_upvalues.push((slot, upvalue.clone()));
+        upvalue
+    }
+
+    // Closes the upvalues of the locals from slot `first` on, whose values
+    // go back to the slots
+    fn close_upvalues(&mut self, first: usize) {
+        let (closed, open) = std::mem::take(&mut self.open_upvalues)
+            .into_iter()
+            .partition(|(slot, _)| *slot >= first);
+        self.open_upvalues = open;
+        for (slot, upvalue) in closed {
+            if self.locals.len() <= slot {
+                self.locals.resize(slot + 1, PackedValue::NIL);
+            }
+            self.locals[slot] = upvalue.get().into();
+        }
+    }
+
+    fn upvalue(&self, index: usize) -> LuaResult<Rc<Upvalue>> {
+        match &self.function {
+            Value::Closure(closure) => closure.upvalue(index),
+            _ => None,
+        }
+        .ok_or_else(|| "No upvalue for variable".into())
+    }
+
+    fn is_protected(&self) -> bool {
+        matches!(self.entry, Entry::Protected(_))
+    }
+
+    // Where the frame is running, as `chunk:line: `, or empty when the line
+    // is unknown
+    fn position(&self) -> String {
+        match self.pc.checked_sub(1).and_then(|pc| self.chunk.line_numbers.get(pc)) {
+            Some(&line) if line > 0 => format!("{}:{}: ", self.chunk.source, line),
+            _ => String::new(),
+        }
+    }
+}
+
+struct Resumer {
+    coroutine: Value,
+    stack: ValueStack,
+    call_stack: Vec<CallFrame>,
+}
+
+// Control transfers a native function asks for when it returns, carried out
+// by the dispatch loop since natives cannot suspend or enter frames
+enum Transfer {
+    Yield(Vec<Value>),
+    /// A function and its arguments, and the message handler of `xpcall`
+    ProtectedCall(Vec<Value>, Option<Value>),
 }
 
 impl LuaJitRuntime {
@@ -25,82 +229,509 @@ impl LuaJitRuntime {
         let stdlib = crate::vm::StandardLibrary::new();
         let mut runtime = Self {
             globals: HashMap::new(),
-            stack: Vec::new(),
+            stack: ValueStack::new(),
             call_stack: Vec::new(),
             jit_compiler: JitCompiler::new(),
             stdlib,
+            heap: Heap::new(),
+            finalizing: false,
+            warnings: Vec::new(),
+            current: Value::Nil,
+            main_thread: CoroutineRef::new(Coroutine::main()),
+            resumers: Vec::new(),
+            string_metatable: Value::Nil,
+            registry: Value::Nil,
+            native_depth: 0,
+            transfer: None,
+            yielded: None,
+            random: Random::from_time().0,
+            process_spawning: false,
+            pattern_step_limit: crate::pattern::DEFAULT_STEP_LIMIT,
+            native_caller: false,
         };
 
         runtime.add_builtins();
         runtime
     }
 
-    pub fn with_config(_config: crate::LunaConfig) -> Self {
-        let runtime = Self::new();
+    pub fn with_config(config: crate::LunaConfig) -> Self {
+        let mut runtime = Self::new();
+        runtime.gc_set_incremental(config.gc_pause, config.gc_step_multiplier, config.gc_step_size);
+        if config.gc_mode == GcMode::Generational {
+            runtime.gc_set_generational(config.gc_minor_multiplier, config.gc_major_multiplier);
+        }
+        runtime.process_spawning = config.allow_process_spawning;
+        runtime.pattern_step_limit = config.pattern_step_limit;
         runtime
     }
 
     fn add_builtins(&mut self) {
-        self.globals.insert("print".to_string(), Value::Function(0));
+        // Library functions are namespaced ("math.abs"); everything else is a
+        // global
+        for (name, function) in self.stdlib.get_all_functions() {
+            if !name.contains('.') {
+                self.globals.insert(name, function);
+            }
+        }
 
         let mut math_table = std::collections::HashMap::new();
-        if let Some((id, _)) = self.stdlib.get_function("math.abs") {
-            math_table.insert("abs".to_string(), Value::Function(id));
+        for name in [
+            "abs", "ceil", "floor", "max", "min", "fmod", "modf", "sqrt", "exp", "log", "sin", "cos", "tan", "asin",
+            "acos", "atan", "deg", "rad", "ult", "tointeger", "type", "random", "randomseed",
+        ] {
+            if let Some(function) = self.builtin(&format!("math.{}", name)) {
+                math_table.insert(name.to_string(), function);
+            }
         }
-        if let Some((id, _)) = self.stdlib.get_function("math.sqrt") {
-            math_table.insert("sqrt".to_string(), Value::Function(id));
+        math_table.insert("pi".to_string(), Value::Number(std::f64::consts::PI));
+        math_table.insert("huge".to_string(), Value::Number(f64::INFINITY));
+        // Numbers are floats, so these are the bounds within which every
+        // integer is exact, not those of a 64-bit integer as in Lua 5.4
+        math_table.insert("maxinteger".to_string(), Value::Number(9007199254740992.0));
+        math_table.insert("mininteger".to_string(), Value::Number(-9007199254740992.0));
+        let math_table = self.heap.alloc_table(LuaTable::from(math_table));
+        self.globals.insert("math".to_string(), Value::Table(math_table));
+
+        let mut string_table = std::collections::HashMap::new();
+        for name in [
+            "len", "sub", "upper", "lower", "char", "byte", "rep", "reverse", "find", "match", "gmatch", "gsub",
+            "format", "pack", "packsize", "unpack",
+        ] {
+            if let Some(function) = self.builtin(&format!("string.{}", name)) {
+                string_table.insert(name.to_string(), function);
+            }
         }
-        if let Some((id, _)) = self.stdlib.get_function("math.max") {
-            math_table.insert("max".to_string(), Value::Function(id));
+        let string_table = self.heap.alloc_table(LuaTable::from(string_table));
+        self.globals.insert("string".to_string(), Value::Table(string_table.clone()));
+
+        let mut string_metatable = std::collections::HashMap::new();
+        string_metatable.insert("__index".to_string(), Value::Table(string_table));
+        self.string_metatable = Value::Table(self.heap.alloc_table(LuaTable::from(string_metatable)));
+
+        let mut table_table = std::collections::HashMap::new();
+        for name in ["insert", "remove", "concat", "sort", "unpack", "pack", "move"] {
+            if let Some(function) = self.builtin(&format!("table.{}", name)) {
+                table_table.insert(name.to_string(), function);
+            }
         }
-        if let Some((id, _)) = self.stdlib.get_function("math.min") {
-            math_table.insert("min".to_string(), Value::Function(id));
+        let table_table = self.heap.alloc_table(LuaTable::from(table_table));
+        self.globals.insert("table".to_string(), Value::Table(table_table));
+
+        let mut coroutine_table = std::collections::HashMap::new();
+        for name in ["create", "resume", "yield", "status", "wrap", "isyieldable", "running", "close"] {
+            if let Some(function) = self.builtin(&format!("coroutine.{}", name)) {
+                coroutine_table.insert(name.to_string(), function);
+            }
         }
-        self.globals.insert("math".to_string(), Value::Table(math_table));
+        let coroutine_table = self.heap.alloc_table(LuaTable::from(coroutine_table));
+        self.globals.insert("coroutine".to_string(), Value::Table(coroutine_table));
 
-        let mut string_table = std::collections::HashMap::new();
-        if let Some((id, _)) = self.stdlib.get_function("string.len") {
-            string_table.insert("len".to_string(), Value::Function(id));
+        self.registry = Value::Table(self.heap.alloc_table(LuaTable::new()));
+
+        // File handles share one metatable, whose `__index` holds the methods
+        let mut file_methods = std::collections::HashMap::new();
+        for name in ["close", "flush", "lines", "read", "seek", "setvbuf", "write"] {
+            if let Some(function) = self.builtin(&format!("file.{}", name)) {
+                file_methods.insert(name.to_string(), function);
+            }
+        }
+        let mut file_metatable = std::collections::HashMap::new();
+        file_metatable.insert("__index".to_string(), Value::Table(self.heap.alloc_table(LuaTable::from(file_methods))));
+        file_metatable.insert("__name".to_string(), Value::String(crate::stdlib::FILE_HANDLE.into()));
+        for name in ["__gc", "__tostring"] {
+            if let Some(function) = self.builtin(&format!("file.{}", name)) {
+                file_metatable.insert(name.to_string(), function);
+            }
+        }
+        let file_metatable = self.heap.alloc_table(LuaTable::from(file_metatable));
+        self.registry_set(crate::stdlib::FILE_HANDLE, Value::Table(file_metatable.clone()));
+        let mut weak_keys = std::collections::HashMap::new();
+        weak_keys.insert("__mode".to_string(), Value::String("k".into()));
+        let open_files = self.heap.alloc_table(LuaTable::new());
+        open_files.borrow_mut().set_metatable(Some(self.heap.alloc_table(LuaTable::from(weak_keys))));
+        self.registry_set(crate::stdlib::IO_FILES, Value::Table(open_files));
+
+        let mut io_table = std::collections::HashMap::new();
+        for name in [
+            "close", "flush", "input", "lines", "open", "output", "popen", "read", "tmpfile", "type", "write",
+        ] {
+            if let Some(function) = self.builtin(&format!("io.{}", name)) {
+                io_table.insert(name.to_string(), function);
+            }
+        }
+        for (name, file) in [("stdin", LuaFile::stdin()), ("stdout", LuaFile::stdout()), ("stderr", LuaFile::stderr())] {
+            let file = Value::Userdata(self.create_userdata(file, Some(file_metatable.clone())));
+            io_table.insert(name.to_string(), file);
+        }
+        self.registry_set(crate::stdlib::IO_INPUT, io_table["stdin"].clone());
+        self.registry_set(crate::stdlib::IO_OUTPUT, io_table["stdout"].clone());
+        let io_table = self.heap.alloc_table(LuaTable::from(io_table));
+        self.globals.insert("io".to_string(), Value::Table(io_table));
+
+        let mut os_table = std::collections::HashMap::new();
+        for name in [
+            "clock", "date", "difftime", "execute", "exit", "getenv", "remove", "rename", "time", "tmpname",
+        ] {
+            if let Some(function) = self.builtin(&format!("os.{}", name)) {
+                os_table.insert(name.to_string(), function);
+            }
+        }
+        let os_table = self.heap.alloc_table(LuaTable::from(os_table));
+        self.globals.insert("os".to_string(), Value::Table(os_table));
+
+        let mut fs_table = std::collections::HashMap::new();
+        for name in ["attributes", "chdir", "currentdir", "dir", "mkdir", "rmdir", "symlinkattributes", "touch"] {
+            if let Some(function) = self.builtin(&format!("fs.{}", name)) {
+                fs_table.insert(name.to_string(), function);
+            }
+        }
+        let fs_table = self.heap.alloc_table(LuaTable::from(fs_table));
+        self.globals.insert("fs".to_string(), Value::Table(fs_table));
+
+        let mut directory_methods = std::collections::HashMap::new();
+        let mut directory_metatable = std::collections::HashMap::new();
+        for name in ["next", "close"] {
+            if let Some(function) = self.builtin(&format!("directory.{}", name)) {
+                directory_methods.insert(name.to_string(), function);
+            }
+        }
+        if let Some(close) = self.builtin("directory.close") {
+            directory_metatable.insert("__gc".to_string(), close);
+        }
+        directory_metatable
+            .insert("__index".to_string(), Value::Table(self.heap.alloc_table(LuaTable::from(directory_methods))));
+        directory_metatable.insert("__name".to_string(), Value::String(crate::stdlib::DIRECTORY_HANDLE.into()));
+        let directory_metatable = self.heap.alloc_table(LuaTable::from(directory_metatable));
+        self.registry_set(crate::stdlib::DIRECTORY_HANDLE, Value::Table(directory_metatable));
+
+        let mut utf8_table = std::collections::HashMap::new();
+        for name in ["char", "codes", "codepoint", "len", "offset"] {
+            if let Some(function) = self.builtin(&format!("utf8.{}", name)) {
+                utf8_table.insert(name.to_string(), function);
+            }
+        }
+        utf8_table.insert("charpattern".to_string(), Value::String(crate::stdlib::UTF8_CHARPATTERN.into()));
+        let utf8_table = self.heap.alloc_table(LuaTable::from(utf8_table));
+        self.globals.insert("utf8".to_string(), Value::Table(utf8_table));
+
+        // `require` finds modules through the package table, and keeps the
+        // ones it loaded, and the standard libraries, in `package.loaded`
+        let loaded = self.heap.alloc_table(LuaTable::new());
+        for name in ["coroutine", "fs", "io", "math", "os", "string", "table", "utf8"] {
+            if let Some(library) = self.globals.get(name) {
+                loaded.set(Value::String(name.into()), library.clone());
+            }
+        }
+        let preload = self.heap.alloc_table(LuaTable::new());
+        let mut searchers = LuaTable::new();
+        for (i, name) in ["package.searcher_preload", "package.searcher_lua"].iter().enumerate() {
+            if let Some(searcher) = self.builtin(name) {
+                searchers.set(Value::Number((i + 1) as f64), searcher);
+            }
+        }
+        let mut package_table = std::collections::HashMap::new();
+        if let Some(searchpath) = self.builtin("package.searchpath") {
+            package_table.insert("searchpath".to_string(), searchpath);
+        }
+        package_table.insert("config".to_string(), Value::String(crate::package::CONFIG.into()));
+        package_table.insert("path".to_string(), Value::String(crate::package::initial_path().into()));
+        package_table.insert("loaded".to_string(), Value::Table(loaded.clone()));
+        package_table.insert("preload".to_string(), Value::Table(preload.clone()));
+        package_table.insert("searchers".to_string(), Value::Table(self.heap.alloc_table(searchers)));
+        let package_table = Value::Table(self.heap.alloc_table(LuaTable::from(package_table)));
+        loaded.set(Value::String("package".into()), package_table.clone());
+        self.registry_set(crate::stdlib::PACKAGE, package_table.clone());
+        self.registry_set(crate::stdlib::LOADED, Value::Table(loaded));
+        self.registry_set(crate::stdlib::PRELOAD, Value::Table(preload));
+        let loading = self.heap.alloc_table(LuaTable::new());
+        self.registry_set(crate::stdlib::LOADING, Value::Table(loading));
+        self.globals.insert("package".to_string(), package_table);
+    }
+
+    pub(crate) fn registry_get(&self, key: &str) -> Value {
+        match &self.registry {
+            Value::Table(registry) => registry.get_field(key),
+            _ => Value::Nil,
+        }
+    }
+
+    pub(crate) fn registry_set(&self, key: &str, value: Value) {
+        if let Value::Table(registry) = &self.registry {
+            registry.set(Value::String(key.into()), value);
+        }
+    }
+
+    /// Runs every pending finalizer, reachable or not, as closing the state
+    /// does.
+    pub(crate) fn close_state(&mut self) {
+        self.heap.finalize_all();
+        self.run_finalizers();
+    }
+
+    /// Whether `os.execute` and `io.popen` may start processes.
+    pub(crate) fn allows_process_spawning(&self) -> bool {
+        self.process_spawning
+    }
+
+    /// The generator behind `math.random`.
+    pub(crate) fn pattern_step_limit(&self) -> usize {
+        self.pattern_step_limit
+    }
+
+    pub(crate) fn random(&mut self) -> &mut Random {
+        &mut self.random
+    }
+
+    /// Looks up a builtin by its registered name, e.g. `"next"` or
+    /// `"math.abs"`.
+    pub(crate) fn builtin(&self, name: &str) -> Option<Value> {
+        self.stdlib.get_function(name)
+    }
+
+    /// Runs a full garbage collection cycle and returns the number of
+    /// objects it freed.
+    pub fn gc_collect(&mut self) -> usize {
+        let freed = self.heap.collect(gc_roots(&self.stack, &self.globals, &self.string_metatable, &self.registry, &self.call_stack, &self.current, &self.resumers));
+        self.run_finalizers();
+        freed
+    }
+
+    /// Performs one incremental step (or a generational collection). Returns
+    /// true if a collection cycle finished.
+    pub fn gc_step(&mut self) -> bool {
+        let finished = self.heap.step(gc_roots(&self.stack, &self.globals, &self.string_metatable, &self.registry, &self.call_stack, &self.current, &self.resumers));
+        self.run_finalizers();
+        finished
+    }
+
+    /// Switches the collector to incremental mode and returns the previous
+    /// mode. Zero leaves a parameter unchanged.
+    pub fn gc_set_incremental(&mut self, pause: usize, step_multiplier: usize, step_size: u32) -> GcMode {
+        self.heap.set_incremental(pause, step_multiplier, step_size)
+    }
+
+    /// Switches the collector to generational mode and returns the previous
+    /// mode. Zero leaves a parameter unchanged.
+    pub fn gc_set_generational(&mut self, minor_multiplier: usize, major_multiplier: usize) -> GcMode {
+        let roots = gc_roots(&self.stack, &self.globals, &self.string_metatable, &self.registry, &self.call_stack, &self.current, &self.resumers);
+        let previous = self.heap.set_generational(roots, minor_multiplier, major_multiplier);
+        self.run_finalizers();
+        previous
+    }
+
+    pub fn gc_mode(&self) -> GcMode {
+        self.heap.mode()
+    }
+
+    pub fn gc_stats(&self) -> &GcStats {
+        self.heap.stats()
+    }
+
+    /// Estimated number of bytes held by live heap objects.
+    pub fn memory_used(&self) -> usize {
+        self.heap.memory_used()
+    }
+
+    pub fn gc_stop(&mut self) {
+        self.heap.stop();
+    }
+
+    pub fn gc_restart(&mut self) {
+        self.heap.restart();
+    }
+
+    pub fn gc_is_running(&self) -> bool {
+        self.heap.is_running()
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.get(name).cloned().unwrap_or(Value::Nil)
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(name.to_string(), value);
+    }
+
+    /// Keeps `value` alive across collections for as long as the returned
+    /// handle lives. Objects the host holds any other way are kept as well,
+    /// but are only found once everything the runtime reaches is marked, so
+    /// a handle is cheaper for values held across many collections.
+    pub fn root(&self, value: Value) -> Root {
+        self.heap.root(value)
+    }
+
+    /// Allocates an empty table managed by this runtime's collector.
+    pub fn create_table(&mut self) -> TableRef {
+        self.heap.alloc_table(LuaTable::new())
+    }
+
+    /// Creates a native function that gets `upvalues` on every call, managed
+    /// by this runtime's collector.
+    pub fn create_function(&mut self, name: &str, function: BoundFunction, upvalues: Vec<Value>) -> NativeRef {
+        self.heap
+            .alloc_native(NativeFunction::with_upvalues(name, NativeKind::Bound(function), upvalues))
+    }
+
+    /// Wraps a host value in a full userdata managed by this runtime's
+    /// collector, marking it for finalization if `metatable` has a `__gc`
+    /// field.
+    pub fn create_userdata<T: Any>(&mut self, payload: T, metatable: Option<TableRef>) -> UserdataRef {
+        let userdata = self.heap.alloc_userdata(Userdata::new(payload).with_metatable(metatable));
+        self.heap.check_finalizer(&Value::Userdata(userdata.clone()));
+        userdata
+    }
+
+    /// The metatable of a table or full userdata.
+    pub fn get_metatable(&self, value: &Value) -> Option<TableRef> {
+        match value {
+            Value::Table(table) => table.borrow().metatable(),
+            Value::Userdata(userdata) => userdata.metatable(),
+            Value::String(_) => match &self.string_metatable {
+                Value::Table(metatable) => Some(metatable.clone()),
+                _ => None,
+            },
+            _ => None,
         }
-        if let Some((id, _)) = self.stdlib.get_function("string.sub") {
-            string_table.insert("sub".to_string(), Value::Function(id));
+    }
+
+    /// Sets the metatable of a table or full userdata, marking it for
+    /// finalization if the metatable has a `__gc` field.
+    pub fn set_metatable(&mut self, object: &Value, metatable: Option<TableRef>) -> Result<(), String> {
+        match object {
+            Value::Table(table) => table.borrow_mut().set_metatable(metatable),
+            Value::Userdata(userdata) => userdata.set_metatable(metatable),
+            other => return Err(format!("cannot set the metatable of a {} value", other.type_name())),
         }
-        if let Some((id, _)) = self.stdlib.get_function("string.upper") {
-            string_table.insert("upper".to_string(), Value::Function(id));
+        self.heap.check_finalizer(object);
+        Ok(())
+    }
+
+    // Looks up `event` in the metatable of `value`
+    fn metamethod(&self, value: &Value, event: &str) -> Value {
+        match self.get_metatable(value) {
+            Some(metatable) => metatable.get_field(event),
+            None => Value::Nil,
         }
-        if let Some((id, _)) = self.stdlib.get_function("string.lower") {
-            string_table.insert("lower".to_string(), Value::Function(id));
+    }
+
+    /// Converts a value to a string as `tostring` does, calling its
+    /// `__tostring` metamethod if it has one.
+    pub(crate) fn tostring(&mut self, value: &Value) -> LuaResult<LuaString> {
+        let handler = self.metamethod(value, "__tostring");
+        if matches!(handler, Value::Nil) {
+            return Ok(match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string().into(),
+            });
         }
-        if let Some((id, _)) = self.stdlib.get_function("string.char") {
-            string_table.insert("char".to_string(), Value::Function(id));
+        match self.call_function(&handler, std::slice::from_ref(value))?.into_iter().next() {
+            Some(Value::String(s)) => Ok(s),
+            Some(n @ Value::Number(_)) => Ok(n.to_string().into()),
+            _ => Err("'__tostring' must return a string".into()),
         }
-        if let Some((id, _)) = self.stdlib.get_function("string.byte") {
-            string_table.insert("byte".to_string(), Value::Function(id));
+    }
+
+    /// Warnings raised while running, such as errors inside finalizers.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    // Calls the `__gc` metamethod of every object the collector found
+    // unreachable. Finalizers that trigger another collection do not run
+    // the new ones recursively; they are picked up by this loop instead.
+    fn run_finalizers(&mut self) {
+        if self.finalizing {
+            return;
+        }
+        self.finalizing = true;
+        while let Some(object) = self.heap.next_finalizer() {
+            let finalizer = self.metamethod(&object, "__gc");
+            if !matches!(finalizer, Value::Native(_) | Value::Closure(_)) {
+                continue;
+            }
+            if let Err(e) = self.call_function(&finalizer, &[object]) {
+                self.warn(format!("error in __gc ({})", e));
+            }
+        }
+        self.finalizing = false;
+    }
+
+    fn maybe_collect(&mut self) {
+        if self.heap.should_step() {
+            self.gc_step();
         }
-        self.globals.insert("string".to_string(), Value::Table(string_table));
     }
 
     pub fn execute(&mut self, code: &str) -> Result<Value, crate::error::LuaError> {
-        match self.execute_internal(code) {
-            Ok(value) => Ok(value),
-            Err(msg) => Err(crate::error::LuaError::runtime_error(&msg)),
+        self.execute_internal(code.as_bytes())
+    }
+
+    /// Runs the script at `path`. Modules next to it can be loaded with
+    /// `require`, wherever the program runs from.
+    pub fn execute_file(&mut self, path: impl AsRef<Path>) -> Result<Value, crate::error::LuaError> {
+        let path = path.as_ref();
+        let source = std::fs::read(path).map_err(|e| {
+            let message = crate::file::error_parts(&e).0;
+            crate::error::LuaError::runtime_error(&format!("cannot open {}: {}", path.display(), message))
+        })?;
+        let directory = path.parent().map_or(String::new(), |directory| directory.to_string_lossy().into_owned());
+        if let Value::Table(package) = self.registry_get(crate::stdlib::PACKAGE) {
+            let templates = crate::package::script_templates(&directory);
+            let path = match package.get_field("path") {
+                Value::String(path) => crate::package::prepend_templates(&templates, &path.to_str_lossy()),
+                _ => templates,
+            };
+            package.set(Value::String("path".into()), Value::String(path.into()));
         }
+        self.execute_internal(&source)
     }
 
-    fn execute_internal(&mut self, source: &str) -> Result<Value, String> {
+    /// Compiles `source` into a function that runs it as a chunk called
+    /// `name`, without running it. The function reads and writes globals
+    /// in `env` when that is Some. Syntax errors start with the chunk name
+    /// and line, as in `[string "x = "]:1: Unexpected end of input`.
+    pub(crate) fn load(&mut self, source: &[u8], name: &str, env: Option<Value>) -> Result<Value, String> {
+        let id = chunk_id(name);
+        let mut lexer = Lexer::new(source);
+        let tokens = lexer
+            .tokenize()
+            .map_err(|msg| format!("{}:{}: {}", id, lexer.line(), msg))?;
+        let mut parser = Parser::new(tokens);
+        let program = parser
+            .parse()
+            .map_err(|msg| format!("{}:{}: {}", id, parser.line(), msg))?;
+        let chunk = Compiler::with_source(&id)
+            .compile(&program)
+            .map_err(|msg| format!("{}: {}", id, msg))?;
+        let proto = FunctionProto {
+            name: name.to_string(),
+            param_count: 0,
+            chunk: Rc::new(chunk),
+            upvalues: Vec::new(),
+        };
+        let closure = LuaClosure::new(Rc::new(proto), env, Vec::new());
+        Ok(Value::Closure(self.heap.alloc_closure(closure)))
+    }
+
+    fn execute_internal(&mut self, source: &[u8]) -> LuaResult<Value> {
         let mut lexer = Lexer::new(source);
         let tokens = lexer.tokenize()?;
 
         let mut parser = Parser::new(tokens);
         let program = parser.parse()?;
 
-        let mut compiler = Compiler::new();
+        // Named after the code itself, as strings passed to `load` are
+        let mut compiler = Compiler::with_source(&chunk_id(&String::from_utf8_lossy(source)));
         let chunk = compiler.compile(&program)?;
 
         self.execute_with_jit(&chunk, &mut self.jit_compiler.clone())
     }
 
-    fn execute_instruction(&mut self, instruction: &crate::bytecode::Instruction) -> Result<(), String> {
+    fn execute_instruction(&mut self, instruction: &crate::bytecode::Instruction) -> LuaResult<()> {
         use crate::bytecode::Instruction;
 
         match instruction {
@@ -108,89 +739,148 @@ impl LuaJitRuntime {
                 self.stack.push(value.clone());
             }
             Instruction::LoadGlobal(name) => {
-                let value = self.globals.get(name).cloned().unwrap_or(Value::Nil);
+                let value = match self.frame_env() {
+                    Some(env) => self.index_value(env, Value::String(name.as_str().into()))?,
+                    None => self.globals.get(name).cloned().unwrap_or(Value::Nil),
+                };
                 self.stack.push(value);
             }
             Instruction::StoreGlobal(name) => {
-                if let Some(value) = self.stack.pop() {
-                    self.globals.insert(name.clone(), value);
-                } else {
-                    return Err("Stack underflow".to_string());
+                let value = match self.stack.pop() {
+                    Some(value) => value,
+                    None => return Err("Stack underflow".into()),
+                };
+                match self.frame_env() {
+                    Some(env) => self.set_index_value(env, Value::String(name.as_str().into()), value)?,
+                    None => {
+                        self.globals.insert(name.clone(), value);
+                    }
                 }
             }
             Instruction::Call(arg_count) => {
-                if self.stack.len() < arg_count + 1 {
-                    return Err("Not enough arguments for call".to_string());
-                }
-
-                let func = self.stack.pop().unwrap();
-                let mut args = Vec::new();
-                for _ in 0..*arg_count {
-                    if let Some(arg) = self.stack.pop() {
-                        args.push(arg);
-                    }
-                }
-                args.reverse();
-
-                match func {
-                    Value::Function(id) => {
-                        if id == 0 {
-                            for (i, arg) in args.iter().enumerate() {
-                                if i > 0 {
-                                    print!("\t");
-                                }
-                                print!("{}", arg);
-                            }
-                            println!();
-                            self.stack.push(Value::Nil);
-                        } else if let Some(builtin_func) = self.stdlib.get_function_by_id(id) {
-                            match builtin_func(&args) {
-                                Ok(result) => self.stack.push(result),
-                                Err(e) => return Err(format!("Function error: {}", e)),
-                            }
-                        } else {
-                            return Err(format!("Unknown function ID: {}", id));
-                        }
-                    }
-                    _ => {
-                        return Err(format!("Cannot call non-function value: {:?}", func));
-                    }
-                }
+                self.call_value(*arg_count, 1)?;
+            }
+            Instruction::CallMulti(arg_count, result_count) => {
+                self.call_value(*arg_count, *result_count)?;
+            }
+            Instruction::CallSpread(arg_count, result_count) => {
+                let func = self.stack.pop().unwrap_or(Value::Nil);
+                let count = self.pop_count()?;
+                self.stack.push(func);
+                self.call_value(arg_count + count, *result_count)?;
+            }
+            Instruction::MakeFunction(index) => {
+                // Functions share the environment of the chunk defining them
+                let frame = match self.call_stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return Err("No call frame for function".into()),
+                };
+                let proto = frame.chunk.functions[*index].clone();
+                let upvalues = proto
+                    .upvalues
+                    .iter()
+                    .map(|capture| match *capture {
+                        Capture::Local(slot) => Ok(frame.capture(slot)),
+                        Capture::Upvalue(index) => frame.upvalue(index),
+                    })
+                    .collect::<LuaResult<Vec<_>>>()?;
+                let closure = LuaClosure::new(proto, frame.env.clone(), upvalues);
+                let closure = self.heap.alloc_closure(closure);
+                self.stack.push(Value::Closure(closure));
             }
             Instruction::GetIndex => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for index access".to_string());
+                    return Err("Not enough operands for index access".into());
                 }
                 let key = self.stack.pop().unwrap();
                 let table = self.stack.pop().unwrap();
-
-                match table {
-                    Value::Table(ref t) => {
-                        let key_str = key.to_string();
-                        let value = t.get(&key_str).cloned().unwrap_or(Value::Nil);
-                        self.stack.push(value);
-                    }
-                    _ => {
-                        let table_name = table.to_string();
-                        let field_name = key.to_string();
-                        let full_name = format!("{}.{}", table_name, field_name);
-
-                        if let Some((id, _)) = self.stdlib.get_function(&full_name) {
-                            self.stack.push(Value::Function(id));
-                        } else {
-                            self.stack.push(Value::Nil);
-                        }
-                    }
+                let value = self.index_value(table, key)?;
+                self.stack.push(value);
+            }
+            Instruction::GetMethod(arg_count) => {
+                let key = self.stack.pop();
+                let receiver = self.stack.len().checked_sub(arg_count + 1)
+                    .and_then(|index| self.stack.get(index))
+                    .map(|value| Value::clone(&value));
+                let method = match (key, receiver) {
+                    (Some(key), Some(receiver)) => self.index_value(receiver, key)?,
+                    _ => return Err("Not enough operands for method call".into()),
+                };
+                self.stack.push(method);
+            }
+            Instruction::GetMethodSpread(arg_count) => {
+                let key = self.stack.pop();
+                let count = match self.stack.last().as_deref() {
+                    Some(Value::Number(count)) => *count as usize,
+                    _ => 0,
+                };
+                // The receiver is below the arguments and their count
+                let receiver = self.stack.len().checked_sub(arg_count + count + 2)
+                    .and_then(|index| self.stack.get(index))
+                    .map(|value| Value::clone(&value));
+                let method = match (key, receiver) {
+                    (Some(key), Some(receiver)) => self.index_value(receiver, key)?,
+                    _ => return Err("Not enough operands for method call".into()),
+                };
+                self.stack.push(method);
+            }
+            Instruction::SetList(first) => {
+                let count = self.pop_count()?;
+                let values: Vec<Value> = self.stack.drain(self.stack.len() - count..).collect();
+                let table = match self.stack.pop() {
+                    Some(table) => table,
+                    None => return Err("Stack underflow".into()),
+                };
+                for (index, value) in (*first..).zip(values) {
+                    self.set_index_value(table.clone(), Value::Number(index as f64), value)?;
                 }
             }
+            Instruction::SetIndex => {
+                if self.stack.len() < 3 {
+                    return Err("Not enough operands for index assignment".into());
+                }
+                let value = self.stack.pop().unwrap();
+                let key = self.stack.pop().unwrap();
+                let table = self.stack.pop().unwrap();
+
+                self.set_index_value(table, key, value)?;
+            }
+            Instruction::NewTable => {
+                let table = self.heap.alloc_table(LuaTable::new());
+                self.stack.push(Value::Table(table));
+                self.maybe_collect();
+            }
             Instruction::Pop => {
                 self.stack.pop();
             }
+            Instruction::Dup => {
+                match self.stack.last() {
+                    Some(value) => self.stack.push(value.clone()),
+                    None => return Err("Stack underflow".into()),
+                }
+            }
             Instruction::Return => {
+                let value = self.stack.pop().unwrap_or(Value::Nil);
+                self.return_from_frame(vec![value]);
+            }
+            Instruction::ReturnMulti(count) => {
+                let start = self.stack.len().saturating_sub(*count);
+                let values = self.stack.drain(start..).collect();
+                self.return_from_frame(values);
+            }
+            Instruction::ReturnSpread(count) => {
+                let count = count + self.pop_count()?;
+                let start = self.stack.len().saturating_sub(count);
+                let values = self.stack.drain(start..).collect();
+                self.return_from_frame(values);
             }
             Instruction::Add => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::number(a + b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for addition".to_string());
+                    return Err("Not enough operands for addition".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -199,12 +889,16 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Number(a_num + b_num));
                     }
-                    _ => return Err("Cannot add non-numeric values".to_string()),
+                    _ => return Err("Cannot add non-numeric values".into()),
                 }
             }
             Instruction::Sub => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::number(a - b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for subtraction".to_string());
+                    return Err("Not enough operands for subtraction".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -213,12 +907,16 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Number(a_num - b_num));
                     }
-                    _ => return Err("Cannot subtract non-numeric values".to_string()),
+                    _ => return Err("Cannot subtract non-numeric values".into()),
                 }
             }
             Instruction::Mul => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::number(a * b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for multiplication".to_string());
+                    return Err("Not enough operands for multiplication".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -227,12 +925,12 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Number(a_num * b_num));
                     }
-                    _ => return Err("Cannot multiply non-numeric values".to_string()),
+                    _ => return Err("Cannot multiply non-numeric values".into()),
                 }
             }
             Instruction::Div => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for division".to_string());
+                    return Err("Not enough operands for division".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -240,41 +938,69 @@ impl LuaJitRuntime {
                 match (a.to_number(), b.to_number()) {
                     (Some(a_num), Some(b_num)) => {
                         if b_num == 0.0 {
-                            return Err("Division by zero".to_string());
+                            return Err("Division by zero".into());
                         }
                         self.stack.push(Value::Number(a_num / b_num));
                     }
-                    _ => return Err("Cannot divide non-numeric values".to_string()),
+                    _ => return Err("Cannot divide non-numeric values".into()),
                 }
             }
             Instruction::LoadLocal(index) => {
                 if let Some(frame) = self.call_stack.last() {
-                    if *index < frame.locals.len() {
-                        self.stack.push(frame.locals[*index].clone());
+                    if let Some(upvalue) = frame.open_upvalue(*index) {
+                        self.stack.push(upvalue.get());
+                    } else if *index < frame.locals.len() {
+                        self.stack.push_packed(frame.locals[*index].clone());
                     } else {
                         self.stack.push(Value::Nil);
                     }
                 } else {
-                    return Err("No call frame for local variable".to_string());
+                    return Err("No call frame for local variable".into());
                 }
             }
             Instruction::StoreLocal(index) => {
-                if let Some(value) = self.stack.pop() {
+                if let Some(value) = self.stack.pop_packed() {
                     if let Some(frame) = self.call_stack.last_mut() {
-                        while frame.locals.len() <= *index {
-                            frame.locals.push(Value::Nil);
+                        if let Some(upvalue) = frame.open_upvalue(*index) {
+                            upvalue.set(value.into_value());
+                        } else {
+                            if frame.locals.len() <= *index {
+                                frame.locals.resize(*index + 1, PackedValue::NIL);
+                            }
+                            frame.locals[*index] = value;
                         }
-                        frame.locals[*index] = value;
                     } else {
-                        return Err("No call frame for local variable".to_string());
+                        return Err("No call frame for local variable".into());
                     }
                 } else {
-                    return Err("Stack underflow".to_string());
+                    return Err("Stack underflow".into());
+                }
+            }
+            Instruction::GetUpvalue(index) => {
+                let upvalue = match self.call_stack.last() {
+                    Some(frame) => frame.upvalue(*index)?,
+                    None => return Err("No call frame for upvalue".into()),
+                };
+                self.stack.push(upvalue.get());
+            }
+            Instruction::SetUpvalue(index) => {
+                let upvalue = match self.call_stack.last() {
+                    Some(frame) => frame.upvalue(*index)?,
+                    None => return Err("No call frame for upvalue".into()),
+                };
+                match self.stack.pop() {
+                    Some(value) => upvalue.set(value),
+                    None => return Err("Stack underflow".into()),
+                }
+            }
+            Instruction::CloseUpvalues(first) => {
+                if let Some(frame) = self.call_stack.last_mut() {
+                    frame.close_upvalues(*first);
                 }
             }
             Instruction::Neg => {
                 if self.stack.is_empty() {
-                    return Err("Not enough operands for negation".to_string());
+                    return Err("Not enough operands for negation".into());
                 }
                 let operand = self.stack.pop().unwrap();
 
@@ -282,12 +1008,12 @@ impl LuaJitRuntime {
                     Some(n) => {
                         self.stack.push(Value::Number(-n));
                     }
-                    None => return Err("Cannot negate non-numeric value".to_string()),
+                    None => return Err("Cannot negate non-numeric value".into()),
                 }
             }
             Instruction::Mod => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for modulo".to_string());
+                    return Err("Not enough operands for modulo".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -295,16 +1021,16 @@ impl LuaJitRuntime {
                 match (a.to_number(), b.to_number()) {
                     (Some(a_num), Some(b_num)) => {
                         if b_num == 0.0 {
-                            return Err("Modulo by zero".to_string());
+                            return Err("Modulo by zero".into());
                         }
                         self.stack.push(Value::Number(a_num % b_num));
                     }
-                    _ => return Err("Cannot take modulo of non-numeric values".to_string()),
+                    _ => return Err("Cannot take modulo of non-numeric values".into()),
                 }
             }
             Instruction::Pow => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for power".to_string());
+                    return Err("Not enough operands for power".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -313,12 +1039,12 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Number(a_num.powf(b_num)));
                     }
-                    _ => return Err("Cannot raise non-numeric values to power".to_string()),
+                    _ => return Err("Cannot raise non-numeric values to power".into()),
                 }
             }
             Instruction::Equal => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for equality".to_string());
+                    return Err("Not enough operands for equality".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -326,15 +1052,19 @@ impl LuaJitRuntime {
             }
             Instruction::NotEqual => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for inequality".to_string());
+                    return Err("Not enough operands for inequality".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
                 self.stack.push(Value::Boolean(a != b));
             }
             Instruction::Less => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::boolean(a < b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for less than".to_string());
+                    return Err("Not enough operands for less than".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -343,12 +1073,16 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Boolean(a_num < b_num));
                     }
-                    _ => return Err("Cannot compare non-numeric values".to_string()),
+                    _ => return Err("Cannot compare non-numeric values".into()),
                 }
             }
             Instruction::LessEqual => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::boolean(a <= b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for less than or equal".to_string());
+                    return Err("Not enough operands for less than or equal".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -357,12 +1091,16 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Boolean(a_num <= b_num));
                     }
-                    _ => return Err("Cannot compare non-numeric values".to_string()),
+                    _ => return Err("Cannot compare non-numeric values".into()),
                 }
             }
             Instruction::Greater => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::boolean(a > b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for greater than".to_string());
+                    return Err("Not enough operands for greater than".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -371,12 +1109,16 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Boolean(a_num > b_num));
                     }
-                    _ => return Err("Cannot compare non-numeric values".to_string()),
+                    _ => return Err("Cannot compare non-numeric values".into()),
                 }
             }
             Instruction::GreaterEqual => {
+                if let Some((a, b)) = self.stack.pop_numbers() {
+                    self.stack.push_packed(PackedValue::boolean(a >= b));
+                    return Ok(());
+                }
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for greater than or equal".to_string());
+                    return Err("Not enough operands for greater than or equal".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -385,12 +1127,12 @@ impl LuaJitRuntime {
                     (Some(a_num), Some(b_num)) => {
                         self.stack.push(Value::Boolean(a_num >= b_num));
                     }
-                    _ => return Err("Cannot compare non-numeric values".to_string()),
+                    _ => return Err("Cannot compare non-numeric values".into()),
                 }
             }
             Instruction::And => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for logical and".to_string());
+                    return Err("Not enough operands for logical and".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -398,7 +1140,7 @@ impl LuaJitRuntime {
             }
             Instruction::Or => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for logical or".to_string());
+                    return Err("Not enough operands for logical or".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
@@ -406,20 +1148,32 @@ impl LuaJitRuntime {
             }
             Instruction::Not => {
                 if self.stack.is_empty() {
-                    return Err("Not enough operands for logical not".to_string());
+                    return Err("Not enough operands for logical not".into());
                 }
                 let operand = self.stack.pop().unwrap();
                 self.stack.push(Value::Boolean(!operand.is_truthy()));
             }
             Instruction::Concat => {
                 if self.stack.len() < 2 {
-                    return Err("Not enough operands for concatenation".to_string());
+                    return Err("Not enough operands for concatenation".into());
                 }
                 let b = self.stack.pop().unwrap();
                 let a = self.stack.pop().unwrap();
 
-                let result = format!("{}{}", a.to_string(), b.to_string());
-                self.stack.push(Value::String(result));
+                // Build the result in a single buffer; short results that
+                // already exist are shared by the interner
+                let capacity = match (&a, &b) {
+                    (Value::String(x), Value::String(y)) => x.len() + y.len(),
+                    _ => 0,
+                };
+                let mut result = Vec::with_capacity(capacity);
+                for operand in [&a, &b] {
+                    match operand {
+                        Value::String(s) => result.extend_from_slice(s),
+                        other => result.extend_from_slice(other.to_string().as_bytes()),
+                    }
+                }
+                self.stack.push(Value::String(result.into()));
             }
             Instruction::Jump(target) => {
                 if let Some(frame) = self.call_stack.last_mut() {
@@ -427,88 +1181,673 @@ impl LuaJitRuntime {
                 }
             }
             Instruction::JumpIfFalse(target) => {
-                if let Some(condition) = self.stack.pop() {
-                    if !condition.is_truthy() {
-                        if let Some(frame) = self.call_stack.last_mut() {
+                if let Some(condition) = self.stack.pop_packed() {
+                    if let Some(frame) = self.call_stack.last_mut() {
+                        if !condition.is_truthy() {
                             frame.pc = *target;
                         }
                     }
                 } else {
-                    return Err("Stack underflow for jump condition".to_string());
+                    return Err("Stack underflow for jump condition".into());
                 }
             }
             Instruction::JumpIfTrue(target) => {
-                if let Some(condition) = self.stack.pop() {
-                    if condition.is_truthy() {
-                        if let Some(frame) = self.call_stack.last_mut() {
+                if let Some(condition) = self.stack.pop_packed() {
+                    if let Some(frame) = self.call_stack.last_mut() {
+                        if condition.is_truthy() {
                             frame.pc = *target;
                         }
                     }
                 } else {
-                    return Err("Stack underflow for jump condition".to_string());
+                    return Err("Stack underflow for jump condition".into());
                 }
             }
             _ => {
-                return Err(format!("Unimplemented instruction: {:?}", instruction));
+                return Err(format!("Unimplemented instruction: {:?}", instruction).into());
             }
         }
 
         Ok(())
     }
 
-    pub fn print_stats(&self) {
-        self.jit_compiler.print_stats();
+    // The environment of the running function, None when it uses the
+    // globals
+    fn frame_env(&self) -> Option<Value> {
+        self.call_stack.last().and_then(|frame| frame.env.clone())
     }
-}
 
-impl JitEnabled for LuaJitRuntime {
-    fn execute_with_jit(&mut self, chunk: &Chunk, _jit: &mut JitCompiler) -> Result<Value, String> {
-        let frame = CallFrame {
-            chunk: chunk.clone(),
+    // Reads `object[key]`, falling back to the `__index` metamethod when a
+    // table has no such field or `object` is a userdata
+    pub(crate) fn index_value(&mut self, object: Value, key: Value) -> LuaResult<Value> {
+        let mut object = object;
+        for _ in 0..MAX_META_CHAIN {
+            if let Value::Table(table) = &object {
+                let value = table.get(&key);
+                if !matches!(value, Value::Nil) {
+                    return Ok(value);
+                }
+            }
+            let handler = match self.metamethod(&object, "__index") {
+                Value::Nil if matches!(object, Value::Table(_)) => return Ok(Value::Nil),
+                Value::Nil => return Err(format!("attempt to index a {} value", object.type_name()).into()),
+                handler => handler,
+            };
+            if matches!(handler, Value::Native(_) | Value::Closure(_)) {
+                let results = self.call_function(&handler, &[object, key])?;
+                return Ok(results.into_iter().next().unwrap_or(Value::Nil));
+            }
+            object = handler;
+        }
+        Err("'__index' chain too long; possible loop".into())
+    }
+
+    // Performs `object[key] = value`, going through the `__newindex`
+    // metamethod when a table has no such field or `object` is a userdata
+    fn set_index_value(&mut self, object: Value, key: Value, value: Value) -> LuaResult<()> {
+        let mut object = object;
+        for _ in 0..MAX_META_CHAIN {
+            let handler = self.metamethod(&object, "__newindex");
+            if let Value::Table(table) = &object {
+                if matches!(handler, Value::Nil) || !matches!(table.get(&key), Value::Nil) {
+                    crate::table::check_key(&key)?;
+                    table.set(key, value);
+                    return Ok(());
+                }
+            }
+            match handler {
+                Value::Nil => return Err(format!("attempt to index a {} value", object.type_name()).into()),
+                Value::Native(_) | Value::Closure(_) => {
+                    self.call_function(&handler, &[object, key, value])?;
+                    return Ok(());
+                }
+                handler => object = handler,
+            }
+        }
+        Err("'__newindex' chain too long; possible loop".into())
+    }
+
+    // Pushes `values` as `count` results, padded with nils or cut short, or
+    // all of them and how many there are for MULTIPLE
+    fn push_results(&mut self, mut values: Vec<Value>, count: usize) {
+        if count == MULTIPLE {
+            let count = values.len();
+            self.stack.extend(values);
+            self.stack.push(Value::Number(count as f64));
+        } else {
+            values.resize(count, Value::Nil);
+            self.stack.extend(values);
+        }
+    }
+
+    // Takes the count a MULTIPLE call left on top of its results
+    fn pop_count(&mut self) -> LuaResult<usize> {
+        match self.stack.pop() {
+            Some(Value::Number(count)) if count as usize <= self.stack.len() => Ok(count as usize),
+            _ => Err("Stack underflow".into()),
+        }
+    }
+
+    // Takes the results a MULTIPLE call left on the stack
+    fn pop_results(&mut self) -> LuaResult<Vec<Value>> {
+        let count = self.pop_count()?;
+        Ok(self.stack.drain(self.stack.len() - count..).collect())
+    }
+
+    // Calls the function on top of the stack with the `arg_count` values
+    // below it. Lua functions get a new frame that the dispatch loop picks
+    // up; native functions run to completion here. Either way exactly
+    // `result_count` values end up on the stack, or all of them and their
+    // count for MULTIPLE, unless the call yields.
+    fn call_value(&mut self, arg_count: usize, result_count: usize) -> LuaResult<()> {
+        if self.stack.len() < arg_count + 1 {
+            return Err("Not enough arguments for call".into());
+        }
+
+        match self.stack.pop().unwrap() {
+            Value::Closure(closure) => self.enter_closure(&closure, arg_count, result_count, Entry::Lua),
+            func @ Value::Native(_) => {
+                let results = self.call_native(func, arg_count, false)?;
+                if let Some(transfer) = self.transfer.take() {
+                    return self.perform_transfer(transfer, result_count);
+                }
+                self.push_results(results, result_count);
+                Ok(())
+            }
+            other => {
+                // Callable objects get themselves as the first argument
+                let handler = self.metamethod(&other, "__call");
+                if matches!(handler, Value::Nil) {
+                    return Err(format!("attempt to call a {} value", other.type_name()).into());
+                }
+                self.stack.insert(self.stack.len() - arg_count, other);
+                self.stack.push(handler);
+                self.call_value(arg_count + 1, result_count)
+            }
+        }
+    }
+
+    fn perform_transfer(&mut self, transfer: Transfer, result_count: usize) -> LuaResult<()> {
+        match transfer {
+            Transfer::Yield(values) => {
+                if let Some(coroutine) = self.current_coroutine() {
+                    coroutine.borrow_mut().yield_results = result_count;
+                }
+                self.yielded = Some(values);
+                Ok(())
+            }
+            Transfer::ProtectedCall(mut args, handler) => {
+                let func = args.remove(0);
+                if let Value::Closure(closure) = &func {
+                    let arg_count = args.len();
+                    self.stack.extend(args);
+                    return self.enter_closure(closure, arg_count, result_count, Entry::Protected(handler));
+                }
+                let results = self.call_protected(func, &args, handler);
+                self.push_results(results, result_count);
+                Ok(())
+            }
+        }
+    }
+
+    // Runs a protected call to completion, returning true and the results
+    // or false and the error, as the message handler made it if there is one
+    fn call_protected(&mut self, func: Value, args: &[Value], handler: Option<Value>) -> Vec<Value> {
+        match self.call_function(&func, args) {
+            Ok(mut results) => {
+                results.insert(0, Value::Boolean(true));
+                results
+            }
+            Err(e) => {
+                let e = match handler {
+                    Some(handler) if !matches!(e, LuaError::Thrown { handled: true, .. }) => {
+                        self.apply_handler(&handler, e)
+                    }
+                    _ => e,
+                };
+                vec![Value::Boolean(false), e.into_value()]
+            }
+        }
+    }
+
+    // Passes an error just raised to the message handler of the innermost
+    // protected call, while the frames that raised it are still there to
+    // be inspected. Errors already handled, or on their way to a `pcall`
+    // without a handler, are left as they are.
+    fn handle_error(&mut self, error: LuaError) -> LuaError {
+        if matches!(error, LuaError::Thrown { handled: true, .. }) {
+            return error;
+        }
+        let index = match self.call_stack.iter().rposition(CallFrame::is_protected) {
+            Some(index) => index,
+            None => return error,
+        };
+        // Taken out while it runs, so that its own errors are not handled
+        // again
+        let handler = match &mut self.call_stack[index].entry {
+            Entry::Protected(handler) => match handler.take() {
+                Some(handler) => handler,
+                None => return error,
+            },
+            _ => unreachable!(),
+        };
+        let error = self.apply_handler(&handler, error);
+        self.call_stack[index].entry = Entry::Protected(Some(handler));
+        error
+    }
+
+    fn apply_handler(&mut self, handler: &Value, error: LuaError) -> LuaError {
+        let value = match self.call_function(handler, &[error.into_value()]) {
+            Ok(results) => results.into_iter().next().unwrap_or(Value::Nil),
+            Err(_) => Value::String("error in error handling".into()),
+        };
+        LuaError::Thrown { value, handled: true }
+    }
+
+    fn enter_closure(
+        &mut self,
+        closure: &ClosureRef,
+        arg_count: usize,
+        results: usize,
+        entry: Entry,
+    ) -> LuaResult<()> {
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err("stack overflow".into());
+        }
+
+        let proto = closure.proto();
+        let base = self.stack.len() - arg_count;
+        let mut locals: Vec<PackedValue> = self.stack.drain_packed(base..).collect();
+        locals.resize(proto.param_count, PackedValue::NIL);
+
+        self.call_stack.push(CallFrame {
+            chunk: proto.chunk.clone(),
             pc: 0,
-            locals: Vec::new(),
+            locals,
+            base,
+            results,
+            entry,
+            env: closure.env(),
+            function: Value::Closure(closure.clone()),
+            open_upvalues: Vec::new(),
+        });
+        Ok(())
+    }
+
+    fn return_from_frame(&mut self, mut values: Vec<Value>) {
+        if let Some(frame) = self.call_stack.pop() {
+            self.stack.truncate(frame.base);
+            if frame.is_protected() {
+                values.insert(0, Value::Boolean(true));
+            }
+            self.push_results(values, frame.results);
+        }
+    }
+
+    // Unwinds the frames above `depth` down to the innermost protected call,
+    // which then returns false and the error. Errors no protected call
+    // above `depth` catches are passed on.
+    fn unwind(&mut self, depth: usize, error: LuaError) -> LuaResult<()> {
+        let index = match self.call_stack[depth..].iter().rposition(CallFrame::is_protected) {
+            Some(index) => depth + index,
+            None => return Err(error),
         };
+        let frame = self.call_stack.drain(index..).next().unwrap();
+        self.stack.truncate(frame.base);
+        self.push_results(vec![Value::Boolean(false), error.into_value()], frame.results);
+        Ok(())
+    }
 
-        self.call_stack.push(frame);
-        self.stack.clear();
+    // Runs a native function whose `arg_count` arguments are on top of the
+    // stack and returns all of its results. `native_caller` tells whether
+    // another native function rather than a Lua frame is calling it.
+    fn call_native(&mut self, func: Value, arg_count: usize, native_caller: bool) -> LuaResult<Vec<Value>> {
+        // Arguments stay on the stack until the call returns so the
+        // collector still sees them as roots
+        let base = self.stack.len() - arg_count;
+        let args = self.stack.values_from(base);
 
-        loop {
-            let (instruction, should_increment_pc) = {
-                let frame = match self.call_stack.last() {
-                    Some(frame) => frame,
-                    None => break,
-                };
+        let native = match func {
+            Value::Native(native) => native,
+            _ => return Err(format!("Cannot call non-function value: {:?}", func).into()),
+        };
+        let caller = std::mem::replace(&mut self.native_caller, native_caller);
+        let result = match native.kind() {
+            NativeKind::Builtin(builtin_func) => builtin_func(&args).map(|result| vec![result]),
+            NativeKind::Runtime(runtime_func) => runtime_func(self, &args),
+            NativeKind::Bound(bound_func) => {
+                // The function stays reachable too, and with it its upvalues
+                let upvalues = native.upvalues();
+                self.stack.push(Value::Native(native));
+                bound_func(self, &upvalues, &args)
+            }
+        };
 
-                if frame.pc >= frame.chunk.instructions.len() {
-                    self.call_stack.pop();
-                    if self.call_stack.is_empty() {
-                        break;
+        self.native_caller = caller;
+        self.stack.truncate(base);
+        result
+    }
+
+    /// Calls any function value from native code, running Lua functions to
+    /// completion before returning their results.
+    pub(crate) fn call_function(&mut self, func: &Value, args: &[Value]) -> LuaResult<Vec<Value>> {
+        let base = self.stack.len();
+        self.stack.extend(args.iter().cloned());
+
+        let closure = match func {
+            Value::Closure(closure) => closure,
+            Value::Native(_) => {
+                let results = self.call_native(func.clone(), args.len(), true)?;
+                return match self.transfer.take() {
+                    None => Ok(results),
+                    Some(Transfer::Yield(_)) => Err("attempt to yield across a C-call boundary".into()),
+                    Some(Transfer::ProtectedCall(mut args, handler)) => {
+                        let func = args.remove(0);
+                        Ok(self.call_protected(func, &args, handler))
                     }
-                    continue;
+                };
+            }
+            other => {
+                self.stack.truncate(base);
+                let handler = self.metamethod(other, "__call");
+                if matches!(handler, Value::Nil) {
+                    return Err(format!("attempt to call a {} value", other.type_name()).into());
                 }
+                let args: Vec<Value> = std::iter::once(other.clone()).chain(args.iter().cloned()).collect();
+                return self.call_function(&handler, &args);
+            }
+        };
 
-                let instruction = frame.chunk.instructions[frame.pc].clone();
-                let should_increment = !matches!(instruction,
-                    crate::bytecode::Instruction::Jump(_) |
-                    crate::bytecode::Instruction::JumpIfFalse(_) |
-                    crate::bytecode::Instruction::JumpIfTrue(_)
-                );
+        let depth = self.call_stack.len();
+        self.native_depth += 1;
+        let result = self
+            .enter_closure(closure, args.len(), MULTIPLE, Entry::Native)
+            .and_then(|_| self.run(depth));
+        self.native_depth -= 1;
+        if let Err(e) = result {
+            self.call_stack.truncate(depth);
+            self.stack.truncate(base);
+            return Err(e);
+        }
+        self.pop_results()
+    }
 
-                (instruction, should_increment)
+    // Executes instructions until the call stack is back down to `depth` or
+    // the running coroutine yields
+    fn run(&mut self, depth: usize) -> LuaResult<()> {
+        while self.call_stack.len() > depth {
+            // Holding the chunk rather than a copy of the instruction keeps
+            // constants and names from being cloned on every dispatch
+            let (chunk, pc) = {
+                let frame = self.call_stack.last_mut().unwrap();
+                frame.pc += 1;
+                (frame.chunk.clone(), frame.pc - 1)
             };
+            let result = match chunk.instructions.get(pc) {
+                Some(instruction) => self.execute_instruction(instruction),
+                None => {
+                    self.return_from_frame(Vec::new());
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                let e = self.handle_error(e);
+                self.unwind(depth, e)?;
+            }
+            if self.yielded.is_some() {
+                break;
+            }
+        }
+        Ok(())
+    }
 
-            match self.execute_instruction(&instruction) {
-                Ok(_) => {
-                    if should_increment_pc {
-                        if let Some(frame) = self.call_stack.last_mut() {
-                            frame.pc += 1;
-                        }
-                    }
+    fn current_coroutine(&self) -> Option<&CoroutineRef> {
+        match &self.current {
+            Value::Thread(coroutine) => Some(coroutine),
+            _ => None,
+        }
+    }
+
+    /// The running coroutine, or None on the main thread.
+    pub fn running_coroutine(&self) -> Option<CoroutineRef> {
+        self.current_coroutine().cloned()
+    }
+
+    /// The thread that is running, the main thread included.
+    pub fn running_thread(&self) -> &CoroutineRef {
+        self.current_coroutine().unwrap_or(&self.main_thread)
+    }
+
+    /// Creates a suspended coroutine that will run `function`.
+    pub fn create_coroutine(&mut self, function: Value) -> CoroutineRef {
+        let coroutine = self.heap.alloc_coroutine(Coroutine::new(function));
+        self.maybe_collect();
+        coroutine
+    }
+
+    /// Runs `coroutine` until it yields or finishes. `args` are passed to its
+    /// function on the first resume and returned from the pending `yield`
+    /// after that. Returns the values it yielded or returned, or the error it
+    /// died with.
+    pub fn resume(&mut self, coroutine: &CoroutineRef, args: Vec<Value>) -> Result<Vec<Value>, String> {
+        self.resume_thread(coroutine, args).map_err(|e| e.message().to_string())
+    }
+
+    /// `resume` keeping the error value the coroutine raised.
+    pub(crate) fn resume_thread(&mut self, coroutine: &CoroutineRef, args: Vec<Value>) -> LuaResult<Vec<Value>> {
+        let (stack, call_stack, function, started, yield_results) = {
+            let mut state = coroutine.borrow_mut();
+            match state.status {
+                CoroutineStatus::Suspended => {}
+                CoroutineStatus::Dead => return Err("cannot resume dead coroutine".into()),
+                _ => return Err("cannot resume non-suspended coroutine".into()),
+            }
+            state.status = CoroutineStatus::Running;
+            state.native_depth = self.native_depth;
+            (
+                std::mem::take(&mut state.stack),
+                std::mem::take(&mut state.call_stack),
+                state.function.clone(),
+                std::mem::replace(&mut state.started, true),
+                state.yield_results,
+            )
+        };
+
+        // Switch to the coroutine's stacks, keeping ours reachable
+        self.running_thread().borrow_mut().status = CoroutineStatus::Normal;
+        let resumer = Resumer {
+            coroutine: std::mem::replace(&mut self.current, Value::Thread(coroutine.clone())),
+            stack: std::mem::replace(&mut self.stack, stack),
+            call_stack: std::mem::replace(&mut self.call_stack, call_stack),
+        };
+        self.resumers.push(resumer);
+
+        let result = if started {
+            self.push_results(args, yield_results);
+            self.run(0)
+        } else {
+            let arg_count = args.len();
+            self.stack.extend(args);
+            self.stack.push(function);
+            self.call_value(arg_count, MULTIPLE).and_then(|_| self.run(0))
+        };
+
+        let resumer = self.resumers.pop().unwrap();
+        self.current = resumer.coroutine;
+        self.running_thread().borrow_mut().status = CoroutineStatus::Running;
+        let mut stack = std::mem::replace(&mut self.stack, resumer.stack);
+        let call_stack = std::mem::replace(&mut self.call_stack, resumer.call_stack);
+
+        let mut state = coroutine.borrow_mut();
+        match (result, self.yielded.take()) {
+            (Ok(()), Some(values)) => {
+                state.stack = stack;
+                state.call_stack = call_stack;
+                state.status = CoroutineStatus::Suspended;
+                Ok(values)
+            }
+            (Ok(()), None) => {
+                state.status = CoroutineStatus::Dead;
+                // The function's results, under the count of them
+                stack.pop();
+                Ok(stack.values_from(0))
+            }
+            (Err(e), _) => {
+                state.status = CoroutineStatus::Dead;
+                let value = e.into_value();
+                state.error = Some(value.clone());
+                Err(LuaError::thrown(value))
+            }
+        }
+    }
+
+    /// Suspends the running coroutine with `values` once the native function
+    /// calling this returns.
+    pub(crate) fn yield_values(&mut self, values: Vec<Value>) -> Result<(), String> {
+        let coroutine = match self.current_coroutine() {
+            Some(coroutine) => coroutine,
+            None => return Err("attempt to yield from outside a coroutine".into()),
+        };
+        if coroutine.borrow().native_depth != self.native_depth {
+            return Err("attempt to yield across a C-call boundary".into());
+        }
+        self.transfer = Some(Transfer::Yield(values));
+        Ok(())
+    }
+
+    /// Whether the code calling this could yield.
+    pub(crate) fn is_yieldable(&self) -> bool {
+        match self.current_coroutine() {
+            Some(coroutine) => coroutine.borrow().native_depth == self.native_depth,
+            None => false,
+        }
+    }
+
+    /// Calls `args[0]` with the remaining arguments in protected mode once
+    /// the native function calling this returns. A Lua function runs in a
+    /// frame of the dispatch loop, so it can yield.
+    /// `handler` is the message handler of `xpcall`, which gets each error
+    /// before the frames that raised it are unwound.
+    pub(crate) fn protected_call(&mut self, args: Vec<Value>, handler: Option<Value>) {
+        self.transfer = Some(Transfer::ProtectedCall(args, handler));
+    }
+
+    /// The position of the function at `level` of the call stack as
+    /// `chunk:line: `, counting the running native function as level 1.
+    /// Empty when that function is native or the level is out of range.
+    pub(crate) fn location(&self, level: usize) -> String {
+        let mut frames = self.call_stack.iter().rev();
+        let mut native = self.native_caller;
+        for _ in 1..level {
+            if native {
+                native = false;
+            } else {
+                match frames.next() {
+                    Some(frame) => native = !matches!(frame.entry, Entry::Lua),
+                    None => return String::new(),
                 }
-                Err(e) => return Err(e),
             }
         }
+        match frames.next() {
+            Some(frame) if !native => frame.position(),
+            _ => String::new(),
+        }
+    }
+
+    pub fn print_stats(&self) {
+        self.jit_compiler.print_stats();
+        self.heap.print_stats();
+    }
+}
+
+impl Default for LuaJitRuntime {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Longest chunk name shown in messages, counting the terminating zero of
+// the reference implementation's buffer
+const CHUNK_ID_SIZE: usize = 60;
+
+/// How the chunk called `name` is shown in messages: `=stdin` as `stdin`,
+/// `@main.lua` as `main.lua` and a chunk named after its source as the
+/// start of the source, as in `[string "x = 1..."]`.
+pub(crate) fn chunk_id(name: &str) -> String {
+    if let Some(name) = name.strip_prefix('=') {
+        return prefix(name, CHUNK_ID_SIZE - 1).to_string();
+    }
+    if let Some(filename) = name.strip_prefix('@') {
+        if filename.len() < CHUNK_ID_SIZE {
+            return filename.to_string();
+        }
+        // Keep the end of long paths, where the file name is
+        let mut start = filename.len() - (CHUNK_ID_SIZE - 4);
+        while !filename.is_char_boundary(start) {
+            start += 1;
+        }
+        return format!("...{}", &filename[start..]);
+    }
+    let room = CHUNK_ID_SIZE - "[string \"...\"]".len() - 1;
+    let first_line = name.split('\n').next().unwrap_or_default();
+    if first_line.len() == name.len() && name.len() < room {
+        format!("[string \"{}\"]", name)
+    } else {
+        format!("[string \"{}...\"]", prefix(first_line, room))
+    }
+}
+
+// The longest start of `s` that fits in `max` bytes
+fn prefix(s: &str, max: usize) -> &str {
+    let mut end = max.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// Everything the running thread can reach directly, plus the suspended
+// stacks of the threads waiting for it to yield
+fn gc_roots<'a>(
+    stack: &'a ValueStack,
+    globals: &'a HashMap<String, Value>,
+    string_metatable: &'a Value,
+    registry: &'a Value,
+    call_stack: &'a [CallFrame],
+    current: &'a Value,
+    resumers: &'a [Resumer],
+) -> impl Iterator<Item = FrameValue<'a>> {
+    let threads = std::iter::once((stack, call_stack, current))
+        .chain(resumers.iter().map(|r| (&r.stack, r.call_stack.as_slice(), &r.coroutine)));
+    let thread_roots = threads.flat_map(|(stack, call_stack, coroutine)| {
+        let frame_locals = call_stack.iter().flat_map(CallFrame::references);
+        stack
+            .iter()
+            .chain(std::iter::once(ValueRef::from(coroutine)))
+            .map(FrameValue::Held)
+            .chain(frame_locals)
+    });
+    globals
+        .values()
+        .chain([string_metatable, registry])
+        .map(|value| FrameValue::Held(ValueRef::from(value)))
+        .chain(thread_roots)
+}
+
+impl JitEnabled for LuaJitRuntime {
+    fn execute_with_jit(&mut self, chunk: &Chunk, _jit: &mut JitCompiler) -> LuaResult<Value> {
+        self.stack.clear();
+        let depth = self.call_stack.len();
+        self.call_stack.push(CallFrame {
+            chunk: Rc::new(chunk.clone()),
+            pc: 0,
+            locals: Vec::new(),
+            base: 0,
+            results: 1,
+            entry: Entry::Native,
+            env: None,
+            function: Value::Nil,
+            open_upvalues: Vec::new(),
+        });
+
+        if let Err(e) = self.run(depth) {
+            // Drop the frames of the failed chunk so the next one
+            // starts from a clean call stack
+            self.call_stack.truncate(depth);
+            return Err(e);
+        }
 
         Ok(self.stack.pop().unwrap_or(Value::Nil))
     }
 }
+
+impl Drop for LuaJitRuntime {
+    fn drop(&mut self) {
+        self.close_state();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::NativeRef;
+
+    // A message handler that reports how deep the call stack is and where
+    // the error was raised
+    fn inspect(runtime: &mut LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+        let report = format!("{} frames, {}", runtime.call_stack.len(), runtime.location(2));
+        Ok(vec![Value::String(report.into())])
+    }
+
+    #[test]
+    fn test_handler_runs_before_unwinding() {
+        let mut runtime = LuaJitRuntime::new();
+        runtime.set_global("inspect", Value::Native(NativeRef::runtime("inspect", inspect)));
+
+        let source = "function fail()\n    error({})\nend\nfunction middle()\n    fail()\nend\n\
+                      local ok, err = xpcall(middle, inspect)\nreturn err";
+        let report = runtime.execute(source).unwrap();
+        // The chunk, `middle` and `fail` were all still there
+        assert_eq!(report, Value::String("3 frames, [string \"function fail()...\"]:2: ".into()));
+        assert_eq!(runtime.call_stack.len(), 0);
+    }
+}
@@ -1,151 +1,77 @@
+use crate::coroutine::{CoroutineRef, CoroutineStatus};
 use crate::error::{LuaError, LuaResult};
+use crate::file::{self, Buffering, LuaFile, ReadFormat};
+use crate::filesystem;
+use crate::format;
+use crate::os;
+use crate::pack;
+use crate::package;
+use crate::pattern::{self, Match, Matcher};
+use crate::random::Random;
+use crate::string::LuaString;
+use crate::table::TableRef;
+use crate::userdata::UserdataRef;
 use crate::value::Value;
-use std::collections::HashMap;
-
-/// Built-in function signature
-pub type BuiltinFunction = fn(&[Value]) -> LuaResult<Value>;
-
-/// Registry of all built-in functions
-pub struct StandardLibrary {
-    functions: HashMap<String, (usize, BuiltinFunction)>, // (function_id, function)
-    function_names: Vec<String>,
-}
-
-impl StandardLibrary {
-    pub fn new() -> Self {
-        let mut stdlib = Self {
-            functions: HashMap::new(),
-            function_names: Vec::new(),
-        };
-
-        stdlib.register_core_functions();
-        stdlib.register_string_functions();
-        stdlib.register_math_functions();
-        stdlib.register_table_functions();
-        stdlib.register_io_functions();
-
-        stdlib
-    }
-
-    fn register_function(&mut self, name: &str, func: BuiltinFunction) -> usize {
-        let id = self.function_names.len();
-        self.function_names.push(name.to_string());
-        self.functions.insert(name.to_string(), (id, func));
-        id
-    }
-
-    pub fn get_function(&self, name: &str) -> Option<(usize, BuiltinFunction)> {
-        self.functions.get(name).copied()
-    }
-
-    pub fn get_function_by_id(&self, id: usize) -> Option<BuiltinFunction> {
-        if id < self.function_names.len() {
-            let name = &self.function_names[id];
-            self.functions.get(name).map(|(_, func)| *func)
-        } else {
-            None
-        }
-    }
-
-    pub fn get_function_name(&self, id: usize) -> Option<&str> {
-        self.function_names.get(id).map(|s| s.as_str())
-    }
-
-    pub fn get_all_functions(&self) -> HashMap<String, Value> {
-        self.functions
-            .iter()
-            .map(|(name, (id, _))| (name.clone(), Value::Function(*id)))
-            .collect()
-    }
-
-    // Core functions
-    fn register_core_functions(&mut self) {
-        self.register_function("print", builtin_print);
-        self.register_function("type", builtin_type);
-        self.register_function("tostring", builtin_tostring);
-        self.register_function("tonumber", builtin_tonumber);
-        self.register_function("pairs", builtin_pairs);
-        self.register_function("ipairs", builtin_ipairs);
-        self.register_function("next", builtin_next);
-        self.register_function("rawget", builtin_rawget);
-        self.register_function("rawset", builtin_rawset);
-        self.register_function("getmetatable", builtin_getmetatable);
-        self.register_function("setmetatable", builtin_setmetatable);
-        self.register_function("pcall", builtin_pcall);
-        self.register_function("xpcall", builtin_xpcall);
-        self.register_function("error", builtin_error);
-        self.register_function("assert", builtin_assert);
-    }
-
-    // String functions
-    fn register_string_functions(&mut self) {
-        // String library would be a table in real Lua, but for simplicity
-        // we'll register them as global functions with string. prefix
-        self.register_function("string.len", string_len);
-        self.register_function("string.sub", string_sub);
-        self.register_function("string.upper", string_upper);
-        self.register_function("string.lower", string_lower);
-        self.register_function("string.char", string_char);
-        self.register_function("string.byte", string_byte);
-        self.register_function("string.find", string_find);
-        self.register_function("string.gsub", string_gsub);
-    }
-
-    // Math functions
-    fn register_math_functions(&mut self) {
-        self.register_function("math.abs", math_abs);
-        self.register_function("math.ceil", math_ceil);
-        self.register_function("math.floor", math_floor);
-        self.register_function("math.max", math_max);
-        self.register_function("math.min", math_min);
-        self.register_function("math.sqrt", math_sqrt);
-        self.register_function("math.sin", math_sin);
-        self.register_function("math.cos", math_cos);
-        self.register_function("math.tan", math_tan);
-        self.register_function("math.pi", math_pi);
-        self.register_function("math.random", math_random);
-    }
-
-    // Table functions
-    fn register_table_functions(&mut self) {
-        self.register_function("table.insert", table_insert);
-        self.register_function("table.remove", table_remove);
-        self.register_function("table.concat", table_concat);
-        self.register_function("table.sort", table_sort);
-    }
-
-    // IO functions (simplified)
-    fn register_io_functions(&mut self) {
-        self.register_function("io.write", io_write);
-        self.register_function("io.read", io_read);
-    }
-}
+use std::io::{self, Read, Write};
+use std::process::{ExitStatus, Stdio};
 
 // Core function implementations
 
 pub fn builtin_print(args: &[Value]) -> LuaResult<Value> {
+    let mut out = io::stdout().lock();
     for (i, arg) in args.iter().enumerate() {
         if i > 0 {
-            print!("\t");
+            write_output(&mut out, b"\t")?;
         }
-        print!("{}", arg);
+        write_value(&mut out, arg)?;
     }
-    println!();
+    write_output(&mut out, b"\n")?;
     Ok(Value::Nil)
 }
 
+/// Writes strings as their raw bytes and anything else as its display form.
+fn write_value(out: &mut impl Write, value: &Value) -> LuaResult<()> {
+    match value {
+        Value::String(s) => write_output(out, s),
+        other => write_output(out, other.to_string().as_bytes()),
+    }
+}
+
+fn write_output(out: &mut impl Write, bytes: &[u8]) -> LuaResult<()> {
+    out.write_all(bytes).map_err(|e| LuaError::runtime_error(&e.to_string()))
+}
+
+/// Appends a string or number the way concatenation does.
+fn push_concat_operand(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(s) => buffer.extend_from_slice(s),
+        other => buffer.extend_from_slice(other.to_string().as_bytes()),
+    }
+}
+
 pub fn builtin_type(args: &[Value]) -> LuaResult<Value> {
     if args.len() != 1 {
         return Err(LuaError::argument_error(1, args.len(), "type"));
     }
-    Ok(Value::String(args[0].type_name().to_string()))
+    Ok(Value::String(args[0].type_name().into()))
 }
 
 pub fn builtin_tostring(args: &[Value]) -> LuaResult<Value> {
     if args.len() != 1 {
         return Err(LuaError::argument_error(1, args.len(), "tostring"));
     }
-    Ok(Value::String(args[0].to_string()))
+    match &args[0] {
+        Value::String(_) => Ok(args[0].clone()),
+        other => Ok(Value::String(other.to_string().into())),
+    }
+}
+
+/// `tostring` as scripts see it, which calls a `__tostring` metamethod.
+/// `builtin_tostring` is the plain conversion.
+pub fn builtin_tostring_meta(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let value = args.first().ok_or_else(|| LuaError::bad_argument(1, "tostring", "value expected"))?;
+    let s = runtime.tostring(value)?;
+    Ok(vec![Value::String(s)])
 }
 
 pub fn builtin_tonumber(args: &[Value]) -> LuaResult<Value> {
@@ -155,20 +81,23 @@ pub fn builtin_tonumber(args: &[Value]) -> LuaResult<Value> {
 
     match &args[0] {
         Value::Number(n) => Ok(Value::Number(*n)),
-        Value::String(s) => {
-            if let Ok(n) = s.parse::<f64>() {
-                Ok(Value::Number(n))
-            } else {
-                Ok(Value::Nil)
-            }
-        }
+        Value::String(s) => match s.to_str().ok().and_then(|s| s.trim().parse::<f64>().ok()) {
+            Some(n) => Ok(Value::Number(n)),
+            None => Ok(Value::Nil),
+        },
         _ => Ok(Value::Nil),
     }
 }
 
-pub fn builtin_pairs(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified - would return iterator in real implementation
-    Err(LuaError::runtime_error("pairs not implemented"))
+pub fn builtin_pairs(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        Some(table @ Value::Table(_)) => {
+            let next = runtime.builtin("next").unwrap_or(Value::Nil);
+            Ok(vec![next, table.clone(), Value::Nil])
+        }
+        Some(other) => Err(LuaError::type_error("table", other.type_name(), "pairs")),
+        None => Err(LuaError::argument_error(1, 0, "pairs")),
+    }
 }
 
 pub fn builtin_ipairs(_args: &[Value]) -> LuaResult<Value> {
@@ -176,8 +105,19 @@ pub fn builtin_ipairs(_args: &[Value]) -> LuaResult<Value> {
     Err(LuaError::runtime_error("ipairs not implemented"))
 }
 
-pub fn builtin_next(_args: &[Value]) -> LuaResult<Value> {
-    Err(LuaError::runtime_error("next not implemented"))
+pub fn builtin_next(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let table = match args.first() {
+        Some(Value::Table(table)) => table,
+        Some(other) => return Err(LuaError::type_error("table", other.type_name(), "next")),
+        None => return Err(LuaError::argument_error(1, 0, "next")),
+    };
+    let key = args.get(1).cloned().unwrap_or(Value::Nil);
+
+    match table.borrow().next(&key) {
+        Ok(Some((key, value))) => Ok(vec![key, value]),
+        Ok(None) => Ok(vec![Value::Nil]),
+        Err(msg) => Err(LuaError::runtime_error(&msg)),
+    }
 }
 
 pub fn builtin_rawget(args: &[Value]) -> LuaResult<Value> {
@@ -186,8 +126,7 @@ pub fn builtin_rawget(args: &[Value]) -> LuaResult<Value> {
     }
 
     if let Value::Table(ref table) = args[0] {
-        let key = args[1].to_string();
-        Ok(table.get(&key).cloned().unwrap_or(Value::Nil))
+        Ok(table.get(&args[1]))
     } else {
         Err(LuaError::type_error("table", args[0].type_name(), "rawget"))
     }
@@ -198,42 +137,76 @@ pub fn builtin_rawset(args: &[Value]) -> LuaResult<Value> {
         return Err(LuaError::argument_error(3, args.len(), "rawset"));
     }
 
-    if let Value::Table(mut table) = args[0].clone() {
-        let key = args[1].to_string();
-        table.insert(key, args[2].clone());
-        Ok(Value::Table(table))
+    if let Value::Table(ref table) = args[0] {
+        if let Err(msg) = crate::table::check_key(&args[1]) {
+            return Err(LuaError::runtime_error(&msg));
+        }
+        table.set(args[1].clone(), args[2].clone());
+        Ok(args[0].clone())
     } else {
         Err(LuaError::type_error("table", args[0].type_name(), "rawset"))
     }
 }
 
-pub fn builtin_getmetatable(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified - metatables not implemented
-    Ok(Value::Nil)
+pub fn builtin_getmetatable(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let metatable = args.first().and_then(|value| runtime.get_metatable(value));
+    Ok(vec![metatable.map(Value::Table).unwrap_or(Value::Nil)])
 }
 
-pub fn builtin_setmetatable(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified - metatables not implemented
-    Ok(Value::Nil)
+pub fn builtin_setmetatable(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    if args.len() != 2 {
+        return Err(LuaError::argument_error(2, args.len(), "setmetatable"));
+    }
+
+    if !matches!(args[0], Value::Table(_)) {
+        return Err(LuaError::type_error("table", args[0].type_name(), "setmetatable"));
+    }
+    let metatable = match &args[1] {
+        Value::Table(metatable) => Some(metatable.clone()),
+        Value::Nil => None,
+        other => return Err(LuaError::type_error("nil or table", other.type_name(), "setmetatable")),
+    };
+    runtime
+        .set_metatable(&args[0], metatable)
+        .map_err(|msg| LuaError::runtime_error(&msg))?;
+    Ok(vec![args[0].clone()])
 }
 
-pub fn builtin_pcall(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified - would implement protected call
-    Err(LuaError::runtime_error("pcall not implemented"))
+pub fn builtin_pcall(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    if args.is_empty() {
+        return Err(LuaError::argument_error(1, 0, "pcall"));
+    }
+    runtime.protected_call(args.to_vec(), None);
+    Ok(Vec::new())
 }
 
-pub fn builtin_xpcall(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified - would implement extended protected call
-    Err(LuaError::runtime_error("xpcall not implemented"))
+/// `xpcall(f, msgh, ...)`: `pcall` that passes errors through `msgh` while
+/// the stack that raised them is still in place.
+pub fn builtin_xpcall(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handler = match args.get(1) {
+        Some(handler @ (Value::Closure(_) | Value::Native(_))) => handler.clone(),
+        _ => return Err(expected("function", args, 1, "xpcall")),
+    };
+    let call = std::iter::once(args[0].clone()).chain(args[2..].iter().cloned()).collect();
+    runtime.protected_call(call, Some(handler));
+    Ok(Vec::new())
 }
 
-pub fn builtin_error(args: &[Value]) -> LuaResult<Value> {
-    let message = if args.is_empty() {
-        "error"
-    } else {
-        &args[0].to_string()
+/// `error(message [, level])`: raises `message`, which can be any value.
+/// String messages get the position of the function at `level` in front,
+/// where 1, the default, is the function that called `error`.
+pub fn builtin_error(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let value = args.first().cloned().unwrap_or(Value::Nil);
+    let level = optional_integer(args, 1, 1, "error")?;
+    let value = match value {
+        Value::String(message) if level > 0 => {
+            let mut bytes = runtime.location(level as usize).into_bytes();
+            bytes.extend_from_slice(message.as_bytes());
+            Value::String(bytes.into())
+        }
+        value => value,
     };
-    Err(LuaError::runtime_error(message))
+    Err(LuaError::thrown(value))
 }
 
 pub fn builtin_assert(args: &[Value]) -> LuaResult<Value> {
@@ -242,17 +215,184 @@ pub fn builtin_assert(args: &[Value]) -> LuaResult<Value> {
     }
 
     if !args[0].is_truthy() {
-        let message = if args.len() > 1 {
-            args[1].to_string()
-        } else {
-            "assertion failed!".to_string()
+        return match args.get(1) {
+            Some(message) => Err(LuaError::thrown(message.clone())),
+            None => Err(LuaError::runtime_error("assertion failed!")),
         };
-        return Err(LuaError::runtime_error(&message));
     }
 
     Ok(args[0].clone())
 }
 
+pub fn builtin_collectgarbage(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let option = match args.first() {
+        None | Some(Value::Nil) => "collect".to_string(),
+        Some(Value::String(s)) => s.to_str_lossy().into_owned(),
+        Some(other) => return Err(LuaError::type_error("string", other.type_name(), "collectgarbage")),
+    };
+
+    let result = match option.as_str() {
+        "collect" => {
+            runtime.gc_collect();
+            Value::Number(0.0)
+        }
+        "count" => Value::Number(runtime.memory_used() as f64 / 1024.0),
+        "step" => Value::Boolean(runtime.gc_step()),
+        "stop" => {
+            runtime.gc_stop();
+            Value::Number(0.0)
+        }
+        "restart" => {
+            runtime.gc_restart();
+            Value::Number(0.0)
+        }
+        "isrunning" => Value::Boolean(runtime.gc_is_running()),
+        "incremental" => {
+            let previous = runtime.gc_set_incremental(
+                gc_parameter(args, 1),
+                gc_parameter(args, 2),
+                gc_parameter(args, 3) as u32,
+            );
+            Value::String(previous.name().into())
+        }
+        "generational" => {
+            let previous = runtime.gc_set_generational(gc_parameter(args, 1), gc_parameter(args, 2));
+            Value::String(previous.name().into())
+        }
+        _ => {
+            return Err(LuaError::runtime_error(&format!(
+                "bad argument #1 to 'collectgarbage' (invalid option '{}')",
+                option
+            )))
+        }
+    };
+    Ok(vec![result])
+}
+
+// Optional numeric tuning argument to collectgarbage; 0 means "keep current"
+fn gc_parameter(args: &[Value], index: usize) -> usize {
+    args.get(index)
+        .and_then(|value| value.to_number())
+        .map(|n| n.max(0.0) as usize)
+        .unwrap_or(0)
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])`: compiles a string, or the
+/// pieces a reader function returns until it returns nil or an empty
+/// string, into a function. Errors are returned as nil and a message.
+pub fn builtin_load(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let (source, default_name) = match args.first() {
+        Some(Value::String(s)) => (Ok(s.as_bytes().to_vec()), s.to_str_lossy().into_owned()),
+        Some(reader @ (Value::Closure(_) | Value::Native(_))) => (read_chunk(runtime, reader), "=(load)".to_string()),
+        _ => return Err(expected("function", args, 0, "load")),
+    };
+    let chunkname = optional_string(args, 1, &default_name, "load")?;
+    let mode = optional_string(args, 2, "bt", "load")?;
+    // An explicit nil environment is kept, leaving the chunk no globals
+    let env = args.get(3).cloned();
+    let result = source.and_then(|source| load_chunk(runtime, &source, &chunkname, &mode, env));
+    Ok(load_result(result))
+}
+
+/// `loadfile([filename [, mode [, env]]])`: like `load` on the contents of
+/// a file, or of standard input when there is no file name.
+pub fn builtin_loadfile(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let filename = optional_filename(args, 0, "loadfile")?;
+    let mode = optional_string(args, 1, "bt", "loadfile")?;
+    let env = args.get(2).cloned();
+    Ok(load_result(load_file(runtime, filename.as_deref(), &mode, env)))
+}
+
+/// `dofile([filename])`: runs a file, or standard input, and returns what
+/// it returns. Unlike `loadfile`, errors are raised.
+pub fn builtin_dofile(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let filename = optional_filename(args, 0, "dofile")?;
+    let function = load_file(runtime, filename.as_deref(), "bt", None).map_err(|msg| LuaError::runtime_error(&msg))?;
+    runtime.call_function(&function, &[])
+}
+
+fn load_result(result: Result<Value, String>) -> Vec<Value> {
+    match result {
+        Ok(function) => vec![function],
+        Err(msg) => vec![Value::Nil, Value::String(msg.into())],
+    }
+}
+
+fn optional_filename(args: &[Value], index: usize, function: &str) -> LuaResult<Option<String>> {
+    match args.get(index) {
+        None | Some(Value::Nil) => Ok(None),
+        Some(_) => Ok(Some(bytes_argument(args, index, function)?.to_str_lossy().into_owned())),
+    }
+}
+
+// Concatenates the pieces a `load` reader function returns, up to the
+// first nil or empty string
+fn read_chunk(runtime: &mut crate::runtime::LuaJitRuntime, reader: &Value) -> Result<Vec<u8>, String> {
+    let mut source = Vec::new();
+    loop {
+        let piece = runtime.call_function(reader, &[]).map_err(|e| e.into_value().to_string())?;
+        match piece.into_iter().next() {
+            None | Some(Value::Nil) => return Ok(source),
+            Some(Value::String(piece)) if piece.as_bytes().is_empty() => return Ok(source),
+            Some(Value::String(piece)) => source.extend_from_slice(piece.as_bytes()),
+            Some(n @ Value::Number(_)) => source.extend_from_slice(n.to_string().as_bytes()),
+            Some(_) => return Err("reader function must return a string".to_string()),
+        }
+    }
+}
+
+// Reads a chunk for `loadfile` and `dofile`. A first line starting with
+// `#`, such as `#!/usr/bin/env luna`, is skipped.
+fn load_file(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    filename: Option<&str>,
+    mode: &str,
+    env: Option<Value>,
+) -> Result<Value, String> {
+    let (source, chunkname) = match filename {
+        Some(filename) => {
+            let source = std::fs::read(filename)
+                .map_err(|e| format!("cannot open {}: {}", filename, file::error_parts(&e).0))?;
+            (source, format!("@{}", filename))
+        }
+        None => {
+            let mut source = Vec::new();
+            io::stdin()
+                .read_to_end(&mut source)
+                .map_err(|e| format!("cannot read stdin: {}", file::error_parts(&e).0))?;
+            (source, "=stdin".to_string())
+        }
+    };
+    let source = match source.first() {
+        // The newline stays so that line numbers match the file
+        Some(b'#') => source.iter().position(|&b| b == b'\n').map_or(&[][..], |end| &source[end..]),
+        _ => &source[..],
+    };
+    load_chunk(runtime, source, &chunkname, mode, env)
+}
+
+// Compiles a chunk once `mode` allows its kind: "t" for text, "b" for
+// binary or "bt" for both
+fn load_chunk(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    source: &[u8],
+    chunkname: &str,
+    mode: &str,
+    env: Option<Value>,
+) -> Result<Value, String> {
+    // Precompiled chunks start with an escape character, as in "\x1bLua"
+    let binary = source.first() == Some(&0x1b);
+    let (kind, letter) = if binary { ("binary", 'b') } else { ("text", 't') };
+    if !mode.contains(letter) {
+        return Err(format!("attempt to load a {} chunk (mode is '{}')", kind, mode));
+    }
+    if binary {
+        let id = crate::runtime::chunk_id(chunkname);
+        return Err(format!("{}: bad binary format (precompiled chunks are not supported)", id));
+    }
+    runtime.load(source, chunkname, env)
+}
+
 // String function implementations
 pub fn string_len(args: &[Value]) -> LuaResult<Value> {
     if args.len() != 1 {
@@ -277,27 +417,38 @@ pub fn string_sub(args: &[Value]) -> LuaResult<Value> {
     };
 
     let start = match args[1].to_number() {
-        Some(n) => n as i32,
+        Some(n) => n as i64,
         None => return Err(LuaError::type_error("number", args[1].type_name(), "string.sub")),
     };
 
     let end = if args.len() == 3 {
         match args[2].to_number() {
-            Some(n) => n as i32,
+            Some(n) => n as i64,
             None => return Err(LuaError::type_error("number", args[2].type_name(), "string.sub")),
         }
     } else {
-        s.len() as i32
+        -1
     };
 
-    // Lua uses 1-based indexing
-    let start_idx = if start > 0 { start - 1 } else { 0 }.max(0) as usize;
-    let end_idx = if end > 0 { end } else { s.len() as i32 }.min(s.len() as i32) as usize;
+    // Lua uses 1-based byte positions; negative ones count from the end
+    let len = s.len() as i64;
+    let start_idx = match start {
+        n if n > 0 => n,
+        0 => 1,
+        n if n < -len => 1,
+        n => len + n + 1,
+    };
+    let end_idx = match end {
+        n if n > len => len,
+        n if n >= 0 => n,
+        n if n < -len => 0,
+        n => len + n + 1,
+    };
 
-    if start_idx >= s.len() || start_idx >= end_idx {
-        Ok(Value::String(String::new()))
+    if start_idx > end_idx {
+        Ok(Value::String("".into()))
     } else {
-        Ok(Value::String(s[start_idx..end_idx].to_string()))
+        Ok(Value::String(s[start_idx as usize - 1..end_idx as usize].into()))
     }
 }
 
@@ -307,7 +458,7 @@ pub fn string_upper(args: &[Value]) -> LuaResult<Value> {
     }
 
     if let Value::String(s) = &args[0] {
-        Ok(Value::String(s.to_uppercase()))
+        Ok(Value::String(s.to_ascii_uppercase().into()))
     } else {
         Err(LuaError::type_error("string", args[0].type_name(), "string.upper"))
     }
@@ -319,321 +470,2358 @@ pub fn string_lower(args: &[Value]) -> LuaResult<Value> {
     }
 
     if let Value::String(s) = &args[0] {
-        Ok(Value::String(s.to_lowercase()))
+        Ok(Value::String(s.to_ascii_lowercase().into()))
     } else {
         Err(LuaError::type_error("string", args[0].type_name(), "string.lower"))
     }
 }
 
-pub fn string_char(args: &[Value]) -> LuaResult<Value> {
-    let mut result = String::new();
+// Longest string `string.rep` will build
+const MAX_REP_SIZE: usize = i32::MAX as usize;
 
-    for arg in args {
-        if let Some(n) = arg.to_number() {
-            if n >= 0.0 && n <= 255.0 {
-                result.push(n as u8 as char);
-            } else {
-                return Err(LuaError::runtime_error("character code out of range"));
-            }
-        } else {
-            return Err(LuaError::type_error("number", arg.type_name(), "string.char"));
+/// `string.rep(s, n [, sep])`: `n` copies of `s` separated by `sep`.
+pub fn string_rep(args: &[Value]) -> LuaResult<Value> {
+    let s = string_argument(args, 0, "string.rep")?;
+    let count = match args.get(1).and_then(Value::to_number) {
+        Some(n) => n,
+        None => return Err(LuaError::type_error("number", args.get(1).map_or("no value", Value::type_name), "string.rep")),
+    };
+    let separator = match args.get(2) {
+        None | Some(Value::Nil) => LuaString::from(""),
+        Some(_) => string_argument(args, 2, "string.rep")?,
+    };
+    if count < 1.0 || s.len() + separator.len() == 0 {
+        return Ok(Value::String("".into()));
+    }
+
+    let count = count as usize;
+    let size = (s.len() + separator.len())
+        .checked_mul(count)
+        .map(|size| size - separator.len())
+        .filter(|&size| size <= MAX_REP_SIZE);
+    let mut result = match size {
+        Some(size) => Vec::with_capacity(size),
+        None => return Err(LuaError::runtime_error("resulting string too large")),
+    };
+    for i in 0..count {
+        if i > 0 {
+            result.extend_from_slice(&separator);
         }
+        result.extend_from_slice(&s);
     }
+    Ok(Value::String(result.into()))
+}
 
-    Ok(Value::String(result))
+pub fn string_reverse(args: &[Value]) -> LuaResult<Value> {
+    let s = string_argument(args, 0, "string.reverse")?;
+    Ok(Value::String(s.iter().rev().copied().collect::<Vec<u8>>().into()))
 }
 
-pub fn string_byte(args: &[Value]) -> LuaResult<Value> {
-    if args.is_empty() || args.len() > 3 {
-        return Err(LuaError::argument_error(1, args.len(), "string.byte"));
+/// `string.char(...)`: the string made of the given byte codes.
+pub fn string_char(args: &[Value]) -> LuaResult<Value> {
+    let mut result = Vec::with_capacity(args.len());
+    for index in 0..args.len() {
+        match u8::try_from(integer_argument(args, index, "char")?) {
+            Ok(byte) => result.push(byte),
+            Err(_) => return Err(LuaError::bad_argument(index + 1, "char", "value out of range")),
+        }
     }
+    Ok(Value::String(result.into()))
+}
 
-    let s = match &args[0] {
-        Value::String(s) => s,
-        _ => return Err(LuaError::type_error("string", args[0].type_name(), "string.byte")),
+/// `string.byte(s [, i [, j]])`: the codes of the bytes from `i` to `j`,
+/// where `i` defaults to 1 and `j` to `i`.
+pub fn string_byte(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = bytes_argument(args, 0, "byte")?;
+    let len = s.len() as i64;
+
+    // Lua uses 1-based byte positions; negative ones count from the end
+    let start = match optional_integer(args, 1, 1, "byte")? {
+        n if n > 0 => n,
+        0 => 1,
+        n if n < -len => 1,
+        n => len + n + 1,
     };
-
-    let index = if args.len() > 1 {
-        match args[1].to_number() {
-            Some(n) => (n as i32 - 1).max(0) as usize,
-            None => 0,
-        }
-    } else {
-        0
+    let end = match optional_integer(args, 2, start, "byte")? {
+        n if n > len => len,
+        n if n >= 0 => n,
+        n if n < -len => 0,
+        n => len + n + 1,
     };
 
-    if index < s.len() {
-        Ok(Value::Number(s.bytes().nth(index).unwrap_or(0) as f64))
-    } else {
-        Ok(Value::Nil)
+    if start > end {
+        return Ok(Vec::new());
     }
+    Ok(s[start as usize - 1..end as usize].iter().map(|&byte| Value::Number(byte as f64)).collect())
 }
 
-pub fn string_find(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified implementation
-    Err(LuaError::runtime_error("string.find not implemented"))
+/// Accepts a string, or a number converted the way `tostring` would.
+fn string_argument(args: &[Value], index: usize, function: &str) -> LuaResult<LuaString> {
+    match args.get(index) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(n @ Value::Number(_)) => Ok(n.to_string().into()),
+        Some(other) => Err(LuaError::type_error("string", other.type_name(), function)),
+        None => Err(LuaError::argument_error(index + 1, args.len(), function)),
+    }
 }
 
-pub fn string_gsub(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified implementation
-    Err(LuaError::runtime_error("string.gsub not implemented"))
+/// Converts an optional 1-based `init` argument, which counts from the end
+/// when negative, into a byte offset. The offset may lie past the end.
+fn start_offset(args: &[Value], index: usize, len: usize, function: &str) -> LuaResult<usize> {
+    let init = match args.get(index) {
+        None | Some(Value::Nil) => 1,
+        Some(value) => match value.to_number() {
+            Some(n) => n as i64,
+            None => return Err(LuaError::type_error("number", value.type_name(), function)),
+        },
+    };
+    let len = len as i64;
+    let position = match init {
+        n if n > 0 => n,
+        n if n < -len => 1,
+        0 => 1,
+        n => len + n + 1,
+    };
+    Ok(position as usize - 1)
 }
 
-// Math function implementations
-pub fn math_abs(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.abs"));
-    }
+fn pattern_error(message: String) -> LuaError {
+    LuaError::runtime_error(&message)
+}
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.abs()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.abs"))
+pub fn string_find(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = string_argument(args, 0, "string.find")?;
+    let pattern = string_argument(args, 1, "string.find")?;
+    let init = start_offset(args, 2, s.len(), "string.find")?;
+    if init > s.len() {
+        return Ok(vec![Value::Nil]);
     }
-}
 
-pub fn math_ceil(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.ceil"));
+    let plain = args.get(3).is_some_and(Value::is_truthy);
+    if plain || !pattern::has_specials(&pattern) {
+        return Ok(match pattern::find_plain(&s, &pattern, init) {
+            Some(start) => vec![Value::Number((start + 1) as f64), Value::Number((start + pattern.len()) as f64)],
+            None => vec![Value::Nil],
+        });
     }
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.ceil()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.ceil"))
+    let mut matcher = Matcher::new(&s, &pattern).with_step_limit(runtime.pattern_step_limit());
+    match matcher.find(init).map_err(pattern_error)? {
+        Some(found) => {
+            let mut results = vec![Value::Number((found.start + 1) as f64), Value::Number(found.end as f64)];
+            if !found.captures.is_empty() {
+                results.extend(found.values(&s));
+            }
+            Ok(results)
+        }
+        None => Ok(vec![Value::Nil]),
     }
 }
 
-pub fn math_floor(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.floor"));
+pub fn string_match(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = string_argument(args, 0, "string.match")?;
+    let pattern = string_argument(args, 1, "string.match")?;
+    let init = start_offset(args, 2, s.len(), "string.match")?;
+    if init > s.len() {
+        return Ok(vec![Value::Nil]);
     }
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.floor()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.floor"))
+    let mut matcher = Matcher::new(&s, &pattern).with_step_limit(runtime.pattern_step_limit());
+    match matcher.find(init).map_err(pattern_error)? {
+        Some(found) => Ok(found.values(&s)),
+        None => Ok(vec![Value::Nil]),
     }
 }
 
-pub fn math_max(args: &[Value]) -> LuaResult<Value> {
-    if args.is_empty() {
-        return Err(LuaError::argument_error(1, args.len(), "math.max"));
+// Where a `gmatch` iterator continues from
+struct GmatchState {
+    subject: LuaString,
+    pattern: LuaString,
+    position: usize,
+    // End of the previous match, where an empty match is not accepted again
+    last_end: Option<usize>,
+}
+
+/// Returns an iterator over the matches of a pattern, yielding the captures
+/// of each one.
+pub fn string_gmatch(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let subject = string_argument(args, 0, "string.gmatch")?;
+    let pattern = string_argument(args, 1, "string.gmatch")?;
+    let position = start_offset(args, 2, subject.len(), "string.gmatch")?.min(subject.len() + 1);
+    let state = runtime.create_userdata(
+        GmatchState {
+            subject,
+            pattern,
+            position,
+            last_end: None,
+        },
+        None,
+    );
+    let iterator = runtime.create_function("string.gmatch", gmatch_next, vec![Value::Userdata(state)]);
+    Ok(vec![Value::Native(iterator)])
+}
+
+fn gmatch_next(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    upvalues: &[Value],
+    _args: &[Value],
+) -> LuaResult<Vec<Value>> {
+    let state = match upvalues.first() {
+        Some(Value::Userdata(state)) => state,
+        _ => return Err(LuaError::runtime_error("invalid string.gmatch iterator")),
+    };
+    let mut state = state.borrow_mut::<GmatchState>()?;
+    let (subject, pattern) = (state.subject.clone(), state.pattern.clone());
+
+    let mut matcher = Matcher::unanchored(&subject, &pattern).with_step_limit(runtime.pattern_step_limit());
+    while state.position <= subject.len() {
+        if let Some(found) = matcher.match_at(state.position).map_err(pattern_error)? {
+            if state.last_end != Some(found.end) {
+                state.position = found.end;
+                state.last_end = Some(found.end);
+                return Ok(found.values(&subject));
+            }
+        }
+        state.position += 1;
     }
+    Ok(vec![Value::Nil])
+}
 
-    let mut max = match args[0].to_number() {
-        Some(n) => n,
-        None => return Err(LuaError::type_error("number", args[0].type_name(), "math.max")),
+/// Replaces matches of a pattern with a string, the value a table holds for
+/// the first capture, or the result of a function called with the captures.
+/// Returns the new string and the number of matches replaced.
+pub fn string_gsub(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = string_argument(args, 0, "string.gsub")?;
+    let pattern = string_argument(args, 1, "string.gsub")?;
+    let replacement = match args.get(2) {
+        Some(value @ (Value::String(_) | Value::Number(_) | Value::Table(_) | Value::Native(_) | Value::Closure(_))) => {
+            value.clone()
+        }
+        Some(other) => return Err(LuaError::type_error("string/function/table", other.type_name(), "string.gsub")),
+        None => return Err(LuaError::argument_error(3, args.len(), "string.gsub")),
+    };
+    let max_count = match args.get(3) {
+        None | Some(Value::Nil) => usize::MAX,
+        Some(value) => match value.to_number() {
+            Some(n) => n.max(0.0) as usize,
+            None => return Err(LuaError::type_error("number", value.type_name(), "string.gsub")),
+        },
     };
 
-    for arg in &args[1..] {
-        if let Some(n) = arg.to_number() {
-            if n > max {
-                max = n;
+    let mut matcher = Matcher::new(&s, &pattern).with_step_limit(runtime.pattern_step_limit());
+    let mut result = Vec::with_capacity(s.len());
+    let mut position = 0;
+    let mut last_end = None;
+    let mut count = 0;
+    while count < max_count {
+        match matcher.match_at(position).map_err(pattern_error)? {
+            Some(found) if last_end != Some(found.end) => {
+                count += 1;
+                add_replacement(runtime, &mut result, &s, &found, &replacement)?;
+                position = found.end;
+                last_end = Some(found.end);
             }
-        } else {
-            return Err(LuaError::type_error("number", arg.type_name(), "math.max"));
+            _ if position < s.len() => {
+                result.push(s[position]);
+                position += 1;
+            }
+            _ => break,
+        }
+        if matcher.is_anchored() {
+            break;
         }
     }
-
-    Ok(Value::Number(max))
+    result.extend_from_slice(&s[position..]);
+    Ok(vec![Value::String(result.into()), Value::Number(count as f64)])
 }
 
-pub fn math_min(args: &[Value]) -> LuaResult<Value> {
-    if args.is_empty() {
-        return Err(LuaError::argument_error(1, args.len(), "math.min"));
+fn add_replacement(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    out: &mut Vec<u8>,
+    subject: &[u8],
+    found: &Match,
+    replacement: &Value,
+) -> LuaResult<()> {
+    let value = match replacement {
+        Value::Table(_) => {
+            let key = found.values(subject).swap_remove(0);
+            runtime.index_value(replacement.clone(), key)?
+        }
+        Value::Native(_) | Value::Closure(_) => runtime
+            .call_function(replacement, &found.values(subject))?
+            .into_iter()
+            .next()
+            .unwrap_or(Value::Nil),
+        template => return expand_template(out, subject, found, template),
+    };
+
+    match value {
+        // False or nil keeps the original match
+        Value::Nil | Value::Boolean(false) => out.extend_from_slice(&subject[found.start..found.end]),
+        Value::String(_) | Value::Number(_) => push_concat_operand(out, &value),
+        other => {
+            let msg = format!("invalid replacement value (a {})", other.type_name());
+            return Err(LuaError::runtime_error(&msg));
+        }
     }
+    Ok(())
+}
 
-    let mut min = match args[0].to_number() {
-        Some(n) => n,
-        None => return Err(LuaError::type_error("number", args[0].type_name(), "math.min")),
+// Appends a replacement string, where `%0` stands for the whole match, `%1`
+// to `%9` for the captures and `%%` for a percent sign
+fn expand_template(out: &mut Vec<u8>, subject: &[u8], found: &Match, template: &Value) -> LuaResult<()> {
+    let template = match template {
+        Value::String(s) => s.clone(),
+        other => LuaString::from(other.to_string()),
     };
+    let mut bytes = template.iter();
+    while let Some(&c) = bytes.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'%') => out.push(b'%'),
+            Some(b'0') => push_concat_operand(out, &found.whole(subject)),
+            Some(&digit @ b'1'..=b'9') => match found.capture((digit - b'1') as usize, subject) {
+                Some(capture) => push_concat_operand(out, &capture),
+                None => {
+                    let msg = format!("invalid capture index %{} in replacement string", digit as char);
+                    return Err(LuaError::runtime_error(&msg));
+                }
+            },
+            _ => return Err(LuaError::runtime_error("invalid use of '%' in replacement string")),
+        }
+    }
+    Ok(())
+}
+
+/// Formats its arguments after a `printf`-style template. `%s` converts
+/// with `__tostring` and `%q` writes a literal that reads back as Lua.
+pub fn string_format(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let template = string_argument(args, 0, "string.format")?;
+    let mut out = Vec::with_capacity(template.len());
+    let mut index = 0;
+    let mut i = 0;
+    while i < template.len() {
+        let c = template[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if template.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
 
-    for arg in &args[1..] {
-        if let Some(n) = arg.to_number() {
-            if n < min {
-                min = n;
+        let (spec, len) = format::Spec::parse(&template[i..]).map_err(|msg| LuaError::runtime_error(&msg))?;
+        i += len;
+        index += 1;
+        // Only %s and %q take any value; the numeric conversions report a
+        // missing argument as "number expected, got no value"
+        let any_value = || args.get(index).ok_or_else(|| LuaError::bad_argument(index + 1, "format", "no value"));
+        match spec.conversion {
+            b'c' => out.extend(format::format_char(&spec, integer_argument(args, index, "format")? as u8)),
+            b'd' | b'i' => out.extend(format::format_integer(&spec, integer_argument(args, index, "format")?)),
+            b'u' | b'o' | b'x' | b'X' => {
+                let n = integer_argument(args, index, "format")?;
+                out.extend(format::format_unsigned(&spec, n as u64));
             }
-        } else {
-            return Err(LuaError::type_error("number", arg.type_name(), "math.min"));
+            b'q' => {
+                let value = any_value()?;
+                let literal = format::quote(value).map_err(|msg| LuaError::bad_argument(index + 1, "format", &msg))?;
+                out.extend(literal);
+            }
+            b's' => {
+                let s = runtime.tostring(any_value()?)?;
+                out.extend(format::format_string(&spec, &s));
+            }
+            _ => out.extend(format::format_float(&spec, number_argument(args, index, "format")?)),
         }
     }
-
-    Ok(Value::Number(min))
+    Ok(vec![Value::String(out.into())])
 }
 
-pub fn math_sqrt(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.sqrt"));
+// Argument checks below word their errors as reference Lua does, naming the
+// argument by position: "bad argument #2 to 'pack' (number expected, got nil)"
+
+/// Reads a number argument that must have an exact integer value.
+fn integer_argument(args: &[Value], index: usize, function: &str) -> LuaResult<i64> {
+    match args.get(index).and_then(Value::to_number) {
+        Some(n) => float_to_integer(n)
+            .ok_or_else(|| LuaError::bad_argument(index + 1, function, "number has no integer representation")),
+        None => Err(expected("number", args, index, function)),
     }
+}
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.sqrt()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.sqrt"))
+/// Reads an optional integer argument, which is `default` when absent or nil.
+fn optional_integer(args: &[Value], index: usize, default: i64, function: &str) -> LuaResult<i64> {
+    match args.get(index) {
+        None | Some(Value::Nil) => Ok(default),
+        Some(_) => integer_argument(args, index, function),
     }
 }
 
-pub fn math_sin(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.sin"));
+fn number_argument(args: &[Value], index: usize, function: &str) -> LuaResult<f64> {
+    match args.get(index).and_then(Value::to_number) {
+        Some(n) => Ok(n),
+        None => Err(expected("number", args, index, function)),
     }
+}
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.sin()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.sin"))
+/// Reads an optional string argument, which is `default` when absent or nil.
+fn optional_string(args: &[Value], index: usize, default: &str, function: &str) -> LuaResult<String> {
+    match args.get(index) {
+        None | Some(Value::Nil) => Ok(default.to_string()),
+        Some(_) => Ok(bytes_argument(args, index, function)?.to_str_lossy().into_owned()),
     }
 }
 
-pub fn math_cos(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.cos"));
+/// Reads a string argument, converting numbers.
+fn bytes_argument(args: &[Value], index: usize, function: &str) -> LuaResult<LuaString> {
+    match args.get(index) {
+        Some(Value::String(s)) => Ok(s.clone()),
+        Some(n @ Value::Number(_)) => Ok(n.to_string().into()),
+        _ => Err(expected("string", args, index, function)),
     }
+}
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.cos()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.cos"))
+fn expected(type_name: &str, args: &[Value], index: usize, function: &str) -> LuaError {
+    let found = args.get(index).map_or("no value", Value::type_name);
+    LuaError::bad_argument(index + 1, function, &format!("{} expected, got {}", type_name, found))
+}
+
+fn pack_format_error(error: pack::FormatError, function: &str) -> LuaError {
+    match error {
+        pack::FormatError::Argument(msg) => LuaError::bad_argument(1, function, &msg),
+        pack::FormatError::Message(msg) => LuaError::runtime_error(&msg),
     }
 }
 
-pub fn math_tan(args: &[Value]) -> LuaResult<Value> {
-    if args.len() != 1 {
-        return Err(LuaError::argument_error(1, args.len(), "math.tan"));
+/// `string.pack(fmt, v1, v2, ...)`: the values serialized in binary form.
+pub fn string_pack(args: &[Value]) -> LuaResult<Value> {
+    let format = bytes_argument(args, 0, "pack")?;
+    let mut options = pack::Format::new(&format);
+    let mut out = Vec::new();
+    let mut index = 0;
+    while let Some(item) = options.next_item(out.len()) {
+        let item = item.map_err(|e| pack_format_error(e, "pack"))?;
+        let little_endian = options.little_endian();
+        out.resize(out.len() + item.padding, pack::PAD_BYTE);
+        if item.kind.takes_value() {
+            index += 1;
+        }
+        match item.kind {
+            pack::Kind::Int => {
+                let n = integer_argument(args, index, "pack")?;
+                if item.size < 8 {
+                    let limit = 1i64 << (item.size * 8 - 1);
+                    if n < -limit || n >= limit {
+                        return Err(LuaError::bad_argument(index + 1, "pack", "integer overflow"));
+                    }
+                }
+                pack::pack_int(&mut out, n as u64, little_endian, item.size, n < 0);
+            }
+            pack::Kind::Unsigned => {
+                let n = integer_argument(args, index, "pack")?;
+                if item.size < 8 && (n as u64) >= 1u64 << (item.size * 8) {
+                    return Err(LuaError::bad_argument(index + 1, "pack", "unsigned overflow"));
+                }
+                pack::pack_int(&mut out, n as u64, little_endian, item.size, false);
+            }
+            pack::Kind::Float => {
+                let n = number_argument(args, index, "pack")? as f32;
+                out.extend(if little_endian { n.to_le_bytes() } else { n.to_be_bytes() });
+            }
+            pack::Kind::Double => {
+                let n = number_argument(args, index, "pack")?;
+                out.extend(if little_endian { n.to_le_bytes() } else { n.to_be_bytes() });
+            }
+            pack::Kind::Fixed => {
+                let s = bytes_argument(args, index, "pack")?;
+                if s.len() > item.size {
+                    return Err(LuaError::bad_argument(index + 1, "pack", "string longer than given size"));
+                }
+                out.extend_from_slice(&s);
+                out.resize(out.len() + item.size - s.len(), pack::PAD_BYTE);
+            }
+            pack::Kind::Counted => {
+                let s = bytes_argument(args, index, "pack")?;
+                if item.size < 8 && s.len() as u64 >= 1u64 << (item.size * 8) {
+                    let msg = "string length does not fit in given size";
+                    return Err(LuaError::bad_argument(index + 1, "pack", msg));
+                }
+                pack::pack_int(&mut out, s.len() as u64, little_endian, item.size, false);
+                out.extend_from_slice(&s);
+            }
+            pack::Kind::Zero => {
+                let s = bytes_argument(args, index, "pack")?;
+                if s.contains(&0) {
+                    return Err(LuaError::bad_argument(index + 1, "pack", "string contains zeros"));
+                }
+                out.extend_from_slice(&s);
+                out.push(0);
+            }
+            pack::Kind::Padding => out.push(pack::PAD_BYTE),
+            pack::Kind::Align | pack::Kind::Nop => {}
+        }
     }
+    Ok(Value::String(out.into()))
+}
 
-    if let Some(n) = args[0].to_number() {
-        Ok(Value::Number(n.tan()))
-    } else {
-        Err(LuaError::type_error("number", args[0].type_name(), "math.tan"))
+/// `string.packsize(fmt)`: the length of what `string.pack` produces for a
+/// format without variable-length options.
+pub fn string_packsize(args: &[Value]) -> LuaResult<Value> {
+    let format = bytes_argument(args, 0, "packsize")?;
+    let mut options = pack::Format::new(&format);
+    let mut total: usize = 0;
+    while let Some(item) = options.next_item(total) {
+        let item = item.map_err(|e| pack_format_error(e, "packsize"))?;
+        if matches!(item.kind, pack::Kind::Counted | pack::Kind::Zero) {
+            return Err(LuaError::bad_argument(1, "packsize", "variable-length format"));
+        }
+        total = match total.checked_add(item.padding + item.size) {
+            Some(total) if total <= i32::MAX as usize => total,
+            _ => return Err(LuaError::bad_argument(1, "packsize", "format result too large")),
+        };
     }
+    Ok(Value::Number(total as f64))
 }
 
-pub fn math_pi(_args: &[Value]) -> LuaResult<Value> {
-    Ok(Value::Number(std::f64::consts::PI))
+/// `string.unpack(fmt, s [, pos])`: the values packed in `s` from byte
+/// `pos`, followed by the position after the last one read.
+pub fn string_unpack(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let format = bytes_argument(args, 0, "unpack")?;
+    let data = bytes_argument(args, 1, "unpack")?;
+    let start = optional_integer(args, 2, 1, "unpack")?;
+    let len = data.len() as i64;
+    let mut position = match start {
+        n if n > 0 => n - 1,
+        n if n < -len => 0,
+        0 => 0,
+        n => len + n,
+    } as usize;
+    if position > data.len() {
+        return Err(LuaError::bad_argument(3, "unpack", "initial position out of string"));
+    }
+
+    let too_short = || LuaError::bad_argument(2, "unpack", "data string too short");
+    let mut options = pack::Format::new(&format);
+    let mut results = Vec::new();
+    while let Some(item) = options.next_item(position) {
+        let item = item.map_err(|e| pack_format_error(e, "unpack"))?;
+        let little_endian = options.little_endian();
+        if item.padding + item.size > data.len() - position {
+            return Err(too_short());
+        }
+        position += item.padding;
+        let bytes = &data[position..position + item.size];
+        match item.kind {
+            pack::Kind::Int | pack::Kind::Unsigned => {
+                let n = pack::unpack_int(bytes, little_endian, item.kind == pack::Kind::Int)
+                    .map_err(|msg| LuaError::runtime_error(&msg))?;
+                results.push(Value::Number(n as f64));
+            }
+            pack::Kind::Float => {
+                let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+                let n = if little_endian { f32::from_le_bytes(bytes) } else { f32::from_be_bytes(bytes) };
+                results.push(Value::Number(n as f64));
+            }
+            pack::Kind::Double => {
+                let mut array = [0; 8];
+                array.copy_from_slice(bytes);
+                let n = if little_endian { f64::from_le_bytes(array) } else { f64::from_be_bytes(array) };
+                results.push(Value::Number(n));
+            }
+            pack::Kind::Fixed => results.push(Value::String(bytes.into())),
+            pack::Kind::Counted => {
+                let len = pack::unpack_int(bytes, little_endian, false).map_err(|msg| LuaError::runtime_error(&msg))?;
+                let start = position + item.size;
+                if (len as u64) > (data.len() - start) as u64 {
+                    return Err(too_short());
+                }
+                results.push(Value::String(data[start..start + len as usize].into()));
+                position += len as usize;
+            }
+            pack::Kind::Zero => {
+                let len = match data[position..].iter().position(|&b| b == 0) {
+                    Some(len) => len,
+                    None => return Err(LuaError::bad_argument(2, "unpack", "unfinished string for format 'z'")),
+                };
+                results.push(Value::String(data[position..position + len].into()));
+                position += len + 1;
+            }
+            pack::Kind::Padding | pack::Kind::Align | pack::Kind::Nop => {}
+        }
+        position += item.size;
+    }
+    results.push(Value::Number((position + 1) as f64));
+    Ok(results)
 }
 
-pub fn math_random(args: &[Value]) -> LuaResult<Value> {
-    use std::collections::hash_map::DefaultHasher;
-    use std::hash::{Hash, Hasher};
+// UTF-8 function implementations
 
-    // Simple deterministic "random" for now
-    let mut hasher = DefaultHasher::new();
-    std::ptr::addr_of!(args).hash(&mut hasher);
-    let random_val = (hasher.finish() % 10000) as f64 / 10000.0;
+/// Pattern matching exactly one UTF-8 byte sequence, `utf8.charpattern`.
+pub const UTF8_CHARPATTERN: &[u8] = b"[\0-\x7F\xC2-\xFD][\x80-\xBF]*";
 
-    match args.len() {
-        0 => Ok(Value::Number(random_val)),
-        1 => {
-            if let Some(max) = args[0].to_number() {
-                Ok(Value::Number((random_val * max).floor() + 1.0))
-            } else {
-                Err(LuaError::type_error("number", args[0].type_name(), "math.random"))
+// Largest code the original UTF-8 can encode, in up to six bytes
+const MAX_UTF: i64 = 0x7FFF_FFFF;
+const MAX_UNICODE: u32 = 0x10_FFFF;
+
+fn is_continuation(s: &[u8], index: usize) -> bool {
+    s.get(index).is_some_and(|&b| b & 0xC0 == 0x80)
+}
+
+/// Decodes the sequence at the start of `s` into its code and length.
+/// Strict decoding also rejects surrogates and codes above U+10FFFF, which
+/// the `lax` variants accept.
+fn utf8_decode(s: &[u8], strict: bool) -> Option<(u32, usize)> {
+    // Smallest code each sequence length may encode, ruling out overlong forms
+    const LIMITS: [u32; 6] = [u32::MAX, 0x80, 0x800, 0x1_0000, 0x20_0000, 0x400_0000];
+    let mut c = *s.first()? as u32;
+    let mut code = c;
+    let mut count = 0;
+    if c >= 0x80 {
+        code = 0;
+        while c & 0x40 != 0 {
+            count += 1;
+            match s.get(count) {
+                Some(&byte) if count < LIMITS.len() && byte & 0xC0 == 0x80 => code = (code << 6) | (byte & 0x3F) as u32,
+                _ => return None,
             }
+            c <<= 1;
         }
-        2 => {
-            if let (Some(min), Some(max)) = (args[0].to_number(), args[1].to_number()) {
-                let range = max - min + 1.0;
-                Ok(Value::Number((random_val * range).floor() + min))
-            } else {
-                Err(LuaError::runtime_error("math.random expects numbers"))
-            }
+        code |= (c & 0x7F) << (count * 5);
+        if code as i64 > MAX_UTF || code < LIMITS[count] {
+            return None;
         }
-        _ => Err(LuaError::argument_error(2, args.len(), "math.random")),
     }
+    if strict && (code > MAX_UNICODE || (0xD800..=0xDFFF).contains(&code)) {
+        return None;
+    }
+    Some((code, count + 1))
 }
 
-// Table function implementations
-pub fn table_insert(args: &[Value]) -> LuaResult<Value> {
-    if args.len() < 2 || args.len() > 3 {
-        return Err(LuaError::argument_error(2, args.len(), "table.insert"));
+// Converts a 1-based position that counts from the end when negative
+fn utf8_position(position: i64, len: usize) -> i64 {
+    match position {
+        n if n >= 0 => n,
+        n if n.unsigned_abs() > len as u64 => 0,
+        n => len as i64 + n + 1,
     }
+}
 
-    if let Value::Table(mut table) = args[0].clone() {
-        if args.len() == 2 {
-            // Insert at end
-            let len = table.len();
-            table.insert((len + 1).to_string(), args[1].clone());
-        } else {
-            // Insert at position
-            if let Some(pos) = args[1].to_number() {
-                table.insert(pos.to_string(), args[2].clone());
-            } else {
-                return Err(LuaError::type_error("number", args[1].type_name(), "table.insert"));
-            }
+fn invalid_utf8(position: usize) -> LuaError {
+    LuaError::runtime_error(&format!("invalid UTF-8 code at position {}", position))
+}
+
+/// `utf8.char(...)`: the codes encoded and concatenated.
+pub fn utf8_char(args: &[Value]) -> LuaResult<Value> {
+    let mut result = Vec::with_capacity(args.len());
+    for index in 0..args.len() {
+        let code = integer_argument(args, index, "char")?;
+        if !(0..=MAX_UTF).contains(&code) {
+            return Err(LuaError::bad_argument(index + 1, "char", "value out of range"));
         }
-        Ok(Value::Table(table))
-    } else {
-        Err(LuaError::type_error("table", args[0].type_name(), "table.insert"))
+        crate::lexer::encode_utf8_extended(code as u32, &mut result);
     }
+    Ok(Value::String(result.into()))
 }
 
-pub fn table_remove(args: &[Value]) -> LuaResult<Value> {
-    if args.is_empty() || args.len() > 2 {
-        return Err(LuaError::argument_error(1, args.len(), "table.remove"));
+/// `utf8.codepoint(s [, i [, j [, lax]]])`: the codes of the characters
+/// starting between bytes `i` and `j`.
+pub fn utf8_codepoint(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = bytes_argument(args, 0, "codepoint")?;
+    let start = utf8_position(optional_integer(args, 1, 1, "codepoint")?, s.len());
+    let end = utf8_position(optional_integer(args, 2, start, "codepoint")?, s.len());
+    let strict = !args.get(3).is_some_and(Value::is_truthy);
+    if start < 1 {
+        return Err(LuaError::bad_argument(2, "codepoint", "out of bounds"));
+    }
+    if end > s.len() as i64 {
+        return Err(LuaError::bad_argument(3, "codepoint", "out of bounds"));
     }
 
-    if let Value::Table(mut table) = args[0].clone() {
-        let pos = if args.len() == 2 {
-            match args[1].to_number() {
-                Some(n) => n.to_string(),
-                None => return Err(LuaError::type_error("number", args[1].type_name(), "table.remove")),
+    let mut codes = Vec::new();
+    let mut position = start as usize - 1;
+    while (position as i64) < end {
+        match utf8_decode(&s[position..], strict) {
+            Some((code, len)) => {
+                codes.push(Value::Number(code as f64));
+                position += len;
             }
-        } else {
-            table.len().to_string()
-        };
-
-        Ok(table.remove(&pos).unwrap_or(Value::Nil))
-    } else {
-        Err(LuaError::type_error("table", args[0].type_name(), "table.remove"))
+            None => return Err(invalid_utf8(position + 1)),
+        }
     }
+    Ok(codes)
 }
 
-pub fn table_concat(args: &[Value]) -> LuaResult<Value> {
-    if args.is_empty() || args.len() > 4 {
-        return Err(LuaError::argument_error(1, args.len(), "table.concat"));
+/// `utf8.len(s [, i [, j [, lax]]])`: the number of characters starting
+/// between bytes `i` and `j`, or nil and the position of the first invalid
+/// byte.
+pub fn utf8_len(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = bytes_argument(args, 0, "len")?;
+    let len = s.len() as i64;
+    let start = utf8_position(optional_integer(args, 1, 1, "len")?, s.len());
+    let end = utf8_position(optional_integer(args, 2, -1, "len")?, s.len());
+    let strict = !args.get(3).is_some_and(Value::is_truthy);
+    if start < 1 || start - 1 > len {
+        return Err(LuaError::bad_argument(2, "len", "initial position out of bounds"));
+    }
+    if end > len {
+        return Err(LuaError::bad_argument(3, "len", "final position out of bounds"));
     }
 
-    if let Value::Table(table) = &args[0] {
-        let sep = if args.len() > 1 {
-            match &args[1] {
-                Value::String(s) => s.clone(),
-                _ => return Err(LuaError::type_error("string", args[1].type_name(), "table.concat")),
-            }
-        } else {
-            String::new()
-        };
+    let mut count = 0;
+    let mut position = start - 1;
+    while position < end {
+        match utf8_decode(&s[position as usize..], strict) {
+            Some((_, len)) => position += len as i64,
+            None => return Ok(vec![Value::Nil, Value::Number((position + 1) as f64)]),
+        }
+        count += 1;
+    }
+    Ok(vec![Value::Number(count as f64)])
+}
+
+/// `utf8.offset(s, n [, i])`: the byte position where the `n`th character
+/// counting from byte `i` starts. `n = 0` finds the start of the character
+/// containing byte `i`.
+pub fn utf8_offset(args: &[Value]) -> LuaResult<Value> {
+    let s = bytes_argument(args, 0, "offset")?;
+    let len = s.len() as i64;
+    let mut n = integer_argument(args, 1, "offset")?;
+    let default = if n >= 0 { 1 } else { len + 1 };
+    let mut position = utf8_position(optional_integer(args, 2, default, "offset")?, s.len()) - 1;
+    if position < 0 || position > len {
+        return Err(LuaError::bad_argument(3, "offset", "position out of bounds"));
+    }
 
-        let mut result = String::new();
-        let values: Vec<_> = table.values().collect();
-        for (i, value) in values.iter().enumerate() {
-            if i > 0 {
-                result.push_str(&sep);
+    let continuation = |position: i64| is_continuation(&s, position as usize);
+    if n == 0 {
+        while position > 0 && continuation(position) {
+            position -= 1;
+        }
+    } else if continuation(position) {
+        return Err(LuaError::runtime_error("initial position is a continuation byte"));
+    } else if n < 0 {
+        while n < 0 && position > 0 {
+            position -= 1;
+            while position > 0 && continuation(position) {
+                position -= 1;
             }
-            result.push_str(&value.to_string());
+            n += 1;
         }
-
-        Ok(Value::String(result))
     } else {
-        Err(LuaError::type_error("table", args[0].type_name(), "table.concat"))
+        // The first character is the one at `i`
+        n -= 1;
+        while n > 0 && position < len {
+            position += 1;
+            while continuation(position) {
+                position += 1;
+            }
+            n -= 1;
+        }
     }
+    Ok(if n == 0 { Value::Number((position + 1) as f64) } else { Value::Nil })
 }
 
-pub fn table_sort(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified implementation
-    Err(LuaError::runtime_error("table.sort not implemented"))
+/// `utf8.codes(s [, lax])`: an iterator over the positions and codes of the
+/// characters in `s`.
+pub fn utf8_codes(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let s = bytes_argument(args, 0, "codes")?;
+    let lax = args.get(1).is_some_and(Value::is_truthy);
+    if is_continuation(&s, 0) {
+        return Err(LuaError::bad_argument(1, "codes", "invalid UTF-8 code"));
+    }
+    let iterator = runtime.create_function("utf8.codes", utf8_codes_next, vec![Value::Boolean(lax)]);
+    Ok(vec![Value::Native(iterator), Value::String(s), Value::Number(0.0)])
 }
 
-// IO function implementations
-pub fn io_write(args: &[Value]) -> LuaResult<Value> {
-    for arg in args {
-        print!("{}", arg);
+// Called with the string and the position of the previous character
+fn utf8_codes_next(
+    _runtime: &mut crate::runtime::LuaJitRuntime,
+    upvalues: &[Value],
+    args: &[Value],
+) -> LuaResult<Vec<Value>> {
+    let strict = !matches!(upvalues.first(), Some(Value::Boolean(true)));
+    let s = bytes_argument(args, 0, "for iterator")?;
+    let mut position = match args.get(1).and_then(Value::to_number) {
+        Some(n) if n >= 0.0 => n as usize,
+        _ => s.len(),
+    };
+    while position < s.len() && is_continuation(&s, position) {
+        position += 1;
+    }
+    if position >= s.len() {
+        return Ok(vec![Value::Nil]);
+    }
+    match utf8_decode(&s[position..], strict) {
+        Some((code, len)) if !is_continuation(&s, position + len) => {
+            Ok(vec![Value::Number((position + 1) as f64), Value::Number(code as f64)])
+        }
+        _ => Err(invalid_utf8(position + 1)),
     }
-    Ok(Value::Nil)
 }
 
-pub fn io_read(_args: &[Value]) -> LuaResult<Value> {
-    // Simplified implementation
-    Err(LuaError::runtime_error("io.read not implemented"))
+// Math function implementations
+//
+// Luna deliberately has no integer subtype: every number is an f64, unlike
+// Lua 5.4. The functions that deal in integers treat a float with an
+// integral value as one. So `math.type(1.0)` is "integer" where 5.4 says
+// "float", `math.maxinteger` and `math.mininteger` are +-2^53, the bounds
+// within which every integer is exact, and integers beyond those round like
+// any other float.
+
+/// The integer `n` represents exactly, if any.
+fn float_to_integer(n: f64) -> Option<i64> {
+    // The upper bound is 2^63, which is not itself an i64
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < -(i64::MIN as f64) {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+/// Integral results drop the sign of a negative zero, which the integers
+/// of reference Lua do not have; the rest are unchanged.
+fn push_number_integer(n: f64) -> Value {
+    match float_to_integer(n) {
+        Some(i) => Value::Number(i as f64),
+        None => Value::Number(n),
+    }
+}
+
+fn math_unary(args: &[Value], function: &str, op: fn(f64) -> f64) -> LuaResult<Value> {
+    Ok(Value::Number(op(number_argument(args, 0, function)?)))
+}
+
+pub fn math_abs(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "abs", f64::abs)
+}
+
+pub fn math_ceil(args: &[Value]) -> LuaResult<Value> {
+    Ok(push_number_integer(number_argument(args, 0, "ceil")?.ceil()))
+}
+
+pub fn math_floor(args: &[Value]) -> LuaResult<Value> {
+    Ok(push_number_integer(number_argument(args, 0, "floor")?.floor()))
+}
+
+/// `math.max(x, ...)`: the largest argument, returned as given.
+pub fn math_max(args: &[Value]) -> LuaResult<Value> {
+    let mut max = number_argument(args, 0, "max")?;
+    let mut chosen = 0;
+    for i in 1..args.len() {
+        let n = number_argument(args, i, "max")?;
+        if n > max {
+            max = n;
+            chosen = i;
+        }
+    }
+    Ok(args[chosen].clone())
+}
+
+/// `math.min(x, ...)`: the smallest argument, returned as given.
+pub fn math_min(args: &[Value]) -> LuaResult<Value> {
+    let mut min = number_argument(args, 0, "min")?;
+    let mut chosen = 0;
+    for i in 1..args.len() {
+        let n = number_argument(args, i, "min")?;
+        if n < min {
+            min = n;
+            chosen = i;
+        }
+    }
+    Ok(args[chosen].clone())
+}
+
+/// `math.fmod(x, y)`: the remainder of `x / y` rounding the quotient
+/// towards zero.
+pub fn math_fmod(args: &[Value]) -> LuaResult<Value> {
+    let x = number_argument(args, 0, "fmod")?;
+    let y = number_argument(args, 1, "fmod")?;
+    if float_to_integer(x).is_some() && float_to_integer(y) == Some(0) {
+        return Err(LuaError::bad_argument(2, "fmod", "zero"));
+    }
+    Ok(Value::Number(x % y))
+}
+
+/// `math.modf(x)`: the integral and fractional parts of `x`.
+pub fn math_modf(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let x = number_argument(args, 0, "modf")?;
+    let integral = x.trunc();
+    let fraction = if integral == x { 0.0 } else { x - integral };
+    Ok(vec![Value::Number(integral), Value::Number(fraction)])
+}
+
+pub fn math_sqrt(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "sqrt", f64::sqrt)
+}
+
+pub fn math_exp(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "exp", f64::exp)
+}
+
+pub fn math_deg(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "deg", f64::to_degrees)
+}
+
+pub fn math_rad(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "rad", f64::to_radians)
+}
+
+/// `math.log(x [, base])`: the logarithm of `x`, natural by default.
+pub fn math_log(args: &[Value]) -> LuaResult<Value> {
+    let x = number_argument(args, 0, "log")?;
+    let log = match args.get(1) {
+        None | Some(Value::Nil) => x.ln(),
+        Some(_) => match number_argument(args, 1, "log")? {
+            2.0 => x.log2(),
+            10.0 => x.log10(),
+            base => x.ln() / base.ln(),
+        },
+    };
+    Ok(Value::Number(log))
+}
+
+pub fn math_sin(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "sin", f64::sin)
+}
+
+pub fn math_cos(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "cos", f64::cos)
+}
+
+pub fn math_tan(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "tan", f64::tan)
+}
+
+pub fn math_asin(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "asin", f64::asin)
+}
+
+pub fn math_acos(args: &[Value]) -> LuaResult<Value> {
+    math_unary(args, "acos", f64::acos)
+}
+
+/// `math.atan(y [, x])`: the arc tangent of `y / x`, using the signs of
+/// both to find the quadrant.
+pub fn math_atan(args: &[Value]) -> LuaResult<Value> {
+    let y = number_argument(args, 0, "atan")?;
+    let x = match args.get(1) {
+        None | Some(Value::Nil) => 1.0,
+        Some(_) => number_argument(args, 1, "atan")?,
+    };
+    Ok(Value::Number(y.atan2(x)))
+}
+
+/// `math.ult(m, n)`: whether `m < n` when both are read as unsigned.
+pub fn math_ult(args: &[Value]) -> LuaResult<Value> {
+    let m = integer_argument(args, 0, "ult")?;
+    let n = integer_argument(args, 1, "ult")?;
+    Ok(Value::Boolean((m as u64) < (n as u64)))
+}
+
+/// `math.tointeger(x)`: `x` as a number if it has an exact integer value,
+/// otherwise nil.
+pub fn math_tointeger(args: &[Value]) -> LuaResult<Value> {
+    let integer = match args.first() {
+        Some(value @ (Value::Number(_) | Value::String(_))) => value.to_number().and_then(float_to_integer),
+        Some(_) => None,
+        None => return Err(LuaError::bad_argument(1, "tointeger", "value expected")),
+    };
+    Ok(integer.map_or(Value::Nil, |i| Value::Number(i as f64)))
+}
+
+/// `math.type(x)`: "integer" when `x` has an integral value, "float" for
+/// other numbers, or nil when `x` is not a number. This looks at the value
+/// rather than a subtype, so `math.type(1.0)` is "integer".
+pub fn math_type(args: &[Value]) -> LuaResult<Value> {
+    match args.first() {
+        Some(Value::Number(n)) if float_to_integer(*n).is_some() => Ok(Value::String("integer".into())),
+        Some(Value::Number(_)) => Ok(Value::String("float".into())),
+        Some(_) => Ok(Value::Nil),
+        None => Err(LuaError::bad_argument(1, "type", "value expected")),
+    }
+}
+
+/// `math.random([m [, n]])`: a float in [0, 1) without arguments, otherwise
+/// an integer in [m, n], where `m` defaults to 1. `math.random(0)` gives an
+/// integer made of all 64 random bits, but it comes back as a float like
+/// every number here: values beyond 2^53 round to the nearest float, which
+/// drops up to 11 of the low bits, so the result is not uniform over all
+/// 64-bit integers. Ranges wider than 2^53 lose precision the same way.
+pub fn math_random(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let (low, high) = match args.len() {
+        0 => return Ok(vec![Value::Number(runtime.random().next_float())]),
+        1 => {
+            let high = integer_argument(args, 0, "random")?;
+            if high == 0 {
+                return Ok(vec![Value::Number(runtime.random().next_u64() as i64 as f64)]);
+            }
+            (1, high)
+        }
+        2 => (integer_argument(args, 0, "random")?, integer_argument(args, 1, "random")?),
+        _ => return Err(LuaError::runtime_error("wrong number of arguments")),
+    };
+    if low > high {
+        return Err(LuaError::bad_argument(1, "random", "range is empty"));
+    }
+
+    // Work unsigned so that ranges wider than i64::MAX still fit
+    let random = runtime.random();
+    let value = random.next_u64();
+    let offset = random.project(value, (high as u64).wrapping_sub(low as u64));
+    Ok(vec![Value::Number(offset.wrapping_add(low as u64) as i64 as f64)])
+}
+
+/// `math.randomseed([x [, y]])`: reseeds the generator, from the clock when
+/// called without arguments, and returns the two seed components.
+pub fn math_randomseed(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let (n1, n2) = if args.is_empty() {
+        let (random, n1, n2) = Random::from_time();
+        *runtime.random() = random;
+        (n1, n2)
+    } else {
+        let n1 = integer_argument(args, 0, "randomseed")? as u64;
+        let n2 = optional_integer(args, 1, 0, "randomseed")? as u64;
+        *runtime.random() = Random::seeded(n1, n2);
+        (n1, n2)
+    };
+    Ok(vec![Value::Number(n1 as i64 as f64), Value::Number(n2 as i64 as f64)])
+}
+
+// Table function implementations
+fn table_argument(args: &[Value], index: usize, function: &str) -> LuaResult<TableRef> {
+    match args.get(index) {
+        Some(Value::Table(table)) => Ok(table.clone()),
+        _ => Err(expected("table", args, index, function)),
+    }
+}
+
+fn sequence_length(table: &TableRef) -> i64 {
+    table.borrow().border() as i64
+}
+
+fn key(i: i64) -> Value {
+    Value::Number(i as f64)
+}
+
+/// `table.insert(list, [pos,] value)`: inserts `value` at `pos`, shifting
+/// up the elements after it. Appends when `pos` is omitted.
+pub fn table_insert(args: &[Value]) -> LuaResult<Value> {
+    let table = table_argument(args, 0, "insert")?;
+    let end = sequence_length(&table) + 1;
+    let position = match args.len() {
+        2 => end,
+        3 => {
+            let position = integer_argument(args, 1, "insert")?;
+            // Unsigned, so that positions below 1 are out of bounds too
+            if (position as u64).wrapping_sub(1) >= end as u64 {
+                return Err(LuaError::bad_argument(2, "insert", "position out of bounds"));
+            }
+            for i in (position + 1..=end).rev() {
+                table.set(key(i), table.get(&key(i - 1)));
+            }
+            position
+        }
+        _ => return Err(LuaError::runtime_error("wrong number of arguments to 'insert'")),
+    };
+    table.set(key(position), args[args.len() - 1].clone());
+    Ok(Value::Nil)
+}
+
+/// `table.remove(list [, pos])`: removes and returns the element at `pos`,
+/// shifting down the elements after it. Removes the last one by default.
+pub fn table_remove(args: &[Value]) -> LuaResult<Value> {
+    let table = table_argument(args, 0, "remove")?;
+    let size = sequence_length(&table);
+    let mut position = optional_integer(args, 1, size, "remove")?;
+    // One past the end is allowed, as is position 0 of an empty list
+    if position != size && (position as u64).wrapping_sub(1) > size as u64 {
+        return Err(LuaError::bad_argument(2, "remove", "position out of bounds"));
+    }
+
+    let removed = table.get(&key(position));
+    while position < size {
+        table.set(key(position), table.get(&key(position + 1)));
+        position += 1;
+    }
+    table.set(key(position), Value::Nil);
+    Ok(removed)
+}
+
+/// `table.concat(list [, sep [, i [, j]]])`: `list[i]..sep..list[i+1]..sep..list[j]`.
+pub fn table_concat(args: &[Value]) -> LuaResult<Value> {
+    let table = table_argument(args, 0, "concat")?;
+    let separator = match args.get(1) {
+        None | Some(Value::Nil) => LuaString::from(""),
+        Some(_) => bytes_argument(args, 1, "concat")?,
+    };
+    let first = optional_integer(args, 2, 1, "concat")?;
+    let last = match args.get(3) {
+        None | Some(Value::Nil) => sequence_length(&table),
+        Some(_) => integer_argument(args, 3, "concat")?,
+    };
+
+    let mut result = Vec::new();
+    let mut i = first;
+    while i <= last {
+        match table.get(&key(i)) {
+            value @ (Value::String(_) | Value::Number(_)) => push_concat_operand(&mut result, &value),
+            _ => {
+                let msg = format!("invalid value (at index {}) in table for 'concat'", i);
+                return Err(LuaError::runtime_error(&msg));
+            }
+        }
+        if i == last {
+            break;
+        }
+        result.extend_from_slice(&separator);
+        i += 1;
+    }
+    Ok(Value::String(result.into()))
+}
+
+/// `table.unpack(list [, i [, j]])`: the elements from `list[i]` to `list[j]`.
+pub fn table_unpack(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let table = table_argument(args, 0, "unpack")?;
+    let first = optional_integer(args, 1, 1, "unpack")?;
+    let last = match args.get(2) {
+        None | Some(Value::Nil) => sequence_length(&table),
+        Some(_) => integer_argument(args, 2, "unpack")?,
+    };
+    if first > last {
+        return Ok(Vec::new());
+    }
+    let count = (last as u64).wrapping_sub(first as u64);
+    if count >= i32::MAX as u64 {
+        return Err(LuaError::runtime_error("too many results to unpack"));
+    }
+    let table = table.borrow();
+    Ok((first..=last).map(|i| table.get(&key(i))).collect())
+}
+
+/// `table.pack(...)`: a new table with the arguments at 1, 2, ... and their
+/// count in field `n`.
+pub fn table_pack(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let table = runtime.create_table();
+    {
+        let mut table = table.borrow_mut();
+        for (i, value) in args.iter().enumerate() {
+            table.set(key(i as i64 + 1), value.clone());
+        }
+        table.set(Value::String("n".into()), key(args.len() as i64));
+    }
+    Ok(vec![Value::Table(table)])
+}
+
+/// `table.move(a1, f, e, t [, a2])`: copies `a1[f..e]` to `a2[t..]` and
+/// returns `a2`, which defaults to `a1`. Overlapping ranges are copied in
+/// the order that keeps the source intact.
+pub fn table_move(args: &[Value]) -> LuaResult<Value> {
+    let source = table_argument(args, 0, "move")?;
+    let first = integer_argument(args, 1, "move")?;
+    let last = integer_argument(args, 2, "move")?;
+    let target = integer_argument(args, 3, "move")?;
+    let destination = match args.get(4) {
+        None | Some(Value::Nil) => source.clone(),
+        Some(_) => table_argument(args, 4, "move")?,
+    };
+
+    if last >= first {
+        if first <= 0 && last >= i64::MAX + first {
+            return Err(LuaError::bad_argument(3, "move", "too many elements to move"));
+        }
+        let count = last - first;
+        if target > i64::MAX - count {
+            return Err(LuaError::bad_argument(4, "move", "destination wrap around"));
+        }
+        if target > last || target <= first || !destination.ptr_eq(&source) {
+            for i in 0..=count {
+                destination.set(key(target + i), source.get(&key(first + i)));
+            }
+        } else {
+            for i in (0..=count).rev() {
+                destination.set(key(target + i), source.get(&key(first + i)));
+            }
+        }
+    }
+    Ok(Value::Table(destination))
+}
+
+/// `table.sort(list [, comp])`: sorts `list[1..#list]` in place, using
+/// `comp(a, b)` as "a comes before b" when given and `<` otherwise.
+pub fn table_sort(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let table = table_argument(args, 0, "sort")?;
+    let size = sequence_length(&table);
+    if size > 1 {
+        if size >= i32::MAX as i64 {
+            return Err(LuaError::bad_argument(1, "sort", "array too big"));
+        }
+        let comparator = match args.get(1) {
+            None | Some(Value::Nil) => Value::Nil,
+            Some(function @ (Value::Native(_) | Value::Closure(_))) => function.clone(),
+            Some(_) => return Err(expected("function", args, 1, "sort")),
+        };
+
+        let mut values: Vec<Value> = (1..=size).map(|i| table.get(&key(i))).collect();
+        let mut sorter = Sorter { runtime, comparator: &comparator };
+        let last = values.len() - 1;
+        sorter.sort(&mut values, 0, last)?;
+        let mut table = table.borrow_mut();
+        for (i, value) in values.into_iter().enumerate() {
+            table.set(key(i as i64 + 1), value);
+        }
+    }
+    Ok(Vec::new())
+}
+
+/// The quicksort of the reference implementation, which notices comparators
+/// that are not a strict order instead of running off the ends.
+struct Sorter<'a> {
+    runtime: &'a mut crate::runtime::LuaJitRuntime,
+    comparator: &'a Value,
+}
+
+impl Sorter<'_> {
+    fn less(&mut self, a: &Value, b: &Value) -> LuaResult<bool> {
+        if !matches!(self.comparator, Value::Nil) {
+            let results = self.runtime.call_function(self.comparator, &[a.clone(), b.clone()])?;
+            return Ok(results.first().is_some_and(Value::is_truthy));
+        }
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => Ok(a < b),
+            (Value::String(a), Value::String(b)) => Ok(a < b),
+            _ if a.type_name() == b.type_name() => {
+                Err(LuaError::runtime_error(&format!("attempt to compare two {} values", a.type_name())))
+            }
+            _ => {
+                let msg = format!("attempt to compare {} with {}", a.type_name(), b.type_name());
+                Err(LuaError::runtime_error(&msg))
+            }
+        }
+    }
+
+    fn sort(&mut self, values: &mut [Value], mut low: usize, mut high: usize) -> LuaResult<()> {
+        while low < high {
+            // Order the first, middle and last elements; the middle one is
+            // then the pivot
+            if self.less(&values[high], &values[low])? {
+                values.swap(low, high);
+            }
+            if high - low == 1 {
+                break;
+            }
+            let middle = low + (high - low) / 2;
+            if self.less(&values[middle], &values[low])? {
+                values.swap(middle, low);
+            } else if self.less(&values[high], &values[middle])? {
+                values.swap(middle, high);
+            }
+            if high - low == 2 {
+                break;
+            }
+            let pivot = values[middle].clone();
+            values.swap(middle, high - 1);
+            let split = self.partition(values, low, high, &pivot)?;
+
+            // Recurse into the smaller half and loop over the larger one
+            if split - low < high - split {
+                self.sort(values, low, split - 1)?;
+                low = split + 1;
+            } else {
+                self.sort(values, split + 1, high)?;
+                high = split - 1;
+            }
+        }
+        Ok(())
+    }
+
+    // Partitions `values[low..=high]` around the pivot stored at `high - 1`,
+    // returning where the pivot ends up
+    fn partition(&mut self, values: &mut [Value], low: usize, high: usize, pivot: &Value) -> LuaResult<usize> {
+        let mut i = low;
+        let mut j = high - 1;
+        loop {
+            i += 1;
+            while self.less(&values[i], pivot)? {
+                if i == high - 1 {
+                    return Err(LuaError::runtime_error("invalid order function for sorting"));
+                }
+                i += 1;
+            }
+            j -= 1;
+            while self.less(pivot, &values[j])? {
+                if j < i {
+                    return Err(LuaError::runtime_error("invalid order function for sorting"));
+                }
+                j -= 1;
+            }
+            if j < i {
+                values.swap(high - 1, i);
+                return Ok(i);
+            }
+            values.swap(i, j);
+        }
+    }
+}
+
+// IO function implementations
+
+/// Registry key of the metatable shared by file handles.
+pub const FILE_HANDLE: &str = "FILE*";
+
+/// Registry keys of the default input and output files.
+pub const IO_INPUT: &str = "_IO_input";
+pub const IO_OUTPUT: &str = "_IO_output";
+
+/// Registry key of a weak-keyed table of every file handle opened, which
+/// `os.exit` flushes.
+pub const IO_FILES: &str = "_IO_files";
+
+/// The file handle at `index`, open or closed.
+fn file_handle(args: &[Value], index: usize, function: &str) -> LuaResult<UserdataRef> {
+    match args.get(index) {
+        Some(Value::Userdata(userdata)) if userdata.is::<LuaFile>() => Ok(userdata.clone()),
+        _ => Err(expected("FILE*", args, index, function)),
+    }
+}
+
+fn open_file(args: &[Value], index: usize, function: &str) -> LuaResult<UserdataRef> {
+    let handle = file_handle(args, index, function)?;
+    if handle.borrow::<LuaFile>()?.is_closed() {
+        return Err(LuaError::runtime_error("attempt to use a closed file"));
+    }
+    Ok(handle)
+}
+
+/// The default input or output file, which must still be open.
+fn default_file(runtime: &crate::runtime::LuaJitRuntime, key: &str) -> LuaResult<UserdataRef> {
+    match runtime.registry_get(key) {
+        Value::Userdata(handle) if handle.is::<LuaFile>() && !handle.borrow::<LuaFile>()?.is_closed() => Ok(handle),
+        _ => {
+            let which = if key == IO_INPUT { "input" } else { "output" };
+            Err(LuaError::runtime_error(&format!("default {} file is closed", which)))
+        }
+    }
+}
+
+/// `value` on success, otherwise nil, the error message (prefixed with
+/// `path` if given) and the error number.
+fn file_result(result: io::Result<Value>, path: Option<&str>) -> Vec<Value> {
+    match result {
+        Ok(value) => vec![value],
+        Err(e) => {
+            let (message, code) = file::error_parts(&e);
+            let message = match path {
+                Some(path) => format!("{}: {}", path, message),
+                None => message,
+            };
+            vec![Value::Nil, Value::String(message.into()), Value::Number(code as f64)]
+        }
+    }
+}
+
+fn new_file(runtime: &mut crate::runtime::LuaJitRuntime, file: LuaFile) -> Value {
+    let metatable = match runtime.registry_get(FILE_HANDLE) {
+        Value::Table(metatable) => Some(metatable),
+        _ => None,
+    };
+    let handle = Value::Userdata(runtime.create_userdata(file, metatable));
+    if let Value::Table(files) = runtime.registry_get(IO_FILES) {
+        files.set(handle.clone(), Value::Boolean(true));
+    }
+    handle
+}
+
+fn parse_read_formats(formats: &[Value], function: &str) -> LuaResult<Vec<ReadFormat>> {
+    if formats.is_empty() {
+        return Ok(vec![ReadFormat::Line]);
+    }
+    let mut parsed = Vec::with_capacity(formats.len());
+    for (i, format) in formats.iter().enumerate() {
+        let format = match format {
+            Value::Number(_) => ReadFormat::Count(integer_argument(formats, i, function)?.max(0) as usize),
+            Value::String(s) => match ReadFormat::parse(s) {
+                Some(format) => format,
+                None => return Err(LuaError::bad_argument(i + 1, function, "invalid format")),
+            },
+            _ => return Err(expected("string", formats, i, function)),
+        };
+        parsed.push(format);
+    }
+    Ok(parsed)
+}
+
+/// Reads one value per format, stopping after the first that fails with a
+/// nil.
+fn read_values(handle: &UserdataRef, formats: &[ReadFormat]) -> LuaResult<io::Result<Vec<Value>>> {
+    let mut file = handle.borrow_mut::<LuaFile>()?;
+    let mut values = Vec::with_capacity(formats.len());
+    for format in formats {
+        let value = match *format {
+            ReadFormat::Number => file.read_numeral().map(|numeral| {
+                Value::String(numeral.into()).to_number().map(Value::Number)
+            }),
+            ReadFormat::Line => file.read_line(false).map(|line| line.map(|l| Value::String(l.into()))),
+            ReadFormat::LineWithNewline => file.read_line(true).map(|line| line.map(|l| Value::String(l.into()))),
+            ReadFormat::All => file.read_all().map(|all| Some(Value::String(all.into()))),
+            ReadFormat::Count(count) => file.read_count(count).map(|bytes| bytes.map(|b| Value::String(b.into()))),
+        };
+        match value {
+            Ok(Some(value)) => values.push(value),
+            Ok(None) => {
+                values.push(Value::Nil);
+                break;
+            }
+            Err(e) => return Ok(Err(e)),
+        }
+    }
+    Ok(Ok(values))
+}
+
+/// Writes strings and numbers, returning the file.
+fn write_values(handle: UserdataRef, values: &[Value]) -> LuaResult<Vec<Value>> {
+    for (i, value) in values.iter().enumerate() {
+        if !matches!(value, Value::String(_) | Value::Number(_)) {
+            return Err(expected("string", values, i, "write"));
+        }
+    }
+    let written = {
+        let mut file = handle.borrow_mut::<LuaFile>()?;
+        let mut buffer = Vec::new();
+        values.iter().try_for_each(|value| {
+            buffer.clear();
+            push_concat_operand(&mut buffer, value);
+            file.write(&buffer)
+        })
+    };
+    Ok(file_result(written.map(|_| Value::Userdata(handle)), None))
+}
+
+fn close_file(handle: &UserdataRef) -> LuaResult<Vec<Value>> {
+    let mut file = handle.borrow_mut::<LuaFile>()?;
+    if file.is_standard() {
+        return Ok(vec![Value::Nil, Value::String("cannot close standard file".into())]);
+    }
+    Ok(match file.close_with_status() {
+        Ok(Some(status)) => exec_result(Ok(status)),
+        closed => file_result(closed.map(|_| Value::Boolean(true)), None),
+    })
+}
+
+/// How a process ended, as `os.execute` and closing a pipe report it: true
+/// or nil, then `"exit"` and the exit code or `"signal"` and the signal.
+fn exec_result(status: io::Result<ExitStatus>) -> Vec<Value> {
+    match status {
+        Ok(status) => {
+            let (what, code) = os::termination(&status);
+            let success = if what == "exit" && code == 0 { Value::Boolean(true) } else { Value::Nil };
+            vec![success, Value::String(what.into()), Value::Number(code as f64)]
+        }
+        Err(e) => file_result(Err(e), None),
+    }
+}
+
+fn check_process_spawning(runtime: &crate::runtime::LuaJitRuntime, function: &str) -> LuaResult<()> {
+    if runtime.allows_process_spawning() {
+        Ok(())
+    } else {
+        Err(LuaError::runtime_error(&format!("'{}' is disabled in this runtime", function)))
+    }
+}
+
+fn lines_iterator(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    handle: UserdataRef,
+    close_at_end: bool,
+    formats: &[Value],
+) -> LuaResult<Vec<Value>> {
+    parse_read_formats(formats, "lines")?;
+    let mut upvalues = vec![Value::Userdata(handle), Value::Boolean(close_at_end)];
+    upvalues.extend_from_slice(formats);
+    Ok(vec![Value::Native(runtime.create_function("lines", io_lines_next, upvalues))])
+}
+
+// Upvalues are the file, whether to close it at the end, and the formats
+fn io_lines_next(
+    _runtime: &mut crate::runtime::LuaJitRuntime,
+    upvalues: &[Value],
+    _args: &[Value],
+) -> LuaResult<Vec<Value>> {
+    let handle = file_handle(upvalues, 0, "lines")?;
+    if handle.borrow::<LuaFile>()?.is_closed() {
+        return Err(LuaError::runtime_error("file is already closed"));
+    }
+    let formats = parse_read_formats(&upvalues[2..], "lines")?;
+    match read_values(&handle, &formats)? {
+        Ok(values) => {
+            let finished = matches!(values.first(), None | Some(Value::Nil));
+            if finished && matches!(upvalues[1], Value::Boolean(true)) {
+                let _ = handle.borrow_mut::<LuaFile>()?.close();
+            }
+            Ok(values)
+        }
+        Err(e) => Err(LuaError::runtime_error(&file::error_parts(&e).0)),
+    }
+}
+
+/// `io.open(filename [, mode])`: a new file handle, or nil, a message and
+/// an error number.
+pub fn io_open(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "open")?.to_str_lossy().into_owned();
+    let mode = match args.get(1) {
+        None | Some(Value::Nil) => "r".to_string(),
+        Some(_) => bytes_argument(args, 1, "open")?.to_str_lossy().into_owned(),
+    };
+    match LuaFile::open(&path, &mode) {
+        Some(Ok(file)) => Ok(vec![new_file(runtime, file)]),
+        Some(Err(e)) => Ok(file_result(Err(e), Some(&path))),
+        None => Err(LuaError::bad_argument(2, "open", "invalid mode")),
+    }
+}
+
+/// `io.close([file])`: closes `file`, or the default output file.
+pub fn io_close(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = match args.first() {
+        None | Some(Value::Nil) => default_file(runtime, IO_OUTPUT)?,
+        Some(_) => open_file(args, 0, "close")?,
+    };
+    close_file(&handle)
+}
+
+pub fn io_read(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = default_file(runtime, IO_INPUT)?;
+    let formats = parse_read_formats(args, "read")?;
+    Ok(read_values(&handle, &formats)?.unwrap_or_else(|e| file_result(Err(e), None)))
+}
+
+pub fn io_write(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    write_values(default_file(runtime, IO_OUTPUT)?, args)
+}
+
+pub fn io_flush(runtime: &mut crate::runtime::LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = default_file(runtime, IO_OUTPUT)?;
+    let flushed = handle.borrow_mut::<LuaFile>()?.flush();
+    Ok(file_result(flushed.map(|_| Value::Boolean(true)), None))
+}
+
+/// `io.lines([filename, ...])`: an iterator over the file, which it closes
+/// at the end, or over the default input file, which stays open.
+pub fn io_lines(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        None | Some(Value::Nil) => {
+            let handle = default_file(runtime, IO_INPUT)?;
+            lines_iterator(runtime, handle, false, args.get(1..).unwrap_or_default())
+        }
+        Some(_) => {
+            let path = bytes_argument(args, 0, "lines")?.to_str_lossy().into_owned();
+            let file = match LuaFile::open(&path, "r") {
+                Some(Ok(file)) => file,
+                Some(Err(e)) => return Err(LuaError::runtime_error(&format!("{}: {}", path, file::error_parts(&e).0))),
+                None => unreachable!("\"r\" is a valid mode"),
+            };
+            let handle = match new_file(runtime, file) {
+                Value::Userdata(handle) => handle,
+                _ => unreachable!(),
+            };
+            lines_iterator(runtime, handle, true, &args[1..])
+        }
+    }
+}
+
+// Shared by `io.input` and `io.output`: switches the default file to a
+// handle or a newly opened file and returns the current one
+fn set_default_file(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    args: &[Value],
+    key: &str,
+    mode: &str,
+    function: &str,
+) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        None | Some(Value::Nil) => {}
+        Some(Value::String(path)) => {
+            let path = path.to_str_lossy().into_owned();
+            let file = match LuaFile::open(&path, mode) {
+                Some(Ok(file)) => file,
+                Some(Err(e)) => {
+                    let msg = format!("cannot open file '{}' ({})", path, file::error_parts(&e).0);
+                    return Err(LuaError::runtime_error(&msg));
+                }
+                None => unreachable!("\"r\" and \"w\" are valid modes"),
+            };
+            let handle = new_file(runtime, file);
+            runtime.registry_set(key, handle);
+        }
+        Some(_) => {
+            let handle = open_file(args, 0, function)?;
+            runtime.registry_set(key, Value::Userdata(handle));
+        }
+    }
+    Ok(vec![runtime.registry_get(key)])
+}
+
+/// `io.input([file])`: sets the default input file and returns it.
+pub fn io_input(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    set_default_file(runtime, args, IO_INPUT, "r", "input")
+}
+
+/// `io.output([file])`: sets the default output file and returns it.
+pub fn io_output(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    set_default_file(runtime, args, IO_OUTPUT, "w", "output")
+}
+
+/// `io.popen(prog [, mode])`: runs `prog` through the shell, returning a
+/// handle that reads its output (mode `"r"`, the default) or writes its
+/// input (`"w"`). Closing the handle waits for the process and reports how
+/// it exited, as `os.execute` does.
+pub fn io_popen(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let program = bytes_argument(args, 0, "popen")?;
+    let mode = match args.get(1) {
+        None | Some(Value::Nil) => LuaString::from("r"),
+        Some(_) => bytes_argument(args, 1, "popen")?,
+    };
+    let mut command = os::shell(&program);
+    match &mode[..] {
+        b"r" => command.stdout(Stdio::piped()),
+        b"w" => command.stdin(Stdio::piped()),
+        _ => return Err(LuaError::bad_argument(2, "popen", "invalid mode")),
+    };
+    check_process_spawning(runtime, "popen")?;
+    let _ = io::stdout().flush();
+    match command.spawn() {
+        Ok(child) => Ok(vec![new_file(runtime, LuaFile::from_process(child))]),
+        Err(e) => Ok(file_result(Err(e), Some(&program.to_str_lossy()))),
+    }
+}
+
+/// `io.tmpfile()`: a new file opened for update, removed when closed.
+pub fn io_tmpfile(runtime: &mut crate::runtime::LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+    match LuaFile::temporary() {
+        Ok(file) => Ok(vec![new_file(runtime, file)]),
+        Err(e) => Ok(file_result(Err(e), None)),
+    }
+}
+
+/// `io.type(obj)`: "file", "closed file", or nil if `obj` is no file.
+pub fn io_type(args: &[Value]) -> LuaResult<Value> {
+    match args.first() {
+        Some(Value::Userdata(userdata)) if userdata.is::<LuaFile>() => {
+            let closed = userdata.borrow::<LuaFile>()?.is_closed();
+            Ok(Value::String(if closed { "closed file" } else { "file" }.into()))
+        }
+        Some(_) => Ok(Value::Nil),
+        None => Err(LuaError::bad_argument(1, "type", "value expected")),
+    }
+}
+
+// File handle methods. Argument positions in errors count from the first
+// argument after the handle, as they do for method calls in Lua.
+
+pub fn file_close(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    close_file(&open_file(args, 0, "close")?)
+}
+
+pub fn file_flush(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = open_file(args, 0, "flush")?;
+    let flushed = handle.borrow_mut::<LuaFile>()?.flush();
+    Ok(file_result(flushed.map(|_| Value::Boolean(true)), None))
+}
+
+/// `file:lines(...)`: an iterator reading the given formats, leaving the
+/// file open at the end.
+pub fn file_lines(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = open_file(args, 0, "lines")?;
+    lines_iterator(runtime, handle, false, &args[1..])
+}
+
+/// `file:read(...)`: one value per format, `"l"` by default.
+pub fn file_read(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = open_file(args, 0, "read")?;
+    let formats = parse_read_formats(&args[1..], "read")?;
+    Ok(read_values(&handle, &formats)?.unwrap_or_else(|e| file_result(Err(e), None)))
+}
+
+/// `file:seek([whence [, offset]])`: moves to `offset` from the start
+/// (`"set"`), the current position (`"cur"`, the default) or the end
+/// (`"end"`), returning the new position from the start.
+pub fn file_seek(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = open_file(args, 0, "seek")?;
+    let rest = &args[1..];
+    let whence = match rest.first() {
+        None | Some(Value::Nil) => LuaString::from("cur"),
+        Some(_) => bytes_argument(rest, 0, "seek")?,
+    };
+    let offset = optional_integer(rest, 1, 0, "seek")?;
+    let position = match &whence[..] {
+        b"set" if offset < 0 => return Ok(file_result(Err(file::invalid_argument()), None)),
+        b"set" => io::SeekFrom::Start(offset as u64),
+        b"cur" => io::SeekFrom::Current(offset),
+        b"end" => io::SeekFrom::End(offset),
+        _ => {
+            let msg = format!("invalid option '{}'", whence.to_str_lossy());
+            return Err(LuaError::bad_argument(1, "seek", &msg));
+        }
+    };
+    let sought = handle.borrow_mut::<LuaFile>()?.seek(position);
+    Ok(file_result(sought.map(|offset| Value::Number(offset as f64)), None))
+}
+
+/// `file:setvbuf(mode [, size])`: buffering `"no"`, `"full"` or `"line"`.
+pub fn file_setvbuf(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = open_file(args, 0, "setvbuf")?;
+    let rest = &args[1..];
+    let mode = bytes_argument(rest, 0, "setvbuf")?;
+    let size = optional_integer(rest, 1, file::BUFFER_SIZE as i64, "setvbuf")?.max(1) as usize;
+    let buffering = match &mode[..] {
+        b"no" => Buffering::No,
+        b"full" => Buffering::Full(size),
+        b"line" => Buffering::Line(size),
+        _ => {
+            let msg = format!("invalid option '{}'", mode.to_str_lossy());
+            return Err(LuaError::bad_argument(1, "setvbuf", &msg));
+        }
+    };
+    let set = handle.borrow_mut::<LuaFile>()?.set_buffering(buffering);
+    Ok(file_result(set.map(|_| Value::Boolean(true)), None))
+}
+
+/// `file:write(...)`: writes strings and numbers, returning the file.
+pub fn file_write(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    write_values(open_file(args, 0, "write")?, &args[1..])
+}
+
+/// `__gc` of file handles: closes files the script forgot to close.
+pub fn file_gc(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    if let Some(Value::Userdata(handle)) = args.first() {
+        if let Ok(mut file) = handle.borrow_mut::<LuaFile>() {
+            if !file.is_standard() {
+                let _ = file.close();
+            }
+        }
+    }
+    Ok(Vec::new())
+}
+
+pub fn file_tostring(args: &[Value]) -> LuaResult<Value> {
+    let handle = file_handle(args, 0, "tostring")?;
+    let text = if handle.borrow::<LuaFile>()?.is_closed() {
+        "file (closed)".to_string()
+    } else {
+        format!("file ({:p})", handle.as_ptr())
+    };
+    Ok(Value::String(text.into()))
+}
+
+// OS function implementations
+
+/// `os.time([table])`: the current time, or the local time the fields of
+/// `table` describe. The fields are normalized in place, so that
+/// `{year = 2024, month = 1, day = 32}` becomes February 1st.
+pub fn os_time(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let table = match args.first() {
+        None | Some(Value::Nil) => {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |elapsed| elapsed.as_secs() as i64);
+            return Ok(vec![Value::Number(now as f64)]);
+        }
+        Some(Value::Table(table)) => table.clone(),
+        Some(_) => return Err(expected("table", args, 0, "time")),
+    };
+    let fields = os::DateTime {
+        year: date_field(&table, "year", None, 1900)?,
+        month: date_field(&table, "month", None, 1)?,
+        day: date_field(&table, "day", None, 0)?,
+        hour: date_field(&table, "hour", Some(12), 0)?,
+        min: date_field(&table, "min", Some(0), 0)?,
+        sec: date_field(&table, "sec", Some(0), 0)?,
+        isdst: match table.get_field("isdst") {
+            Value::Nil => None,
+            value => Some(value.is_truthy()),
+        },
+        ..os::DateTime::default()
+    };
+    let (time, normalized) = os::make_time(&fields)
+        .ok_or_else(|| LuaError::runtime_error("time result cannot be represented in this installation"))?;
+    set_date_fields(&table, &normalized);
+    Ok(vec![Value::Number(time as f64)])
+}
+
+// Reads a field of an `os.time` table, which must fit a C int once
+// `delta` is taken off (1900 for years, as `struct tm` counts them)
+fn date_field(table: &TableRef, name: &str, default: Option<i64>, delta: i64) -> LuaResult<i64> {
+    let value = table.get_field(name);
+    let n = match value.to_number().and_then(float_to_integer) {
+        Some(n) => n,
+        None => {
+            return match (value, default) {
+                (Value::Nil, Some(default)) => Ok(default),
+                (Value::Nil, None) => {
+                    Err(LuaError::runtime_error(&format!("field '{}' missing in date table", name)))
+                }
+                _ => Err(LuaError::runtime_error(&format!("field '{}' is not an integer", name))),
+            }
+        }
+    };
+    if n.checked_sub(delta).and_then(|n| i32::try_from(n).ok()).is_none() {
+        return Err(LuaError::runtime_error(&format!("field '{}' is out-of-bound", name)));
+    }
+    Ok(n)
+}
+
+fn set_date_fields(table: &TableRef, fields: &os::DateTime) {
+    for (name, value) in [
+        ("year", fields.year),
+        ("month", fields.month),
+        ("day", fields.day),
+        ("hour", fields.hour),
+        ("min", fields.min),
+        ("sec", fields.sec),
+        ("yday", fields.yday),
+        ("wday", fields.wday),
+    ] {
+        table.set(Value::String(name.into()), Value::Number(value as f64));
+    }
+    if let Some(isdst) = fields.isdst {
+        table.set(Value::String("isdst".into()), Value::Boolean(isdst));
+    }
+}
+
+/// `os.clock()`: processor time used by the program, in seconds.
+pub fn os_clock(_args: &[Value]) -> LuaResult<Value> {
+    Ok(Value::Number(os::cpu_time()))
+}
+
+/// `os.date([format [, time]])`: `time` formatted as `strftime` would, in
+/// UTC if `format` starts with `!`. The format `"*t"` returns a table of
+/// the date fields instead.
+pub fn os_date(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let format = match args.first() {
+        None | Some(Value::Nil) => LuaString::from("%c"),
+        Some(_) => bytes_argument(args, 0, "date")?,
+    };
+    let time = match args.get(1) {
+        None | Some(Value::Nil) => std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64),
+        Some(_) => integer_argument(args, 1, "date")?,
+    };
+    let (utc, format) = match format.strip_prefix(b"!") {
+        Some(rest) => (true, rest),
+        None => (false, &format[..]),
+    };
+    let calendar = os::Calendar::at(time, utc)
+        .ok_or_else(|| LuaError::runtime_error("date result cannot be represented in this installation"))?;
+    if format == b"*t" {
+        let table = runtime.create_table();
+        set_date_fields(&table, &calendar.fields());
+        return Ok(vec![Value::Table(table)]);
+    }
+
+    let mut out = Vec::new();
+    let mut rest = format;
+    while let Some((&c, tail)) = rest.split_first() {
+        if c != b'%' {
+            out.push(c);
+            rest = tail;
+            continue;
+        }
+        let len = os::conversion_length(tail).ok_or_else(|| {
+            let msg = format!("invalid conversion specifier '%{}'", String::from_utf8_lossy(tail));
+            LuaError::bad_argument(1, "date", &msg)
+        })?;
+        out.extend(calendar.format(&rest[..len + 1]));
+        rest = &tail[len..];
+    }
+    Ok(vec![Value::String(out.into())])
+}
+
+/// `os.difftime(t2, t1)`: the seconds from `t1` to `t2`.
+pub fn os_difftime(args: &[Value]) -> LuaResult<Value> {
+    let t2 = integer_argument(args, 0, "difftime")?;
+    let t1 = integer_argument(args, 1, "difftime")?;
+    Ok(Value::Number(t2 as f64 - t1 as f64))
+}
+
+/// `os.getenv(name)`: the value of an environment variable, or nil.
+pub fn os_getenv(args: &[Value]) -> LuaResult<Value> {
+    let name = bytes_argument(args, 0, "getenv")?;
+    Ok(os::getenv(&name).map_or(Value::Nil, |value| Value::String(value.into())))
+}
+
+/// `os.remove(filename)`: deletes a file or empty directory, returning true
+/// or nil, a message and an error number.
+pub fn os_remove(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "remove")?.to_str_lossy().into_owned();
+    let removed = std::fs::symlink_metadata(&path).and_then(|metadata| {
+        if metadata.is_dir() {
+            std::fs::remove_dir(&path)
+        } else {
+            std::fs::remove_file(&path)
+        }
+    });
+    Ok(file_result(removed.map(|_| Value::Boolean(true)), Some(&path)))
+}
+
+/// `os.rename(oldname, newname)`: true, or nil, a message and an error
+/// number.
+pub fn os_rename(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let from = bytes_argument(args, 0, "rename")?.to_str_lossy().into_owned();
+    let to = bytes_argument(args, 1, "rename")?.to_str_lossy().into_owned();
+    let renamed = std::fs::rename(&from, &to);
+    Ok(file_result(renamed.map(|_| Value::Boolean(true)), None))
+}
+
+/// `os.tmpname()`: the name of a new, empty file for temporary use, which
+/// the caller removes.
+pub fn os_tmpname(_args: &[Value]) -> LuaResult<Value> {
+    match file::create_temporary() {
+        Ok((_, path)) => Ok(Value::String(path.to_string_lossy().into_owned().into())),
+        Err(_) => Err(LuaError::runtime_error("unable to generate a unique filename")),
+    }
+}
+
+/// `os.execute([command])`: runs `command` through the shell and reports how
+/// it exited. Without a command, tells whether a shell is available.
+pub fn os_execute(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let command = match args.first() {
+        None | Some(Value::Nil) => return Ok(vec![Value::Boolean(runtime.allows_process_spawning())]),
+        Some(_) => bytes_argument(args, 0, "execute")?,
+    };
+    check_process_spawning(runtime, "execute")?;
+    let _ = io::stdout().flush();
+    Ok(exec_result(os::shell(&command).status()))
+}
+
+/// `os.exit([code [, close]])`: ends the program with `code` (true for
+/// success, the default, and false for failure), after flushing every open
+/// file. If `close` is true the state is closed first, running pending
+/// finalizers.
+pub fn os_exit(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let code = match args.first() {
+        Some(Value::Boolean(true)) => 0,
+        Some(Value::Boolean(false)) => 1,
+        _ => optional_integer(args, 0, 0, "exit")? as i32,
+    };
+    if args.get(1).is_some_and(Value::is_truthy) {
+        runtime.close_state();
+    }
+    if let Value::Table(files) = runtime.registry_get(IO_FILES) {
+        let handles: Vec<Value> = files.borrow().iter().map(|(key, _)| (*key).clone()).collect();
+        for handle in handles {
+            if let Value::Userdata(handle) = handle {
+                if let Ok(mut file) = handle.borrow_mut::<LuaFile>() {
+                    let _ = file.flush();
+                }
+            }
+        }
+    }
+    let _ = io::stdout().flush();
+    let _ = io::stderr().flush();
+    std::process::exit(code)
+}
+
+// Filesystem function implementations, after LuaFileSystem
+
+/// Registry key of the metatable shared by directory objects.
+pub const DIRECTORY_HANDLE: &str = "directory metatable";
+
+/// `fs.attributes(path [, name | table])`: the attributes of the file at
+/// `path` in a new table or `table`, or just the one called `name`.
+/// Returns nil, a message and an error number if there is no such file.
+pub fn fs_attributes(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    file_attributes(runtime, args, "attributes", false)
+}
+
+/// `fs.symlinkattributes(path [, name | table])`: like `fs.attributes`,
+/// but describes a symbolic link itself, adding its `target`.
+pub fn fs_symlinkattributes(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    file_attributes(runtime, args, "symlinkattributes", true)
+}
+
+fn file_attributes(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    args: &[Value],
+    function: &str,
+    link: bool,
+) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, function)?.to_str_lossy().into_owned();
+    let metadata = if link { std::fs::symlink_metadata(&path) } else { std::fs::metadata(&path) };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            let (message, code) = file::error_parts(&e);
+            let message = format!("cannot obtain information from file '{}': {}", path, message);
+            return Ok(vec![Value::Nil, Value::String(message.into()), Value::Number(code as f64)]);
+        }
+    };
+    let target = if link && metadata.file_type().is_symlink() {
+        std::fs::read_link(&path).ok().map(|target| Value::String(target.into_os_string().into_encoded_bytes().into()))
+    } else {
+        None
+    };
+    let attribute = |name: &str| match filesystem::attribute(&metadata, name) {
+        Some(filesystem::Attribute::Integer(n)) => Value::Number(n as f64),
+        Some(filesystem::Attribute::Text(text)) => Value::String(text.into()),
+        None => Value::Nil,
+    };
+
+    let table = match args.get(1) {
+        Some(Value::String(name)) => {
+            let name = name.to_str_lossy();
+            if link && name == "target" {
+                return Ok(vec![target.unwrap_or(Value::Nil)]);
+            }
+            if !filesystem::ATTRIBUTE_NAMES.contains(&&name[..]) {
+                return Err(LuaError::runtime_error(&format!("invalid attribute name '{}'", name)));
+            }
+            return Ok(vec![attribute(&name)]);
+        }
+        Some(Value::Table(table)) => table.clone(),
+        _ => runtime.create_table(),
+    };
+    for name in filesystem::ATTRIBUTE_NAMES {
+        table.set(Value::String((*name).into()), attribute(name));
+    }
+    if let Some(target) = target {
+        table.set(Value::String("target".into()), target);
+    }
+    Ok(vec![Value::Table(table)])
+}
+
+/// `fs.chdir(path)`: changes the working directory, returning true, or nil
+/// and a message.
+pub fn fs_chdir(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "chdir")?.to_str_lossy().into_owned();
+    match std::env::set_current_dir(&path) {
+        Ok(()) => Ok(vec![Value::Boolean(true)]),
+        Err(e) => {
+            let message = format!("Unable to change working directory to '{}'\n{}\n", path, file::error_parts(&e).0);
+            Ok(vec![Value::Nil, Value::String(message.into())])
+        }
+    }
+}
+
+/// `fs.currentdir()`: the working directory, or nil, a message and an
+/// error number.
+pub fn fs_currentdir(_runtime: &mut crate::runtime::LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+    let current = std::env::current_dir().map(|path| Value::String(path.into_os_string().into_encoded_bytes().into()));
+    Ok(file_result(current, None))
+}
+
+/// `fs.dir(path)`: an iterator over the names in a directory, and the
+/// directory object it steps through. Raises an error if the directory
+/// cannot be opened.
+pub fn fs_dir(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "dir")?.to_str_lossy().into_owned();
+    let directory = filesystem::Directory::open(&path).map_err(|e| {
+        LuaError::runtime_error(&format!("cannot open {}: {}", path, file::error_parts(&e).0))
+    })?;
+    let metatable = match runtime.registry_get(DIRECTORY_HANDLE) {
+        Value::Table(metatable) => Some(metatable),
+        _ => None,
+    };
+    let directory = Value::Userdata(runtime.create_userdata(directory, metatable));
+    let next = runtime.builtin("directory.next").unwrap_or(Value::Nil);
+    Ok(vec![next, directory])
+}
+
+/// `fs.mkdir(path)`: creates a directory, returning true, or nil, a message
+/// and an error number.
+pub fn fs_mkdir(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "mkdir")?.to_str_lossy().into_owned();
+    Ok(file_result(std::fs::create_dir(&path).map(|_| Value::Boolean(true)), None))
+}
+
+/// `fs.rmdir(path)`: removes an empty directory, returning true, or nil, a
+/// message and an error number.
+pub fn fs_rmdir(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "rmdir")?.to_str_lossy().into_owned();
+    Ok(file_result(std::fs::remove_dir(&path).map(|_| Value::Boolean(true)), None))
+}
+
+/// `fs.touch(path [, atime [, mtime]])`: sets the access and modification
+/// times of an existing file, both to now by default and `mtime` to `atime`
+/// if only that is given.
+pub fn fs_touch(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let path = bytes_argument(args, 0, "touch")?.to_str_lossy().into_owned();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let access = optional_integer(args, 1, now, "touch")?;
+    let modification = optional_integer(args, 2, access, "touch")?;
+    let touched = filesystem::touch(&path, access, modification);
+    Ok(file_result(touched.map(|_| Value::Boolean(true)), None))
+}
+
+// Directory object methods
+
+fn directory_handle(args: &[Value], function: &str) -> LuaResult<UserdataRef> {
+    match args.first() {
+        Some(Value::Userdata(userdata)) if userdata.is::<filesystem::Directory>() => Ok(userdata.clone()),
+        _ => Err(expected("directory", args, 0, function)),
+    }
+}
+
+/// The next name in the directory, or nothing at the end.
+pub fn directory_next(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = directory_handle(args, "next")?;
+    let mut directory = handle.borrow_mut::<filesystem::Directory>()?;
+    if directory.is_closed() {
+        return Err(LuaError::bad_argument(1, "next", "closed directory"));
+    }
+    match directory.next_name() {
+        Ok(Some(name)) => Ok(vec![Value::String(name.into())]),
+        Ok(None) => Ok(Vec::new()),
+        Err(e) => Err(LuaError::runtime_error(&file::error_parts(&e).0)),
+    }
+}
+
+/// Closes the directory; also its `__gc`.
+pub fn directory_close(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let handle = directory_handle(args, "close")?;
+    handle.borrow_mut::<filesystem::Directory>()?.close();
+    Ok(Vec::new())
+}
+
+// Package function implementations
+
+/// Registry keys of the package table, `package.loaded` and
+/// `package.preload`, which `require` keeps using even if scripts replace
+/// the fields.
+pub const PACKAGE: &str = "_PACKAGE";
+pub const LOADED: &str = "_LOADED";
+pub const PRELOAD: &str = "_PRELOAD";
+
+/// Registry key of the set of modules whose loaders are running, to catch
+/// modules that require each other.
+pub const LOADING: &str = "_LOADING";
+
+fn registry_table(runtime: &crate::runtime::LuaJitRuntime, key: &str) -> LuaResult<TableRef> {
+    match runtime.registry_get(key) {
+        Value::Table(table) => Ok(table),
+        _ => Err(LuaError::runtime_error(&format!("registry table '{}' is missing", key))),
+    }
+}
+
+/// `require(name)`: loads a module once, returning what its loader returned
+/// (or true) and the loader data, such as the file it came from.
+pub fn builtin_require(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let name = bytes_argument(args, 0, "require")?;
+    let key = Value::String(name.clone());
+    let loaded = registry_table(runtime, LOADED)?;
+    let module = loaded.get(&key);
+    if module.is_truthy() {
+        return Ok(vec![module]);
+    }
+    let loading = registry_table(runtime, LOADING)?;
+    if loading.get(&key).is_truthy() {
+        let msg = format!("loop detected requiring module '{}'", name.to_str_lossy());
+        return Err(LuaError::runtime_error(&msg));
+    }
+
+    let (loader, data) = find_loader(runtime, &name)?;
+    loading.set(key.clone(), Value::Boolean(true));
+    let results = runtime.call_function(&loader, &[key.clone(), data.clone()]);
+    loading.set(key.clone(), Value::Nil);
+    let results = results?;
+    if let Some(module) = results.into_iter().next().filter(|module| !matches!(module, Value::Nil)) {
+        loaded.set(key.clone(), module);
+    }
+    if matches!(loaded.get(&key), Value::Nil) {
+        loaded.set(key.clone(), Value::Boolean(true));
+    }
+    Ok(vec![loaded.get(&key), data])
+}
+
+// Asks each of `package.searchers` in turn for a loader, collecting what
+// the others say about where they looked
+fn find_loader(runtime: &mut crate::runtime::LuaJitRuntime, name: &LuaString) -> LuaResult<(Value, Value)> {
+    let searchers = match registry_table(runtime, PACKAGE)?.get_field("searchers") {
+        Value::Table(searchers) => searchers,
+        _ => return Err(LuaError::runtime_error("'package.searchers' must be a table")),
+    };
+    let mut message = String::new();
+    for i in 1.. {
+        let searcher = searchers.get(&key(i));
+        if matches!(searcher, Value::Nil) {
+            break;
+        }
+        let mut results = runtime
+            .call_function(&searcher, &[Value::String(name.clone())])?
+            .into_iter();
+        match results.next() {
+            Some(loader @ (Value::Closure(_) | Value::Native(_))) => {
+                return Ok((loader, results.next().unwrap_or(Value::Nil)));
+            }
+            Some(Value::String(found)) => {
+                message.push_str("\n\t");
+                message.push_str(&found.to_str_lossy());
+            }
+            _ => {}
+        }
+    }
+    let msg = format!("module '{}' not found:{}", name.to_str_lossy(), message);
+    Err(LuaError::runtime_error(&msg))
+}
+
+/// `package.searchpath(name, path [, sep [, rep]])`: the first file the
+/// templates in `path` name that can be opened, or nil and the list of
+/// files tried.
+pub fn package_searchpath(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let name = bytes_argument(args, 0, "searchpath")?.to_str_lossy().into_owned();
+    let path = bytes_argument(args, 1, "searchpath")?.to_str_lossy().into_owned();
+    let sep = optional_string(args, 2, ".", "searchpath")?;
+    let rep = optional_string(args, 3, "/", "searchpath")?;
+    Ok(match package::search_path(&name, &path, &sep, &rep) {
+        Ok(filename) => vec![Value::String(filename.into())],
+        Err(tried) => vec![Value::Nil, Value::String(tried.into())],
+    })
+}
+
+/// The first searcher: a loader from `package.preload`.
+pub fn package_searcher_preload(
+    runtime: &mut crate::runtime::LuaJitRuntime,
+    args: &[Value],
+) -> LuaResult<Vec<Value>> {
+    let name = bytes_argument(args, 0, "searcher")?;
+    match registry_table(runtime, PRELOAD)?.get(&Value::String(name.clone())) {
+        Value::Nil => {
+            let msg = format!("no field package.preload['{}']", name.to_str_lossy());
+            Ok(vec![Value::String(msg.into())])
+        }
+        loader => Ok(vec![loader, Value::String(":preload:".into())]),
+    }
+}
+
+/// The second searcher: a Lua file found along `package.path`, compiled
+/// into a loader that gets the file name as its data.
+pub fn package_searcher_lua(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let name = bytes_argument(args, 0, "searcher")?.to_str_lossy().into_owned();
+    let path = match registry_table(runtime, PACKAGE)?.get_field("path") {
+        Value::String(path) => path.to_str_lossy().into_owned(),
+        _ => return Err(LuaError::runtime_error("'package.path' must be a string")),
+    };
+    let filename = match package::search_path(&name, &path, ".", "/") {
+        Ok(filename) => filename,
+        Err(tried) => return Ok(vec![Value::String(tried.into())]),
+    };
+    let loader = std::fs::read(&filename)
+        .map_err(|e| file::error_parts(&e).0)
+        .and_then(|source| runtime.load(&source, &format!("@{}", filename), None));
+    match loader {
+        Ok(loader) => Ok(vec![loader, Value::String(filename.into())]),
+        Err(msg) => {
+            let msg = format!("error loading module '{}' from file '{}':\n\t{}", name, filename, msg);
+            Err(LuaError::runtime_error(&msg))
+        }
+    }
+}
+
+fn check_coroutine<'a>(args: &'a [Value], function: &str) -> LuaResult<&'a CoroutineRef> {
+    match args.first() {
+        Some(Value::Thread(coroutine)) => Ok(coroutine),
+        Some(other) => Err(LuaError::type_error("thread", other.type_name(), function)),
+        None => Err(LuaError::argument_error(1, 0, function)),
+    }
+}
+
+pub fn coroutine_create(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    match args.first() {
+        Some(function @ (Value::Native(_) | Value::Closure(_))) => {
+            Ok(vec![Value::Thread(runtime.create_coroutine(function.clone()))])
+        }
+        Some(other) => Err(LuaError::type_error("function", other.type_name(), "coroutine.create")),
+        None => Err(LuaError::argument_error(1, 0, "coroutine.create")),
+    }
+}
+
+pub fn coroutine_resume(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let coroutine = check_coroutine(args, "coroutine.resume")?.clone();
+    match runtime.resume_thread(&coroutine, args[1..].to_vec()) {
+        Ok(mut values) => {
+            values.insert(0, Value::Boolean(true));
+            Ok(values)
+        }
+        Err(e) => Ok(vec![Value::Boolean(false), e.into_value()]),
+    }
+}
+
+pub fn coroutine_yield(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    runtime
+        .yield_values(args.to_vec())
+        .map_err(|msg| LuaError::runtime_error(&msg))?;
+    Ok(Vec::new())
+}
+
+pub fn coroutine_status(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let coroutine = check_coroutine(args, "coroutine.status")?;
+    Ok(vec![Value::String(coroutine.status().name().into())])
+}
+
+/// Returns a callable that resumes a new coroutine and raises its errors.
+/// The coroutine is kept in the callable's first slot.
+pub fn coroutine_wrap(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let coroutine = coroutine_create(runtime, args)?.remove(0);
+    let wrapper = runtime.create_table();
+    wrapper.set(Value::Number(1.0), coroutine);
+    let metatable = runtime.create_table();
+    let call = runtime.builtin("coroutine.call_wrapped").unwrap_or(Value::Nil);
+    metatable.set(Value::String("__call".into()), call);
+    let wrapper = Value::Table(wrapper);
+    runtime
+        .set_metatable(&wrapper, Some(metatable))
+        .map_err(|msg| LuaError::runtime_error(&msg))?;
+    Ok(vec![wrapper])
+}
+
+pub fn coroutine_call_wrapped(runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let coroutine = match args.first() {
+        Some(Value::Table(wrapper)) => match wrapper.get(&Value::Number(1.0)) {
+            Value::Thread(coroutine) => coroutine,
+            other => return Err(LuaError::type_error("thread", other.type_name(), "coroutine.wrap")),
+        },
+        _ => return Err(LuaError::runtime_error("invalid coroutine.wrap function")),
+    };
+    runtime.resume_thread(&coroutine, args[1..].to_vec())
+}
+
+pub fn coroutine_isyieldable(runtime: &mut crate::runtime::LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+    Ok(vec![Value::Boolean(runtime.is_yieldable())])
+}
+
+pub fn coroutine_running(runtime: &mut crate::runtime::LuaJitRuntime, _args: &[Value]) -> LuaResult<Vec<Value>> {
+    let is_main = runtime.running_coroutine().is_none();
+    Ok(vec![Value::Thread(runtime.running_thread().clone()), Value::Boolean(is_main)])
+}
+
+/// Kills a suspended or dead coroutine, dropping its stack. Returns false
+/// and the error if it had died with one.
+pub fn coroutine_close(_runtime: &mut crate::runtime::LuaJitRuntime, args: &[Value]) -> LuaResult<Vec<Value>> {
+    let coroutine = check_coroutine(args, "coroutine.close")?;
+    let mut state = coroutine.borrow_mut();
+    match state.status {
+        CoroutineStatus::Suspended | CoroutineStatus::Dead => {}
+        status => {
+            let msg = format!("cannot close a {} coroutine", status.name());
+            return Err(LuaError::runtime_error(&msg));
+        }
+    }
+    state.status = CoroutineStatus::Dead;
+    let stack = std::mem::take(&mut state.stack);
+    let call_stack = std::mem::take(&mut state.call_stack);
+    let error = state.error.take();
+    drop(state);
+    drop((stack, call_stack));
+
+    match error {
+        Some(e) => Ok(vec![Value::Boolean(false), e]),
+        None => Ok(vec![Value::Boolean(true)]),
+    }
 }
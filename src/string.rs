@@ -0,0 +1,277 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::rc::{Rc, Weak};
+
+/// Strings up to this many bytes are interned, so equal short strings share
+/// a single allocation and compare by pointer.
+pub const MAX_SHORT_LEN: usize = 40;
+
+struct StringData {
+    // Computed up front for short strings, on first use for long ones
+    hash: Cell<Option<u64>>,
+    interned: bool,
+    bytes: Box<[u8]>,
+}
+
+impl Drop for StringData {
+    fn drop(&mut self) {
+        if self.interned {
+            let ptr = self as *const StringData;
+            let hash = self.hash.get().unwrap_or_default();
+            // The interner is gone if the thread is shutting down
+            let _ = INTERNER.try_with(|interner| {
+                if let Ok(mut interner) = interner.try_borrow_mut() {
+                    interner.remove(hash, ptr);
+                }
+            });
+        }
+    }
+}
+
+// Short strings currently alive, bucketed by hash. Entries are weak so the
+// table never keeps a string alive; strings remove themselves when dropped.
+#[derive(Default)]
+struct Interner {
+    buckets: HashMap<u64, Vec<Weak<StringData>>>,
+}
+
+impl Interner {
+    fn remove(&mut self, hash: u64, ptr: *const StringData) {
+        if let Some(bucket) = self.buckets.get_mut(&hash) {
+            bucket.retain(|weak| weak.as_ptr() != ptr);
+            if bucket.is_empty() {
+                self.buckets.remove(&hash);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::new(Interner::default());
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Immutable, reference-counted Lua string. Like in reference Lua it holds
+/// arbitrary bytes, which need not be valid UTF-8. Cloning only bumps a
+/// count, and the hash is computed at most once.
+#[derive(Clone)]
+pub struct LuaString(Rc<StringData>);
+
+impl LuaString {
+    pub fn new(bytes: &[u8]) -> Self {
+        if bytes.len() > MAX_SHORT_LEN {
+            return Self::long(bytes.into());
+        }
+        Self::intern(bytes)
+    }
+
+    fn long(bytes: Box<[u8]>) -> Self {
+        Self(Rc::new(StringData {
+            hash: Cell::new(None),
+            interned: false,
+            bytes,
+        }))
+    }
+
+    // Returns the existing copy of `bytes` if there is one. `bytes` is only
+    // turned into a buffer of its own to create a new one, which takes over
+    // an owned buffer instead of copying it.
+    fn intern<B: AsRef<[u8]> + Into<Box<[u8]>>>(bytes: B) -> Self {
+        let hash = hash_bytes(bytes.as_ref());
+        INTERNER.with(|interner| {
+            let mut interner = interner.borrow_mut();
+            let bucket = interner.buckets.entry(hash).or_default();
+            for weak in bucket.iter() {
+                if let Some(existing) = weak.upgrade() {
+                    if *existing.bytes == *bytes.as_ref() {
+                        return Self(existing);
+                    }
+                }
+            }
+
+            let data = Rc::new(StringData {
+                hash: Cell::new(Some(hash)),
+                interned: true,
+                bytes: bytes.into(),
+            });
+            bucket.push(Rc::downgrade(&data));
+            Self(data)
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0.bytes
+    }
+
+    /// The contents as `&str`, if they are valid UTF-8.
+    pub fn to_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.0.bytes)
+    }
+
+    /// The contents as text, with invalid UTF-8 replaced by U+FFFD.
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0.bytes)
+    }
+
+    pub fn hash_value(&self) -> u64 {
+        match self.0.hash.get() {
+            Some(hash) => hash,
+            None => {
+                let hash = hash_bytes(&self.0.bytes);
+                self.0.hash.set(Some(hash));
+                hash
+            }
+        }
+    }
+
+    pub fn ptr_eq(&self, other: &LuaString) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const StringData))
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl AsRef<[u8]> for LuaString {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    // Reuses the buffer unless an equal string already exists
+    fn from(bytes: Vec<u8>) -> Self {
+        if bytes.len() > MAX_SHORT_LEN {
+            return Self::long(bytes.into_boxed_slice());
+        }
+        Self::intern(bytes)
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(text: &str) -> Self {
+        Self::new(text.as_bytes())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(text: String) -> Self {
+        Self::from(text.into_bytes())
+    }
+}
+
+impl PartialEq for LuaString {
+    fn eq(&self, other: &Self) -> bool {
+        if self.ptr_eq(other) {
+            return true;
+        }
+        // Equal short strings are always the same object
+        if self.0.interned && other.0.interned {
+            return false;
+        }
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for LuaString {}
+
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LuaString {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl Hash for LuaString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash_value());
+    }
+}
+
+impl fmt::Display for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_str_lossy())
+    }
+}
+
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\"", self.as_bytes().escape_ascii())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_strings_are_shared() {
+        let a = LuaString::from("hello");
+        let b = LuaString::from(String::from("hello"));
+        assert!(a.ptr_eq(&b));
+
+        let long = "x".repeat(MAX_SHORT_LEN + 1);
+        let c = LuaString::from(long.as_str());
+        let d = LuaString::from(long);
+        assert!(!c.ptr_eq(&d));
+        assert_eq!(c, d);
+        assert_eq!(c.hash_value(), d.hash_value());
+    }
+
+    #[test]
+    fn test_invalid_utf8_is_kept() {
+        let bytes = LuaString::from(vec![b'a', 0xC8, 0]);
+        assert_eq!(bytes.as_bytes(), &[b'a', 0xC8, 0]);
+        assert!(bytes.to_str().is_err());
+        assert_eq!(bytes.to_str_lossy(), "a\u{FFFD}\0");
+        assert_eq!(LuaString::from("é").len(), 2);
+    }
+
+    #[test]
+    fn test_dropped_strings_leave_the_interner() {
+        let text = "only used by this test";
+        drop(LuaString::from(text));
+        let hash = hash_bytes(text.as_bytes());
+        INTERNER.with(|interner| assert!(!interner.borrow().buckets.contains_key(&hash)));
+    }
+}
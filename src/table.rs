@@ -0,0 +1,559 @@
+use crate::gc::{write_barrier, GcBox, GcHeader, GcObject, Trace, Tracer};
+use crate::nanbox::{PackedValue, ValueRef};
+use crate::value::Value;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
+
+/// How a table's metatable `__mode` field lets the collector treat its
+/// entries. Only objects are ever removed from weak tables; strings,
+/// numbers and booleans are values and always stay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeakMode {
+    Strong,
+    /// `__mode = "k"`: an ephemeron table, where a value is only reachable
+    /// through the table while its key is reachable from somewhere else
+    Keys,
+    /// `__mode = "v"`
+    Values,
+    /// `__mode = "kv"`
+    Both,
+}
+
+/// Keys 1 to n of a sequence are kept in an array part, indexed directly.
+/// Other keys are hashed into entries that keep their insertion order, so
+/// that `next` can resume a traversal from any key. Removing a key leaves a
+/// tombstone in its slot until the table next grows, which keeps a
+/// traversal valid while fields are being cleared, whether by the script or
+/// by the collector.
+#[derive(Debug, Clone, Default)]
+pub struct LuaTable {
+    // `array[i]` holds key `i + 1`; nil slots are holes in the sequence
+    array: Vec<PackedValue>,
+    array_live: usize,
+    entries: Vec<(PackedValue, PackedValue)>,
+    index: HashMap<PackedValue, usize>,
+    live: usize,
+    metatable: Option<TableRef>,
+}
+
+impl LuaTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        if let Some(slot) = self.array_slot(key) {
+            return self.array[slot].to_value();
+        }
+        match self.slot(key) {
+            Some(slot) => self.entries[slot].1.to_value(),
+            None => Value::Nil,
+        }
+    }
+
+    fn slot(&self, key: &Value) -> Option<usize> {
+        self.index.get(&*PackedValue::borrowed(key)).copied()
+    }
+
+    // Where `key` is in the array part, if it is an integer from 1 to the
+    // array's length
+    fn array_slot(&self, key: &Value) -> Option<usize> {
+        match *key {
+            Value::Number(n) if n >= 1.0 && n <= self.array.len() as f64 && n.fract() == 0.0 => Some(n as usize - 1),
+            _ => None,
+        }
+    }
+
+    /// Convenience lookup for string keys.
+    pub fn get_field(&self, name: &str) -> Value {
+        self.get(&Value::String(name.into()))
+    }
+
+    /// Stores `value` under `key`, removing the entry if `value` is nil.
+    /// Nil and NaN keys must be rejected by the caller.
+    pub fn set(&mut self, key: Value, value: Value) {
+        debug_assert!(check_key(&key).is_ok(), "invalid table key: {:?}", key);
+
+        let value = PackedValue::from(value);
+        if let Some(slot) = self.array_slot(&key) {
+            let entry = &mut self.array[slot];
+            match (entry.is_nil(), value.is_nil()) {
+                (true, false) => self.array_live += 1,
+                (false, true) => self.array_live -= 1,
+                _ => {}
+            }
+            *entry = value;
+            return;
+        }
+
+        // A key just past the array extends it, even if the hash part has a
+        // tombstone for it
+        let append = !value.is_nil() && key == Value::Number((self.array.len() + 1) as f64);
+        if let Some(slot) = self.slot(&key).filter(|&slot| !append || !self.entries[slot].1.is_nil()) {
+            let entry = &mut self.entries[slot].1;
+            match (entry.is_nil(), value.is_nil()) {
+                (true, false) => self.live += 1,
+                (false, true) => self.live -= 1,
+                _ => {}
+            }
+            *entry = value;
+            return;
+        }
+        if value.is_nil() {
+            return;
+        }
+        if append {
+            self.array.push(value);
+            self.array_live += 1;
+            self.migrate();
+            return;
+        }
+
+        if self.entries.len() - self.live >= self.live.max(8) {
+            self.compact();
+        }
+        let key = PackedValue::from(key);
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, value));
+        self.live += 1;
+    }
+
+    // Moves the keys that follow on from the array part out of the hash part,
+    // leaving tombstones behind
+    fn migrate(&mut self) {
+        while let Some(slot) = self.slot(&Value::Number((self.array.len() + 1) as f64)) {
+            let value = std::mem::take(&mut self.entries[slot].1);
+            if value.is_nil() {
+                break;
+            }
+            self.live -= 1;
+            self.array.push(value);
+            self.array_live += 1;
+        }
+        if self.entries.len() - self.live >= self.live.max(8) {
+            self.compact();
+        }
+    }
+
+    pub fn remove(&mut self, key: &Value) -> Option<Value> {
+        if let Some(slot) = self.array_slot(key) {
+            let value = std::mem::take(&mut self.array[slot]);
+            if value.is_nil() {
+                return None;
+            }
+            self.array_live -= 1;
+            return Some(value.into_value());
+        }
+        let slot = self.slot(key)?;
+        let value = std::mem::take(&mut self.entries[slot].1);
+        if value.is_nil() {
+            None
+        } else {
+            self.live -= 1;
+            Some(value.into_value())
+        }
+    }
+
+    /// Returns the entry that follows `key` in traversal order, or the first
+    /// entry if `key` is nil. The array part comes first, in order.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>, String> {
+        // Positions count the array slots and then the hash slots
+        let start = match key {
+            Value::Nil => 0,
+            key => match (self.array_slot(key), self.slot(key)) {
+                (Some(slot), _) => slot + 1,
+                (None, Some(slot)) => self.array.len() + slot + 1,
+                (None, None) => return Err("invalid key to 'next'".to_string()),
+            },
+        };
+        let array = self.array.iter().enumerate().skip(start).find(|(_, value)| !value.is_nil());
+        if let Some((slot, value)) = array {
+            return Ok(Some((Value::Number((slot + 1) as f64), value.to_value())));
+        }
+        Ok(self.entries[start.saturating_sub(self.array.len())..]
+            .iter()
+            .find(|(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.to_value(), value.to_value())))
+    }
+
+    pub fn len(&self) -> usize {
+        self.array_live + self.live
+    }
+
+    /// The length of the sequence part, `#t`: an index `n` where `t[n]` is
+    /// non-nil and `t[n + 1]` is nil, or 0 when `t[1]` is nil. With holes in
+    /// the sequence, any such border may be returned.
+    pub fn border(&self) -> usize {
+        let size = self.array.len();
+        if size > 0 && self.array[size - 1].is_nil() {
+            // Bisect the array between a present index, or 0, and an absent one
+            let (mut i, mut j) = (0, size);
+            while j - i > 1 {
+                let middle = (i + j) / 2;
+                if self.array[middle - 1].is_nil() {
+                    j = middle;
+                } else {
+                    i = middle;
+                }
+            }
+            return i;
+        }
+        if self.live == 0 {
+            return size;
+        }
+
+        let present = |i: usize| !matches!(self.get(&Value::Number(i as f64)), Value::Nil);
+        if size == 0 && !present(1) {
+            return 0;
+        }
+
+        // Double an index that is present until one is absent, then bisect
+        let mut i = size.max(1);
+        let mut j = i * 2;
+        while present(j) {
+            i = j;
+            if j > MAX_EXACT_INDEX {
+                // Past the integers a float holds exactly; walk instead
+                let mut n = 1;
+                while present(n + 1) {
+                    n += 1;
+                }
+                return n;
+            }
+            j *= 2;
+        }
+        while j - i > 1 {
+            let middle = (i + j) / 2;
+            if present(middle) {
+                i = middle;
+            } else {
+                j = middle;
+            }
+        }
+        i
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (ValueRef<'_>, ValueRef<'_>)> {
+        let array = self
+            .array
+            .iter()
+            .enumerate()
+            .filter(|(_, value)| !value.is_nil())
+            .map(|(slot, value)| (ValueRef::number((slot + 1) as f64), value.get()));
+        let hash = self
+            .entries
+            .iter()
+            .filter(|(_, value)| !value.is_nil())
+            .map(|(key, value)| (key.get(), value.get()));
+        array.chain(hash)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = ValueRef<'_>> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.metatable.clone()
+    }
+
+    pub fn set_metatable(&mut self, metatable: Option<TableRef>) {
+        self.metatable = metatable;
+    }
+
+    pub fn weak_mode(&self) -> WeakMode {
+        let metatable = match &self.metatable {
+            Some(metatable) => metatable.borrow(),
+            None => return WeakMode::Strong,
+        };
+        match metatable.get_field("__mode") {
+            Value::String(mode) => match (mode.contains(&b'k'), mode.contains(&b'v')) {
+                (true, true) => WeakMode::Both,
+                (true, false) => WeakMode::Keys,
+                (false, true) => WeakMode::Values,
+                (false, false) => WeakMode::Strong,
+            },
+            _ => WeakMode::Strong,
+        }
+    }
+
+    /// Turns every entry rejected by `keep` into a tombstone and returns how
+    /// many were removed.
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Value, &Value) -> bool) -> usize {
+        let mut removed = 0;
+        for (slot, value) in self.array.iter_mut().enumerate() {
+            if !value.is_nil() && !keep(&Value::Number((slot + 1) as f64), &value.get()) {
+                *value = PackedValue::NIL;
+                removed += 1;
+            }
+        }
+        self.array_live -= removed;
+        for (key, value) in &mut self.entries {
+            if !value.is_nil() && !keep(&key.get(), &value.get()) {
+                *value = PackedValue::NIL;
+                self.live -= 1;
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    fn compact(&mut self) {
+        self.entries.retain(|(_, value)| !value.is_nil());
+        self.index.clear();
+        for (slot, (key, _)) in self.entries.iter().enumerate() {
+            self.index.insert(key.clone(), slot);
+        }
+    }
+}
+
+// Largest index the border search doubles to, 2^53
+const MAX_EXACT_INDEX: usize = 1 << 53;
+
+/// Rejects the two values Lua does not allow as table keys.
+pub fn check_key(key: &Value) -> Result<(), String> {
+    match key {
+        Value::Nil => Err("table index is nil".to_string()),
+        Value::Number(n) if n.is_nan() => Err("table index is NaN".to_string()),
+        _ => Ok(()),
+    }
+}
+
+impl From<HashMap<String, Value>> for LuaTable {
+    fn from(fields: HashMap<String, Value>) -> Self {
+        let mut table = Self::new();
+        for (name, value) in fields {
+            table.set(Value::String(name.into()), value);
+        }
+        table
+    }
+}
+
+impl LuaTable {
+    /// Traverses up to `count` slots starting at slot `start`, along with
+    /// the metatable when starting from the beginning, so that a large table
+    /// can be traversed over several incremental steps. Slots number the
+    /// array part and then the hash part. Returns the slot to resume at,
+    /// which is past the end once the table is done.
+    pub(crate) fn trace_slots(&self, tracer: &mut Tracer, start: usize, count: usize) -> usize {
+        if start == 0 {
+            if let Some(metatable) = &self.metatable {
+                tracer.mark_table(metatable);
+            }
+        }
+        let mode = self.weak_mode();
+        let end = start.saturating_add(count).min(self.slot_count());
+
+        // Array keys are numbers, which are never collected
+        let size = self.array.len();
+        if matches!(mode, WeakMode::Strong | WeakMode::Keys) {
+            for value in self.array.get(start.min(size)..end.min(size)).unwrap_or_default() {
+                tracer.mark_value(&value.get());
+            }
+        }
+        let hash = start.saturating_sub(size)..end.saturating_sub(size);
+        for (key, value) in self.entries.get(hash).unwrap_or_default() {
+            if value.is_nil() {
+                continue;
+            }
+            let (key, value) = (key.get(), value.get());
+            match mode {
+                WeakMode::Strong => {
+                    tracer.mark_value(&key);
+                    tracer.mark_value(&value);
+                }
+                WeakMode::Values => tracer.mark_value(&key),
+                // Values behind keys that are not marked yet are picked up
+                // once marking converges, if the key turns out to be alive
+                WeakMode::Keys => {
+                    if tracer.is_alive(&key) {
+                        tracer.mark_value(&value);
+                    }
+                }
+                WeakMode::Both => {}
+            }
+        }
+        end
+    }
+
+    // Visits every reference the table holds, weak or not, including the
+    // keys of removed entries and the copies the index keeps, for a tracer
+    // that is counting them
+    pub(crate) fn count_references(&self, tracer: &mut Tracer) {
+        if let Some(metatable) = &self.metatable {
+            tracer.mark_table(metatable);
+        }
+        for value in &self.array {
+            tracer.mark_value(&value.get());
+        }
+        for (key, value) in &self.entries {
+            tracer.mark_value(&key.get());
+            tracer.mark_value(&value.get());
+        }
+        for key in self.index.keys() {
+            tracer.mark_value(&key.get());
+        }
+    }
+
+    pub(crate) fn slot_count(&self) -> usize {
+        self.array.len() + self.entries.len()
+    }
+}
+
+impl Trace for LuaTable {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.trace_slots(tracer, 0, usize::MAX);
+    }
+
+    fn clear_references(&mut self) -> Vec<Value> {
+        self.index.clear();
+        self.live = 0;
+        self.array_live = 0;
+        let mut references: Vec<Value> = self.metatable.take().map(Value::Table).into_iter().collect();
+        references.extend(self.array.drain(..).map(PackedValue::into_value));
+        for (key, value) in self.entries.drain(..) {
+            references.push(key.into_value());
+            references.push(value.into_value());
+        }
+        references
+    }
+
+    // Counts the table's own slots, so it can be checked after every
+    // mutation. Strings are shared and reference counted on their own.
+    fn estimated_size(&self) -> usize {
+        let slot = 3 * std::mem::size_of::<PackedValue>() + std::mem::size_of::<usize>();
+        std::mem::size_of::<GcBox<RefCell<LuaTable>>>()
+            + self.array.capacity() * std::mem::size_of::<PackedValue>()
+            + self.entries.capacity().max(self.index.capacity()) * slot
+    }
+}
+
+/// Shared handle to a heap-allocated table. Clones alias the same table.
+#[derive(Clone)]
+pub struct TableRef(Rc<GcBox<RefCell<LuaTable>>>);
+
+impl TableRef {
+    /// Allocates a table that is not registered with any collector. Use
+    /// `Heap::alloc_table` for tables that may take part in cycles.
+    pub fn new(table: LuaTable) -> Self {
+        Self(Rc::new(GcBox::new(RefCell::new(table))))
+    }
+
+    pub(crate) fn from_gc_box(gc_box: GcBox<RefCell<LuaTable>>) -> Self {
+        Self(Rc::new(gc_box))
+    }
+
+    pub(crate) fn from_rc(gc_box: Rc<GcBox<RefCell<LuaTable>>>) -> Self {
+        Self(gc_box)
+    }
+
+    pub(crate) fn gc_box(&self) -> &Rc<GcBox<RefCell<LuaTable>>> {
+        &self.0
+    }
+
+    pub fn borrow(&self) -> Ref<'_, LuaTable> {
+        self.0.borrow()
+    }
+
+    /// Mutable access to the table. Goes through the collector's write
+    /// barrier, so prefer `borrow` when only reading.
+    pub fn borrow_mut(&self) -> TableMut<'_> {
+        if self.0.header().needs_barrier() {
+            write_barrier(GcObject::Table(self.clone()));
+        }
+        let table = self.0.borrow_mut();
+        let size = table.estimated_size();
+        TableMut {
+            table,
+            header: self.0.header(),
+            size,
+        }
+    }
+
+    pub fn get(&self, key: &Value) -> Value {
+        self.borrow().get(key)
+    }
+
+    pub fn get_field(&self, name: &str) -> Value {
+        self.borrow().get_field(name)
+    }
+
+    pub fn set(&self, key: Value, value: Value) {
+        self.borrow_mut().set(key, value);
+    }
+
+    pub fn ptr_eq(&self, other: &TableRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const GcBox<RefCell<LuaTable>>))
+    }
+}
+
+/// A table borrowed for writing by `TableRef::borrow_mut`. Growing or
+/// shrinking the table is charged to the heap when the borrow ends.
+pub struct TableMut<'a> {
+    table: RefMut<'a, LuaTable>,
+    header: &'a GcHeader,
+    size: usize,
+}
+
+impl Deref for TableMut<'_> {
+    type Target = LuaTable;
+
+    fn deref(&self) -> &LuaTable {
+        &self.table
+    }
+}
+
+impl DerefMut for TableMut<'_> {
+    fn deref_mut(&mut self) -> &mut LuaTable {
+        &mut self.table
+    }
+}
+
+impl Drop for TableMut<'_> {
+    fn drop(&mut self) {
+        let size = self.table.estimated_size();
+        if size != self.size {
+            self.header.resize(size);
+        }
+    }
+}
+
+impl From<HashMap<String, Value>> for TableRef {
+    fn from(entries: HashMap<String, Value>) -> Self {
+        Self::new(LuaTable::from(entries))
+    }
+}
+
+impl PartialEq for TableRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl fmt::Debug for TableRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Tables may be cyclic, so never recurse into the contents here
+        write!(f, "table: {:p}", self.as_ptr())
+    }
+}
@@ -0,0 +1,188 @@
+use crate::error::{LuaError, LuaResult};
+use crate::gc::{write_barrier, GcBox, GcObject, Tracer};
+use crate::table::TableRef;
+use crate::value::Value;
+use std::any::{Any, TypeId};
+use std::cell::{Ref, RefCell, RefMut};
+use std::fmt;
+use std::rc::Rc;
+
+/// A host object handed to Lua. Scripts only reach the payload through the
+/// metamethods of its metatable; the host borrows it back as its concrete
+/// type. The payload is opaque to the collector, so Lua values stored
+/// inside it do not keep anything alive.
+pub struct Userdata {
+    payload: RefCell<Box<dyn Any>>,
+    // Kept outside the payload so that neither the collector nor a type
+    // check has to borrow a payload the host may be mutating
+    type_id: TypeId,
+    type_name: &'static str,
+    size: usize,
+    metatable: RefCell<Option<TableRef>>,
+}
+
+impl Userdata {
+    pub fn new<T: Any>(value: T) -> Self {
+        Self {
+            payload: RefCell::new(Box::new(value)),
+            type_id: TypeId::of::<T>(),
+            type_name: std::any::type_name::<T>(),
+            size: std::mem::size_of::<T>(),
+            metatable: RefCell::new(None),
+        }
+    }
+
+    pub fn with_metatable(mut self, metatable: Option<TableRef>) -> Self {
+        self.metatable = RefCell::new(metatable);
+        self
+    }
+
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        if let Some(metatable) = &*self.metatable.borrow() {
+            tracer.mark_table(metatable);
+        }
+    }
+
+    pub(crate) fn clear_references(&self) -> Vec<Value> {
+        self.metatable.take().map(Value::Table).into_iter().collect()
+    }
+
+    pub(crate) fn estimated_size(&self) -> usize {
+        std::mem::size_of::<GcBox<Userdata>>() + self.size
+    }
+}
+
+/// Shared handle to a full userdata. Clones alias the same object.
+#[derive(Clone)]
+pub struct UserdataRef(Rc<GcBox<Userdata>>);
+
+impl UserdataRef {
+    /// Allocates a userdata that is not registered with any collector. Use
+    /// `Heap::alloc_userdata` for userdata that may take part in cycles.
+    pub fn new(userdata: Userdata) -> Self {
+        Self(Rc::new(GcBox::new(userdata)))
+    }
+
+    pub(crate) fn from_gc_box(gc_box: GcBox<Userdata>) -> Self {
+        Self(Rc::new(gc_box))
+    }
+
+    pub(crate) fn from_rc(gc_box: Rc<GcBox<Userdata>>) -> Self {
+        Self(gc_box)
+    }
+
+    pub(crate) fn gc_box(&self) -> &Rc<GcBox<Userdata>> {
+        &self.0
+    }
+
+    /// Whether the payload is a `T`.
+    pub fn is<T: Any>(&self) -> bool {
+        self.0.type_id == TypeId::of::<T>()
+    }
+
+    /// Borrows the payload as a `T`. Fails if the payload has another type
+    /// or is mutably borrowed, for instance by a host function further up
+    /// the call stack.
+    pub fn borrow<T: Any>(&self) -> LuaResult<Ref<'_, T>> {
+        self.check_type::<T>()?;
+        let payload = self
+            .0
+            .payload
+            .try_borrow()
+            .map_err(|_| LuaError::runtime_error("userdata is already mutably borrowed"))?;
+        Ok(Ref::map(payload, |payload| payload.downcast_ref::<T>().unwrap()))
+    }
+
+    /// Mutably borrows the payload as a `T`. Fails if the payload has
+    /// another type or is borrowed at all, so a host function that calls
+    /// back into Lua cannot be handed the same payload twice.
+    pub fn borrow_mut<T: Any>(&self) -> LuaResult<RefMut<'_, T>> {
+        self.check_type::<T>()?;
+        let payload = self
+            .0
+            .payload
+            .try_borrow_mut()
+            .map_err(|_| LuaError::runtime_error("userdata is already borrowed"))?;
+        Ok(RefMut::map(payload, |payload| payload.downcast_mut::<T>().unwrap()))
+    }
+
+    fn check_type<T: Any>(&self) -> LuaResult<()> {
+        if self.is::<T>() {
+            Ok(())
+        } else {
+            Err(LuaError::type_error(
+                std::any::type_name::<T>(),
+                self.0.type_name,
+                "userdata borrow",
+            ))
+        }
+    }
+
+    pub fn metatable(&self) -> Option<TableRef> {
+        self.0.metatable.borrow().clone()
+    }
+
+    /// Replaces the metatable. Use `LuaJitRuntime::set_metatable` to also
+    /// have a `__gc` field in the new metatable take effect.
+    pub fn set_metatable(&self, metatable: Option<TableRef>) {
+        if self.0.header().needs_barrier() {
+            write_barrier(GcObject::Userdata(self.clone()));
+        }
+        *self.0.metatable.borrow_mut() = metatable;
+    }
+
+    pub fn ptr_eq(&self, other: &UserdataRef) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+
+    pub fn as_ptr(&self) -> *const () {
+        Rc::as_ptr(&self.0) as *const ()
+    }
+
+    pub(crate) fn into_raw(self) -> *const () {
+        Rc::into_raw(self.0) as *const ()
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must come from `into_raw`; its reference is taken over.
+    pub(crate) unsafe fn from_raw(ptr: *const ()) -> Self {
+        Self(Rc::from_raw(ptr as *const GcBox<Userdata>))
+    }
+}
+
+impl PartialEq for UserdataRef {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other)
+    }
+}
+
+impl fmt::Debug for UserdataRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "userdata: {:p}", self.as_ptr())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_borrow_checks_type() {
+        let userdata = UserdataRef::new(Userdata::new(5u32));
+        assert!(userdata.is::<u32>());
+        assert_eq!(*userdata.borrow::<u32>().unwrap(), 5);
+        assert!(userdata.borrow::<i64>().is_err());
+    }
+
+    #[test]
+    fn test_mutable_borrow_is_exclusive() {
+        let userdata = UserdataRef::new(Userdata::new(vec![1, 2, 3]));
+        let mut payload = userdata.borrow_mut::<Vec<i32>>().unwrap();
+        payload.push(4);
+        assert!(userdata.borrow::<Vec<i32>>().is_err());
+        assert!(userdata.borrow_mut::<Vec<i32>>().is_err());
+        drop(payload);
+        assert_eq!(userdata.borrow::<Vec<i32>>().unwrap().len(), 4);
+    }
+}
@@ -1,14 +1,28 @@
-use std::collections::HashMap;
+use crate::coroutine::CoroutineRef;
+use crate::function::{ClosureRef, NativeRef};
+use crate::string::LuaString;
+use crate::table::TableRef;
+use crate::userdata::UserdataRef;
+use std::ffi::c_void;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Nil,
     Boolean(bool),
     Number(f64),
-    String(String),
-    Table(HashMap<String, Value>),
-    Function(usize), // Function ID
+    String(LuaString),
+    Table(TableRef),
+    /// A function implemented in Rust
+    Native(NativeRef),
+    /// A function written in Lua
+    Closure(ClosureRef),
+    Userdata(UserdataRef),
+    Thread(CoroutineRef),
+    /// A bare pointer owned by the host, compared by address. Like in C Lua
+    /// it has no metatable of its own.
+    LightUserdata(*mut c_void),
 }
 
 impl Value {
@@ -19,7 +33,9 @@ impl Value {
             Value::Number(_) => "number",
             Value::String(_) => "string",
             Value::Table(_) => "table",
-            Value::Function(_) => "function",
+            Value::Native(_) | Value::Closure(_) => "function",
+            Value::Userdata(_) | Value::LightUserdata(_) => "userdata",
+            Value::Thread(_) => "thread",
         }
     }
 
@@ -34,12 +50,35 @@ impl Value {
     pub fn to_number(&self) -> Option<f64> {
         match self {
             Value::Number(n) => Some(*n),
-            Value::String(s) => s.parse().ok(),
+            Value::String(s) => s.to_str().ok()?.parse().ok(),
             _ => None,
         }
     }
 }
 
+// Values are used directly as table keys. NaN is the only value that is not
+// equal to itself and tables never accept it as a key.
+impl Eq for Value {}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Nil => {}
+            Value::Boolean(b) => b.hash(state),
+            // 0.0 and -0.0 compare equal, so they have to hash the same
+            Value::Number(n) => (if *n == 0.0 { 0.0f64 } else { *n }).to_bits().hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Table(t) => t.as_ptr().hash(state),
+            Value::Native(n) => n.as_ptr().hash(state),
+            Value::Closure(c) => c.as_ptr().hash(state),
+            Value::Userdata(u) => u.as_ptr().hash(state),
+            Value::Thread(t) => t.as_ptr().hash(state),
+            Value::LightUserdata(p) => p.hash(state),
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -54,7 +93,11 @@ impl fmt::Display for Value {
             }
             Value::String(s) => write!(f, "{}", s),
             Value::Table(_) => write!(f, "table"),
-            Value::Function(id) => write!(f, "function:{}", id),
+            Value::Native(n) => write!(f, "builtin: {:p}", n.as_ptr()),
+            Value::Closure(c) => write!(f, "function: {:p}", c.as_ptr()),
+            Value::Userdata(u) => write!(f, "userdata: {:p}", u.as_ptr()),
+            Value::Thread(t) => write!(f, "thread: {:p}", t.as_ptr()),
+            Value::LightUserdata(p) => write!(f, "userdata: {:p}", p),
         }
     }
 }
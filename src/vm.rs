@@ -1,19 +1,27 @@
 use crate::error::LuaResult;
+use crate::function::NativeRef;
+use crate::runtime::LuaJitRuntime;
 use crate::value::Value;
 use std::collections::HashMap;
 
 pub type BuiltinFunction = fn(&[Value]) -> LuaResult<Value>;
 
+/// Builtins that need access to the runtime itself (collector, stacks) or
+/// that return more than one value.
+pub type RuntimeFunction = fn(&mut LuaJitRuntime, &[Value]) -> LuaResult<Vec<Value>>;
+
+/// Runtime functions created with values of their own, passed as the second
+/// argument on every call.
+pub type BoundFunction = fn(&mut LuaJitRuntime, &[Value], &[Value]) -> LuaResult<Vec<Value>>;
+
 pub struct StandardLibrary {
-    functions: HashMap<String, (usize, BuiltinFunction)>,
-    function_names: Vec<String>,
+    functions: HashMap<String, NativeRef>,
 }
 
 impl StandardLibrary {
     pub fn new() -> Self {
         let mut stdlib = Self {
             functions: HashMap::new(),
-            function_names: Vec::new(),
         };
 
         stdlib.register_core_functions();
@@ -21,38 +29,33 @@ impl StandardLibrary {
         stdlib.register_math_functions();
         stdlib.register_table_functions();
         stdlib.register_io_functions();
+        stdlib.register_os_functions();
+        stdlib.register_fs_functions();
+        stdlib.register_package_functions();
+        stdlib.register_coroutine_functions();
+        stdlib.register_utf8_functions();
 
         stdlib
     }
 
-    fn register_function(&mut self, name: &str, func: BuiltinFunction) -> usize {
-        let id = self.function_names.len();
-        self.function_names.push(name.to_string());
-        self.functions.insert(name.to_string(), (id, func));
-        id
+    fn register_function(&mut self, name: &str, func: BuiltinFunction) {
+        self.functions.insert(name.to_string(), NativeRef::builtin(name, func));
     }
 
-    pub fn get_function(&self, name: &str) -> Option<(usize, BuiltinFunction)> {
-        self.functions.get(name).copied()
+    fn register_runtime_function(&mut self, name: &str, func: RuntimeFunction) {
+        self.functions.insert(name.to_string(), NativeRef::runtime(name, func));
     }
 
-    pub fn get_function_by_id(&self, id: usize) -> Option<BuiltinFunction> {
-        if id < self.function_names.len() {
-            let name = &self.function_names[id];
-            self.functions.get(name).map(|(_, func)| *func)
-        } else {
-            None
-        }
-    }
-
-    pub fn get_function_name(&self, id: usize) -> Option<&str> {
-        self.function_names.get(id).map(|s| s.as_str())
+    /// The function registered as `name`. Repeated lookups return the same
+    /// function value.
+    pub fn get_function(&self, name: &str) -> Option<Value> {
+        self.functions.get(name).cloned().map(Value::Native)
     }
 
     pub fn get_all_functions(&self) -> HashMap<String, Value> {
         self.functions
             .iter()
-            .map(|(name, (id, _))| (name.clone(), Value::Function(*id)))
+            .map(|(name, function)| (name.clone(), Value::Native(function.clone())))
             .collect()
     }
 
@@ -60,33 +63,45 @@ impl StandardLibrary {
     fn register_core_functions(&mut self) {
         self.register_function("print", crate::stdlib::builtin_print);
         self.register_function("type", crate::stdlib::builtin_type);
-        self.register_function("tostring", crate::stdlib::builtin_tostring);
+        self.register_runtime_function("tostring", crate::stdlib::builtin_tostring_meta);
         self.register_function("tonumber", crate::stdlib::builtin_tonumber);
-        self.register_function("pairs", crate::stdlib::builtin_pairs);
+        self.register_runtime_function("pairs", crate::stdlib::builtin_pairs);
         self.register_function("ipairs", crate::stdlib::builtin_ipairs);
-        self.register_function("next", crate::stdlib::builtin_next);
+        self.register_runtime_function("next", crate::stdlib::builtin_next);
         self.register_function("rawget", crate::stdlib::builtin_rawget);
         self.register_function("rawset", crate::stdlib::builtin_rawset);
-        self.register_function("getmetatable", crate::stdlib::builtin_getmetatable);
-        self.register_function("setmetatable", crate::stdlib::builtin_setmetatable);
-        self.register_function("pcall", crate::stdlib::builtin_pcall);
-        self.register_function("xpcall", crate::stdlib::builtin_xpcall);
-        self.register_function("error", crate::stdlib::builtin_error);
+        self.register_runtime_function("getmetatable", crate::stdlib::builtin_getmetatable);
+        self.register_runtime_function("setmetatable", crate::stdlib::builtin_setmetatable);
+        self.register_runtime_function("pcall", crate::stdlib::builtin_pcall);
+        self.register_runtime_function("xpcall", crate::stdlib::builtin_xpcall);
+        self.register_runtime_function("error", crate::stdlib::builtin_error);
         self.register_function("assert", crate::stdlib::builtin_assert);
+        self.register_runtime_function("collectgarbage", crate::stdlib::builtin_collectgarbage);
+        self.register_runtime_function("load", crate::stdlib::builtin_load);
+        self.register_runtime_function("loadfile", crate::stdlib::builtin_loadfile);
+        self.register_runtime_function("dofile", crate::stdlib::builtin_dofile);
     }
 
     // String functions
     fn register_string_functions(&mut self) {
-        // String library would be a table in real Lua, but for simplicity
-        // we'll register them as global functions with string. prefix
+        // Collected into the `string` table, which strings also reach as
+        // methods through their shared metatable
         self.register_function("string.len", crate::stdlib::string_len);
         self.register_function("string.sub", crate::stdlib::string_sub);
         self.register_function("string.upper", crate::stdlib::string_upper);
         self.register_function("string.lower", crate::stdlib::string_lower);
         self.register_function("string.char", crate::stdlib::string_char);
-        self.register_function("string.byte", crate::stdlib::string_byte);
-        self.register_function("string.find", crate::stdlib::string_find);
-        self.register_function("string.gsub", crate::stdlib::string_gsub);
+        self.register_runtime_function("string.byte", crate::stdlib::string_byte);
+        self.register_function("string.rep", crate::stdlib::string_rep);
+        self.register_function("string.reverse", crate::stdlib::string_reverse);
+        self.register_function("string.pack", crate::stdlib::string_pack);
+        self.register_function("string.packsize", crate::stdlib::string_packsize);
+        self.register_runtime_function("string.unpack", crate::stdlib::string_unpack);
+        self.register_runtime_function("string.find", crate::stdlib::string_find);
+        self.register_runtime_function("string.match", crate::stdlib::string_match);
+        self.register_runtime_function("string.gmatch", crate::stdlib::string_gmatch);
+        self.register_runtime_function("string.gsub", crate::stdlib::string_gsub);
+        self.register_runtime_function("string.format", crate::stdlib::string_format);
     }
 
     // Math functions
@@ -100,8 +115,20 @@ impl StandardLibrary {
         self.register_function("math.sin", crate::stdlib::math_sin);
         self.register_function("math.cos", crate::stdlib::math_cos);
         self.register_function("math.tan", crate::stdlib::math_tan);
-        self.register_function("math.pi", crate::stdlib::math_pi);
-        self.register_function("math.random", crate::stdlib::math_random);
+        self.register_function("math.asin", crate::stdlib::math_asin);
+        self.register_function("math.acos", crate::stdlib::math_acos);
+        self.register_function("math.atan", crate::stdlib::math_atan);
+        self.register_function("math.exp", crate::stdlib::math_exp);
+        self.register_function("math.deg", crate::stdlib::math_deg);
+        self.register_function("math.rad", crate::stdlib::math_rad);
+        self.register_function("math.log", crate::stdlib::math_log);
+        self.register_function("math.fmod", crate::stdlib::math_fmod);
+        self.register_runtime_function("math.modf", crate::stdlib::math_modf);
+        self.register_function("math.ult", crate::stdlib::math_ult);
+        self.register_function("math.tointeger", crate::stdlib::math_tointeger);
+        self.register_function("math.type", crate::stdlib::math_type);
+        self.register_runtime_function("math.random", crate::stdlib::math_random);
+        self.register_runtime_function("math.randomseed", crate::stdlib::math_randomseed);
     }
 
     // Table functions
@@ -109,12 +136,101 @@ impl StandardLibrary {
         self.register_function("table.insert", crate::stdlib::table_insert);
         self.register_function("table.remove", crate::stdlib::table_remove);
         self.register_function("table.concat", crate::stdlib::table_concat);
-        self.register_function("table.sort", crate::stdlib::table_sort);
+        self.register_function("table.move", crate::stdlib::table_move);
+        self.register_runtime_function("table.sort", crate::stdlib::table_sort);
+        self.register_runtime_function("table.unpack", crate::stdlib::table_unpack);
+        self.register_runtime_function("table.pack", crate::stdlib::table_pack);
     }
 
-    // IO functions (simplified)
+    // IO functions
     fn register_io_functions(&mut self) {
-        self.register_function("io.write", crate::stdlib::io_write);
-        self.register_function("io.read", crate::stdlib::io_read);
+        self.register_runtime_function("io.open", crate::stdlib::io_open);
+        self.register_runtime_function("io.close", crate::stdlib::io_close);
+        self.register_runtime_function("io.read", crate::stdlib::io_read);
+        self.register_runtime_function("io.write", crate::stdlib::io_write);
+        self.register_runtime_function("io.flush", crate::stdlib::io_flush);
+        self.register_runtime_function("io.lines", crate::stdlib::io_lines);
+        self.register_runtime_function("io.input", crate::stdlib::io_input);
+        self.register_runtime_function("io.output", crate::stdlib::io_output);
+        self.register_runtime_function("io.popen", crate::stdlib::io_popen);
+        self.register_runtime_function("io.tmpfile", crate::stdlib::io_tmpfile);
+        self.register_function("io.type", crate::stdlib::io_type);
+
+        // Methods and metamethods of file handles
+        self.register_runtime_function("file.close", crate::stdlib::file_close);
+        self.register_runtime_function("file.flush", crate::stdlib::file_flush);
+        self.register_runtime_function("file.lines", crate::stdlib::file_lines);
+        self.register_runtime_function("file.read", crate::stdlib::file_read);
+        self.register_runtime_function("file.seek", crate::stdlib::file_seek);
+        self.register_runtime_function("file.setvbuf", crate::stdlib::file_setvbuf);
+        self.register_runtime_function("file.write", crate::stdlib::file_write);
+        self.register_runtime_function("file.__gc", crate::stdlib::file_gc);
+        self.register_function("file.__tostring", crate::stdlib::file_tostring);
+    }
+
+    // OS functions
+    fn register_os_functions(&mut self) {
+        self.register_function("os.clock", crate::stdlib::os_clock);
+        self.register_runtime_function("os.date", crate::stdlib::os_date);
+        self.register_function("os.difftime", crate::stdlib::os_difftime);
+        self.register_runtime_function("os.execute", crate::stdlib::os_execute);
+        self.register_runtime_function("os.exit", crate::stdlib::os_exit);
+        self.register_function("os.getenv", crate::stdlib::os_getenv);
+        self.register_runtime_function("os.remove", crate::stdlib::os_remove);
+        self.register_runtime_function("os.rename", crate::stdlib::os_rename);
+        self.register_runtime_function("os.time", crate::stdlib::os_time);
+        self.register_function("os.tmpname", crate::stdlib::os_tmpname);
+    }
+
+    // Filesystem functions
+    fn register_fs_functions(&mut self) {
+        self.register_runtime_function("fs.attributes", crate::stdlib::fs_attributes);
+        self.register_runtime_function("fs.chdir", crate::stdlib::fs_chdir);
+        self.register_runtime_function("fs.currentdir", crate::stdlib::fs_currentdir);
+        self.register_runtime_function("fs.dir", crate::stdlib::fs_dir);
+        self.register_runtime_function("fs.mkdir", crate::stdlib::fs_mkdir);
+        self.register_runtime_function("fs.rmdir", crate::stdlib::fs_rmdir);
+        self.register_runtime_function("fs.symlinkattributes", crate::stdlib::fs_symlinkattributes);
+        self.register_runtime_function("fs.touch", crate::stdlib::fs_touch);
+
+        // Methods of directory objects
+        self.register_runtime_function("directory.next", crate::stdlib::directory_next);
+        self.register_runtime_function("directory.close", crate::stdlib::directory_close);
+    }
+
+    // Package functions
+    fn register_package_functions(&mut self) {
+        self.register_runtime_function("require", crate::stdlib::builtin_require);
+        self.register_runtime_function("package.searchpath", crate::stdlib::package_searchpath);
+        self.register_runtime_function("package.searcher_preload", crate::stdlib::package_searcher_preload);
+        self.register_runtime_function("package.searcher_lua", crate::stdlib::package_searcher_lua);
+    }
+
+    // Coroutine functions
+    fn register_coroutine_functions(&mut self) {
+        self.register_runtime_function("coroutine.create", crate::stdlib::coroutine_create);
+        self.register_runtime_function("coroutine.resume", crate::stdlib::coroutine_resume);
+        self.register_runtime_function("coroutine.yield", crate::stdlib::coroutine_yield);
+        self.register_runtime_function("coroutine.status", crate::stdlib::coroutine_status);
+        self.register_runtime_function("coroutine.wrap", crate::stdlib::coroutine_wrap);
+        self.register_runtime_function("coroutine.call_wrapped", crate::stdlib::coroutine_call_wrapped);
+        self.register_runtime_function("coroutine.isyieldable", crate::stdlib::coroutine_isyieldable);
+        self.register_runtime_function("coroutine.running", crate::stdlib::coroutine_running);
+        self.register_runtime_function("coroutine.close", crate::stdlib::coroutine_close);
+    }
+
+    // UTF-8 functions
+    fn register_utf8_functions(&mut self) {
+        self.register_function("utf8.char", crate::stdlib::utf8_char);
+        self.register_runtime_function("utf8.codes", crate::stdlib::utf8_codes);
+        self.register_runtime_function("utf8.codepoint", crate::stdlib::utf8_codepoint);
+        self.register_runtime_function("utf8.len", crate::stdlib::utf8_len);
+        self.register_function("utf8.offset", crate::stdlib::utf8_offset);
+    }
+}
+
+impl Default for StandardLibrary {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -0,0 +1,27 @@
+//! Helpers shared by the integration tests. Each test crate uses only some
+//! of them.
+#![allow(dead_code)]
+
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+pub fn eval(runtime: &mut LuaJitRuntime, expression: &str) -> Value {
+    runtime.execute(&format!("return {}", expression)).unwrap()
+}
+
+/// Runs `body` as the body of a function and returns all of its results.
+pub fn results(runtime: &mut LuaJitRuntime, body: &str) -> Vec<Value> {
+    let packed = match runtime.execute(&format!("return table.pack((function()\n{}\nend)())", body)).unwrap() {
+        Value::Table(packed) => packed,
+        other => panic!("expected a table, got {:?}", other),
+    };
+    let count = match packed.get_field("n") {
+        Value::Number(count) => count as usize,
+        other => panic!("expected a count, got {:?}", other),
+    };
+    (1..=count).map(|index| packed.get(&Value::Number(index as f64))).collect()
+}
+
+pub fn string(s: &str) -> Value {
+    Value::String(s.into())
+}
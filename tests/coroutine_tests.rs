@@ -0,0 +1,286 @@
+mod common;
+
+use common::{eval, results, string};
+use luna::coroutine::CoroutineStatus;
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn coroutine(runtime: &mut LuaJitRuntime, name: &str) -> luna::coroutine::CoroutineRef {
+    match runtime.get_global(name) {
+        Value::Thread(coroutine) => coroutine,
+        other => panic!("expected a thread, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_resume_passes_values_both_ways() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        function body(a)
+            local b = coroutine.yield(a + 1)
+            local c = coroutine.yield(b * 2)
+            return c .. "!"
+        end
+        co = coroutine.create(body)
+    "#;
+    runtime.execute(source).unwrap();
+    let co = coroutine(&mut runtime, "co");
+
+    assert_eq!(runtime.execute("return type(co)").unwrap(), string("thread"));
+    assert_eq!(co.status(), CoroutineStatus::Suspended);
+    assert_eq!(runtime.resume(&co, vec![Value::Number(1.0)]), Ok(vec![Value::Number(2.0)]));
+    assert_eq!(runtime.resume(&co, vec![Value::Number(5.0)]), Ok(vec![Value::Number(10.0)]));
+    assert_eq!(runtime.resume(&co, vec![string("end")]), Ok(vec![string("end!")]));
+    assert_eq!(co.status(), CoroutineStatus::Dead);
+    assert!(runtime.resume(&co, Vec::new()).is_err());
+}
+
+#[test]
+fn test_resume_from_lua() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        co = coroutine.create(function(x, y)
+            local z = coroutine.yield(x + y, x * y)
+            return z, "done"
+        end)
+        local ok, sum, product = coroutine.resume(co, 2, 3)
+        first = {ok, sum, product}
+        local ok, z, status = coroutine.resume(co, "z")
+        second = {ok, z, status}
+        local ok, err = coroutine.resume(co)
+        third = {ok, err}
+    "#;
+    runtime.execute(source).unwrap();
+
+    let number = Value::Number;
+    assert_eq!(results(&mut runtime, "return table.unpack(first)"), vec![Value::Boolean(true), number(5.0), number(6.0)]);
+    assert_eq!(results(&mut runtime, "return table.unpack(second)"), vec![Value::Boolean(true), string("z"), string("done")]);
+    assert_eq!(
+        results(&mut runtime, "return table.unpack(third, 1, 2)"),
+        vec![Value::Boolean(false), string("cannot resume dead coroutine")]
+    );
+    assert_eq!(runtime.execute("return coroutine.status(co)").unwrap(), string("dead"));
+}
+
+#[test]
+fn test_yield_across_nested_calls() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        function inner(x)
+            coroutine.yield(x)
+            coroutine.yield(x + 1)
+        end
+        function outer()
+            inner(10)
+            inner(20)
+            return "done"
+        end
+        gen = coroutine.wrap(outer)
+    "#;
+    runtime.execute(source).unwrap();
+
+    for expected in [10.0, 11.0, 20.0, 21.0] {
+        assert_eq!(runtime.execute("return gen()").unwrap(), Value::Number(expected));
+    }
+    assert_eq!(runtime.execute("return gen()").unwrap(), string("done"));
+    assert!(runtime.execute("return gen()").is_err());
+}
+
+#[test]
+fn test_yield_through_pcall() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        function body()
+            local ok = pcall(function()
+                coroutine.yield(1)
+                coroutine.yield(2)
+                error("boom")
+            end)
+            coroutine.yield(ok)
+            return "after"
+        end
+        gen = coroutine.wrap(body)
+    "#;
+    runtime.execute(source).unwrap();
+
+    assert_eq!(runtime.execute("return gen()").unwrap(), Value::Number(1.0));
+    assert_eq!(runtime.execute("return gen()").unwrap(), Value::Number(2.0));
+    assert_eq!(runtime.execute("return gen()").unwrap(), Value::Boolean(false));
+    assert_eq!(runtime.execute("return gen()").unwrap(), string("after"));
+}
+
+#[test]
+fn test_wrap_as_generic_for_iterator() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        limit = 4
+        total = 0
+        for v in coroutine.wrap(function()
+            local i = 1
+            while i <= limit do
+                coroutine.yield(i)
+                i = i + 1
+            end
+        end) do
+            total = total + v
+        end
+        return total
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(10.0));
+}
+
+#[test]
+fn test_coroutines_have_separate_stacks() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        function count(start)
+            local i = start
+            while true do
+                coroutine.yield(i)
+                i = i + 1
+            end
+        end
+        a = coroutine.create(count)
+        b = coroutine.create(count)
+        coroutine.resume(a, 100)
+        coroutine.resume(b, 200)
+    "#;
+    runtime.execute(source).unwrap();
+    let a = coroutine(&mut runtime, "a");
+    let b = coroutine(&mut runtime, "b");
+
+    assert_eq!(runtime.resume(&a, Vec::new()), Ok(vec![Value::Number(101.0)]));
+    assert_eq!(runtime.resume(&b, Vec::new()), Ok(vec![Value::Number(201.0)]));
+    assert_eq!(runtime.resume(&a, Vec::new()), Ok(vec![Value::Number(102.0)]));
+}
+
+#[test]
+fn test_status_running_and_normal() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        outer = coroutine.create(function()
+            coroutine.yield(coroutine.status(outer))
+            coroutine.resume(inner)
+        end)
+        inner = coroutine.create(function()
+            seen = coroutine.status(outer)
+        end)
+    "#;
+    runtime.execute(source).unwrap();
+    let outer = coroutine(&mut runtime, "outer");
+
+    assert_eq!(runtime.resume(&outer, Vec::new()), Ok(vec![string("running")]));
+    assert_eq!(runtime.resume(&outer, Vec::new()), Ok(Vec::new()));
+    assert_eq!(runtime.get_global("seen"), string("normal"));
+    assert_eq!(outer.status(), CoroutineStatus::Dead);
+}
+
+#[test]
+fn test_running_and_isyieldable() {
+    let mut runtime = LuaJitRuntime::new();
+    assert_eq!(runtime.execute("return coroutine.isyieldable()").unwrap(), Value::Boolean(false));
+
+    // The main thread is a thread too, and the only one that is main
+    let main = results(&mut runtime, "return coroutine.running()");
+    assert!(matches!(main[0], Value::Thread(_)), "unexpected thread: {:?}", main[0]);
+    assert_eq!(main[1], Value::Boolean(true));
+    runtime.execute("main = coroutine.running()").unwrap();
+    assert_eq!(eval(&mut runtime, "coroutine.status(main)"), string("running"));
+
+    let source = r#"
+        co = coroutine.create(function()
+            local thread, is_main = coroutine.running()
+            coroutine.yield(thread == co, is_main)
+            coroutine.yield(coroutine.isyieldable(), coroutine.status(main))
+        end)
+    "#;
+    runtime.execute(source).unwrap();
+    let co = coroutine(&mut runtime, "co");
+    assert_eq!(runtime.resume(&co, Vec::new()), Ok(vec![Value::Boolean(true), Value::Boolean(false)]));
+    assert_eq!(runtime.resume(&co, Vec::new()), Ok(vec![Value::Boolean(true), string("normal")]));
+
+    let source = r#"
+        local ok, err = coroutine.resume(main)
+        return err
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("cannot resume non-suspended coroutine"));
+}
+
+#[test]
+fn test_errors_kill_the_coroutine() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime
+        .execute("co = coroutine.create(function() error('broken') end)")
+        .unwrap();
+    let co = coroutine(&mut runtime, "co");
+
+    let error = runtime.resume(&co, Vec::new()).unwrap_err();
+    assert!(error.contains("broken"), "unexpected error: {}", error);
+    assert_eq!(co.status(), CoroutineStatus::Dead);
+    assert_eq!(runtime.execute("return coroutine.close(co)").unwrap(), Value::Boolean(false));
+}
+
+#[test]
+fn test_close_suspended_coroutine() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        co = coroutine.create(function() coroutine.yield(1) end)
+        coroutine.resume(co)
+        closed = coroutine.close(co)
+        return coroutine.status(co)
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("dead"));
+    assert_eq!(runtime.get_global("closed"), Value::Boolean(true));
+}
+
+#[test]
+fn test_yield_outside_coroutine_is_an_error() {
+    let mut runtime = LuaJitRuntime::new();
+    assert!(runtime.execute("coroutine.yield(1)").is_err());
+}
+
+#[test]
+fn test_yield_across_native_call_is_an_error() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        t = setmetatable({}, { __index = function(t, k) coroutine.yield(k) end })
+        co = coroutine.create(function() return t.x end)
+    "#;
+    runtime.execute(source).unwrap();
+    let co = coroutine(&mut runtime, "co");
+
+    let error = runtime.resume(&co, Vec::new()).unwrap_err();
+    assert!(error.contains("C-call boundary"), "unexpected error: {}", error);
+}
+
+#[test]
+fn test_pcall_catches_errors() {
+    let mut runtime = LuaJitRuntime::new();
+    assert_eq!(runtime.execute("return pcall(error, 'x')").unwrap(), Value::Boolean(false));
+    assert_eq!(
+        runtime.execute("return pcall(function() return 1 end)").unwrap(),
+        Value::Boolean(true)
+    );
+    let error = runtime
+        .execute("local ok, err = pcall(function() error('inner') end) return err")
+        .unwrap();
+    assert!(error.to_string().contains("inner"), "unexpected error: {}", error);
+}
+
+#[test]
+fn test_suspended_coroutine_keeps_its_locals_alive() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        co = coroutine.create(function()
+            local t = { value = 5 }
+            coroutine.yield(1)
+            return t.value
+        end)
+        coroutine.resume(co)
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    let co = coroutine(&mut runtime, "co");
+    assert_eq!(runtime.resume(&co, Vec::new()), Ok(vec![Value::Number(5.0)]));
+}
@@ -0,0 +1,137 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+// Runs `call` and leaves its first two results in `ok` and `err`
+fn protected(runtime: &mut LuaJitRuntime, call: &str) -> (Value, Value) {
+    let source = format!("local status, value = {}\nok = status\nerr = value", call);
+    runtime.execute(&source).unwrap();
+    (runtime.get_global("ok"), runtime.get_global("err"))
+}
+
+#[test]
+fn test_pcall_returns_false_and_the_error() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("function fail() error('broken', 0) end").unwrap();
+    assert_eq!(protected(&mut runtime, "pcall(fail)"), (Value::Boolean(false), string("broken")));
+    runtime.execute("function succeed(x) return x * 2 end").unwrap();
+    assert_eq!(protected(&mut runtime, "pcall(succeed, 21)"), (Value::Boolean(true), Value::Number(42.0)));
+
+    // Errors the runtime raises itself come back as their message
+    runtime.execute("function index() return nothing.field end").unwrap();
+    let (ok, err) = protected(&mut runtime, "pcall(index)");
+    assert_eq!(ok, Value::Boolean(false));
+    assert!(err.to_string().contains("attempt to index a nil value"), "{}", err);
+    assert_eq!(eval(&mut runtime, "pcall(error)"), Value::Boolean(false));
+}
+
+#[test]
+fn test_error_values_keep_their_type() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("function fail() error({code = 42}) end").unwrap();
+    protected(&mut runtime, "pcall(fail)");
+    assert_eq!(eval(&mut runtime, "err.code"), Value::Number(42.0));
+
+    runtime.execute("function raise(value) error(value) end").unwrap();
+    assert_eq!(protected(&mut runtime, "pcall(raise)"), (Value::Boolean(false), Value::Nil));
+    assert_eq!(protected(&mut runtime, "pcall(raise, 7)").1, Value::Number(7.0));
+    assert_eq!(protected(&mut runtime, "pcall(raise, true)").1, Value::Boolean(true));
+
+    runtime.execute("function check() assert(false, {reason = 'failed'}) end").unwrap();
+    protected(&mut runtime, "pcall(check)");
+    assert_eq!(eval(&mut runtime, "err.reason"), string("failed"));
+
+    // Through coroutines too
+    runtime.execute("co = coroutine.create(fail)").unwrap();
+    protected(&mut runtime, "coroutine.resume(co)");
+    assert_eq!(eval(&mut runtime, "err.code"), Value::Number(42.0));
+
+    let error = runtime.execute("error({})").unwrap_err();
+    assert_eq!(error.to_string(), "(error object is a table value)");
+}
+
+#[test]
+fn test_error_level_selects_the_position() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = "function fail(message, level)\n    error(message, level)\nend\n\
+                  function caller(level)\n    fail('boom', level)\nend";
+    runtime.execute(source).unwrap();
+    let chunk = "[string \"function fail(message, level)...\"]";
+
+    let (_, err) = protected(&mut runtime, "pcall(caller)");
+    assert_eq!(err, string(&format!("{}:2: boom", chunk)));
+    let (_, err) = protected(&mut runtime, "pcall(caller, 2)");
+    assert_eq!(err, string(&format!("{}:5: boom", chunk)));
+    // Level 3 is `pcall`, which has no position
+    assert_eq!(protected(&mut runtime, "pcall(caller, 3)").1, string("boom"));
+    assert_eq!(protected(&mut runtime, "pcall(caller, 0)").1, string("boom"));
+    assert_eq!(protected(&mut runtime, "pcall(error, 'x')").1, string("x"));
+
+    let error = runtime.execute("\nerror('top level')").unwrap_err();
+    assert_eq!(error.to_string(), "[string \"...\"]:2: top level");
+}
+
+#[test]
+fn test_pcall_restores_the_stack() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function deep(n)
+            local marker = n
+            if n == 0 then
+                error("bottom")
+            end
+            return deep(n - 1) + marker
+        end
+        function count()
+            local total = 0
+            for i = 1, 100 do
+                local before = i
+                pcall(deep, 20)
+                total = total + before
+            end
+            return total
+        end
+        return count()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(5050.0));
+    // Unprotected errors still leave the runtime usable
+    assert!(runtime.execute("deep(3)").is_err());
+    assert_eq!(eval(&mut runtime, "count()"), Value::Number(5050.0));
+}
+
+#[test]
+fn test_xpcall_passes_errors_through_the_handler() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function handler(e)
+            return "handled: " .. e
+        end
+        function fail()
+            error("oops", 0)
+        end
+        function add(a, b)
+            return a + b
+        end
+    "#;
+    runtime.execute(source).unwrap();
+    assert_eq!(protected(&mut runtime, "xpcall(fail, handler)").1, string("handled: oops"));
+    assert_eq!(eval(&mut runtime, "xpcall(add, handler, 1, 2)"), Value::Boolean(true));
+
+    // Native functions and errors from inside `pcall` are handled as well
+    assert_eq!(protected(&mut runtime, "xpcall(error, handler)").0, Value::Boolean(false));
+    runtime.execute("function nested() pcall(fail) error('outer', 0) end").unwrap();
+    assert_eq!(protected(&mut runtime, "xpcall(nested, handler)").1, string("handled: outer"));
+
+    runtime.execute("function broken(e) error('again') end").unwrap();
+    assert_eq!(protected(&mut runtime, "xpcall(fail, broken)").1, string("error in error handling"));
+
+    let error = runtime.execute("xpcall(fail)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'xpcall' (function expected, got no value)"), "{}", error);
+}
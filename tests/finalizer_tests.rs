@@ -0,0 +1,187 @@
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+const SETUP: &str = r#"
+    log = {}
+    count = 0
+    mt = {
+        __gc = function(o)
+            count = count + 1
+            log[count] = o.name
+        end
+    }
+"#;
+
+fn runtime_with_log() -> LuaJitRuntime {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.execute(SETUP).unwrap();
+    runtime
+}
+
+fn logged(runtime: &mut LuaJitRuntime, index: usize) -> Value {
+    runtime.execute(&format!("return log[{}]", index)).unwrap()
+}
+
+fn name(s: &str) -> Value {
+    Value::String(s.into())
+}
+
+#[test]
+fn test_finalizer_runs_after_collection() {
+    let mut runtime = runtime_with_log();
+
+    runtime.execute("setmetatable({ name = 'a' }, mt)").unwrap();
+    assert_eq!(runtime.execute("return count").unwrap(), Value::Number(0.0));
+
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return count").unwrap(), Value::Number(1.0));
+    assert_eq!(logged(&mut runtime, 1), name("a"));
+}
+
+#[test]
+fn test_reachable_object_is_not_finalized() {
+    let mut runtime = runtime_with_log();
+
+    runtime.execute("keep = setmetatable({ name = 'kept' }, mt)").unwrap();
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return count").unwrap(), Value::Number(0.0));
+}
+
+#[test]
+fn test_finalizers_run_in_reverse_order_of_marking() {
+    let mut runtime = runtime_with_log();
+
+    let source = r#"
+        local a = { name = "a" }
+        local b = { name = "b" }
+        local c = { name = "c" }
+        setmetatable(b, mt)
+        setmetatable(a, mt)
+        setmetatable(c, mt)
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(logged(&mut runtime, 1), name("c"));
+    assert_eq!(logged(&mut runtime, 2), name("a"));
+    assert_eq!(logged(&mut runtime, 3), name("b"));
+}
+
+#[test]
+fn test_gc_field_added_after_setmetatable_is_ignored() {
+    let mut runtime = runtime_with_log();
+
+    let source = r#"
+        late = {}
+        setmetatable({ name = "late" }, late)
+        late.__gc = mt.__gc
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return count").unwrap(), Value::Number(0.0));
+}
+
+#[test]
+fn test_resurrected_object_keeps_its_contents() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        finalized = 0
+        local mt = {
+            __gc = function(o)
+                finalized = finalized + 1
+                saved = o
+            end
+        }
+        local object = setmetatable({ child = { value = 42 } }, mt)
+        object.me = object
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return saved.child.value").unwrap(), Value::Number(42.0));
+    assert_eq!(runtime.execute("return saved.me == saved").unwrap(), Value::Boolean(true));
+
+    // The finalizer only runs once; the second time the object is freed
+    let baseline = runtime.memory_used();
+    runtime.execute("saved = nil").unwrap();
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return finalized").unwrap(), Value::Number(1.0));
+    assert!(runtime.memory_used() < baseline);
+}
+
+#[test]
+fn test_object_being_finalized_leaves_weak_values_but_not_weak_keys() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        by_value = setmetatable({}, { __mode = "v" })
+        by_key = setmetatable({}, { __mode = "k" })
+        local mt = {
+            __gc = function(o)
+                in_values = by_value[1]
+                in_keys = by_key[o]
+            end
+        }
+        local object = setmetatable({}, mt)
+        by_value[1] = object
+        by_key[object] = "attached"
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return in_values").unwrap(), Value::Nil);
+    assert_eq!(runtime.execute("return in_keys").unwrap(), name("attached"));
+
+    // Once finalized, the object is collected for good
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return next(by_key)").unwrap(), Value::Nil);
+}
+
+#[test]
+fn test_finalizer_errors_become_warnings() {
+    let mut runtime = runtime_with_log();
+
+    let source = r#"
+        local failing = { __gc = function(o) error("boom") end }
+        setmetatable({ name = "first" }, mt)
+        setmetatable({}, failing)
+        setmetatable({ name = "last" }, mt)
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return count").unwrap(), Value::Number(2.0));
+    assert_eq!(runtime.warnings().len(), 1);
+    assert!(runtime.warnings()[0].starts_with("error in __gc"));
+    assert!(runtime.warnings()[0].contains("boom"));
+}
+
+#[test]
+fn test_finalizers_run_with_incremental_steps() {
+    let mut runtime = runtime_with_log();
+
+    runtime.execute("setmetatable({ name = 'stepped' }, mt)").unwrap();
+    while !runtime.gc_step() {}
+
+    assert_eq!(logged(&mut runtime, 1), name("stepped"));
+}
+
+#[test]
+fn test_pending_finalizers_run_when_runtime_is_dropped() {
+    let mut runtime = runtime_with_log();
+
+    runtime.execute("keep = setmetatable({ name = 'kept' }, mt)").unwrap();
+    runtime.execute("setmetatable({ name = 'garbage' }, mt)").unwrap();
+    let log = match runtime.execute("return log").unwrap() {
+        Value::Table(log) => log,
+        other => panic!("expected table, got {:?}", other),
+    };
+
+    drop(runtime);
+
+    assert_eq!(log.borrow().len(), 2);
+    let names: Vec<Value> = log.borrow().values().map(|name| name.clone()).collect();
+    assert!(names.contains(&name("kept")));
+    assert!(names.contains(&name("garbage")));
+}
@@ -0,0 +1,153 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+use std::path::PathBuf;
+
+/// A runtime with the global `root` naming a fresh directory in the
+/// temporary directory, removed with its contents when the test ends.
+struct Scratch {
+    runtime: LuaJitRuntime,
+    root: PathBuf,
+}
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("luna_fs_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir(&root).unwrap();
+        let mut runtime = LuaJitRuntime::new();
+        runtime.set_global("root", string(root.to_str().unwrap()));
+        Self { runtime, root }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[test]
+fn test_dir_lists_entries() {
+    let mut scratch = Scratch::new("dir");
+    std::fs::write(scratch.root.join("a.txt"), "a").unwrap();
+    std::fs::create_dir(scratch.root.join("sub")).unwrap();
+    let runtime = &mut scratch.runtime;
+
+    let source = r#"
+        names = {}
+        count = 0
+        for name in fs.dir(root) do
+            names[name] = fs.attributes(root .. "/" .. name, "mode")
+            count = count + 1
+        end
+        return count
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(4.0));
+    assert_eq!(eval(runtime, "names['.']"), string("directory"));
+    assert_eq!(eval(runtime, "names['..']"), string("directory"));
+    assert_eq!(eval(runtime, "names['a.txt']"), string("file"));
+    assert_eq!(eval(runtime, "names.sub"), string("directory"));
+
+    let root = runtime.get_global("root");
+    let values = luna::stdlib::fs_dir(runtime, &[root]).unwrap();
+    assert_eq!(values.len(), 2);
+    runtime.set_global("d", values[1].clone());
+    assert_eq!(eval(runtime, "d:next()"), string("."));
+    assert_eq!(eval(runtime, "d:next()"), string(".."));
+    runtime.execute("d:close()").unwrap();
+    let error = runtime.execute("d:next()").unwrap_err();
+    assert!(error.to_string().contains("closed directory"), "{}", error);
+    let error = runtime.execute("fs.dir(root .. '/missing')").unwrap_err();
+    assert!(error.to_string().contains("cannot open"), "{}", error);
+    assert!(error.to_string().contains("No such file or directory"), "{}", error);
+}
+
+#[test]
+fn test_attributes() {
+    let mut scratch = Scratch::new("attributes");
+    std::fs::write(scratch.root.join("data"), "0123456789").unwrap();
+    let runtime = &mut scratch.runtime;
+
+    let source = r#"
+        path = root .. "/data"
+        fs.touch(path, 1000000, 2000000)
+        a = fs.attributes(path)
+        return a.size
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(10.0));
+    assert_eq!(eval(runtime, "a.mode"), string("file"));
+    assert_eq!(eval(runtime, "a.access"), Value::Number(1000000.0));
+    assert_eq!(eval(runtime, "a.modification"), Value::Number(2000000.0));
+    assert_eq!(eval(runtime, "string.len(a.permissions)"), Value::Number(9.0));
+    assert_eq!(eval(runtime, "fs.attributes(path, 'size')"), Value::Number(10.0));
+    assert_eq!(eval(runtime, "fs.attributes(root).mode"), string("directory"));
+
+    let source = r#"
+        t = {}
+        return fs.attributes(path, t) == t and t.size == 10
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+
+    let error = runtime.execute("fs.attributes(path, 'colour')").unwrap_err();
+    assert!(error.to_string().contains("invalid attribute name 'colour'"), "{}", error);
+    let missing = format!("{}/missing", scratch.root.to_str().unwrap());
+    let values = luna::stdlib::fs_attributes(&mut scratch.runtime, &[string(&missing)]).unwrap();
+    let message = format!("cannot obtain information from file '{}': No such file or directory", missing);
+    assert_eq!(values, vec![Value::Nil, string(&message), Value::Number(2.0)]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinkattributes() {
+    let mut scratch = Scratch::new("links");
+    std::fs::write(scratch.root.join("target"), "xyz").unwrap();
+    std::os::unix::fs::symlink("target", scratch.root.join("link")).unwrap();
+    let runtime = &mut scratch.runtime;
+
+    assert_eq!(eval(runtime, "fs.attributes(root .. '/link', 'mode')"), string("file"));
+    assert_eq!(eval(runtime, "fs.symlinkattributes(root .. '/link', 'mode')"), string("link"));
+    assert_eq!(eval(runtime, "fs.symlinkattributes(root .. '/link').target"), string("target"));
+    assert_eq!(eval(runtime, "fs.symlinkattributes(root .. '/link', 'target')"), string("target"));
+    assert_eq!(eval(runtime, "fs.symlinkattributes(root .. '/target', 'target')"), Value::Nil);
+}
+
+#[test]
+fn test_mkdir_rmdir_and_touch() {
+    let mut scratch = Scratch::new("mkdir");
+    let runtime = &mut scratch.runtime;
+
+    assert_eq!(eval(runtime, "fs.mkdir(root .. '/new')"), Value::Boolean(true));
+    assert!(scratch.root.join("new").is_dir());
+    let existing = format!("{}/new", scratch.root.to_str().unwrap());
+    let values = luna::stdlib::fs_mkdir(&mut scratch.runtime, &[string(&existing)]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("File exists"), Value::Number(17.0)]);
+
+    let runtime = &mut scratch.runtime;
+    assert_eq!(eval(runtime, "fs.rmdir(root .. '/new')"), Value::Boolean(true));
+    assert!(!scratch.root.join("new").exists());
+    assert_eq!(eval(runtime, "fs.rmdir(root .. '/new')"), Value::Nil);
+
+    assert_eq!(eval(runtime, "fs.touch(root .. '/absent')"), Value::Nil);
+    std::fs::write(scratch.root.join("present"), "").unwrap();
+    let runtime = &mut scratch.runtime;
+    assert_eq!(eval(runtime, "fs.touch(root .. '/present', 5000)"), Value::Boolean(true));
+    assert_eq!(eval(runtime, "fs.attributes(root .. '/present', 'modification')"), Value::Number(5000.0));
+    assert_eq!(eval(runtime, "fs.touch(root .. '/present')"), Value::Boolean(true));
+    assert_eq!(eval(runtime, "os.time() - fs.attributes(root .. '/present', 'access') < 5"), Value::Boolean(true));
+}
+
+#[test]
+fn test_currentdir_and_chdir() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let current = std::env::current_dir().unwrap();
+    assert_eq!(eval(&mut runtime, "fs.currentdir()"), string(current.to_str().unwrap()));
+    assert_eq!(eval(&mut runtime, "fs.chdir(fs.currentdir())"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "fs.chdir('/nonexistent/luna/dir')"), Value::Nil);
+    let values = luna::stdlib::fs_chdir(&mut runtime, &[string("/nonexistent/luna/dir")]).unwrap();
+    let message = "Unable to change working directory to '/nonexistent/luna/dir'\nNo such file or directory\n";
+    assert_eq!(values, vec![Value::Nil, string(message)]);
+}
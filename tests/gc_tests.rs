@@ -0,0 +1,286 @@
+use luna::gc::GcMode;
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+use luna::LunaConfig;
+
+#[test]
+fn test_collect_frees_cycles() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.gc_stop();
+
+    let baseline = runtime.memory_used();
+
+    let source = r#"
+        for i = 1, 200 do
+            local parent = {}
+            local child = { parent = parent }
+            parent.child = child
+            parent.me = parent
+        end
+    "#;
+    runtime.execute(source).unwrap();
+    assert!(runtime.memory_used() > baseline);
+
+    let freed = runtime.gc_collect();
+    assert_eq!(freed, 400);
+    assert_eq!(runtime.memory_used(), baseline);
+}
+
+#[test]
+fn test_collect_frees_closures_that_capture_themselves() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.gc_stop();
+
+    let baseline = runtime.memory_used();
+
+    let source = r#"
+        for i = 1, 100 do
+            local f
+            f = function() return f end
+        end
+    "#;
+    runtime.execute(source).unwrap();
+    assert!(runtime.memory_used() > baseline);
+
+    assert_eq!(runtime.gc_collect(), 100);
+    assert_eq!(runtime.memory_used(), baseline);
+}
+
+#[test]
+fn test_collect_keeps_reachable_tables() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("keep = { inner = { value = 42 } }").unwrap();
+    runtime.execute("keep.inner.self = keep.inner").unwrap();
+    runtime.gc_collect();
+
+    let result = runtime.execute("return keep.inner.value").unwrap();
+    assert_eq!(result, Value::Number(42.0));
+}
+
+#[test]
+fn test_collectgarbage_options() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("return collectgarbage('count') > 0").unwrap();
+    assert_eq!(result, Value::Boolean(true));
+
+    let result = runtime.execute("return collectgarbage()").unwrap();
+    assert_eq!(result, Value::Number(0.0));
+
+    let result = runtime.execute("return collectgarbage('step')").unwrap();
+    assert!(matches!(result, Value::Boolean(_)));
+
+    runtime.execute("collectgarbage('stop')").unwrap();
+    let result = runtime.execute("return collectgarbage('isrunning')").unwrap();
+    assert_eq!(result, Value::Boolean(false));
+
+    runtime.execute("collectgarbage('restart')").unwrap();
+    assert!(runtime.gc_is_running());
+
+    assert!(runtime.execute("collectgarbage('bogus')").is_err());
+}
+
+#[test]
+fn test_automatic_collection_bounds_memory() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        for i = 1, 20000 do
+            local node = {}
+            node.next = node
+        end
+    "#;
+    runtime.execute(source).unwrap();
+
+    // Without automatic collection every cycle above would still be alive
+    assert!(runtime.memory_used() < 20000 * 64);
+}
+
+#[test]
+fn test_incremental_steps_complete_a_cycle() {
+    let config = LunaConfig {
+        gc_step_multiplier: 1,
+        ..LunaConfig::default()
+    };
+    let mut runtime = LuaJitRuntime::with_config(config);
+    runtime.gc_stop();
+
+    runtime.execute("for i = 1, 50 do local t = {} t.me = t end").unwrap();
+
+    let mut steps = 1;
+    while !runtime.gc_step() {
+        steps += 1;
+    }
+    assert!(steps > 2);
+    assert_eq!(runtime.gc_stats().cycles, 1);
+    assert_eq!(runtime.gc_stats().objects_freed, 50);
+}
+
+#[test]
+fn test_generational_mode_from_config() {
+    let config = LunaConfig {
+        gc_mode: GcMode::Generational,
+        ..LunaConfig::default()
+    };
+    let mut runtime = LuaJitRuntime::with_config(config);
+    assert_eq!(runtime.gc_mode(), GcMode::Generational);
+
+    let source = r#"
+        keep = {}
+        for i = 1, 20000 do
+            local node = {}
+            node.next = node
+        end
+    "#;
+    runtime.execute(source).unwrap();
+
+    assert!(runtime.gc_stats().minor_collections > 0);
+    assert!(runtime.memory_used() < 20000 * 64);
+}
+
+#[test]
+fn test_collectgarbage_switches_modes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("return collectgarbage('generational')").unwrap();
+    assert_eq!(result, Value::String("incremental".into()));
+    assert_eq!(runtime.gc_mode(), GcMode::Generational);
+
+    let result = runtime.execute("return collectgarbage('incremental', 150, 200, 12)").unwrap();
+    assert_eq!(result, Value::String("generational".into()));
+    assert_eq!(runtime.gc_mode(), GcMode::Incremental);
+}
+
+#[test]
+fn test_pause_histogram_records_collections() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.gc_collect();
+    runtime.gc_collect();
+
+    let recorded: usize = runtime.gc_stats().pause_histogram.iter().sum();
+    assert_eq!(recorded, 2);
+}
+
+#[test]
+fn test_tables_rooted_by_the_host_survive_collection() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let table = runtime.create_table();
+    let root = runtime.root(Value::Table(table.clone()));
+    table.set(Value::String("answer".into()), Value::Number(42.0));
+    let inner = runtime.create_table();
+    inner.set(Value::String("outer".into()), Value::Table(table.clone()));
+    table.set(Value::String("inner".into()), Value::Table(inner));
+    runtime.gc_collect();
+    assert_eq!(table.get(&Value::String("answer".into())), Value::Number(42.0));
+    match table.get(&Value::String("inner".into())) {
+        Value::Table(inner) => assert_eq!(inner.get(&Value::String("outer".into())), Value::Table(table.clone())),
+        other => panic!("expected a table, got {:?}", other),
+    }
+
+    // Incremental steps and minor collections see rooted values too
+    while !runtime.gc_step() {}
+    runtime.gc_set_generational(0, 0);
+    let young = runtime.create_table();
+    let young_root = runtime.root(Value::Table(young.clone()));
+    young.set(Value::Number(1.0), Value::Boolean(true));
+    runtime.gc_step();
+    assert_eq!(young.get(&Value::Number(1.0)), Value::Boolean(true));
+    assert_eq!(table.get(&Value::String("answer".into())), Value::Number(42.0));
+
+    // Without the handles, the tables are kept while the host holds them
+    drop(root);
+    drop(young_root);
+    runtime.gc_step();
+    runtime.gc_collect();
+    assert_eq!(table.get(&Value::String("answer".into())), Value::Number(42.0));
+    assert_eq!(young.get(&Value::Number(1.0)), Value::Boolean(true));
+
+    // and the cycle is freed once it lets go
+    drop(table);
+    drop(young);
+    assert_eq!(runtime.gc_collect(), 2);
+}
+
+#[test]
+fn test_objects_held_by_the_host_survive_collection() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("local t = {} t.x = 42 return t").unwrap();
+    let table = runtime.create_table();
+    table.set(Value::String("list".into()), result.clone());
+    let metatable = runtime.create_table();
+    let userdata = runtime.create_userdata(7u8, Some(metatable.clone()));
+    runtime.execute("config = { name = 'luna' }").unwrap();
+    let config = runtime.get_global("config");
+    runtime.execute("config = nil").unwrap();
+    runtime.gc_collect();
+
+    let field = |value: &Value, key: &str| match value {
+        Value::Table(table) => table.get(&Value::String(key.into())),
+        other => panic!("expected a table, got {:?}", other),
+    };
+    assert_eq!(field(&result, "x"), Value::Number(42.0));
+    assert_eq!(field(&Value::Table(table.clone()), "list"), result);
+    assert_eq!(userdata.metatable(), Some(metatable));
+    assert_eq!(field(&config, "name"), Value::String("luna".into()));
+
+    // Minor collections keep them too, along with what they reference
+    runtime.gc_set_generational(0, 0);
+    let young = runtime.create_table();
+    young.set(Value::Number(1.0), Value::Table(runtime.create_table()));
+    runtime.gc_step();
+    match young.get(&Value::Number(1.0)) {
+        Value::Table(_) => {}
+        other => panic!("expected a table, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_root_keeps_returned_values_alive() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("return {a = {b = 1}}").unwrap();
+    let root = runtime.root(result);
+    runtime.gc_collect();
+    let a = match root.value() {
+        Value::Table(table) => table.get(&Value::String("a".into())),
+        other => panic!("expected a table, got {:?}", other),
+    };
+    match a {
+        Value::Table(a) => assert_eq!(a.get(&Value::String("b".into())), Value::Number(1.0)),
+        other => panic!("expected a table, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_rooted_coroutines_survive_collection() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("function counter() coroutine.yield(1) coroutine.yield(2) end").unwrap();
+    let function = runtime.get_global("counter");
+    runtime.execute("counter = nil").unwrap();
+    let co = runtime.create_coroutine(function);
+    let _root = runtime.root(Value::Thread(co.clone()));
+    runtime.gc_collect();
+    assert_eq!(runtime.resume(&co, Vec::new()), Ok(vec![Value::Number(1.0)]));
+    runtime.gc_collect();
+    assert_eq!(runtime.resume(&co, Vec::new()), Ok(vec![Value::Number(2.0)]));
+}
+
+#[test]
+fn test_memory_used_follows_allocation_and_release() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.gc_stop();
+
+    let baseline = runtime.memory_used();
+    runtime.execute("big = {} for i = 1, 1000 do big[i] = i end").unwrap();
+    let grown = runtime.memory_used();
+    assert!(grown > baseline + 1000 * 8);
+
+    // Acyclic garbage is given back as soon as it is dropped
+    runtime.execute("big = nil").unwrap();
+    assert!(runtime.memory_used() < grown - 1000 * 8);
+}
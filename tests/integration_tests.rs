@@ -58,7 +58,7 @@ fn test_if_statement() {
     "#;
 
     let result = runtime.execute(source).unwrap();
-    assert_eq!(result, Value::String("greater".to_string()));
+    assert_eq!(result, Value::String("greater".into()));
 }
 
 #[test]
@@ -167,3 +167,245 @@ fn test_error_handling() {
     let result = runtime.execute("return 5 +");
     assert!(result.is_err());
 }
+
+#[test]
+fn test_lua_functions() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function fact(n)
+            if n <= 1 then
+                return 1
+            end
+            return n * fact(n - 1)
+        end
+        return fact(5)
+    "#;
+    let result = runtime.execute(source).unwrap();
+    assert_eq!(result, Value::Number(120.0));
+
+    let source = r#"
+        local twice = function(f, x) return f(f(x)) end
+        return twice(function(x) return x + 3 end, 1)
+    "#;
+    let result = runtime.execute(source).unwrap();
+    assert_eq!(result, Value::Number(7.0));
+
+    let result = runtime.execute("return type(fact)").unwrap();
+    assert_eq!(result, Value::String("function".into()));
+}
+
+#[test]
+fn test_function_values_have_identity() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("function make() return function() end end").unwrap();
+    let result = runtime.execute("return make() == make()").unwrap();
+    assert_eq!(result, Value::Boolean(false));
+
+    let result = runtime.execute("f = make() return f == f").unwrap();
+    assert_eq!(result, Value::Boolean(true));
+}
+
+#[test]
+fn test_native_function_values() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("return type(print)").unwrap();
+    assert_eq!(result, Value::String("function".into()));
+
+    let result = runtime.execute("return print == print and math.abs == math.abs").unwrap();
+    assert_eq!(result, Value::Boolean(true));
+    let result = runtime.execute("return print == type").unwrap();
+    assert_eq!(result, Value::Boolean(false));
+
+    let result = runtime.execute("local t = {} t[print] = 1 t[type] = 2 return t[print]").unwrap();
+    assert_eq!(result, Value::Number(1.0));
+
+    match runtime.get_global("print") {
+        Value::Native(native) => assert_eq!(native.name(), "print"),
+        other => panic!("expected a native function, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_function_values_print_kind_and_address() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("function fact(n) return n end").unwrap();
+    let builtin = runtime.execute("return tostring(print)").unwrap().to_string();
+    assert!(builtin.starts_with("builtin: 0x"), "unexpected: {}", builtin);
+    let function = runtime.execute("return tostring(fact)").unwrap().to_string();
+    assert!(function.starts_with("function: 0x"), "unexpected: {}", function);
+
+    match runtime.get_global("fact") {
+        Value::Closure(closure) => assert_eq!(closure.name(), "fact"),
+        other => panic!("expected a Lua function, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_closures_capture_enclosing_locals() {
+    let mut runtime = LuaJitRuntime::new();
+
+    // The chunk and the function share the variable while it is in scope,
+    // and the function keeps it afterwards
+    let source = r#"
+        local count = 0
+        function bump() count = count + 1 return count end
+        bump()
+        bump()
+        return count
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(2.0));
+    assert_eq!(runtime.execute("return bump()").unwrap(), Value::Number(3.0));
+
+    let source = r#"
+        function counter()
+            local n = 0
+            return function() n = n + 1 return n end
+        end
+        a = counter()
+        b = counter()
+        a()
+        a()
+        return a() .. "," .. b()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::String("3,1".into()));
+
+    // Functions in between capture the variable too
+    let source = r#"
+        function outer()
+            local x = 1
+            local access = {}
+            access.get = function() return function() return x end end
+            access.add = function(n) x = x + n end
+            return access
+        end
+        local pair = outer()
+        local get = pair.get()
+        pair.add(10)
+        return get()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(11.0));
+}
+
+#[test]
+fn test_each_iteration_has_its_own_locals() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        fs = {}
+        for i = 1, 3 do
+            local j = i * 10
+            fs[i] = function() return i + j end
+        end
+        ws = {}
+        local k = 0
+        while k < 3 do
+            k = k + 1
+            local v = k
+            ws[k] = function() return v end
+        end
+        gs = {}
+        local n = 0
+        for letter in string.gmatch("ab", "%a") do
+            n = n + 1
+            gs[n] = function() return letter end
+        end
+    "#;
+    runtime.execute(source).unwrap();
+    assert_eq!(runtime.execute("return fs[1]() + fs[3]()").unwrap(), Value::Number(44.0));
+    assert_eq!(runtime.execute("return ws[1]() + ws[3]()").unwrap(), Value::Number(4.0));
+    assert_eq!(runtime.execute("return gs[1]() .. gs[2]()").unwrap(), Value::String("ab".into()));
+
+    // Locals of a block are gone once it ends
+    let source = r#"
+        if true then
+            local hidden = 1
+        end
+        return hidden
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Nil);
+}
+
+#[test]
+fn test_coroutines_share_captured_locals() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        local total = 0
+        local add = coroutine.wrap(function()
+            for i = 1, 3 do
+                total = total + i
+                coroutine.yield()
+            end
+        end)
+        add()
+        add()
+        add()
+        return total
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(6.0));
+}
+
+#[test]
+fn test_concatenated_strings_are_equal_keys() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        local long = "a fairly long string that is not interned at all"
+        local t = { key = 1 }
+        t[long] = 2
+        return t["k" .. "ey"] + t["a fairly long string " .. "that is not interned at all"]
+    "#;
+    let result = runtime.execute(source).unwrap();
+    assert_eq!(result, Value::Number(3.0));
+}
+
+#[test]
+fn test_calls_keep_every_result_where_a_list_ends() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function three() return 1, 2, 3 end
+        function none() end
+        local a, b, c, d = three()
+        local e, f = three(), 10
+        x, y = 0, three()
+        local t = {}
+        t.first, t.second = three()
+        local p = table.pack(0, three())
+        local q = table.pack(three(), 0)
+        local r = table.pack((three()))
+        local s = {none()}
+        local u = {0, three()}
+        local locals = a + b + c == 6 and d == nil and e == 1 and f == 10
+        local assigned = x == 0 and y == 1 and t.first == 1 and t.second == 2
+        local packed = p.n == 4 and p[4] == 3 and q.n == 2 and r.n == 1
+        return locals and assigned and packed and s[1] == nil and u[4] == 3
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+}
+
+#[test]
+fn test_multiple_assignment_evaluates_before_storing() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let result = runtime.execute("local a, b = 1, 2 a, b = b, a return a * 10 + b").unwrap();
+    assert_eq!(result, Value::Number(21.0));
+    let result = runtime.execute("local t = {} local i = 1 i, t[i] = 2, 'x' return t[1]").unwrap();
+    assert_eq!(result, Value::String("x".into()));
+}
+
+#[test]
+fn test_print_shows_every_result() {
+    let path = std::env::temp_dir().join(format!("luna_print_{}.lua", std::process::id()));
+    std::fs::write(&path, "print(pcall(error, 'oops', 0))\nprint(table.unpack({1, 2}))\n").unwrap();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_luna")).arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("false\toops\n"), "unexpected output: {}", stdout);
+    assert!(stdout.contains("1\t2\n"), "unexpected output: {}", stdout);
+}
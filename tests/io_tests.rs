@@ -0,0 +1,188 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+use std::path::PathBuf;
+
+/// A runtime with the global `path` naming a fresh file in the temporary
+/// directory, removed when the test ends.
+struct Scratch {
+    runtime: LuaJitRuntime,
+    path: PathBuf,
+}
+
+impl Scratch {
+    fn new(name: &str, contents: Option<&str>) -> Self {
+        let path = std::env::temp_dir().join(format!("luna_io_{}_{}", std::process::id(), name));
+        match contents {
+            Some(contents) => std::fs::write(&path, contents).unwrap(),
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        let mut runtime = LuaJitRuntime::new();
+        runtime.set_global("path", string(path.to_str().unwrap()));
+        Self { runtime, path }
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[test]
+fn test_write_then_read_formats() {
+    let mut scratch = Scratch::new("formats", None);
+    let source = r#"
+        f = io.open(path, "w")
+        f:write("first line\n", 42, " 3.5\n", "tail")
+        return f:close()
+    "#;
+    assert_eq!(scratch.runtime.execute(source).unwrap(), Value::Boolean(true));
+    assert_eq!(std::fs::read_to_string(&scratch.path).unwrap(), "first line\n42 3.5\ntail");
+
+    let runtime = &mut scratch.runtime;
+    runtime.execute("f = io.open(path)").unwrap();
+    assert_eq!(eval(runtime, "f:read('l')"), string("first line"));
+    assert_eq!(eval(runtime, "f:read('n')"), Value::Number(42.0));
+    assert_eq!(eval(runtime, "f:read('n')"), Value::Number(3.5));
+    assert_eq!(eval(runtime, "f:read('L')"), string("\n"));
+    assert_eq!(eval(runtime, "f:read(2)"), string("ta"));
+    assert_eq!(eval(runtime, "f:read('a')"), string("il"));
+    assert_eq!(eval(runtime, "f:read('a')"), string(""));
+    assert_eq!(eval(runtime, "f:read('l')"), Value::Nil);
+    assert_eq!(eval(runtime, "f:read(0)"), Value::Nil);
+    assert_eq!(eval(runtime, "f:seek('set', 6)"), Value::Number(6.0));
+    assert_eq!(eval(runtime, "f:read('*l')"), string("line"));
+    assert_eq!(eval(runtime, "f:seek('end')"), Value::Number(22.0));
+    runtime.execute("f:close()").unwrap();
+
+    let error = runtime.execute("return f:read()").unwrap_err();
+    assert!(error.to_string().contains("attempt to use a closed file"), "{}", error);
+}
+
+#[test]
+fn test_read_multiple_formats() {
+    let mut scratch = Scratch::new("multiple", Some("7 8\nnot a number"));
+    let runtime = &mut scratch.runtime;
+    runtime.execute("f = io.open(path, 'r')").unwrap();
+    let f = runtime.get_global("f");
+
+    let values = luna::stdlib::file_read(runtime, &[f.clone(), string("n"), string("n"), string("l")]).unwrap();
+    assert_eq!(values, vec![Value::Number(7.0), Value::Number(8.0), string("")]);
+    // A failed format ends the results with nil
+    let values = luna::stdlib::file_read(runtime, &[f.clone(), string("n"), string("l")]).unwrap();
+    assert_eq!(values, vec![Value::Nil]);
+
+    let error = luna::stdlib::file_read(runtime, &[f, string("x")]).unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'read' (invalid format)"), "{}", error);
+}
+
+#[test]
+fn test_open_failure_returns_message_and_errno() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let values = luna::stdlib::io_open(&mut runtime, &[string("/nonexistent/luna/file")]).unwrap();
+    assert_eq!(values[0], Value::Nil);
+    assert_eq!(values[1], string("/nonexistent/luna/file: No such file or directory"));
+    assert_eq!(values[2], Value::Number(2.0));
+
+    let error = runtime.execute("return io.open('x', 'rw')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'open' (invalid mode)"), "{}", error);
+}
+
+#[test]
+fn test_lines() {
+    let mut scratch = Scratch::new("lines", Some("a\nbb\n\nccc"));
+    let runtime = &mut scratch.runtime;
+
+    let source = r#"
+        result = ""
+        for line in io.lines(path) do
+            result = result .. "[" .. line .. "]"
+        end
+        return result
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("[a][bb][][ccc]"));
+
+    let source = r#"
+        f = io.open(path)
+        result = ""
+        for chunk in f:lines(2) do
+            result = result .. "<" .. chunk .. ">"
+        end
+        return io.type(f)
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("file"));
+    assert_eq!(eval(runtime, "result"), string("<a\n><bb><\n\n><cc><c>"));
+
+    let error = runtime.execute("for l in io.lines('/nonexistent/luna/file') do end").unwrap_err();
+    assert!(error.to_string().contains("No such file or directory"), "{}", error);
+}
+
+#[test]
+fn test_default_input_and_output() {
+    let mut scratch = Scratch::new("defaults", None);
+    let runtime = &mut scratch.runtime;
+
+    let source = r#"
+        old = io.output()
+        io.output(path)
+        io.write("one\n", 2, "\n")
+        io.close()
+        io.output(old)
+        io.input(path)
+        a = io.read()
+        b = io.read("n")
+        io.input():close()
+        return a .. b
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("one2"));
+    assert_eq!(eval(runtime, "io.output() == io.stdout"), Value::Boolean(true));
+
+    let error = runtime.execute("return io.read()").unwrap_err();
+    assert!(error.to_string().contains("default input file is closed"), "{}", error);
+    let error = runtime.execute("io.input('/nonexistent/luna/file')").unwrap_err();
+    assert!(error.to_string().contains("cannot open file '/nonexistent/luna/file'"), "{}", error);
+}
+
+#[test]
+fn test_types_and_standard_files() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "io.type(io.stdout)"), string("file"));
+    assert_eq!(eval(&mut runtime, "io.type(42)"), Value::Nil);
+    assert_eq!(eval(&mut runtime, "io.type(io.stdin) == io.type(io.stderr)"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "io.stdout:setvbuf('line')"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "io.write('') == io.stdout"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "io.stdout:close()"), Value::Nil);
+    assert_eq!(eval(&mut runtime, "io.type(io.stdout)"), string("file"));
+    assert_eq!(eval(&mut runtime, "string.sub(tostring(io.stderr), 1, 6)"), string("file ("));
+
+    let error = runtime.execute("io.stdout:setvbuf('sometimes')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'setvbuf' (invalid option 'sometimes')"), "{}", error);
+    let error = runtime.execute("io.write({})").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'write' (string expected, got table)"), "{}", error);
+}
+
+#[test]
+fn test_tmpfile() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        f = io.tmpfile()
+        f:write("scratch data")
+        f:seek("set", 8)
+        f:write("DATA")
+        f:seek("set")
+        s = f:read("a")
+        f:close()
+        return s
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("scratch DATA"));
+    assert_eq!(eval(&mut runtime, "io.type(f)"), string("closed file"));
+    assert_eq!(eval(&mut runtime, "tostring(f)"), string("file (closed)"));
+}
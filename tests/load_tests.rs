@@ -0,0 +1,218 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::stdlib::{builtin_load, builtin_loadfile};
+use luna::value::Value;
+
+// The message `load` returned with nil
+fn load_error(runtime: &mut LuaJitRuntime, args: &[Value]) -> String {
+    let values = builtin_load(runtime, args).unwrap();
+    assert_eq!(values.len(), 2, "{:?}", values);
+    assert_eq!(values[0], Value::Nil);
+    values[1].to_string()
+}
+
+fn temporary_file(name: &str, contents: impl AsRef<[u8]>) -> String {
+    let path = std::env::temp_dir().join(format!("luna_load_{}_{}", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_load_compiles_strings() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        f = load("return 1 + 2")
+        return f()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(3.0));
+    assert_eq!(eval(&mut runtime, "type(load('x = 1'))"), string("function"));
+
+    // Nothing runs until the function is called
+    runtime.execute("g = load('loaded = true')").unwrap();
+    assert_eq!(eval(&mut runtime, "loaded"), Value::Nil);
+    runtime.execute("g()").unwrap();
+    assert_eq!(eval(&mut runtime, "loaded"), Value::Boolean(true));
+
+    let error = runtime.execute("load()").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'load' (function expected, got no value)"), "{}", error);
+}
+
+#[test]
+fn test_syntax_errors_name_the_chunk() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let message = load_error(&mut runtime, &[string("x = = 1")]);
+    assert!(message.starts_with("[string \"x = = 1\"]:1: "), "{}", message);
+    let message = load_error(&mut runtime, &[string("x = 1\ny = = 2")]);
+    assert!(message.starts_with("[string \"x = 1...\"]:2: "), "{}", message);
+    let message = load_error(&mut runtime, &[string("x = 1\n\ny = (1"), string("=config")]);
+    assert!(message.starts_with("config:3: "), "{}", message);
+    let message = load_error(&mut runtime, &[string("return ~"), string("@settings.lua")]);
+    assert!(message.starts_with("settings.lua:1: "), "{}", message);
+
+    let long = "x".repeat(100);
+    let message = load_error(&mut runtime, &[string("return return"), string(&format!("={}", long))]);
+    assert!(message.starts_with(&format!("{}:1: ", &long[..59])), "{}", message);
+    let source = format!("return '{}' +", long);
+    let message = load_error(&mut runtime, &[string(&source)]);
+    assert!(message.starts_with(&format!("[string \"{}...\"]:1: ", &source[..45])), "{}", message);
+
+    // Errors come back as values rather than being raised
+    assert_eq!(eval(&mut runtime, "load('return return') == nil"), Value::Boolean(true));
+}
+
+#[test]
+fn test_load_from_a_reader_function() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        parts = {"return ", "'read'", " .. ' in pieces'"}
+        i = 0
+        function reader()
+            i = i + 1
+            return parts[i]
+        end
+        f = load(reader)
+        return f()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("read in pieces"));
+
+    runtime.execute("function bad() return {} end").unwrap();
+    let reader = runtime.get_global("bad");
+    let message = load_error(&mut runtime, &[reader]);
+    assert_eq!(message, "reader function must return a string");
+
+    let source = r#"
+        done = false
+        function once()
+            if done then
+                return nil
+            end
+            done = true
+            return "x = "
+        end
+    "#;
+    runtime.execute(source).unwrap();
+    let once = runtime.get_global("once");
+    let message = load_error(&mut runtime, &[once]);
+    assert!(message.starts_with("(load):1: "), "{}", message);
+}
+
+#[test]
+fn test_mode_selects_text_or_binary() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let message = load_error(&mut runtime, &[string("return 1"), string("chunk"), string("b")]);
+    assert_eq!(message, "attempt to load a text chunk (mode is 'b')");
+    let message = load_error(&mut runtime, &[string("\x1bLua"), string("chunk"), string("t")]);
+    assert_eq!(message, "attempt to load a binary chunk (mode is 't')");
+    let message = load_error(&mut runtime, &[string("\x1bLua"), string("=chunk"), string("bt")]);
+    assert!(message.starts_with("chunk: bad binary format"), "{}", message);
+
+    assert_eq!(eval(&mut runtime, "load('return 7', 'chunk', 't')()"), Value::Number(7.0));
+    assert_eq!(eval(&mut runtime, "load('return 8', 'chunk', 'bt')()"), Value::Number(8.0));
+}
+
+#[test]
+fn test_env_holds_the_chunk_globals() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        config = {}
+        f = load("width = 80 height = width * 2", "=config", "t", config)
+        f()
+        return config.height
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(160.0));
+    assert_eq!(eval(&mut runtime, "width"), Value::Nil);
+
+    // Functions the chunk defines keep using its environment
+    let source = r#"
+        load("function area() return width * height end", "=config", "t", config)()
+        width = 1
+        return config.area()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(12800.0));
+    assert_eq!(eval(&mut runtime, "area"), Value::Nil);
+
+    // Metamethods apply, so a sandbox can expose chosen globals
+    let source = r#"
+        sandbox = setmetatable({}, {__index = {math = math}})
+        return load("return math.floor(2.5)", "=sandbox", "t", sandbox)()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(2.0));
+
+    let source = string("return x");
+    let values = builtin_load(&mut runtime, &[source, string("=nil env"), string("t"), Value::Nil]).unwrap();
+    runtime.set_global("f", values[0].clone());
+    let error = runtime.execute("f()").unwrap_err();
+    assert!(error.to_string().contains("attempt to index a nil value"), "{}", error);
+}
+
+#[test]
+fn test_env_survives_collection() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        getter = load("x = 42 function get() return x end return get", "=c", "t", {})()
+        collectgarbage()
+        collectgarbage()
+        return getter()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(42.0));
+}
+
+#[test]
+fn test_loadfile_and_dofile() {
+    let mut runtime = LuaJitRuntime::new();
+    let script = temporary_file("script.lua", "#!/usr/bin/env luna\nran = true\nreturn 1 + 1");
+    let broken = temporary_file("broken.lua", "x = 1\n\nreturn return");
+    runtime.set_global("script", string(&script));
+    runtime.set_global("broken", string(&broken));
+
+    assert_eq!(eval(&mut runtime, "dofile(script)"), Value::Number(2.0));
+    assert_eq!(eval(&mut runtime, "ran"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "loadfile(script)()"), Value::Number(2.0));
+
+    let values = builtin_loadfile(&mut runtime, &[string(&broken)]).unwrap();
+    assert_eq!(values[0], Value::Nil);
+    assert!(values[1].to_string().starts_with(&format!("{}:3: ", broken)), "{}", values[1]);
+    let error = runtime.execute("dofile(broken)").unwrap_err();
+    assert!(error.to_string().contains(&format!("{}:3: ", broken)), "{}", error);
+
+    let values = builtin_loadfile(&mut runtime, &[string("/nonexistent/luna.lua")]).unwrap();
+    let message = "cannot open /nonexistent/luna.lua: No such file or directory";
+    assert_eq!(values, vec![Value::Nil, string(message)]);
+    let error = runtime.execute("dofile('/nonexistent/luna.lua')").unwrap_err();
+    assert!(error.to_string().contains(message), "{}", error);
+
+    let config = temporary_file("config.lua", "name = 'from file'");
+    runtime.set_global("config", string(&config));
+    assert_eq!(eval(&mut runtime, "loadfile(config, 'b') == nil"), Value::Boolean(true));
+    runtime.execute("settings = {} loadfile(config, 't', settings)()").unwrap();
+    assert_eq!(eval(&mut runtime, "settings.name"), string("from file"));
+    assert_eq!(eval(&mut runtime, "name"), Value::Nil);
+
+    for path in [script, broken, config] {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+#[test]
+fn test_chunks_keep_bytes_that_are_not_utf8() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("f = load(\"return '\" .. string.char(255, 254) .. \"'\")").unwrap();
+    assert_eq!(eval(&mut runtime, "string.byte(f(), 2)"), Value::Number(254.0));
+    assert_eq!(eval(&mut runtime, "f() == string.char(255, 254)"), Value::Boolean(true));
+
+    let script = temporary_file("latin1.lua", b"return '\xe9t\xe9'");
+    let values = builtin_loadfile(&mut runtime, &[string(&script)]).unwrap();
+    runtime.set_global("g", values[0].clone());
+    assert_eq!(eval(&mut runtime, "g() == string.char(233, 116, 233)"), Value::Boolean(true));
+    let result = runtime.execute_file(&script).unwrap();
+    assert_eq!(result, Value::String(b"\xe9t\xe9"[..].into()));
+}
@@ -0,0 +1,189 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn number(runtime: &mut LuaJitRuntime, expression: &str) -> f64 {
+    match eval(runtime, expression) {
+        Value::Number(n) => n,
+        other => panic!("{} is {:?}, not a number", expression, other),
+    }
+}
+
+#[test]
+fn test_constants_are_numbers() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.pi"), Value::Number(std::f64::consts::PI));
+    assert_eq!(eval(&mut runtime, "math.huge"), Value::Number(f64::INFINITY));
+    assert_eq!(eval(&mut runtime, "-math.huge < -1000000"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "type(math.pi)"), string("number"));
+    // Every number is a float, so these bound the exact integers rather
+    // than a 64-bit integer type, and the next integer rounds back down
+    assert_eq!(eval(&mut runtime, "math.maxinteger"), Value::Number(9007199254740992.0));
+    assert_eq!(eval(&mut runtime, "math.mininteger"), Value::Number(-9007199254740992.0));
+    assert_eq!(eval(&mut runtime, "math.maxinteger + 1 == math.maxinteger"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "math.tointeger(math.mininteger) == math.mininteger"), Value::Boolean(true));
+}
+
+#[test]
+fn test_rounding_keeps_integers() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.floor(3.7)"), Value::Number(3.0));
+    assert_eq!(eval(&mut runtime, "math.floor(-3.2)"), Value::Number(-4.0));
+    assert_eq!(eval(&mut runtime, "math.ceil(3.2)"), Value::Number(4.0));
+    assert_eq!(eval(&mut runtime, "math.type(math.ceil(-0.5))"), string("integer"));
+    assert_eq!(eval(&mut runtime, "math.floor('2.5')"), Value::Number(2.0));
+    assert_eq!(eval(&mut runtime, "math.abs(-7)"), Value::Number(7.0));
+    assert_eq!(eval(&mut runtime, "tostring(math.floor(-0.5) + 1)"), string("0"));
+
+    let error = runtime.execute("return math.floor('x')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'floor' (number expected, got string)"), "{}", error);
+}
+
+#[test]
+fn test_max_and_min() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.max(3, 9, -2, 9)"), Value::Number(9.0));
+    assert_eq!(eval(&mut runtime, "math.min(3, 9, -2)"), Value::Number(-2.0));
+    assert_eq!(eval(&mut runtime, "math.max(1.5)"), Value::Number(1.5));
+
+    let error = runtime.execute("return math.max()").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'max' (number expected, got no value)"), "{}", error);
+    let error = runtime.execute("return math.min(1, {})").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'min' (number expected, got table)"), "{}", error);
+}
+
+#[test]
+fn test_fmod_and_modf() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.fmod(7, 3)"), Value::Number(1.0));
+    assert_eq!(eval(&mut runtime, "math.fmod(-7, 3)"), Value::Number(-1.0));
+    assert_eq!(eval(&mut runtime, "math.fmod(5.5, 2)"), Value::Number(1.5));
+    let error = runtime.execute("return math.fmod(1, 0)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'fmod' (zero)"), "{}", error);
+
+    let modf = luna::stdlib::math_modf(&mut runtime, &[Value::Number(3.75)]).unwrap();
+    assert_eq!(modf, vec![Value::Number(3.0), Value::Number(0.75)]);
+    let modf = luna::stdlib::math_modf(&mut runtime, &[Value::Number(-2.5)]).unwrap();
+    assert_eq!(modf, vec![Value::Number(-2.0), Value::Number(-0.5)]);
+    let modf = luna::stdlib::math_modf(&mut runtime, &[Value::Number(f64::INFINITY)]).unwrap();
+    assert_eq!(modf, vec![Value::Number(f64::INFINITY), Value::Number(0.0)]);
+}
+
+#[test]
+fn test_exponentials_and_trigonometry() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.log(8, 2)"), Value::Number(3.0));
+    assert_eq!(eval(&mut runtime, "math.log(1000, 10)"), Value::Number(3.0));
+    assert!((number(&mut runtime, "math.log(81, 3)") - 4.0).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.log(math.exp(2))") - 2.0).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.asin(1)") - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.acos(-1)") - std::f64::consts::PI).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.atan(1)") - std::f64::consts::FRAC_PI_4).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.atan(1, -1)") - 3.0 * std::f64::consts::FRAC_PI_4).abs() < 1e-12);
+    assert!((number(&mut runtime, "math.atan(-1, -1)") + 3.0 * std::f64::consts::FRAC_PI_4).abs() < 1e-12);
+}
+
+#[test]
+fn test_angle_conversions() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.deg(math.pi)"), Value::Number(180.0));
+    assert_eq!(eval(&mut runtime, "math.rad(180)"), Value::Number(std::f64::consts::PI));
+    assert_eq!(eval(&mut runtime, "math.deg(0)"), Value::Number(0.0));
+    assert!((number(&mut runtime, "math.deg(math.rad(45))") - 45.0).abs() < 1e-12);
+    assert!(runtime.execute("return math.rad('x')").is_err());
+}
+
+#[test]
+fn test_integer_functions() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "math.ult(1, 2)"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "math.ult(-1, 2)"), Value::Boolean(false));
+    assert_eq!(eval(&mut runtime, "math.ult(2, -1)"), Value::Boolean(true));
+    let error = runtime.execute("return math.ult(1.5, 2)").unwrap_err();
+    assert!(error.to_string().contains("number has no integer representation"), "{}", error);
+
+    assert_eq!(eval(&mut runtime, "math.tointeger(3)"), Value::Number(3.0));
+    assert_eq!(eval(&mut runtime, "math.tointeger('8')"), Value::Number(8.0));
+    assert_eq!(eval(&mut runtime, "math.tointeger(3.5)"), Value::Nil);
+    assert_eq!(eval(&mut runtime, "math.tointeger({})"), Value::Nil);
+
+    assert_eq!(eval(&mut runtime, "math.type(1)"), string("integer"));
+    // There is no integer subtype, so an integral float counts as an
+    // integer, unlike in Lua 5.4
+    assert_eq!(eval(&mut runtime, "math.type(1.0)"), string("integer"));
+    assert_eq!(eval(&mut runtime, "math.type(1.5)"), string("float"));
+    assert_eq!(eval(&mut runtime, "math.type(math.huge)"), string("float"));
+    assert_eq!(eval(&mut runtime, "math.type('1')"), Value::Nil);
+    let error = runtime.execute("return math.type()").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'type' (value expected)"), "{}", error);
+}
+
+#[test]
+fn test_random_is_reproducible() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        math.randomseed(42)
+        s = ""
+        for i = 1, 10 do
+            s = s .. math.random(1, 1000) .. " "
+        end
+        return s
+    "#;
+    let first = runtime.execute(source).unwrap();
+    assert_eq!(runtime.execute(source).unwrap(), first);
+
+    let other = runtime.execute(&source.replace("42", "43")).unwrap();
+    assert_ne!(other, first);
+
+    let seeds = luna::stdlib::math_randomseed(&mut runtime, &[Value::Number(7.0), Value::Number(3.0)]).unwrap();
+    assert_eq!(seeds, vec![Value::Number(7.0), Value::Number(3.0)]);
+    let seeds = luna::stdlib::math_randomseed(&mut runtime, &[Value::Number(7.0)]).unwrap();
+    assert_eq!(seeds, vec![Value::Number(7.0), Value::Number(0.0)]);
+}
+
+#[test]
+fn test_random_ranges() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        seen = {}
+        ok = true
+        for i = 1, 500 do
+            x = math.random(-2, 3)
+            if x < -2 or x > 3 or math.type(x) ~= "integer" then
+                ok = false
+            end
+            seen[x] = true
+            f = math.random()
+            if f < 0 or f >= 1 then
+                ok = false
+            end
+            if math.random(4) < 1 or math.random(4) > 4 then
+                ok = false
+            end
+        end
+        return ok and seen[-2] and seen[0] and seen[3]
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "math.random(5, 5)"), Value::Number(5.0));
+    assert_eq!(eval(&mut runtime, "math.type(math.random(0))"), string("integer"));
+    // All 64 bits are drawn, then rounded to a float, whose low bits are
+    // zero above 2^53
+    let source = "math.randomseed(42) local n = math.random(0) return n < -9007199254740992 or n > 9007199254740992";
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+
+    let error = runtime.execute("return math.random(3, 1)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'random' (range is empty)"), "{}", error);
+    let error = runtime.execute("return math.random(1, 2, 3)").unwrap_err();
+    assert!(error.to_string().contains("wrong number of arguments"), "{}", error);
+    let error = runtime.execute("return math.random(1.5)").unwrap_err();
+    assert!(error.to_string().contains("number has no integer representation"), "{}", error);
+}
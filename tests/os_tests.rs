@@ -0,0 +1,126 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn scratch_path(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("luna_os_{}_{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_time_normalizes_the_table() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        t = {year = 2021, month = 1, day = 32, hour = 10}
+        stamp = os.time(t)
+        return t.month * 100 + t.day
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(201.0));
+    assert_eq!(eval(&mut runtime, "t.yday"), Value::Number(32.0));
+    assert_eq!(eval(&mut runtime, "t.wday"), Value::Number(2.0));
+    assert_eq!(eval(&mut runtime, "t.min + t.sec"), Value::Number(0.0));
+    assert_eq!(eval(&mut runtime, "os.time({year = 2021, month = 2, day = 1, hour = 10}) == stamp"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "os.time({year = 2021, month = 2, day = 2}) - stamp"), Value::Number(26.0 * 3600.0));
+    assert_eq!(eval(&mut runtime, "math.type(os.time())"), string("integer"));
+
+    let error = runtime.execute("return os.time({year = 2021, month = 1})").unwrap_err();
+    assert!(error.to_string().contains("field 'day' missing in date table"), "{}", error);
+    let error = runtime.execute("return os.time({year = 2021, month = 1.5, day = 1})").unwrap_err();
+    assert!(error.to_string().contains("field 'month' is not an integer"), "{}", error);
+    let error = runtime.execute("return os.time(1)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'time' (table expected, got number)"), "{}", error);
+}
+
+#[test]
+fn test_date_formats() {
+    let mut runtime = LuaJitRuntime::new();
+
+    // 2000-03-01 12:34:56 UTC
+    assert_eq!(eval(&mut runtime, "os.date('!%Y-%m-%d %H:%M:%S', 951914096)"), string("2000-03-01 12:34:56"));
+    assert_eq!(eval(&mut runtime, "os.date('!%A, %d %B (%j) 100%%', 951914096)"), string("Wednesday, 01 March (061) 100%"));
+    assert_eq!(eval(&mut runtime, "os.date('!%Ey', 951914096)"), string("00"));
+    assert_eq!(eval(&mut runtime, "os.date('!', 951914096)"), string(""));
+    assert_eq!(eval(&mut runtime, "type(os.date())"), string("string"));
+
+    let error = runtime.execute("return os.date('%Y %Q')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'date' (invalid conversion specifier '%Q')"), "{}", error);
+    let error = runtime.execute("return os.date('%Ez')").unwrap_err();
+    assert!(error.to_string().contains("invalid conversion specifier '%Ez'"), "{}", error);
+    let error = runtime.execute("return os.date('%c', 1.5)").unwrap_err();
+    assert!(error.to_string().contains("number has no integer representation"), "{}", error);
+}
+
+#[test]
+fn test_date_table_round_trips() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        t = os.date("!*t", 951914096)
+        return t.year .. "-" .. t.month .. "-" .. t.day .. " " .. t.hour .. ":" .. t.min .. ":" .. t.sec
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("2000-3-1 12:34:56"));
+    assert_eq!(eval(&mut runtime, "t.wday * 1000 + t.yday"), Value::Number(4061.0));
+    assert_eq!(eval(&mut runtime, "t.isdst"), Value::Boolean(false));
+
+    let source = r#"
+        now = os.time()
+        return os.time(os.date("*t", now)) == now
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+}
+
+#[test]
+fn test_clock_difftime_and_getenv() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "type(os.clock())"), string("number"));
+    assert_eq!(eval(&mut runtime, "os.clock() >= 0"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "os.difftime(100, 40)"), Value::Number(60.0));
+    let error = runtime.execute("return os.difftime(100)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'difftime' (number expected, got no value)"), "{}", error);
+
+    let path = std::env::var("PATH").unwrap();
+    assert_eq!(eval(&mut runtime, "os.getenv('PATH')"), string(&path));
+    assert_eq!(eval(&mut runtime, "os.getenv('LUNA_SURELY_UNSET_VARIABLE')"), Value::Nil);
+}
+
+#[test]
+fn test_remove_rename_and_tmpname() {
+    let mut runtime = LuaJitRuntime::new();
+    let from = scratch_path("from");
+    let to = scratch_path("to");
+    std::fs::write(&from, "contents").unwrap();
+    runtime.set_global("from", string(&from));
+    runtime.set_global("to", string(&to));
+
+    assert_eq!(eval(&mut runtime, "os.rename(from, to)"), Value::Boolean(true));
+    assert_eq!(std::fs::read_to_string(&to).unwrap(), "contents");
+    assert_eq!(eval(&mut runtime, "os.remove(to)"), Value::Boolean(true));
+    assert!(!std::path::Path::new(&to).exists());
+
+    let values = luna::stdlib::os_remove(&mut runtime, &[string(&to)]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string(&format!("{}: No such file or directory", to)), Value::Number(2.0)]);
+    let values = luna::stdlib::os_rename(&mut runtime, &[string(&from), string(&to)]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("No such file or directory"), Value::Number(2.0)]);
+
+    let name = match eval(&mut runtime, "os.tmpname()") {
+        Value::String(name) => name.to_str_lossy().into_owned(),
+        other => panic!("tmpname returned {:?}", other),
+    };
+    assert!(std::path::Path::new(&name).is_file());
+    runtime.set_global("name", string(&name));
+    assert_eq!(eval(&mut runtime, "os.remove(name)"), Value::Boolean(true));
+}
+
+#[test]
+fn test_exit_checks_its_code() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let error = runtime.execute("os.exit('x')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #1 to 'exit' (number expected, got string)"), "{}", error);
+    let error = runtime.execute("os.exit(1.5)").unwrap_err();
+    assert!(error.to_string().contains("number has no integer representation"), "{}", error);
+}
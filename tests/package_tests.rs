@@ -0,0 +1,186 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+use std::path::PathBuf;
+
+/// A runtime whose `package.path` searches a fresh directory, removed with
+/// its contents when the test ends. The global `root` names the directory.
+struct Modules {
+    runtime: LuaJitRuntime,
+    root: PathBuf,
+}
+
+impl Modules {
+    fn new(name: &str) -> Self {
+        let root = std::env::temp_dir().join(format!("luna_package_{}_{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir(&root).unwrap();
+        let mut runtime = LuaJitRuntime::new();
+        runtime.set_global("root", string(root.to_str().unwrap()));
+        runtime.execute("package.path = root .. '/?.lua;' .. root .. '/?/init.lua'").unwrap();
+        Self { runtime, root }
+    }
+
+    fn write(&self, file: &str, source: &str) {
+        let path = self.root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, source).unwrap();
+    }
+}
+
+impl Drop for Modules {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.root);
+    }
+}
+
+#[test]
+fn test_require_caches_modules() {
+    let mut modules = Modules::new("cache");
+    modules.write(
+        "counter.lua",
+        r#"
+        if loads == nil then
+            loads = 0
+        end
+        loads = loads + 1
+        local M = {}
+        M.name = "counter"
+        return M
+    "#,
+    );
+    modules.write("nested/deep.lua", "return 'deep'");
+    modules.write("pkg/init.lua", "return 'package init'");
+    let runtime = &mut modules.runtime;
+
+    let source = r#"
+        a = require("counter")
+        b = require("counter")
+        return a == b
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+    assert_eq!(eval(runtime, "a.name"), string("counter"));
+    assert_eq!(eval(runtime, "loads"), Value::Number(1.0));
+    assert_eq!(eval(runtime, "package.loaded.counter == a"), Value::Boolean(true));
+    assert_eq!(eval(runtime, "require('nested.deep')"), string("deep"));
+    assert_eq!(eval(runtime, "require('pkg')"), string("package init"));
+
+    // Loaders that return nothing leave true behind
+    modules.write("silent.lua", "x = 1");
+    assert_eq!(eval(&mut modules.runtime, "require('silent')"), Value::Boolean(true));
+
+    let runtime = &mut modules.runtime;
+    assert_eq!(eval(runtime, "require('string') == string"), Value::Boolean(true));
+    assert_eq!(eval(runtime, "package.loaded.package == package"), Value::Boolean(true));
+
+    let values = luna::stdlib::builtin_require(runtime, &[string("nested.deep")]).unwrap();
+    assert_eq!(values, vec![string("deep")]);
+    runtime.execute("package.loaded['nested.deep'] = nil").unwrap();
+    let values = luna::stdlib::builtin_require(runtime, &[string("nested.deep")]).unwrap();
+    let filename = modules.root.join("nested/deep.lua");
+    assert_eq!(values, vec![string("deep"), string(filename.to_str().unwrap())]);
+}
+
+#[test]
+fn test_require_reports_every_path_tried() {
+    let mut modules = Modules::new("missing");
+    let root = modules.root.to_str().unwrap().to_string();
+
+    let error = modules.runtime.execute("require('absent.module')").unwrap_err().to_string();
+    let expected = format!(
+        "module 'absent.module' not found:\n\tno field package.preload['absent.module']\n\t\
+         no file '{0}/absent/module.lua'\n\tno file '{0}/absent/module/init.lua'",
+        root
+    );
+    assert!(error.contains(&expected), "{}", error);
+
+    modules.write("broken.lua", "return return");
+    let error = modules.runtime.execute("require('broken')").unwrap_err().to_string();
+    let expected = format!("error loading module 'broken' from file '{}/broken.lua'", root);
+    assert!(error.contains(&expected), "{}", error);
+
+    let error = modules.runtime.execute("package.path = 1 require('x')").unwrap_err().to_string();
+    assert!(error.contains("'package.path' must be a string"), "{}", error);
+}
+
+#[test]
+fn test_circular_requires_are_detected() {
+    let mut modules = Modules::new("circular");
+    modules.write("a.lua", "require('b') return 'a'");
+    modules.write("b.lua", "require('a') return 'b'");
+
+    let error = modules.runtime.execute("require('a')").unwrap_err();
+    assert!(error.to_string().contains("loop detected requiring module 'a'"), "{}", error);
+    // A failed load leaves nothing behind, so fixing the module is enough
+    modules.write("b.lua", "return 'b'");
+    assert_eq!(eval(&mut modules.runtime, "require('a')"), string("a"));
+}
+
+#[test]
+fn test_preload_and_searchers() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function make(name)
+            return name .. " from preload"
+        end
+        package.preload.virtual = make
+        return require("virtual")
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("virtual from preload"));
+    let values = luna::stdlib::package_searcher_preload(&mut runtime, &[string("virtual")]).unwrap();
+    assert_eq!(values[1], string(":preload:"));
+
+    let source = r#"
+        function always(name)
+            return make
+        end
+        table.insert(package.searchers, 1, always)
+        return require("anything")
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("anything from preload"));
+
+    let error = runtime.execute("package.searchers = nil require('other')").unwrap_err();
+    assert!(error.to_string().contains("'package.searchers' must be a table"), "{}", error);
+}
+
+#[test]
+fn test_searchpath() {
+    let modules = Modules::new("searchpath");
+    modules.write("a/b.lua", "");
+    let mut runtime = LuaJitRuntime::new();
+    let root = modules.root.to_str().unwrap();
+    runtime.set_global("root", string(root));
+
+    let found = format!("{}/a/b.lua", root);
+    assert_eq!(eval(&mut runtime, "package.searchpath('a.b', root .. '/?.lua')"), string(&found));
+    assert_eq!(eval(&mut runtime, "package.searchpath('a_b', root .. '/?.lua', '_')"), string(&found));
+    let path = string("/nonexistent/?.lua;/nonexistent/?/x.lua");
+    let values = luna::stdlib::package_searchpath(&mut runtime, &[string("x"), path]).unwrap();
+    let tried = "no file '/nonexistent/x.lua'\n\tno file '/nonexistent/x/x.lua'";
+    assert_eq!(values, vec![Value::Nil, string(tried)]);
+
+    assert_eq!(eval(&mut runtime, "type(package.path)"), string("string"));
+    assert_eq!(eval(&mut runtime, "string.sub(package.config, 1, 1)"), string("/"));
+}
+
+#[test]
+fn test_modules_resolve_next_to_the_script() {
+    let modules = Modules::new("script");
+    modules.write("app/main.lua", "helper = require('helper') return helper.greeting");
+    modules.write("app/helper.lua", "local M = {} M.greeting = 'hello' return M");
+
+    let mut runtime = LuaJitRuntime::new();
+    let result = runtime.execute_file(modules.root.join("app/main.lua")).unwrap();
+    assert_eq!(result, string("hello"));
+
+    // Running another script from the same directory adds nothing new
+    let path = eval(&mut runtime, "package.path");
+    runtime.execute_file(modules.root.join("app/helper.lua")).unwrap();
+    assert_eq!(eval(&mut runtime, "package.path"), path);
+
+    let error = runtime.execute_file(modules.root.join("app/absent.lua")).unwrap_err();
+    assert!(error.to_string().contains("cannot open"), "{}", error);
+}
@@ -0,0 +1,91 @@
+#![cfg(unix)]
+
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+use luna::LunaConfig;
+
+fn spawning_runtime() -> LuaJitRuntime {
+    LuaJitRuntime::with_config(LunaConfig { allow_process_spawning: true, ..LunaConfig::default() })
+}
+
+#[test]
+fn test_execute_reports_exit_status() {
+    let mut runtime = spawning_runtime();
+
+    assert_eq!(eval(&mut runtime, "os.execute()"), Value::Boolean(true));
+    let values = luna::stdlib::os_execute(&mut runtime, &[string("true")]).unwrap();
+    assert_eq!(values, vec![Value::Boolean(true), string("exit"), Value::Number(0.0)]);
+    let values = luna::stdlib::os_execute(&mut runtime, &[string("exit 3")]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("exit"), Value::Number(3.0)]);
+    let values = luna::stdlib::os_execute(&mut runtime, &[string("kill -9 $$")]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("signal"), Value::Number(9.0)]);
+}
+
+#[test]
+fn test_popen_reads_output() {
+    let mut runtime = spawning_runtime();
+
+    let source = r#"
+        p = io.popen("echo first; echo second")
+        a = p:read("l")
+        b = p:read("a")
+        return a .. "|" .. b
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("first|second\n"));
+    assert_eq!(eval(&mut runtime, "io.type(p)"), string("file"));
+    let p = runtime.get_global("p");
+    let values = luna::stdlib::file_close(&mut runtime, &[p]).unwrap();
+    assert_eq!(values, vec![Value::Boolean(true), string("exit"), Value::Number(0.0)]);
+
+    let source = r#"
+        result = ""
+        for line in io.popen("printf 'x\ny\n'"):lines() do
+            result = result .. line
+        end
+        return result
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("xy"));
+
+    runtime.execute("p = io.popen('exit 5')").unwrap();
+    let p = runtime.get_global("p");
+    let values = luna::stdlib::file_close(&mut runtime, &[p]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("exit"), Value::Number(5.0)]);
+}
+
+#[test]
+fn test_popen_writes_input() {
+    let mut runtime = spawning_runtime();
+    let path = std::env::temp_dir().join(format!("luna_popen_{}", std::process::id()));
+    runtime.set_global("path", string(path.to_str().unwrap()));
+
+    let source = r#"
+        p = io.popen("cat > '" .. path .. "'", "w")
+        p:write("piped ", 42, "\n")
+        return p:close()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Boolean(true));
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "piped 42\n");
+    let _ = std::fs::remove_file(&path);
+
+    let error = runtime.execute("io.popen('true', 'rw')").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'popen' (invalid mode)"), "{}", error);
+    runtime.execute("p = io.popen('cat > /dev/null', 'w')").unwrap();
+    let p = runtime.get_global("p");
+    let values = luna::stdlib::file_read(&mut runtime, &[p, string("l")]).unwrap();
+    assert_eq!(values, vec![Value::Nil, string("Bad file descriptor"), Value::Number(9.0)]);
+}
+
+#[test]
+fn test_spawning_is_off_by_default() {
+    assert!(!LunaConfig::default().allow_process_spawning);
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "os.execute()"), Value::Boolean(false));
+    let error = runtime.execute("os.execute('true')").unwrap_err();
+    assert!(error.to_string().contains("'execute' is disabled in this runtime"), "{}", error);
+    let error = runtime.execute("io.popen('true')").unwrap_err();
+    assert!(error.to_string().contains("'popen' is disabled in this runtime"), "{}", error);
+}
@@ -0,0 +1,469 @@
+mod common;
+
+use common::{eval, results, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn bytes(b: &[u8]) -> Value {
+    Value::String(b.into())
+}
+
+#[test]
+fn test_char_produces_single_bytes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "string.char(200, 0, 65)"), bytes(&[200, 0, 65]));
+    assert_eq!(eval(&mut runtime, "string.len(string.char(200))"), Value::Number(1.0));
+    assert_eq!(eval(&mut runtime, "string.byte(string.char(255))"), Value::Number(255.0));
+    assert!(runtime.execute("return string.char(256)").is_err());
+}
+
+#[test]
+fn test_char_rejects_codes_that_are_not_bytes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let error = runtime.execute("return string.char(65, 256)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'char' (value out of range)"), "{}", error);
+    let error = runtime.execute("return string.char(-1)").unwrap_err();
+    assert!(error.to_string().contains("(value out of range)"), "{}", error);
+    let error = runtime.execute("return string.char(65.5)").unwrap_err();
+    assert!(error.to_string().contains("(number has no integer representation)"), "{}", error);
+    assert_eq!(eval(&mut runtime, "string.char()"), bytes(b""));
+    assert_eq!(eval(&mut runtime, "string.char('72', 105.0)"), bytes(b"Hi"));
+}
+
+#[test]
+fn test_escape_sequences_produce_bytes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, r#""\200\0\65""#), bytes(&[200, 0, 65]));
+    assert_eq!(eval(&mut runtime, r#""\xC3\xA9" == "é""#), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, r#""\u{E9}" == "é""#), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, r#""a\tb\n\\\"'""#), bytes(b"a\tb\n\\\"'"));
+    assert_eq!(eval(&mut runtime, "\"one\\z\n      two\""), bytes(b"onetwo"));
+    assert!(runtime.execute(r#"return "\256""#).is_err());
+    assert!(runtime.execute(r#"return "\q""#).is_err());
+}
+
+#[test]
+fn test_sub_and_byte_use_byte_positions() {
+    let mut runtime = LuaJitRuntime::new();
+
+    // "é" is two bytes; slicing through it must not panic
+    assert_eq!(eval(&mut runtime, r#"string.sub("é!", 2)"#), bytes(&[0xA9, b'!']));
+    assert_eq!(eval(&mut runtime, r#"string.len("é")"#), Value::Number(2.0));
+    assert_eq!(eval(&mut runtime, r#"string.sub("hello", -3)"#), bytes(b"llo"));
+    assert_eq!(eval(&mut runtime, r#"string.sub("hello", 2, -2)"#), bytes(b"ell"));
+    assert_eq!(eval(&mut runtime, r#"string.sub("hello", -100, 100)"#), bytes(b"hello"));
+    assert_eq!(eval(&mut runtime, r#"string.sub("hello", 4, 2)"#), bytes(b""));
+    assert_eq!(eval(&mut runtime, r#"string.byte("abc", -1)"#), Value::Number(99.0));
+    assert_eq!(eval(&mut runtime, r#"string.byte("abc", 4)"#), Value::Nil);
+}
+
+#[test]
+fn test_byte_returns_every_byte_in_the_range() {
+    let mut runtime = LuaJitRuntime::new();
+    let numbers = |codes: &[u8]| codes.iter().map(|&code| Value::Number(code as f64)).collect::<Vec<_>>();
+
+    assert_eq!(results(&mut runtime, r#"return ("hello"):byte(2, 4)"#), numbers(b"ell"));
+    assert_eq!(results(&mut runtime, r#"return string.byte("hello", -3, -1)"#), numbers(b"llo"));
+    assert_eq!(results(&mut runtime, r#"return string.byte("hello", -10, 10)"#), numbers(b"hello"));
+    assert_eq!(results(&mut runtime, r#"return string.byte("hello", -1)"#), numbers(b"o"));
+    assert_eq!(results(&mut runtime, r#"return string.byte("hello", 3, 1)"#), numbers(b""));
+    assert_eq!(results(&mut runtime, r#"return string.byte("")"#), numbers(b""));
+    assert!(runtime.execute(r#"return string.byte("abc", 1.5)"#).is_err());
+}
+
+#[test]
+fn test_case_conversion_only_touches_ascii() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, r#"string.upper("abc\200é")"#), bytes(b"ABC\xC8\xC3\xA9"));
+    assert_eq!(eval(&mut runtime, r#"string.lower("ABC\200")"#), bytes(b"abc\xC8"));
+}
+
+#[test]
+fn test_concatenation_keeps_bytes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, r#""\255" .. 1 .. "\0""#), bytes(b"\xFF1\0"));
+    assert_eq!(eval(&mut runtime, r#"tostring("\200") == "\200""#), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, r#"tonumber(" 42 ")"#), Value::Number(42.0));
+}
+
+fn number(n: f64) -> Value {
+    Value::Number(n)
+}
+
+#[test]
+fn test_find_returns_positions_and_captures() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        local s, e = ("hello world"):find("wor")
+        return s, e
+    "#;
+    assert_eq!(results(&mut runtime, source), vec![number(7.0), number(9.0)]);
+    assert_eq!(
+        results(&mut runtime, r#"return string.find("key = 42", "(%a+) = (%d+)")"#),
+        vec![number(1.0), number(8.0), string("key"), string("42")]
+    );
+    assert_eq!(results(&mut runtime, r#"return string.find("a.b", ".", 2, true)"#), vec![number(2.0), number(2.0)]);
+    assert_eq!(results(&mut runtime, r#"return string.find("abc", "b", -1)"#), vec![Value::Nil]);
+    assert_eq!(results(&mut runtime, r#"return string.find("abc", "", 10)"#), vec![Value::Nil]);
+    assert_eq!(eval(&mut runtime, r#"string.find("x = 1", "=")"#), number(3.0));
+}
+
+#[test]
+fn test_match_captures() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, r#"string.match("  trim me  ", "^%s*(.-)%s*$")"#), string("trim me"));
+    assert_eq!(eval(&mut runtime, r#"string.match("2024-06-01", "(%d+)-%d+")"#), string("2024"));
+    assert_eq!(eval(&mut runtime, r#"string.match("hello", "()ll")"#), number(3.0));
+    assert_eq!(eval(&mut runtime, r#"string.match("f(a(b)c)", "%b()")"#), string("(a(b)c)"));
+    assert_eq!(eval(&mut runtime, r#"string.match("THE (quick) fox", "%f[%a]%a+", 5)"#), string("quick"));
+    assert_eq!(eval(&mut runtime, r#"string.match("abc", "%d")"#), Value::Nil);
+
+    let source = r#"
+        local key, value = ("k=v"):match("(%w+)=(%w+)")
+        return key, value
+    "#;
+    assert_eq!(results(&mut runtime, source), vec![string("k"), string("v")]);
+}
+
+#[test]
+fn test_gmatch_iterates_matches() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        words = ""
+        for word in string.gmatch("one two  three", "%a+") do
+            words = words .. "[" .. word .. "]"
+        end
+        return words
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("[one][two][three]"));
+
+    let source = r#"
+        keys = ""
+        for k, v in string.gmatch("a=1, b=2", "(%w+)=(%w+)") do
+            keys = keys .. k .. v
+        end
+        return keys
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("a1b2"));
+
+    // The iterator's state survives collections while it is in use
+    let source = r#"
+        count = 0
+        for digit in string.gmatch("1 2 3 4", "%d") do
+            collectgarbage()
+            count = count + digit
+        end
+        return count
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), number(10.0));
+
+    let source = r#"
+        local next_word = string.gmatch("x y", "%a")
+        local first = next_word()
+        return first .. next_word()
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("xy"));
+    assert_eq!(eval(&mut runtime, r#"type(string.gmatch("", "x"))"#), string("function"));
+}
+
+#[test]
+fn test_gsub_replacements() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(
+        results(&mut runtime, r#"return string.gsub("hello world", "o", "0")"#),
+        vec![string("hell0 w0rld"), number(2.0)]
+    );
+    assert_eq!(
+        results(&mut runtime, r#"return string.gsub("hello world", "(%w+)", "<%1>", 1)"#),
+        vec![string("<hello> world"), number(1.0)]
+    );
+    assert_eq!(results(&mut runtime, r#"return string.gsub("abc", "", "-")"#), vec![string("-a-b-c-"), number(4.0)]);
+    assert_eq!(results(&mut runtime, r#"return string.gsub("abc", "^.", "%0%0")"#), vec![string("aabc"), number(1.0)]);
+
+    runtime.execute("vars = { name = 'luna', version = 5 }").unwrap();
+    assert_eq!(
+        eval(&mut runtime, r#"string.gsub("$name $version $missing", "%$(%w+)", vars)"#),
+        string("luna 5 $missing")
+    );
+    assert_eq!(
+        eval(&mut runtime, r#"string.gsub("1 2 3", "%d", function(d) return d * 2 end)"#),
+        string("2 4 6")
+    );
+    assert!(runtime.execute(r#"return string.gsub("a", "a", "%2")"#).is_err());
+    assert!(runtime.execute(r#"return string.gsub("a", "a", function() return {} end)"#).is_err());
+}
+
+#[test]
+fn test_malformed_and_hostile_patterns_fail() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert!(runtime.execute(r#"return string.find("a", "[a")"#).is_err());
+    assert!(runtime.execute(r#"return string.find("a", "(a")"#).is_err());
+    assert!(runtime.execute(r#"return string.match("a", "%")"#).is_err());
+
+    let source = r#"
+        local s = string.gsub("xxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxxx", "x", "a")
+        return string.find(s, "a*a*a*a*a*a*a*a*a*a*b")
+    "#;
+    let error = runtime.execute(source).unwrap_err();
+    assert!(error.to_string().contains("pattern too complex"), "unexpected error: {}", error);
+}
+
+#[test]
+fn test_quadratic_patterns_fit_the_budget() {
+    let mut runtime = LuaJitRuntime::new();
+
+    // Trimming backtracks over the trailing spaces once per character
+    runtime.execute(r#"s = "  " .. string.rep("word ", 300) .. string.rep(" ", 1500) .. "end  ""#).unwrap();
+    let trimmed = eval(&mut runtime, r#"s:match("^%s*(.-)%s*$")"#);
+    assert_eq!(trimmed.to_string().len(), 3003);
+}
+
+#[test]
+fn test_pattern_step_limit_is_configurable() {
+    use luna::LunaConfig;
+
+    let source = r#"return string.match(string.rep("a", 30), "a*a*a*a*b")"#;
+    assert_eq!(LuaJitRuntime::new().execute(source).unwrap(), Value::Nil);
+
+    let config = LunaConfig { pattern_step_limit: 100, ..LunaConfig::default() };
+    let mut runtime = LuaJitRuntime::with_config(config);
+    let error = runtime.execute(source).unwrap_err();
+    assert!(error.to_string().contains("pattern too complex"), "unexpected error: {}", error);
+}
+
+#[test]
+fn test_format_conversions() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(
+        eval(&mut runtime, r#"string.format("%d|%5i|%-5d|%05d|%+d|%.3d", 42, 42, 42, -42, 7, 7)"#),
+        string("42|   42|42   |-0042|+7|007")
+    );
+    assert_eq!(
+        eval(&mut runtime, r#"string.format("%x %X %#x %o %u %c%c", 255, 255, 255, 8, 3, 76, 117)"#),
+        string("ff FF 0xff 10 3 Lu")
+    );
+    assert_eq!(eval(&mut runtime, r#"string.format("%x", -1)"#), string("ffffffffffffffff"));
+    assert_eq!(
+        eval(&mut runtime, r#"string.format("%.2f %e %g %g %G", 3.14159, 1234.5, 0.00001, 100, 100000000000000000000)"#),
+        string("3.14 1.234500e+03 1e-05 100 1E+20")
+    );
+    assert_eq!(eval(&mut runtime, r#"string.format("%a %A", 1, 0.5)"#), string("0x1p+0 0X1P-1"));
+    assert_eq!(
+        eval(&mut runtime, r#"string.format("[%5s][%-5s][%.2s] %d%%", "ab", "ab", "abc", 100)"#),
+        string("[   ab][ab   ][ab] 100%")
+    );
+    assert_eq!(eval(&mut runtime, r#"string.format("%d", "10")"#), string("10"));
+    assert_eq!(eval(&mut runtime, r#"string.format("%5.1f", 2)"#), string("  2.0"));
+}
+
+#[test]
+fn test_format_s_uses_tostring() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime
+        .execute("point = setmetatable({}, { __tostring = function() return 'point(1, 2)' end })")
+        .unwrap();
+
+    assert_eq!(eval(&mut runtime, r#"string.format("at %s", point)"#), string("at point(1, 2)"));
+    assert_eq!(eval(&mut runtime, r#"string.format("%s %s %s", nil, true, 1.5)"#), string("nil true 1.5"));
+    assert_eq!(eval(&mut runtime, r#"string.format("%s", "a\0b")"#), bytes(b"a\0b"));
+}
+
+#[test]
+fn test_format_q_reads_back() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let original = bytes(b"say \"hi\"\\\n\0\x01 9\r\x7f");
+    runtime.set_global("original", original.clone());
+    for value in ["original", "42", "-7", "0.1", "1/3", "nil", "false"] {
+        let quoted = match eval(&mut runtime, &format!("string.format('%q', {})", value)) {
+            Value::String(s) => s.to_str().unwrap().to_string(),
+            other => panic!("expected a string, got {:?}", other),
+        };
+        let expected = eval(&mut runtime, value);
+        assert_eq!(eval(&mut runtime, &quoted), expected, "{} quoted as {}", value, quoted);
+    }
+
+    // Numbers written with exponents, and ones that have no literal
+    for value in ["math.huge", "-math.huge", "1e300", "2.5E-3", "math.huge - math.huge"] {
+        let source = format!(
+            "local value = {}\nlocal copy = load('return ' .. string.format('%q', value))()\nreturn copy == value or copy ~= copy",
+            value
+        );
+        assert_eq!(runtime.execute(&source).unwrap(), Value::Boolean(true), "{}", value);
+    }
+    assert_eq!(eval(&mut runtime, "string.format('%q', math.huge)"), string("1e9999"));
+    assert!(runtime.execute("return 1e").is_err());
+}
+
+#[test]
+fn test_format_errors_match_reference_wording() {
+    let mut runtime = LuaJitRuntime::new();
+    let error = |runtime: &mut LuaJitRuntime, source: &str| runtime.execute(source).unwrap_err().to_string();
+
+    for (source, message) in [
+        (r#"return string.format("%d", nil)"#, "bad argument #2 to 'format' (number expected, got nil)"),
+        (r#"return string.format("%s %f", 1, {})"#, "bad argument #3 to 'format' (number expected, got table)"),
+        (r#"return string.format("%d", 1.5)"#, "bad argument #2 to 'format' (number has no integer representation)"),
+        (r#"return string.format("%s")"#, "bad argument #2 to 'format' (no value)"),
+        (r#"return string.format("%d")"#, "bad argument #2 to 'format' (number expected, got no value)"),
+        (r#"return string.format("%s %f", 1)"#, "bad argument #3 to 'format' (number expected, got no value)"),
+        (r#"return string.format("%x")"#, "bad argument #2 to 'format' (number expected, got no value)"),
+        (r#"return string.format("%q")"#, "bad argument #2 to 'format' (no value)"),
+        (r#"return string.format("%q", {})"#, "bad argument #2 to 'format' (value has no literal form)"),
+        (r#"return string.format("%y", 1)"#, "invalid conversion '%y' to 'format'"),
+        (r#"return string.format("%123d", 1)"#, "invalid conversion specification: '%123d'"),
+    ] {
+        let actual = error(&mut runtime, source);
+        assert!(actual.contains(message), "{}: unexpected error: {}", source, actual);
+    }
+}
+
+#[test]
+fn test_string_methods() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, r#"("abc"):upper()"#), string("ABC"));
+    assert_eq!(eval(&mut runtime, r#"("hello"):sub(2, 3):rep(2, "-")"#), string("el-el"));
+    runtime.execute("s = 'luna'").unwrap();
+    assert_eq!(eval(&mut runtime, "s:len()"), Value::Number(4.0));
+    assert_eq!(eval(&mut runtime, "s:reverse()"), string("anul"));
+    assert_eq!(eval(&mut runtime, r#"s:format()"#), string("luna"));
+    assert_eq!(eval(&mut runtime, r#"("%d items"):format(3)"#), string("3 items"));
+
+    let source = r#"
+        words = ""
+        for word in ("one two three"):gmatch("%a+") do
+            words = words .. word:sub(1, 1)
+        end
+        return words
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("ott"));
+}
+
+#[test]
+fn test_strings_share_a_metatable() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "getmetatable('a') == getmetatable('b')"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "getmetatable('a').__index == string"), Value::Boolean(true));
+    assert_eq!(eval(&mut runtime, "('x').missing"), Value::Nil);
+
+    // Functions added to the string table become methods
+    runtime.execute("string.shout = function(s) return string.upper(s) .. '!' end").unwrap();
+    assert_eq!(eval(&mut runtime, "('hey'):shout()"), string("HEY!"));
+}
+
+#[test]
+fn test_rep_and_reverse() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "string.rep('ab', 3)"), string("ababab"));
+    assert_eq!(eval(&mut runtime, "string.rep('ab', 3, ', ')"), string("ab, ab, ab"));
+    assert_eq!(eval(&mut runtime, "string.rep('ab', 0)"), string(""));
+    assert_eq!(eval(&mut runtime, "string.reverse('abc')"), string("cba"));
+    assert!(runtime.execute("return string.rep('x', 4000000000)").is_err());
+}
+
+#[test]
+fn test_indexing_non_tables_is_an_error() {
+    let mut runtime = LuaJitRuntime::new();
+
+    for source in ["return (nil).x", "return (5).upper", "local t = true return t:x()"] {
+        let error = runtime.execute(source).unwrap_err();
+        assert!(error.to_string().contains("attempt to index"), "{}: unexpected error: {}", source, error);
+    }
+}
+
+fn unpack(runtime: &mut LuaJitRuntime, format: &str, data: Value) -> Vec<Value> {
+    luna::stdlib::string_unpack(runtime, &[string(format), data]).unwrap()
+}
+
+#[test]
+fn test_pack_integers_and_endianness() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "string.pack('>I2', 258)"), bytes(&[1, 2]));
+    assert_eq!(eval(&mut runtime, "string.pack('<I2', 258)"), bytes(&[2, 1]));
+    assert_eq!(eval(&mut runtime, "string.pack('>i3', -2)"), bytes(&[0xff, 0xff, 0xfe]));
+    assert_eq!(eval(&mut runtime, "string.pack('<b B h', -1, 255, 1)"), bytes(&[0xff, 0xff, 1, 0]));
+    assert_eq!(eval(&mut runtime, "string.pack('>j', 1)"), bytes(&[0, 0, 0, 0, 0, 0, 0, 1]));
+    assert_eq!(eval(&mut runtime, "string.len(string.pack('i16', -1))"), Value::Number(16.0));
+
+    let packed = eval(&mut runtime, "string.pack('>i2 I4 b', -300, 4000000000, 7)");
+    assert_eq!(
+        unpack(&mut runtime, ">i2 I4 b", packed),
+        vec![Value::Number(-300.0), Value::Number(4000000000.0), Value::Number(7.0), Value::Number(8.0)]
+    );
+}
+
+#[test]
+fn test_pack_floats_and_strings() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let packed = eval(&mut runtime, "string.pack('<f d n', 0.5, -2.25, 1/3)");
+    assert_eq!(
+        unpack(&mut runtime, "<f d n", packed),
+        vec![Value::Number(0.5), Value::Number(-2.25), Value::Number(1.0 / 3.0), Value::Number(21.0)]
+    );
+
+    assert_eq!(eval(&mut runtime, "string.pack('>s2', 'hi')"), bytes(&[0, 2, b'h', b'i']));
+    assert_eq!(eval(&mut runtime, "string.pack('z c4', 'ab', 'xy')"), bytes(b"ab\0xy\0\0"));
+    let packed = eval(&mut runtime, "string.pack('s1 z c3', 'one', 'two', 'six')");
+    assert_eq!(
+        unpack(&mut runtime, "s1 z c3", packed),
+        vec![string("one"), string("two"), string("six"), Value::Number(12.0)]
+    );
+}
+
+#[test]
+fn test_pack_alignment_and_padding() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "string.pack('<!4 b i4', 1, 2)"), bytes(&[1, 0, 0, 0, 2, 0, 0, 0]));
+    assert_eq!(eval(&mut runtime, "string.pack('<!4 b x Xi4 b', 1, 2)"), bytes(&[1, 0, 0, 0, 2]));
+    assert_eq!(eval(&mut runtime, "string.pack('<!8 b Xi8', 1)"), bytes(&[1, 0, 0, 0, 0, 0, 0, 0]));
+    assert_eq!(eval(&mut runtime, "string.packsize('!8 b d')"), Value::Number(16.0));
+    assert_eq!(eval(&mut runtime, "string.packsize('i4 c10 x')"), Value::Number(15.0));
+
+    let data = bytes(&[9, 0, 0, 0, 5, 0, 0, 0]);
+    let from_five = luna::stdlib::string_unpack(&mut runtime, &[string("<i4"), data, Value::Number(5.0)]);
+    assert_eq!(from_five, Ok(vec![Value::Number(5.0), Value::Number(9.0)]));
+}
+
+#[test]
+fn test_pack_detects_overflow_and_truncation() {
+    let mut runtime = LuaJitRuntime::new();
+
+    for (source, message) in [
+        ("string.pack('i1', 128)", "bad argument #2 to 'pack' (integer overflow)"),
+        ("string.pack('b b', 1, -129)", "bad argument #3 to 'pack' (integer overflow)"),
+        ("string.pack('I2', -1)", "bad argument #2 to 'pack' (unsigned overflow)"),
+        ("string.pack('i4', 1.5)", "bad argument #2 to 'pack' (number has no integer representation)"),
+        ("string.pack('i4')", "bad argument #2 to 'pack' (number expected, got no value)"),
+        ("string.pack('c2', 'abc')", "bad argument #2 to 'pack' (string longer than given size)"),
+        ("string.pack('s1', string.rep('x', 256))", "(string length does not fit in given size)"),
+        ("string.pack('z', 'a\\0b')", "bad argument #2 to 'pack' (string contains zeros)"),
+        ("string.pack('y', 1)", "invalid format option 'y'"),
+        ("string.pack('i17', 1)", "integral size (17) out of limits [1,16]"),
+        ("string.packsize('s')", "bad argument #1 to 'packsize' (variable-length format)"),
+        ("string.unpack('i4', 'abc')", "bad argument #2 to 'unpack' (data string too short)"),
+        ("string.unpack('z', 'abc')", "unfinished string for format 'z'"),
+        ("string.unpack('s1', '\\5ab')", "data string too short"),
+        ("string.unpack('b', 'a', 3)", "bad argument #3 to 'unpack' (initial position out of string)"),
+        ("string.unpack('i9', string.rep('\\255', 8) .. '\\1')", "9-byte integer does not fit into Lua Integer"),
+    ] {
+        let error = runtime.execute(&format!("return {}", source)).unwrap_err().to_string();
+        assert!(error.contains(message), "{}: unexpected error: {}", source, error);
+    }
+}
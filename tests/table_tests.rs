@@ -0,0 +1,194 @@
+mod common;
+
+use common::{eval, results, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn error(runtime: &mut LuaJitRuntime, source: &str) -> String {
+    runtime.execute(source).unwrap_err().to_string()
+}
+
+#[test]
+fn test_insert_and_remove_shift_elements() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.execute("t = {} t.name = 'x' table.insert(t, 'a') table.insert(t, 'c')").unwrap();
+    runtime.execute("table.insert(t, 2, 'b') table.insert(t, 1, 'z')").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(t, ',')"), string("z,a,b,c"));
+
+    assert_eq!(eval(&mut runtime, "table.remove(t, 1)"), string("z"));
+    assert_eq!(eval(&mut runtime, "table.remove(t)"), string("c"));
+    assert_eq!(eval(&mut runtime, "table.concat(t, ',')"), string("a,b"));
+    assert_eq!(eval(&mut runtime, "t.name"), string("x"));
+
+    runtime.execute("e = {}").unwrap();
+    assert_eq!(eval(&mut runtime, "table.remove(e)"), Value::Nil);
+
+    let message = error(&mut runtime, "table.insert(t, 5, 'x')");
+    assert!(message.contains("bad argument #2 to 'insert' (position out of bounds)"), "{}", message);
+    let message = error(&mut runtime, "table.insert(t, 1, 2, 3)");
+    assert!(message.contains("wrong number of arguments to 'insert'"), "{}", message);
+    let message = error(&mut runtime, "table.remove(t, 7)");
+    assert!(message.contains("bad argument #2 to 'remove' (position out of bounds)"), "{}", message);
+    let message = error(&mut runtime, "table.insert(nil, 1)");
+    assert!(message.contains("bad argument #1 to 'insert' (table expected, got nil)"), "{}", message);
+}
+
+#[test]
+fn test_concat_range() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.execute("t = {'a', 'b', 3, 'd'}").unwrap();
+
+    assert_eq!(eval(&mut runtime, "table.concat(t)"), string("ab3d"));
+    assert_eq!(eval(&mut runtime, "table.concat(t, '-', 2, 3)"), string("b-3"));
+    assert_eq!(eval(&mut runtime, "table.concat(t, '-', 3)"), string("3-d"));
+    assert_eq!(eval(&mut runtime, "table.concat(t, '-', 3, 2)"), string(""));
+
+    runtime.execute("t[2] = {}").unwrap();
+    let message = error(&mut runtime, "return table.concat(t)");
+    assert!(message.contains("invalid value (at index 2) in table for 'concat'"), "{}", message);
+}
+
+#[test]
+fn test_sort() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("t = {5, 2, 8, 1, 9, 3, 7, 4, 6, 10, 2} table.sort(t)").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(t, ' ')"), string("1 2 2 3 4 5 6 7 8 9 10"));
+
+    runtime.execute("t = {'pear', 'apple', 'fig', 'banana'} table.sort(t)").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(t, ' ')"), string("apple banana fig pear"));
+
+    let source = r#"
+        function greater(a, b)
+            return a > b
+        end
+        t = {3, 1, 4, 1, 5, 9, 2, 6}
+        table.sort(t, greater)
+        return table.concat(t, ' ')
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("9 6 5 4 3 2 1 1"));
+
+    let source = r#"
+        function by_age(a, b)
+            return a.age < b.age
+        end
+        people = {{name = 'b', age = 30}, {name = 'a', age = 20}, {name = 'c', age = 25}}
+        table.sort(people, by_age)
+        return people[1].name .. people[2].name .. people[3].name
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("acb"));
+}
+
+#[test]
+fn test_sort_errors() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        function always(a, b)
+            return true
+        end
+        t = {}
+        for i = 1, 20 do
+            t[i] = i
+        end
+        table.sort(t, always)
+    "#;
+    let message = error(&mut runtime, source);
+    assert!(message.contains("invalid order function for sorting"), "{}", message);
+
+    let message = error(&mut runtime, "table.sort({1, 'x', 2})");
+    assert!(message.contains("attempt to compare"), "{}", message);
+    let message = error(&mut runtime, "table.sort({{}, {}})");
+    assert!(message.contains("attempt to compare two table values"), "{}", message);
+    let message = error(&mut runtime, "table.sort({2, 1}, 3)");
+    assert!(message.contains("bad argument #2 to 'sort' (function expected, got number)"), "{}", message);
+}
+
+#[test]
+fn test_pack_and_unpack() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("p = table.pack('a', nil, 'c')").unwrap();
+    assert_eq!(eval(&mut runtime, "p.n"), Value::Number(3.0));
+    assert_eq!(eval(&mut runtime, "p[3]"), string("c"));
+
+    runtime.execute("t = {10, 20, 30}").unwrap();
+    let numbers = |values: &[f64]| values.iter().map(|&n| Value::Number(n)).collect::<Vec<_>>();
+    let source = r#"
+        local a, b, c = table.unpack(t)
+        return a, b, c
+    "#;
+    assert_eq!(results(&mut runtime, source), numbers(&[10.0, 20.0, 30.0]));
+    assert_eq!(results(&mut runtime, "return table.unpack(t, 2)"), numbers(&[20.0, 30.0]));
+    assert_eq!(results(&mut runtime, "return table.unpack(t, 3, 4)"), vec![Value::Number(30.0), Value::Nil]);
+    assert_eq!(results(&mut runtime, "return table.unpack(t, 2, 1)"), Vec::new());
+    assert_eq!(eval(&mut runtime, "table.pack(table.unpack(p, 1, p.n)).n"), Value::Number(3.0));
+
+    let message = error(&mut runtime, "table.unpack(t, 1, 10000000000)");
+    assert!(message.contains("too many results to unpack"), "{}", message);
+}
+
+#[test]
+fn test_move() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("t = {1, 2, 3, 4, 5} table.move(t, 1, 3, 3)").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(t, ',')"), string("1,2,1,2,3"));
+
+    runtime.execute("t = {1, 2, 3, 4, 5} table.move(t, 3, 5, 1)").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(t, ',')"), string("3,4,5,4,5"));
+
+    runtime.execute("a = {1, 2, 3} b = table.move(a, 1, 3, 2, {'x'})").unwrap();
+    assert_eq!(eval(&mut runtime, "table.concat(b, ',')"), string("x,1,2,3"));
+}
+
+#[test]
+fn test_sequences_and_other_keys_share_a_table() {
+    use luna::table::LuaTable;
+
+    // Filled from the end, the keys move into the array part once 1 is set
+    let mut table = LuaTable::new();
+    for i in (1..=5).rev() {
+        table.set(Value::Number(i as f64), Value::Number(i as f64 * 10.0));
+    }
+    table.set(string("name"), string("t"));
+    table.set(Value::Number(1.5), Value::Boolean(true));
+    assert_eq!(table.len(), 7);
+    assert_eq!(table.border(), 5);
+    assert_eq!(table.get(&Value::Number(3.0)), Value::Number(30.0));
+
+    // Traversal visits the sequence in order, then the other keys, and
+    // survives fields being cleared along the way
+    let mut keys = Vec::new();
+    let mut key = Value::Nil;
+    while let Some((next, _)) = table.next(&key).unwrap() {
+        table.set(next.clone(), Value::Nil);
+        keys.push(next.clone());
+        key = next;
+    }
+    let numbers = [1.0, 2.0, 3.0, 4.0, 5.0].map(Value::Number);
+    assert_eq!(keys[..5], numbers);
+    assert_eq!(keys.len(), 7);
+    assert!(table.is_empty());
+    assert_eq!(table.border(), 0);
+
+    // Holes in the array part still give a border
+    let mut table = LuaTable::new();
+    for i in 1..=8 {
+        table.set(Value::Number(i as f64), Value::Boolean(true));
+    }
+    table.set(Value::Number(8.0), Value::Nil);
+    table.set(Value::Number(4.0), Value::Nil);
+    let border = table.border();
+    assert!(border == 3 || border == 7, "{}", border);
+    assert_eq!(table.remove(&Value::Number(2.0)), Some(Value::Boolean(true)));
+    assert_eq!(table.get(&Value::Number(2.0)), Value::Nil);
+    assert_eq!(table.len(), 5);
+
+    // Past the array, a sequence continues into the hash part
+    table.set(Value::Number(8.0), Value::Boolean(true));
+    table.set(Value::Number(10.0), Value::Boolean(true));
+    table.set(Value::Number(9.0), Value::Boolean(true));
+    assert_eq!(table.get(&Value::Number(10.0)), Value::Boolean(true));
+    assert_eq!(table.iter().count(), 8);
+}
@@ -0,0 +1,163 @@
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+fn point_metatable(runtime: &mut LuaJitRuntime) -> luna::table::TableRef {
+    runtime
+        .execute("point_mt = { __index = function(p, key) return key .. '!' end }")
+        .unwrap();
+    match runtime.get_global("point_mt") {
+        Value::Table(metatable) => metatable,
+        other => panic!("expected a table, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_userdata_type() {
+    let mut runtime = LuaJitRuntime::new();
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, None);
+    runtime.set_global("p", Value::Userdata(point));
+    runtime.set_global("light", Value::LightUserdata(std::ptr::null_mut()));
+
+    assert_eq!(runtime.execute("return type(p)").unwrap(), Value::String("userdata".into()));
+    assert_eq!(runtime.execute("return type(light)").unwrap(), Value::String("userdata".into()));
+}
+
+#[test]
+fn test_userdata_identity() {
+    let mut runtime = LuaJitRuntime::new();
+    let a = runtime.create_userdata(1u8, None);
+    let b = runtime.create_userdata(1u8, None);
+    runtime.set_global("a", Value::Userdata(a.clone()));
+    runtime.set_global("a2", Value::Userdata(a));
+    runtime.set_global("b", Value::Userdata(b));
+
+    assert_eq!(runtime.execute("return a == a2").unwrap(), Value::Boolean(true));
+    assert_eq!(runtime.execute("return a == b").unwrap(), Value::Boolean(false));
+}
+
+#[test]
+fn test_light_userdata_with_high_address() {
+    let mut runtime = LuaJitRuntime::new();
+    let high = Value::LightUserdata(usize::MAX as *mut std::ffi::c_void);
+    runtime.set_global("high", high.clone());
+    runtime.set_global("same", high.clone());
+    runtime.set_global("low", Value::LightUserdata(std::ptr::null_mut()));
+
+    runtime.execute("t = { [high] = 'key', value = high }").unwrap();
+    assert_eq!(runtime.execute("return t[same]").unwrap(), Value::String("key".into()));
+    assert_eq!(runtime.execute("return t[low]").unwrap(), Value::Nil);
+    assert_eq!(runtime.execute("return t.value == high").unwrap(), Value::Boolean(true));
+    assert_eq!(runtime.execute("return t.value").unwrap(), high);
+}
+
+#[test]
+fn test_index_goes_through_metatable() {
+    let mut runtime = LuaJitRuntime::new();
+    let metatable = point_metatable(&mut runtime);
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, Some(metatable));
+    runtime.set_global("p", Value::Userdata(point));
+
+    assert_eq!(runtime.execute("return p.name").unwrap(), Value::String("name!".into()));
+
+    runtime.execute("point_mt.__index = { kind = 'point' }").unwrap();
+    assert_eq!(runtime.execute("return p.kind").unwrap(), Value::String("point".into()));
+    assert_eq!(runtime.execute("return getmetatable(p) == point_mt").unwrap(), Value::Boolean(true));
+}
+
+#[test]
+fn test_newindex_goes_through_metatable() {
+    let mut runtime = LuaJitRuntime::new();
+    let source = r#"
+        writes = {}
+        mt = { __newindex = function(p, key, value) writes[key] = value end }
+    "#;
+    runtime.execute(source).unwrap();
+    let metatable = match runtime.get_global("mt") {
+        Value::Table(metatable) => metatable,
+        other => panic!("expected a table, got {:?}", other),
+    };
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, Some(metatable));
+    runtime.set_global("p", Value::Userdata(point));
+
+    runtime.execute("p.x = 5").unwrap();
+    assert_eq!(runtime.execute("return writes.x").unwrap(), Value::Number(5.0));
+}
+
+#[test]
+fn test_index_without_metatable_is_an_error() {
+    let mut runtime = LuaJitRuntime::new();
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, None);
+    runtime.set_global("p", Value::Userdata(point));
+
+    assert!(runtime.execute("return p.x").is_err());
+    assert!(runtime.execute("p.x = 1").is_err());
+}
+
+#[test]
+fn test_host_borrows_payload() {
+    let mut runtime = LuaJitRuntime::new();
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, None);
+
+    point.borrow_mut::<Point>().unwrap().x = 3.0;
+    {
+        let p = point.borrow::<Point>().unwrap();
+        assert_eq!((p.x, p.y), (3.0, 2.0));
+        assert!(point.borrow_mut::<Point>().is_err());
+    }
+    assert!(point.borrow::<String>().is_err());
+}
+
+#[test]
+fn test_metatable_survives_collection() {
+    let mut runtime = LuaJitRuntime::new();
+    point_metatable(&mut runtime);
+    let metatable = match runtime.execute("local mt = point_mt\npoint_mt = nil\nreturn mt").unwrap() {
+        Value::Table(metatable) => metatable,
+        other => panic!("expected a table, got {:?}", other),
+    };
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, Some(metatable));
+    runtime.set_global("p", Value::Userdata(point));
+
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return p.name").unwrap(), Value::String("name!".into()));
+}
+
+#[test]
+fn test_collection_keeps_borrowed_payload_usable() {
+    let mut runtime = LuaJitRuntime::new();
+    let metatable = point_metatable(&mut runtime);
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, Some(metatable));
+    runtime.set_global("p", Value::Userdata(point.clone()));
+
+    let mut payload = point.borrow_mut::<Point>().unwrap();
+    runtime.gc_collect();
+    payload.y = 4.0;
+    drop(payload);
+    assert_eq!(point.borrow::<Point>().unwrap().y, 4.0);
+}
+
+#[test]
+fn test_userdata_finalizer() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime
+        .execute("finalized = 0\nmt = { __gc = function(p) finalized = finalized + 1 end }")
+        .unwrap();
+    let metatable = match runtime.get_global("mt") {
+        Value::Table(metatable) => metatable,
+        other => panic!("expected a table, got {:?}", other),
+    };
+    let point = runtime.create_userdata(Point { x: 1.0, y: 2.0 }, Some(metatable));
+    runtime.set_global("p", Value::Userdata(point));
+
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return finalized").unwrap(), Value::Number(0.0));
+
+    runtime.execute("p = nil").unwrap();
+    runtime.gc_collect();
+    assert_eq!(runtime.execute("return finalized").unwrap(), Value::Number(1.0));
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use common::{eval, string};
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn numbers(values: &[f64]) -> Vec<Value> {
+    values.iter().map(|&n| Value::Number(n)).collect()
+}
+
+#[test]
+fn test_char_encodes_codes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    assert_eq!(eval(&mut runtime, "utf8.char(72, 233, 8364, 128512)"), string("Hé€😀"));
+    assert_eq!(eval(&mut runtime, "utf8.char()"), string(""));
+    assert_eq!(
+        eval(&mut runtime, "utf8.char(2147483647)"),
+        Value::String(vec![0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF].into())
+    );
+    let error = runtime.execute("return utf8.char(65, -1)").unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'char' (value out of range)"), "{}", error);
+}
+
+#[test]
+fn test_charpattern_matches_one_character() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        count = 0
+        for c in string.gmatch("aé€😀", utf8.charpattern) do
+            count = count + 1
+        end
+        return count
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), Value::Number(4.0));
+}
+
+#[test]
+fn test_codepoint() {
+    let mut runtime = LuaJitRuntime::new();
+    let mut codepoint = |args: &[Value]| luna::stdlib::utf8_codepoint(&mut runtime, args);
+
+    assert_eq!(codepoint(&[string("héllo")]), Ok(numbers(&[104.0])));
+    assert_eq!(codepoint(&[string("héllo"), Value::Number(2.0)]), Ok(numbers(&[233.0])));
+    assert_eq!(
+        codepoint(&[string("héllo"), Value::Number(1.0), Value::Number(-1.0)]),
+        Ok(numbers(&[104.0, 233.0, 108.0, 108.0, 111.0]))
+    );
+    assert_eq!(codepoint(&[string("abc"), Value::Number(3.0), Value::Number(2.0)]), Ok(vec![]));
+
+    let error = codepoint(&[string("héllo"), Value::Number(3.0)]).unwrap_err();
+    assert!(error.to_string().contains("invalid UTF-8 code at position 3"), "{}", error);
+    let error = codepoint(&[string("abc"), Value::Number(1.0), Value::Number(4.0)]).unwrap_err();
+    assert!(error.to_string().contains("bad argument #3 to 'codepoint' (out of bounds)"), "{}", error);
+}
+
+#[test]
+fn test_len_reports_invalid_positions() {
+    let mut runtime = LuaJitRuntime::new();
+    let mut len = |args: &[Value]| luna::stdlib::utf8_len(&mut runtime, args);
+
+    assert_eq!(len(&[string("Hé€😀")]), Ok(numbers(&[4.0])));
+    assert_eq!(len(&[string("Hé€😀"), Value::Number(4.0)]), Ok(numbers(&[2.0])));
+    assert_eq!(len(&[string("Hé€😀"), Value::Number(-4.0)]), Ok(numbers(&[1.0])));
+    assert_eq!(len(&[string("")]), Ok(numbers(&[0.0])));
+
+    let invalid = Value::String(b"ab\xFFcd".to_vec().into());
+    assert_eq!(len(&[invalid]), Ok(vec![Value::Nil, Value::Number(3.0)]));
+    // Overlong encoding of '/'
+    let overlong = Value::String(b"\xC0\xAF".to_vec().into());
+    assert_eq!(len(&[overlong]), Ok(vec![Value::Nil, Value::Number(1.0)]));
+
+    let error = len(&[string("abc"), Value::Number(5.0)]).unwrap_err();
+    assert!(error.to_string().contains("bad argument #2 to 'len' (initial position out of bounds)"), "{}", error);
+}
+
+#[test]
+fn test_lax_accepts_surrogates_and_large_codes() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.execute("s = utf8.char(55296, 1114112)").unwrap();
+
+    assert_eq!(eval(&mut runtime, "utf8.len(s)"), Value::Nil);
+    assert_eq!(eval(&mut runtime, "utf8.len(s, 1, -1, true)"), Value::Number(2.0));
+    assert_eq!(eval(&mut runtime, "utf8.codepoint(s, 1, 1, true)"), Value::Number(55296.0));
+    assert!(runtime.execute("return utf8.codepoint(s)").is_err());
+}
+
+#[test]
+fn test_offset() {
+    let mut runtime = LuaJitRuntime::new();
+    runtime.execute("s = 'aé€😀'").unwrap();
+
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, 1)"), Value::Number(1.0));
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, 3)"), Value::Number(4.0));
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, 5)"), Value::Number(11.0));
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, 6)"), Value::Nil);
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, -1)"), Value::Number(7.0));
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, 0, 5)"), Value::Number(4.0));
+    assert_eq!(eval(&mut runtime, "utf8.offset(s, -2, 7)"), Value::Number(2.0));
+
+    let error = runtime.execute("return utf8.offset(s, 1, 3)").unwrap_err();
+    assert!(error.to_string().contains("initial position is a continuation byte"), "{}", error);
+}
+
+#[test]
+fn test_codes_iterates_positions_and_codes() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        result = ""
+        for p, c in utf8.codes("aé€") do
+            result = result .. p .. ":" .. c .. " "
+        end
+        return result
+    "#;
+    assert_eq!(runtime.execute(source).unwrap(), string("1:97 2:233 4:8364 "));
+
+    let source = r#"
+        for p, c in utf8.codes("ab\xFF") do
+        end
+    "#;
+    let error = runtime.execute(source).unwrap_err();
+    assert!(error.to_string().contains("invalid UTF-8 code at position 3"), "{}", error);
+}
@@ -0,0 +1,214 @@
+use luna::runtime::LuaJitRuntime;
+use luna::value::Value;
+
+fn count_entries(runtime: &mut LuaJitRuntime, table: &str) -> Value {
+    let source = format!(
+        r#"
+        count = 0
+        for k, v in pairs({}) do
+            count = count + 1
+        end
+        return count
+    "#,
+        table
+    );
+    runtime.execute(&source).unwrap()
+}
+
+#[test]
+fn test_pairs_visits_every_entry() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        t = { 10, 20, 30, name = "luna" }
+        t.name = nil
+        t[2] = nil
+        sum = 0
+        for k, v in pairs(t) do
+            sum = sum + k + v
+        end
+        return sum
+    "#;
+    let result = runtime.execute(source).unwrap();
+    assert_eq!(result, Value::Number(44.0));
+}
+
+#[test]
+fn test_table_keys_keep_their_type() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("t = {} t[1] = 'number' t['1'] = 'string'").unwrap();
+    assert_eq!(runtime.execute("return t[1]").unwrap(), Value::String("number".into()));
+    assert_eq!(runtime.execute("return t['1']").unwrap(), Value::String("string".into()));
+
+    runtime.execute("key = {} other = {} t[key] = 'key'").unwrap();
+    assert_eq!(runtime.execute("return t[key]").unwrap(), Value::String("key".into()));
+    assert_eq!(runtime.execute("return t[other]").unwrap(), Value::Nil);
+}
+
+#[test]
+fn test_weak_values_are_cleared() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "v" })
+        keep = {}
+        cache[1] = {}
+        cache[2] = keep
+        cache.name = "strings are never collected"
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return cache[1]").unwrap(), Value::Nil);
+    assert_eq!(runtime.execute("return cache[2] == keep").unwrap(), Value::Boolean(true));
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(2.0));
+}
+
+#[test]
+fn test_weak_keys_are_cleared() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "k" })
+        key = {}
+        cache[key] = 1
+        cache[{}] = 2
+        cache.name = 3
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return cache[key]").unwrap(), Value::Number(1.0));
+    assert_eq!(runtime.execute("return cache.name").unwrap(), Value::Number(3.0));
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(2.0));
+}
+
+#[test]
+fn test_ephemeron_value_referring_to_its_key_is_collected() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "k" })
+        local key = {}
+        cache[key] = { owner = key }
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(0.0));
+}
+
+#[test]
+fn test_ephemeron_chain_is_kept_through_live_key() {
+    let mut runtime = LuaJitRuntime::new();
+
+    // The entry for `second` comes first, so its key is only found to be
+    // alive after the entry for `first` has been traversed
+    let source = r#"
+        cache = setmetatable({}, { __mode = "k" })
+        local second = {}
+        first = {}
+        cache[second] = { value = 42 }
+        cache[first] = second
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(2.0));
+    let result = runtime.execute("return cache[cache[first]].value").unwrap();
+    assert_eq!(result, Value::Number(42.0));
+}
+
+#[test]
+fn test_value_reachable_from_elsewhere_keeps_weak_key_entry() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "k" })
+        holder = {}
+        local key = {}
+        cache[key] = "attached"
+        holder.key = key
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+    assert_eq!(
+        runtime.execute("return cache[holder.key]").unwrap(),
+        Value::String("attached".into())
+    );
+
+    runtime.execute("holder.key = nil").unwrap();
+    runtime.gc_collect();
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(0.0));
+}
+
+#[test]
+fn test_all_weak_table_clears_keys_and_values() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "kv" })
+        key = {}
+        value = {}
+        cache[key] = {}
+        cache[{}] = value
+        cache[key] = value
+        cache.name = "kept"
+        cache[1] = {}
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(runtime.execute("return cache[key] == value").unwrap(), Value::Boolean(true));
+    assert_eq!(runtime.execute("return cache[1]").unwrap(), Value::Nil);
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(2.0));
+}
+
+#[test]
+fn test_entries_cleared_during_traversal_are_skipped() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        cache = setmetatable({}, { __mode = "k" })
+        for i = 1, 5 do
+            cache[{}] = i
+        end
+        seen = 0
+        for k, v in pairs(cache) do
+            collectgarbage()
+            seen = seen + 1
+        end
+        return seen
+    "#;
+    // The first key stays alive while it is the loop's control variable
+    let result = runtime.execute(source).unwrap();
+    assert_eq!(result, Value::Number(1.0));
+}
+
+#[test]
+fn test_removing_weak_mode_makes_table_strong() {
+    let mut runtime = LuaJitRuntime::new();
+
+    let source = r#"
+        mt = { __mode = "v" }
+        cache = setmetatable({}, mt)
+        cache[1] = {}
+        mt.__mode = nil
+        cache[2] = {}
+    "#;
+    runtime.execute(source).unwrap();
+    runtime.gc_collect();
+
+    assert_eq!(count_entries(&mut runtime, "cache"), Value::Number(2.0));
+    assert_eq!(runtime.execute("return getmetatable(cache) == mt").unwrap(), Value::Boolean(true));
+}
+
+#[test]
+fn test_next_rejects_unknown_key() {
+    let mut runtime = LuaJitRuntime::new();
+
+    runtime.execute("t = { a = 1 }").unwrap();
+    assert!(runtime.execute("return next(t, 'missing')").is_err());
+    assert_eq!(runtime.execute("return next({})").unwrap(), Value::Nil);
+}